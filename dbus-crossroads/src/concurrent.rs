@@ -0,0 +1,310 @@
+use std::any::Any;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use dbus::channel::Sender;
+use crate::{ConnectionId, Connections, Crossroads, CredentialsLookup, Emitter, IfaceBuilder, IfaceToken, MethodErr, Middleware};
+use crate::crossroads::{BoxedSpawn, Object, Shared};
+
+enum Slot {
+    Here(Object),
+    /// Taken out by an instance handling a call, or reserved by one inserting the object.
+    Out,
+    /// Removed while others were waiting for it.
+    Gone,
+}
+
+struct Entry {
+    slot: Mutex<Slot>,
+    returned: Condvar,
+    /// The interfaces of the object when it was last given back, so that they can be
+    /// looked at without waiting.
+    ifaces: Mutex<HashSet<usize>>,
+}
+
+impl Entry {
+    fn new(slot: Slot) -> Arc<Self> {
+        Arc::new(Entry { slot: Mutex::new(slot), returned: Condvar::new(), ifaces: Default::default() })
+    }
+}
+
+/// The objects of a ConcurrentCrossroads, each behind its own lock.
+///
+/// An object is taken out of the tree while a call to it is handled, and given back afterwards.
+/// Others wanting the object wait until then.
+#[derive(Default)]
+pub (crate) struct Tree(RwLock<BTreeMap<dbus::Path<'static>, Arc<Entry>>>);
+
+impl fmt::Debug for Tree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tree({:?})", self.0.read().unwrap().keys().collect::<Vec<_>>())
+    }
+}
+
+impl Tree {
+    /// Takes the object out, waiting until it is given back if it is taken out already.
+    ///
+    /// Returns None if there is no such object. If `reserve` is true, the path is then reserved
+    /// for an object that will be given back later.
+    pub fn check_out(&self, path: &dbus::Path<'static>, reserve: bool) -> Option<Object> {
+        loop {
+            let entry = if reserve {
+                let mut map = self.0.write().unwrap();
+                match map.get(path) {
+                    Some(e) => e.clone(),
+                    None => { map.insert(path.clone(), Entry::new(Slot::Out)); return None },
+                }
+            } else {
+                self.0.read().unwrap().get(path)?.clone()
+            };
+            let mut slot = entry.slot.lock().unwrap();
+            loop {
+                match std::mem::replace(&mut *slot, Slot::Out) {
+                    Slot::Here(obj) => return Some(obj),
+                    Slot::Out => slot = entry.returned.wait(slot).unwrap(),
+                    Slot::Gone => { *slot = Slot::Gone; break },
+                }
+            }
+        }
+    }
+
+    /// Gives back an object that was taken out, or inserts one at a reserved path.
+    pub fn check_in(&self, path: &dbus::Path<'static>, obj: Object) {
+        let entry = match self.0.read().unwrap().get(path) { Some(e) => e.clone(), None => return };
+        *entry.ifaces.lock().unwrap() = obj.ifaces.clone();
+        *entry.slot.lock().unwrap() = Slot::Here(obj);
+        entry.returned.notify_all();
+    }
+
+    /// Removes the path of an object that was taken out.
+    pub fn forget(&self, path: &dbus::Path<'static>) {
+        let entry = match self.0.write().unwrap().remove(path) { Some(e) => e, None => return };
+        *entry.slot.lock().unwrap() = Slot::Gone;
+        entry.returned.notify_all();
+    }
+
+    pub fn contains(&self, path: &dbus::Path<'static>) -> bool {
+        self.0.read().unwrap().contains_key(path)
+    }
+
+    pub fn has_interface(&self, path: &dbus::Path<'static>, token: usize) -> bool {
+        self.0.read().unwrap().get(path).map(|e| e.ifaces.lock().unwrap().contains(&token)).unwrap_or(false)
+    }
+
+    /// The paths that sort after this one, i e, the ones below it, and then some.
+    pub fn paths_after(&self, path: &dbus::Path<'static>) -> Vec<dbus::Path<'static>> {
+        use std::ops::Bound;
+        let map = self.0.read().unwrap();
+        map.range::<dbus::Path<'static>, _>((Bound::Excluded(path), Bound::Unbounded)).map(|(p, _)| p.clone()).collect()
+    }
+}
+
+/// A tree of objects that can handle method calls to different objects at the same time.
+///
+/// A `Crossroads` instance needs `&mut self` to handle a message, so on a multi-threaded runtime
+/// every method call goes through one lock, and a slow handler blocks calls to all other objects.
+/// `ConcurrentCrossroads` is `Sync` instead: every object has its own lock, so calls to different
+/// objects are handled in parallel, while calls to the same object are handled one at a time.
+///
+/// Interfaces are registered with the usual `IfaceBuilder` API, during setup (i e, before the
+/// instance is shared between threads). When a method of an interface is called from several
+/// threads at once, the interface is built again to get another copy of the method handler,
+/// which is why the closure given to `register` is `Fn` rather than `FnOnce`.
+/// Objects can be inserted and removed at any time.
+///
+/// Every call is handled by a `Crossroads` instance that takes objects out of the shared tree as
+/// it uses them, and gives them back when the call has been handled. Method handlers that take the
+/// whole tree (e g `method_with_cr`) can therefore insert and remove objects, and get the data of
+/// other objects (waiting for calls to these to finish), and "GetManagedObjects" and ObjectManager
+/// signals work as usual. Two handlers waiting for each other's objects deadlock, like with any
+/// other pair of locks. Fallbacks are not supported.
+///
+/// # Example
+///
+/// ```
+/// use std::sync::Arc;
+/// use dbus_crossroads::ConcurrentCrossroads;
+///
+/// let mut cr = ConcurrentCrossroads::new();
+/// let token = cr.register("com.example.Counter", |b| {
+///     b.method("Increment", (), ("value",), |_, count: &mut u32, _: ()| {
+///         *count += 1;
+///         Ok((*count,))
+///     });
+/// });
+/// let cr = Arc::new(cr);
+/// cr.insert("/counter1", &[token], 0u32);
+/// cr.insert("/counter2", &[token], 0u32);
+/// // Now share cr between threads, and call cr.handle_message from each of them.
+/// ```
+pub struct ConcurrentCrossroads {
+    shared: Shared,
+}
+
+impl fmt::Debug for ConcurrentCrossroads {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Concurrent{:?}", self.shared.tree())
+    }
+}
+
+impl ConcurrentCrossroads {
+    /// Creates a new instance, containing only the root path.
+    pub fn new() -> Self {
+        let cr = ConcurrentCrossroads { shared: Shared::new() };
+        cr.insert("/", &[], ());
+        cr
+    }
+
+    /// An instance serving the tree, for handling one call or making one change.
+    fn instance(&self) -> Crossroads {
+        Crossroads::from_shared(self.shared.clone())
+    }
+
+    /// Changes the settings of the instances serving the tree.
+    fn configure<R, F: FnOnce(&mut Crossroads) -> R>(&mut self, f: F) -> R {
+        let mut cr = self.instance();
+        let r = f(&mut cr);
+        self.shared = cr.share();
+        r
+    }
+
+    /// Registers a new interface, like `Crossroads::register`.
+    ///
+    /// The closure is called again whenever another copy of the interface's handlers is needed,
+    /// to handle calls from several threads at once. State captured by the handlers is therefore
+    /// not shared between the copies, see `IfaceBuilder`.
+    pub fn register<T, N, F>(&mut self, name: N, f: F) -> IfaceToken<T>
+    where T: Send + 'static, N: Into<dbus::strings::Interface<'static>>,
+    F: Fn(&mut IfaceBuilder<T>) + Send + Sync + 'static
+    {
+        self.shared.register(name, f)
+    }
+
+    /// Inserts a new path, like `Crossroads::insert`.
+    ///
+    /// If the path already exists, it is overwritten, after waiting for a method call to the
+    /// object being handled to finish.
+    pub fn insert<'z, D, I, N>(&self, name: N, ifaces: I, data: D)
+    where D: Any + Send + 'static, N: Into<dbus::Path<'static>>, I: IntoIterator<Item = &'z IfaceToken<D>>
+    {
+        self.instance().insert(name, ifaces, data)
+    }
+
+    /// Removes an existing path, like `Crossroads::remove`.
+    ///
+    /// If a method call to the object is being handled, this waits until it has finished.
+    pub fn remove<D>(&self, name: &dbus::Path<'static>) -> Option<D>
+    where D: Any + Send + 'static {
+        let mut cr = self.instance();
+        let r = cr.remove(name);
+        if &**name == "/" { cr.insert("/", &[], ()) }
+        r
+    }
+
+    /// Calls f with the data of a path.
+    ///
+    /// Returns None if the path was not found, or if the data was of another type.
+    /// If a method call to the object is being handled, this waits until it has finished.
+    pub fn with_data<D, R, F>(&self, name: &dbus::Path<'static>, f: F) -> Option<R>
+    where D: Any + Send + 'static, F: FnOnce(&mut D) -> R {
+        self.instance().data_mut(name).map(f)
+    }
+
+    /// Handles an incoming message call, like `Crossroads::handle_message`.
+    ///
+    /// Only the objects used are locked while the message is handled, so this can be called
+    /// from several threads at once.
+    #[allow(clippy::result_unit_err)]
+    pub fn handle_message<S: Sender + ?Sized>(&self, message: dbus::Message, conn: &S) -> Result<(), ()> {
+        self.instance().handle_message(message, conn)
+    }
+
+    /// Handles an incoming message that arrived on an attached connection, like
+    /// `Crossroads::handle_message_on`.
+    #[allow(clippy::result_unit_err)]
+    pub fn handle_message_on(&self, message: dbus::Message, id: ConnectionId) -> Result<(), ()> {
+        self.instance().handle_message_on(message, id)
+    }
+
+    /// Attaches a connection, like `Crossroads::attach_connection`.
+    pub fn attach_connection(&self, sender: Arc<dyn Sender + Send + Sync + 'static>) -> ConnectionId {
+        self.instance().attach_connection(sender)
+    }
+
+    /// Detaches a connection. Returns false if there was no such connection.
+    pub fn detach_connection(&self, id: ConnectionId) -> bool {
+        self.instance().detach_connection(id)
+    }
+
+    /// Sets whether signals are sent on an attached connection, like `Crossroads::set_connection_signals`.
+    pub fn set_connection_signals(&self, id: ConnectionId, enabled: bool) -> bool {
+        self.instance().set_connection_signals(id, enabled)
+    }
+
+    /// The attached connections.
+    pub fn connections(&self) -> Connections { self.instance().connections() }
+
+    /// Enables asynchronous methods, like `Crossroads::set_async_support`.
+    pub fn set_async_support(&mut self, x: Option<(Arc<dyn Sender + Send + Sync + 'static>, BoxedSpawn)>) {
+        self.configure(move |cr| { cr.set_async_support(x); });
+    }
+
+    /// Enables ObjectManager signals, like `Crossroads::set_object_manager_support`.
+    pub fn set_object_manager_support(&mut self, x: Option<Arc<dyn Sender + Send + Sync + 'static>>) {
+        self.configure(move |cr| { cr.set_object_manager_support(x); });
+    }
+
+    /// The token representing the built-in implementation of "org.freedesktop.DBus.ObjectManager".
+    pub fn object_manager<T: Send + 'static>(&self) -> IfaceToken<T> { self.instance().object_manager() }
+
+    /// Adds middleware that sees every method call, like `Crossroads::add_middleware`.
+    pub fn add_middleware<M: Middleware>(&mut self, middleware: M) {
+        self.configure(move |cr| cr.add_middleware(middleware));
+    }
+
+    /// Sets the function used to look up the credentials of callers, like
+    /// `Crossroads::set_credentials_lookup`.
+    ///
+    /// The credentials are cached for all objects together. Calls waiting for a non-blocking
+    /// lookup are dispatched from a spawned task, so set async support first.
+    pub fn set_credentials_lookup(&mut self, lookup: Option<CredentialsLookup>) {
+        self.configure(move |cr| cr.set_credentials_lookup(lookup));
+    }
+
+    /// Sets the credentials lookup for callers on an attached connection, like
+    /// `Crossroads::set_connection_credentials_lookup`.
+    pub fn set_connection_credentials_lookup(&self, id: ConnectionId, lookup: Option<CredentialsLookup>) -> bool {
+        self.instance().set_connection_credentials_lookup(id, lookup)
+    }
+
+    /// Sets the polkit authority, like `Crossroads::set_polkit_authority`.
+    ///
+    /// Decisions are cached for all objects together. Like for credentials lookups, set async
+    /// support first if the authority is non-blocking.
+    #[cfg(feature = "polkit")]
+    pub fn set_polkit_authority(&mut self, authority: Option<Box<dyn crate::polkit::Authority>>) {
+        self.configure(move |cr| cr.set_polkit_authority(authority));
+    }
+
+    /// Sets the polkit authority for callers on an attached connection, like
+    /// `Crossroads::set_connection_polkit_authority`.
+    #[cfg(feature = "polkit")]
+    pub fn set_connection_polkit_authority(&self, id: ConnectionId, authority: Option<Box<dyn crate::polkit::Authority>>) -> bool {
+        self.instance().set_connection_polkit_authority(id, authority)
+    }
+
+    /// Returns an emitter, which sends the signals declared in this instance through this connection.
+    pub fn emitter(&self, sender: Arc<dyn Sender + Send + Sync + 'static>) -> Emitter {
+        self.instance().emitter(sender)
+    }
+
+    /// Sends the PropertiesChanged signals queued by property cells, like
+    /// `Crossroads::flush_property_changes`.
+    pub fn flush_property_changes<S: Sender + ?Sized>(&self, conn: &S) -> Result<(), MethodErr> {
+        self.instance().flush_property_changes(conn)
+    }
+}
+
+impl Default for ConcurrentCrossroads {
+    fn default() -> Self { Self::new() }
+}
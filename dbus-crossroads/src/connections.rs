@@ -0,0 +1,110 @@
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use dbus::channel::Sender;
+
+/// Identifies a connection attached to a Crossroads instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(usize);
+
+struct Attached {
+    id: ConnectionId,
+    sender: Arc<dyn Sender + Send + Sync + 'static>,
+    signals: bool,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: usize,
+    list: Vec<Attached>,
+}
+
+/// The connections attached to a Crossroads instance, see `Crossroads::attach_connection`.
+///
+/// Sending a message through this sends a copy of it on every attached connection that
+/// receives signals, or, if made with `only`, on the selected connections.
+/// It can be used wherever a sender for signals is expected, e g with `Crossroads::emitter`
+/// or `Crossroads::set_object_manager_support`.
+#[derive(Clone, Default)]
+pub struct Connections {
+    inner: Arc<RwLock<Inner>>,
+    only: Option<Arc<[ConnectionId]>>,
+}
+
+impl fmt::Debug for Connections {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Connections({:?})", self.ids())
+    }
+}
+
+impl Connections {
+    /// Returns connections that send on the selected connections only.
+    ///
+    /// Connections selected this way receive messages even if they do not receive signals.
+    pub fn only(&self, ids: &[ConnectionId]) -> Connections {
+        Connections { inner: self.inner.clone(), only: Some(ids.into()) }
+    }
+
+    /// The connections that messages are sent on.
+    pub fn ids(&self) -> Vec<ConnectionId> {
+        self.inner.read().unwrap().list.iter().filter(|a| self.selects(a)).map(|a| a.id).collect()
+    }
+
+    fn selects(&self, a: &Attached) -> bool {
+        match &self.only {
+            Some(only) => only.contains(&a.id),
+            None => a.signals,
+        }
+    }
+
+    pub (crate) fn attach(&self, sender: Arc<dyn Sender + Send + Sync + 'static>) -> ConnectionId {
+        let mut inner = self.inner.write().unwrap();
+        let id = ConnectionId(inner.next_id);
+        inner.next_id += 1;
+        inner.list.push(Attached { id, sender, signals: true });
+        id
+    }
+
+    pub (crate) fn detach(&self, id: ConnectionId) -> bool {
+        let mut inner = self.inner.write().unwrap();
+        let len = inner.list.len();
+        inner.list.retain(|a| a.id != id);
+        len != inner.list.len()
+    }
+
+    pub (crate) fn set_signals(&self, id: ConnectionId, enabled: bool) -> bool {
+        let mut inner = self.inner.write().unwrap();
+        inner.list.iter_mut().find(|a| a.id == id).map(|a| a.signals = enabled).is_some()
+    }
+
+    pub (crate) fn get(&self, id: ConnectionId) -> Option<Arc<dyn Sender + Send + Sync + 'static>> {
+        self.inner.read().unwrap().list.iter().find(|a| a.id == id).map(|a| a.sender.clone())
+    }
+
+    pub (crate) fn is_empty(&self) -> bool { self.inner.read().unwrap().list.is_empty() }
+}
+
+impl Sender for Connections {
+    /// Sends the message on every selected connection.
+    ///
+    /// Returns the serial of the message on the last connection, or Err if sending on any of
+    /// the connections failed.
+    fn send(&self, msg: dbus::Message) -> Result<u32, ()> {
+        let senders: Vec<_> = self.inner.read().unwrap().list.iter()
+            .filter(|a| self.selects(a)).map(|a| a.sender.clone()).collect();
+        let mut msg = Some(msg);
+        let mut r = Ok(0);
+        for (i, sender) in senders.iter().enumerate() {
+            let m = if i + 1 == senders.len() { msg.take().unwrap() } else {
+                match msg.as_ref().unwrap().duplicate() {
+                    Ok(m) => m,
+                    Err(_) => { r = Err(()); continue },
+                }
+            };
+            match sender.send(m) {
+                Ok(serial) => if r.is_ok() { r = Ok(serial) },
+                Err(()) => r = Err(()),
+            }
+        }
+        r
+    }
+}
@@ -1,8 +1,13 @@
 use std::marker::PhantomData;
 use dbus::arg::AppendAll;
 use dbus::channel::Sender;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
 use std::sync::Arc;
-use crate::{MethodErr, utils::Dbg};
+use std::future::Future;
+use crate::{ConnectionId, Credentials, MethodErr, Middleware, utils::Dbg};
+use crate::credentials::CredentialsCache;
+use crate::emitter::{coalesce, EmitInfo};
 
 /// Context is the struct that accompanies you through your method call handler,
 /// providing helpful information about the message sent from the client, as well as
@@ -18,6 +23,13 @@ pub struct Context {
     reply: Option<dbus::Message>,
     send_extra: Vec<dbus::Message>,
     send_on_drop: Option<Dbg<Arc<dyn Sender + Send + Sync>>>,
+    signal_sender: Option<Dbg<Arc<dyn Sender + Send + Sync>>>,
+    middleware: Dbg<Vec<Arc<dyn Middleware>>>,
+    extensions: Dbg<HashMap<TypeId, Box<dyn Any + Send>>>,
+    credentials: Option<Arc<CredentialsCache>>,
+    connection: Option<ConnectionId>,
+    emit_info: Option<EmitInfo>,
+    iface_token: Option<usize>,
 }
 
 impl Context {
@@ -36,8 +48,15 @@ impl Context {
             message: msg,
             reply: None,
             send_on_drop: None,
+            signal_sender: None,
             send_extra: vec!(),
             has_error: false,
+            middleware: Dbg(vec!()),
+            extensions: Dbg(HashMap::new()),
+            credentials: None,
+            connection: None,
+            emit_info: None,
+            iface_token: None,
         })
     }
 
@@ -98,11 +117,22 @@ impl Context {
     ///
     /// This is called internally, you should probably not use it.
     pub fn flush_messages<S: dbus::channel::Sender + ?Sized>(&mut self, conn: &S) -> Result<(), ()> {
+        let middleware = std::mem::take(&mut self.middleware.0);
+        for m in middleware.iter().rev() {
+            m.after(self, self.reply.as_ref());
+        }
         if let Some(msg) = self.reply.take() {
             conn.send(msg)?;
         }
+        if let Some(info) = &self.emit_info {
+            let msgs = std::mem::take(&mut self.send_extra);
+            self.send_extra = coalesce(&info.read().unwrap(), msgs);
+        }
         for msg in self.send_extra.drain(..) {
-            conn.send(msg)?;
+            match &self.signal_sender {
+                Some(s) if msg.msg_type() == dbus::MessageType::Signal && msg.destination().is_none() => s.0.send(msg)?,
+                _ => conn.send(msg)?,
+            };
         }
         Ok(())
     }
@@ -116,6 +146,9 @@ impl Context {
     }
 
     /// Adds an extra message to send together with the message reply, e g, a custom signal.
+    ///
+    /// When dispatched by Crossroads, PropertiesChanged signals for the same path, interface and
+    /// destination are merged into one before they are sent.
     pub fn push_msg(&mut self, msg: dbus::Message) { self.send_extra.push(msg); }
 
     /// The current object path.
@@ -139,9 +172,68 @@ impl Context {
     /// Returns true is "reply_err" has been called, or "check" ever returned an error
     pub fn has_error(&self) -> bool { self.has_error }
 
+    /// Stores a value for the duration of this method call, replacing any earlier value of the same type.
+    ///
+    /// This is mostly useful for middleware, to pass state from `before` to `after`, or to the method handler.
+    pub fn insert_extension<T: Any + Send>(&mut self, value: T) {
+        self.extensions.0.insert(TypeId::of::<T>(), Box::new(value));
+    }
+
+    /// Returns a value stored with `insert_extension`.
+    pub fn extension<T: Any + Send>(&self) -> Option<&T> {
+        self.extensions.0.get(&TypeId::of::<T>()).and_then(|x| x.downcast_ref())
+    }
+
+    /// Looks up the credentials of the caller, or returns them from the cache.
+    ///
+    /// This requires a lookup to be set with `Crossroads::set_credentials_lookup`, or, for calls
+    /// arriving on an attached connection, `Crossroads::set_connection_credentials_lookup`.
+    /// The returned future does not borrow the context, so it can be awaited in async methods.
+    pub fn credentials(&self) -> impl Future<Output = Result<Arc<Credentials>, MethodErr>> + Send + 'static {
+        let r = match (&self.credentials, self.message.sender()) {
+            (Some(cache), Some(sender)) => Ok(cache.get(self.connection, sender.into_static())),
+            (None, _) => Err(MethodErr::failed("No credentials lookup set")),
+            (_, None) => Err(MethodErr::failed("Caller is unknown")),
+        };
+        async move { r?.await }
+    }
+
+    /// Returns the credentials of the caller, if they have been looked up already.
+    ///
+    /// This is the case if the method has an access policy whose lookup has completed.
+    pub fn cached_credentials(&self) -> Option<Arc<Credentials>> {
+        let sender = self.message.sender()?;
+        self.credentials.as_ref()?.cached(self.connection, &sender)
+    }
+
+    pub (crate) fn set_credentials(&mut self, value: Option<Arc<CredentialsCache>>, connection: Option<ConnectionId>) {
+        self.credentials = value;
+        self.connection = connection;
+    }
+
+    pub (crate) fn set_emit_info(&mut self, value: EmitInfo) {
+        self.emit_info = Some(value);
+    }
+
+    pub (crate) fn set_middleware(&mut self, value: Vec<Arc<dyn Middleware>>) {
+        self.middleware.0 = value;
+    }
+
     pub (crate) fn set_send_on_drop(&mut self, value: Arc<dyn Sender + Send + Sync>) {
         self.send_on_drop = Some(Dbg(value));
     }
+
+    /// The interface being called, once it is known.
+    pub (crate) fn iface_token(&self) -> Option<usize> { self.iface_token }
+
+    pub (crate) fn set_iface_token(&mut self, token: usize) {
+        self.iface_token = Some(token);
+    }
+
+    /// Signals without a destination are sent here instead of on the connection the reply is sent on.
+    pub (crate) fn set_signal_sender(&mut self, value: Option<Arc<dyn Sender + Send + Sync>>) {
+        self.signal_sender = value.map(Dbg);
+    }
 }
 
 impl Drop for Context {
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{self, Poll, Wake};
+use std::time::Duration;
+use std::fmt;
+use dbus::arg::{prop_cast, PropMap};
+use dbus::strings::BusName;
+use crate::{ConnectionId, MethodErr};
+
+/// Credentials of the connection that sent a message, as reported by the bus.
+///
+/// Fields are None if the bus did not report them, e g, because the platform does not
+/// support them.
+#[derive(Debug, Default)]
+pub struct Credentials {
+    /// The unix user id.
+    pub uid: Option<u32>,
+    /// The unix group ids, including the primary group.
+    pub groups: Option<Vec<u32>>,
+    /// The process id.
+    pub pid: Option<u32>,
+    /// A file descriptor referring to the process (Linux pidfd).
+    ///
+    /// Unlike `pid`, this cannot be recycled if the process exits.
+    pub pidfd: Option<std::fs::File>,
+    /// The security label, e g the SELinux context or AppArmor profile.
+    pub security_label: Option<Vec<u8>>,
+}
+
+impl Credentials {
+    /// Creates credentials from the reply to "org.freedesktop.DBus.GetConnectionCredentials".
+    pub fn from_prop_map(map: &PropMap) -> Self {
+        let mut security_label = prop_cast::<Vec<u8>>(map, "LinuxSecurityLabel").cloned();
+        // The label is nul terminated
+        if let Some(label) = &mut security_label {
+            if label.last() == Some(&0) { label.pop(); }
+        }
+        Credentials {
+            uid: prop_cast(map, "UnixUserID").copied(),
+            groups: prop_cast(map, "UnixGroupIDs").cloned(),
+            pid: prop_cast(map, "ProcessID").copied(),
+            pidfd: prop_cast::<std::fs::File>(map, "ProcessFD").and_then(|f| f.try_clone().ok()),
+            security_label,
+        }
+    }
+
+    /// Returns a lookup that asks the bus for credentials, using a blocking connection.
+    ///
+    /// The returned future makes a blocking call when first polled, so it finishes at once. This
+    /// lets methods and properties with access policies check new callers too, at the cost of
+    /// blocking the thread handling the message for a round trip to the bus.
+    ///
+    /// The connection must be a separate connection to the same bus as the one the
+    /// Crossroads instance is serving, so that it can be used while a message is being handled.
+    pub fn blocking_lookup(conn: Arc<dbus::blocking::SyncConnection>, timeout: Duration) -> CredentialsLookup {
+        CredentialsLookup::blocking(move |name| {
+            let conn = conn.clone();
+            Box::pin(async move {
+                let proxy = conn.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", timeout);
+                let (map,): (PropMap,) = proxy.method_call("org.freedesktop.DBus", "GetConnectionCredentials", (&*name,))?;
+                Ok(Credentials::from_prop_map(&map))
+            })
+        })
+    }
+
+    /// Returns a lookup that asks the bus for credentials, using a non-blocking connection.
+    ///
+    /// The lookup does not block, but since the answer arrives later, calls to methods and properties
+    /// with access policies wait for it in a spawned task. This needs async support, see
+    /// `Crossroads::set_credentials_lookup`.
+    #[cfg(feature = "futures")]
+    pub fn nonblock_lookup(conn: Arc<dbus::nonblock::SyncConnection>, timeout: Duration) -> CredentialsLookup {
+        CredentialsLookup::nonblock(move |name| {
+            let proxy = dbus::nonblock::Proxy::new("org.freedesktop.DBus", "/org/freedesktop/DBus", timeout, conn.clone());
+            Box::pin(async move {
+                let (map,): (PropMap,) = proxy.method_call("org.freedesktop.DBus", "GetConnectionCredentials", (&*name,)).await?;
+                Ok(Credentials::from_prop_map(&map))
+            })
+        })
+    }
+}
+
+type LookupFn = Box<dyn Fn(BusName<'static>) -> Pin<Box<dyn Future<Output = Result<Credentials, MethodErr>> + Send + 'static>> + Send + Sync + 'static>;
+
+/// Looks up the credentials of a unique connection name, see `Crossroads::set_credentials_lookup`.
+pub struct CredentialsLookup {
+    f: LookupFn,
+    nonblock: bool,
+}
+
+impl CredentialsLookup {
+    /// Creates a lookup whose futures finish when first polled, e g because they make a blocking call.
+    pub fn blocking<F>(f: F) -> Self
+    where F: Fn(BusName<'static>) -> Pin<Box<dyn Future<Output = Result<Credentials, MethodErr>> + Send + 'static>> + Send + Sync + 'static {
+        CredentialsLookup { f: Box::new(f), nonblock: false }
+    }
+
+    /// Creates a lookup whose futures may finish later, which needs async support.
+    pub fn nonblock<F>(f: F) -> Self
+    where F: Fn(BusName<'static>) -> Pin<Box<dyn Future<Output = Result<Credentials, MethodErr>> + Send + 'static>> + Send + Sync + 'static {
+        CredentialsLookup { f: Box::new(f), nonblock: true }
+    }
+
+    /// Returns true if this lookup was created with `nonblock`.
+    pub fn is_nonblock(&self) -> bool { self.nonblock }
+
+    /// Looks up the credentials of the connection with this unique name.
+    pub fn lookup(&self, name: BusName<'static>) -> Pin<Box<dyn Future<Output = Result<Credentials, MethodErr>> + Send + 'static>> {
+        (self.f)(name)
+    }
+}
+
+impl fmt::Debug for CredentialsLookup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "CredentialsLookup {{ nonblock: {} }}", self.nonblock) }
+}
+
+/// An access policy for a method or property.
+///
+/// This is a closure deciding whether a caller with the given credentials is allowed access.
+/// Any `Fn(&Credentials) -> bool` can be used where an `Access` is expected.
+#[derive(Clone)]
+pub struct Access(Arc<dyn Fn(&Credentials) -> bool + Send + Sync + 'static>);
+
+impl Access {
+    /// Allows the root user only.
+    pub fn root() -> Self { Self::uid(0) }
+
+    /// Allows this unix user only.
+    pub fn uid(uid: u32) -> Self {
+        Access::from(move |c: &Credentials| c.uid == Some(uid))
+    }
+
+    /// Allows members of this unix group.
+    pub fn group(gid: u32) -> Self {
+        Access::from(move |c: &Credentials| c.groups.as_ref().map(|g| g.contains(&gid)).unwrap_or(false))
+    }
+
+    /// Allows callers that either policy allows.
+    pub fn or(self, other: Access) -> Self {
+        Access::from(move |c: &Credentials| self.allows(c) || other.allows(c))
+    }
+
+    /// Returns true if the caller with these credentials is allowed access.
+    pub fn allows(&self, c: &Credentials) -> bool { (self.0)(c) }
+}
+
+impl<F: Fn(&Credentials) -> bool + Send + Sync + 'static> From<F> for Access {
+    fn from(f: F) -> Self { Access(Arc::new(f)) }
+}
+
+impl fmt::Debug for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Access") }
+}
+
+/// The lookups, and the credentials looked up so far, keyed by connection and unique connection name.
+///
+/// Calls that did not arrive on an attached connection use the connection None.
+#[derive(Default)]
+pub (crate) struct CredentialsCache {
+    lookups: Mutex<HashMap<Option<ConnectionId>, Arc<CredentialsLookup>>>,
+    cache: Mutex<HashMap<CacheKey, Arc<Credentials>>>,
+}
+
+type CacheKey = (Option<ConnectionId>, String);
+
+impl fmt::Debug for CredentialsCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "CredentialsCache") }
+}
+
+impl CredentialsCache {
+    /// Sets the lookup for the connection, forgetting what the old one looked up.
+    pub fn set_lookup(&self, conn: Option<ConnectionId>, lookup: Option<CredentialsLookup>) {
+        let mut lookups = self.lookups.lock().unwrap();
+        match lookup {
+            Some(l) => lookups.insert(conn, Arc::new(l)),
+            None => lookups.remove(&conn),
+        };
+        self.cache.lock().unwrap().retain(|k, _| k.0 != conn);
+    }
+
+    pub fn has_lookup(&self, conn: Option<ConnectionId>) -> bool {
+        self.lookups.lock().unwrap().contains_key(&conn)
+    }
+
+    pub fn cached(&self, conn: Option<ConnectionId>, name: &str) -> Option<Arc<Credentials>> {
+        self.cache.lock().unwrap().get(&(conn, name.into())).cloned()
+    }
+
+    pub fn get(self: &Arc<Self>, conn: Option<ConnectionId>, name: BusName<'static>) -> impl Future<Output = Result<Arc<Credentials>, MethodErr>> + Send + 'static {
+        let this = self.clone();
+        async move {
+            if let Some(c) = this.cached(conn, &name) { return Ok(c) }
+            let lookup = this.lookups.lock().unwrap().get(&conn).cloned();
+            let lookup = lookup.ok_or_else(|| MethodErr::failed("No credentials lookup set"))?;
+            let c = Arc::new(lookup.lookup(name.clone()).await?);
+            this.cache.lock().unwrap().insert((conn, name.to_string()), c.clone());
+            Ok(c)
+        }
+    }
+
+    pub fn remove(&self, conn: Option<ConnectionId>, name: &str) {
+        self.cache.lock().unwrap().remove(&(conn, name.into()));
+    }
+}
+
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+/// Polls the future once, without waiting. Returns None if it is not ready yet.
+///
+/// The future can be polled again later, e g by awaiting it in a task.
+pub (crate) fn poll_now<F: Future + Unpin>(f: &mut F) -> Option<F::Output> {
+    let waker = Arc::new(NoopWaker).into();
+    let mut cx = task::Context::from_waker(&waker);
+    match Pin::new(f).poll(&mut cx) {
+        Poll::Ready(r) => Some(r),
+        Poll::Pending => None,
+    }
+}
@@ -3,9 +3,14 @@ use std::sync::Arc;
 use dbus::channel::{Sender, default_reply};
 use std::future::Future;
 use std::marker::PhantomData;
-use crate::{Context, MethodErr, IfaceBuilder, stdimpl};
-use crate::ifacedesc::Registry;
-use std::collections::{BTreeMap, HashSet};
+use crate::{Access, Context, CredentialsLookup, MethodErr, Middleware, IfaceBuilder, stdimpl};
+use crate::credentials::{poll_now, CredentialsCache};
+use crate::concurrent::Tree;
+use crate::connections::{ConnectionId, Connections};
+use crate::emitter::{self, Emitter, EmitInfo};
+use crate::ifacedesc::{Guard, IfaceDesc, Registry};
+use crate::property::{ChangeQueue, Link};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::any::Any;
 use std::fmt;
 use crate::utils::Dbg;
@@ -15,7 +20,7 @@ const PROPERTIES: usize = 1;
 const OBJECT_MANAGER: usize = 2;
 
 /// Contains a reference to a registered interface.
-pub struct IfaceToken<T: Send + 'static>(usize, PhantomData<&'static T>);
+pub struct IfaceToken<T: Send + 'static>(pub (crate) usize, PhantomData<&'static T>);
 
 impl<T: Send + 'static> Clone for IfaceToken<T> {
     fn clone(&self) -> Self { IfaceToken(self.0, PhantomData) }
@@ -39,18 +44,80 @@ impl<T: Send + 'static> fmt::Debug for IfaceToken<T> {
 
 
 #[derive(Debug)]
-struct Object {
-    ifaces: HashSet<usize>,
-    data: Box<dyn Any + Send + 'static>
+pub (crate) struct Object {
+    pub (crate) ifaces: HashSet<usize>,
+    data: Box<dyn Any + Send + 'static>,
+    // Data of interfaces that have their own, instead of sharing the object's
+    iface_data: HashMap<usize, Box<dyn Any + Send + 'static>>,
+    // Inserted by a fallback resolver, for the duration of a call only
+    temporary: bool,
 }
 
-pub type BoxedSpawn = Box<dyn Fn(Pin<Box<dyn Future<Output = ()> + Send + 'static>>) + Send + 'static>;
+type Resolver = Box<dyn FnMut(&dbus::Path<'static>) -> Option<(Vec<usize>, Box<dyn Any + Send + 'static>)> + Send + 'static>;
+type Enumerator = Box<dyn FnMut(&dbus::Path<'static>) -> Vec<String> + Send + 'static>;
 
+#[derive(Debug)]
+struct Fallback {
+    prefix: dbus::Path<'static>,
+    resolver: Dbg<Resolver>,
+    children: Dbg<Enumerator>,
+}
+
+fn denied(s: &str) -> MethodErr { dbus::StandardError::AccessDenied(s.into()).into() }
+
+pub (crate) fn is_name_owner_changed(msg: &dbus::Message) -> bool {
+    msg.msg_type() == dbus::MessageType::Signal
+        && msg.sender().as_deref() == Some("org.freedesktop.DBus")
+        && msg.interface().as_deref() == Some("org.freedesktop.DBus")
+        && msg.member().as_deref() == Some("NameOwnerChanged")
+}
+
+// True if path is prefix, or below it
+fn is_in_subtree(path: &str, prefix: &str) -> bool {
+    prefix == "/" || path == prefix || (path.starts_with(prefix) && path.as_bytes()[prefix.len()] == b'/')
+}
+
+fn join_path(parent: &str, child: &str) -> Option<dbus::Path<'static>> {
+    let sep = if parent.ends_with('/') { "" } else { "/" };
+    dbus::Path::new(format!("{}{}{}", parent, sep, child)).ok()
+}
+
+pub (crate) type MethodSig = (dbus::Path<'static>, dbus::strings::Interface<'static>, dbus::strings::Member<'static>, String, String);
+
+/// Spawns a future as a new task.
+///
+/// The spawner is shared between the instances serving a `ConcurrentCrossroads`, and may be
+/// called from several threads at once.
+pub type BoxedSpawn = Box<dyn Fn(Pin<Box<dyn Future<Output = ()> + Send + 'static>>) + Send + Sync + 'static>;
+
+#[derive(Clone)]
 struct AsyncSupport {
     sender: Arc<dyn Sender + Send + Sync + 'static>,
-    spawner: BoxedSpawn,
+    // Shared by the instances serving a ConcurrentCrossroads tree
+    spawner: Arc<BoxedSpawn>,
 }
 
+impl AsyncSupport {
+    fn spawn(&self, f: Pin<Box<dyn Future<Output = ()> + Send + 'static>>) { (self.spawner)(f) }
+}
+
+type CheckFuture = Pin<Box<dyn Future<Output = Result<(), MethodErr>> + Send + 'static>>;
+
+/// A method call that waited for its caller to be checked, see `Crossroads::set_deferred_dispatch`.
+pub struct Deferred {
+    ctx: Context,
+    sender: Arc<dyn Sender + Send + Sync + 'static>,
+    connection: Option<ConnectionId>,
+}
+
+impl fmt::Debug for Deferred {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Deferred({:?})", self.ctx.message()) }
+}
+
+/// Hands a method call that waited for its caller to be checked back to the instance, see
+/// `Crossroads::set_deferred_dispatch`.
+pub type DeferredDispatch = Box<dyn Fn(Deferred) + Send + Sync + 'static>;
+
 impl fmt::Debug for AsyncSupport {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "AsyncSupport") }
 }
@@ -66,10 +133,87 @@ impl fmt::Debug for AsyncSupport {
 #[derive(Debug)]
 pub struct Crossroads {
     map: BTreeMap<dbus::Path<'static>, Object>,
-    registry: Registry,
+    registry: Arc<Registry>,
+    add_standard_ifaces: bool,
+    async_support: Option<AsyncSupport>,
+    object_manager_support: Option<Dbg<Arc<dyn Sender + Send + Sync + 'static>>>,
+    fallbacks: Vec<Fallback>,
+    middleware: Dbg<Vec<Arc<dyn Middleware>>>,
+    credentials: Arc<CredentialsCache>,
+    caller: Option<dbus::strings::BusName<'static>>,
+    // The attached connection the call being dispatched arrived on
+    connection: Option<ConnectionId>,
+    #[cfg(feature = "polkit")]
+    allow_interaction: bool,
+    deferred_dispatch: Option<Dbg<Arc<DeferredDispatch>>>,
+    // The call being dispatched has been let past its guard already
+    admitted: bool,
+    emit_info: EmitInfo,
+    changes: ChangeQueue,
+    connections: Connections,
+    reply_sender: Option<Dbg<Arc<dyn Sender + Send + Sync + 'static>>>,
+    #[cfg(feature = "polkit")]
+    polkit: Arc<crate::polkit::AuthorityCache>,
+    // Where objects are taken from, when serving a ConcurrentCrossroads
+    tree: Option<Arc<Tree>>,
+}
+
+/// The parts of a Crossroads instance that the instances serving a ConcurrentCrossroads share.
+#[derive(Clone)]
+pub (crate) struct Shared {
+    registry: Arc<Registry>,
     add_standard_ifaces: bool,
     async_support: Option<AsyncSupport>,
     object_manager_support: Option<Dbg<Arc<dyn Sender + Send + Sync + 'static>>>,
+    middleware: Dbg<Vec<Arc<dyn Middleware>>>,
+    credentials: Arc<CredentialsCache>,
+    emit_info: EmitInfo,
+    changes: ChangeQueue,
+    connections: Connections,
+    #[cfg(feature = "polkit")]
+    polkit: Arc<crate::polkit::AuthorityCache>,
+    tree: Arc<Tree>,
+}
+
+impl Shared {
+    /// Creates the shared parts of a new instance, with an empty tree.
+    pub fn new() -> Self {
+        let mut cr = Crossroads::new();
+        cr.map.clear();
+        cr.tree = Some(Default::default());
+        cr.share()
+    }
+
+    /// Registers an interface, which is built again when needed to handle calls from several threads at once.
+    ///
+    /// # Panics
+    ///
+    /// If an instance made with `Crossroads::from_shared` still exists.
+    pub fn register<T, N, F>(&mut self, name: N, f: F) -> IfaceToken<T>
+    where T: Send + 'static, N: Into<dbus::strings::Interface<'static>>,
+    F: Fn(&mut IfaceBuilder<T>) + Send + Sync + 'static
+    {
+        let iface = IfaceBuilder::build_shared(Some(name.into()), f);
+        IfaceToken(push_iface(&mut self.registry, &self.emit_info, iface), PhantomData)
+    }
+
+    pub fn tree(&self) -> &Tree { &self.tree }
+}
+
+fn push_iface(registry: &mut Arc<Registry>, emit_info: &EmitInfo, iface: IfaceDesc) -> usize {
+    let registry = Arc::get_mut(registry).expect("Interfaces can not be registered while the registry is shared");
+    let x = registry.push(iface);
+    emit_info.write().unwrap().push(registry.emit_info(x));
+    x
+}
+
+impl Drop for Crossroads {
+    fn drop(&mut self) {
+        // Gives back the objects taken out of the tree
+        if let Some(tree) = &self.tree {
+            for (path, obj) in std::mem::take(&mut self.map) { tree.check_in(&path, obj) }
+        }
+    }
 }
 
 impl Crossroads {
@@ -81,6 +225,22 @@ impl Crossroads {
             add_standard_ifaces: true,
             async_support: None,
             object_manager_support: None,
+            fallbacks: vec!(),
+            middleware: Dbg(vec!()),
+            credentials: Default::default(),
+            caller: None,
+            connection: None,
+            #[cfg(feature = "polkit")]
+            allow_interaction: false,
+            deferred_dispatch: None,
+            admitted: false,
+            emit_info: Default::default(),
+            changes: Default::default(),
+            connections: Default::default(),
+            reply_sender: None,
+            #[cfg(feature = "polkit")]
+            polkit: Default::default(),
+            tree: None,
         };
         let t0 = stdimpl::introspectable(&mut cr);
         let t1 = stdimpl::properties(&mut cr);
@@ -107,36 +267,523 @@ impl Crossroads {
     F: FnOnce(&mut IfaceBuilder<T>)
     {
         let iface = IfaceBuilder::build(Some(name.into()), f);
-        let x = self.registry.push(iface);
-        IfaceToken(x, PhantomData)
+        IfaceToken(push_iface(&mut self.registry, &self.emit_info, iface), PhantomData)
+    }
+
+    /// Like register, but the interface can be built again, see `Shared::register`.
+    pub (crate) fn register_shared<T, N, F>(&mut self, name: N, f: F) -> IfaceToken<T>
+    where T: Send + 'static, N: Into<dbus::strings::Interface<'static>>,
+    F: Fn(&mut IfaceBuilder<T>) + Send + Sync + 'static
+    {
+        let iface = IfaceBuilder::build_shared(Some(name.into()), f);
+        IfaceToken(push_iface(&mut self.registry, &self.emit_info, iface), PhantomData)
+    }
+
+    /// Creates an instance serving the shared tree, which objects are taken out of when used,
+    /// and given back when the instance is dropped.
+    pub (crate) fn from_shared(s: Shared) -> Crossroads {
+        Crossroads {
+            map: Default::default(),
+            registry: s.registry,
+            add_standard_ifaces: s.add_standard_ifaces,
+            async_support: s.async_support,
+            object_manager_support: s.object_manager_support,
+            fallbacks: vec!(),
+            middleware: s.middleware,
+            credentials: s.credentials,
+            caller: None,
+            connection: None,
+            #[cfg(feature = "polkit")]
+            allow_interaction: false,
+            deferred_dispatch: None,
+            admitted: false,
+            emit_info: s.emit_info,
+            changes: s.changes,
+            connections: s.connections,
+            reply_sender: None,
+            #[cfg(feature = "polkit")]
+            polkit: s.polkit,
+            tree: Some(s.tree),
+        }
+    }
+
+    /// The shared parts of an instance made with `from_shared`, after changing its settings.
+    pub (crate) fn share(&self) -> Shared {
+        Shared {
+            registry: self.registry.clone(),
+            add_standard_ifaces: self.add_standard_ifaces,
+            async_support: self.async_support.clone(),
+            object_manager_support: self.object_manager_support.clone(),
+            middleware: self.middleware.clone(),
+            credentials: self.credentials.clone(),
+            emit_info: self.emit_info.clone(),
+            changes: self.changes.clone(),
+            connections: self.connections.clone(),
+            #[cfg(feature = "polkit")]
+            polkit: self.polkit.clone(),
+            tree: self.tree.clone().expect("Not serving a shared tree"),
+        }
+    }
+
+    /// Takes an object out of the shared tree, if any, waiting for other instances using it.
+    ///
+    /// Returns true if the object was taken out now.
+    fn check_out(&mut self, path: &dbus::Path<'static>) -> bool {
+        if self.map.contains_key(path) { return false }
+        match self.tree.as_ref().and_then(|t| t.check_out(path, false)) {
+            Some(obj) => { self.map.insert(path.clone(), obj); true },
+            None => false,
+        }
     }
 
     /// Access the data of a certain path.
     ///
     /// Will return none both if the path was not found, and if the found data was of another type.
     pub fn data_mut<D: Any + Send + 'static>(&mut self, name: &dbus::Path<'static>) -> Option<&mut D> {
+        self.check_out(name);
         let obj = self.map.get_mut(name)?;
         obj.data.downcast_mut()
     }
 
+    /// Access the data of an interface on a certain path.
+    ///
+    /// This is the data added with `add_interface_with_data`, or the data of the path if the
+    /// interface has no data of its own. Returns None if the path was not found, and if the
+    /// data was of another type.
+    pub fn interface_data_mut<D: Any + Send + 'static>(&mut self, name: &dbus::Path<'static>, iface: IfaceToken<D>) -> Option<&mut D> {
+        self.object_data_mut(name, Some(iface.0))
+    }
+
+    /// The data that callbacks of the interface get.
+    pub (crate) fn object_data_mut<D: Any + Send + 'static>(&mut self, name: &dbus::Path<'static>, token: Option<usize>) -> Option<&mut D> {
+        self.check_out(name);
+        let obj = self.map.get_mut(name)?;
+        let data = match token {
+            Some(t) if obj.iface_data.contains_key(&t) => obj.iface_data.get_mut(&t).unwrap(),
+            _ => &mut obj.data,
+        };
+        data.downcast_mut()
+    }
+
     /// Inserts a new path.
     ///
     /// If the path already exists, it is overwritten.
     pub fn insert<'z, D, I, N>(&mut self, name: N, ifaces: I, data: D)
     where D: Any + Send + 'static, N: Into<dbus::Path<'static>>, I: IntoIterator<Item = &'z IfaceToken<D>>
     {
-        let ifaces = ifaces.into_iter().map(|x| x.0);
+        let ifaces = self.object_ifaces(ifaces.into_iter().map(|x| x.0));
+        let name = name.into();
+        if let Some(tree) = self.tree.as_ref().filter(|_| !self.map.contains_key(&name)) {
+            // Waits for other instances using the object it replaces, or reserves the path
+            tree.check_out(&name, true);
+        }
+        self.map.insert(name.clone(), Object { ifaces, data: Box::new(data), iface_data: HashMap::new(), temporary: false });
+        self.bind_properties(&name);
+        if let Some(oms) = self.object_manager_support.as_ref() {
+            stdimpl::object_manager_path_added(oms.0.clone(), &name, self);
+        }
+    }
+
+    fn object_ifaces<I: IntoIterator<Item = usize>>(&self, ifaces: I) -> HashSet<usize> {
         let mut ifaces: HashSet<usize> = std::iter::FromIterator::from_iter(ifaces);
         if self.add_standard_ifaces {
             ifaces.insert(INTROSPECTABLE);
-            if ifaces.iter().any(|u| self.registry().has_props(*u)) {
+            if ifaces.iter().any(|u| self.registry.has_props(*u)) {
                 ifaces.insert(PROPERTIES);
             }
         }
-        let name = name.into();
-        self.map.insert(name.clone(), Object { ifaces, data: Box::new(data)});
-        if let Some(oms) = self.object_manager_support.as_ref() {
-            stdimpl::object_manager_path_added(oms.0.clone(), &name, self);
+        ifaces
+    }
+
+    /// Inserts a fallback for a subtree of paths, for objects that are not inserted up front.
+    ///
+    /// When a method is called on a path that has not been inserted, but is at or below the prefix,
+    /// the resolver is called with the path. It returns None if there is no such object, or the
+    /// interfaces the object implements and its data. The object then exists for the duration of
+    /// the call only, so changes to its data are not kept, and no ObjectManager signals are sent.
+    /// If several fallbacks match, the one with the longest prefix is used.
+    ///
+    /// The enumerator returns the names of the direct children of a path in the subtree (e g `"42"`
+    /// for the object `/rows/42`, when called with `/rows`). It is used to list child nodes when
+    /// introspecting, and to find all objects for `GetManagedObjects`.
+    pub fn insert_fallback<D, N, R, E>(&mut self, prefix: N, mut resolver: R, children: E)
+    where D: Any + Send + 'static, N: Into<dbus::Path<'static>>,
+        R: FnMut(&dbus::Path<'static>) -> Option<(Vec<IfaceToken<D>>, D)> + Send + 'static,
+        E: FnMut(&dbus::Path<'static>) -> Vec<String> + Send + 'static,
+    {
+        let prefix = prefix.into();
+        self.fallbacks.retain(|f| f.prefix != prefix);
+        let resolver: Resolver = Box::new(move |path| {
+            let (ifaces, data) = resolver(path)?;
+            Some((ifaces.into_iter().map(|x| x.0).collect(), Box::new(data) as Box<dyn Any + Send>))
+        });
+        self.fallbacks.push(Fallback { prefix, resolver: Dbg(resolver), children: Dbg(Box::new(children)) });
+    }
+
+    /// Removes the fallback with this prefix.
+    ///
+    /// Returns false if there was no such fallback.
+    pub fn remove_fallback(&mut self, prefix: &dbus::Path<'static>) -> bool {
+        let len = self.fallbacks.len();
+        self.fallbacks.retain(|f| &f.prefix != prefix);
+        len != self.fallbacks.len()
+    }
+
+    /// Sets the function used to look up the credentials of callers.
+    ///
+    /// Credentials are needed for access policies (see `MethodDesc::access` and `PropBuilder::access`),
+    /// and are available to method handlers through `Context::credentials`. They are cached
+    /// per connection; to clear the cache when a connection goes away, let `handle_message`
+    /// handle "NameOwnerChanged" signals from the bus.
+    ///
+    /// This lookup is used for calls handled with `handle_message`. Calls arriving on an attached
+    /// connection use the lookup set for that connection with `set_connection_credentials_lookup`,
+    /// and are denied access if it has none.
+    ///
+    /// Access policies are checked before the method handler runs:
+    ///
+    ///  * If the lookup finishes at once (like `Credentials::blocking_lookup`), or the credentials
+    ///    are cached, the policy is checked right away.
+    ///  * Otherwise, the call waits for the lookup in a spawned task, and is then dispatched, or
+    ///    answered with an AccessDenied error. A plain Crossroads hands the call back through
+    ///    `set_deferred_dispatch`; a ConcurrentCrossroads dispatches it from the task.
+    ///
+    /// `Properties.GetAll` and `ObjectManager.GetManagedObjects` do not wait; they leave out the
+    /// properties whose policy cannot be checked yet.
+    ///
+    /// # Panics
+    ///
+    /// If the lookup is non-blocking (see `CredentialsLookup::nonblock`) and calls cannot wait for it,
+    /// i e, async support is not set, or, for a plain Crossroads, `set_deferred_dispatch` is not set.
+    pub fn set_credentials_lookup(&mut self, lookup: Option<CredentialsLookup>) {
+        if lookup.as_ref().map(|l| l.is_nonblock()).unwrap_or(false) {
+            assert!(self.can_defer(), "A non-blocking credentials lookup needs async support, and set_deferred_dispatch unless serving a ConcurrentCrossroads");
+        }
+        self.credentials.set_lookup(None, lookup);
+    }
+
+    /// Sets the function used to look up the credentials of callers on an attached connection,
+    /// like `set_credentials_lookup`.
+    ///
+    /// The lookup must ask the bus this connection is connected to. Credentials are cached
+    /// per attached connection, and cleared when `handle_message_on` handles "NameOwnerChanged"
+    /// signals arriving on it.
+    ///
+    /// Returns false if there is no such connection.
+    ///
+    /// # Panics
+    ///
+    /// Like `set_credentials_lookup`, if the lookup is non-blocking and calls cannot wait for it.
+    pub fn set_connection_credentials_lookup(&mut self, id: ConnectionId, lookup: Option<CredentialsLookup>) -> bool {
+        if self.connections.get(id).is_none() { return false }
+        if lookup.as_ref().map(|l| l.is_nonblock()).unwrap_or(false) {
+            assert!(self.can_defer(), "A non-blocking credentials lookup needs async support, and set_deferred_dispatch unless serving a ConcurrentCrossroads");
+        }
+        self.credentials.set_lookup(Some(id), lookup);
+        true
+    }
+
+    /// Checks the access policy against the credentials of the caller of the current method.
+    ///
+    /// Returns a future doing the check, if the credentials are still being looked up.
+    fn check_access(&self, access: Option<&Access>) -> Result<Option<CheckFuture>, MethodErr> {
+        let access = match access {
+            Some(a) => a.clone(),
+            None => return Ok(None),
+        };
+        if !self.credentials.has_lookup(self.connection) { return Err(denied("Caller credentials are not available")) }
+        let caller = self.caller.clone().ok_or_else(|| denied("Caller is unknown"))?;
+        let mut lookup = Box::pin(self.credentials.get(self.connection, caller));
+        let allows = move |c: Arc<crate::Credentials>| if access.allows(&c) { Ok(()) } else { Err(denied("Access denied")) };
+        match poll_now(&mut lookup) {
+            Some(c) => allows(c?).map(|_| None),
+            None => Ok(Some(Box::pin(async move { allows(lookup.await?) }))),
+        }
+    }
+
+    /// Sets the polkit authority that authorizes callers of methods and properties with a
+    /// polkit action.
+    ///
+    /// Temporary authorizations granted by polkit are cached per connection and action, until
+    /// they expire. Other decisions are not cached.
+    /// Like credentials, the cache is cleared when a connection goes away, if `handle_message`
+    /// handles "NameOwnerChanged" signals from the bus.
+    ///
+    /// This authority is used for calls handled with `handle_message`. Calls arriving on an attached
+    /// connection use the authority set with `set_connection_polkit_authority`, and are denied
+    /// if it has none.
+    ///
+    /// Authorization is checked before the method handler runs. Like for credentials lookups
+    /// (see `set_credentials_lookup`), if the authority does not answer at once, the call waits
+    /// for the answer in a spawned task, and is dispatched only if the caller is authorized.
+    ///
+    /// # Panics
+    ///
+    /// If the authority is non-blocking (see `Authority::is_nonblock`) and calls cannot wait for it,
+    /// i e, async support is not set, or, for a plain Crossroads, `set_deferred_dispatch` is not set.
+    #[cfg(feature = "polkit")]
+    pub fn set_polkit_authority(&mut self, authority: Option<Box<dyn crate::polkit::Authority>>) {
+        if authority.as_ref().map(|a| a.is_nonblock()).unwrap_or(false) {
+            assert!(self.can_defer(), "A non-blocking polkit authority needs async support, and set_deferred_dispatch unless serving a ConcurrentCrossroads");
+        }
+        self.polkit.set_authority(None, authority);
+    }
+
+    /// Sets the polkit authority for callers on an attached connection, like `set_polkit_authority`.
+    ///
+    /// The authority must be the one on the system bus of the machine the callers on this connection
+    /// run on. Temporary authorizations are cached per attached connection.
+    ///
+    /// Returns false if there is no such connection.
+    ///
+    /// # Panics
+    ///
+    /// Like `set_polkit_authority`, if the authority is non-blocking and calls cannot wait for it.
+    #[cfg(feature = "polkit")]
+    pub fn set_connection_polkit_authority(&mut self, id: ConnectionId, authority: Option<Box<dyn crate::polkit::Authority>>) -> bool {
+        if self.connections.get(id).is_none() { return false }
+        if authority.as_ref().map(|a| a.is_nonblock()).unwrap_or(false) {
+            assert!(self.can_defer(), "A non-blocking polkit authority needs async support, and set_deferred_dispatch unless serving a ConcurrentCrossroads");
+        }
+        self.polkit.set_authority(Some(id), authority);
+        true
+    }
+
+    /// Returns the polkit check for the caller of the current method, to be polled now or later.
+    #[cfg(feature = "polkit")]
+    fn polkit_check(&self, action_id: &str) -> Result<CheckFuture, MethodErr> {
+        if !self.polkit.has_authority(self.connection) { return Err(denied("No polkit authority available")) }
+        let caller = self.caller.clone().ok_or_else(|| denied("Caller is unknown"))?;
+        Ok(Box::pin(self.polkit.check(self.connection, caller.to_string(), action_id.to_string(), self.allow_interaction)))
+    }
+
+    /// Checks the guard, returning a future doing the check if it has to wait for credentials
+    /// or polkit.
+    fn check_guard_deferred(&self, guard: Option<&Guard>) -> Result<Option<CheckFuture>, MethodErr> {
+        let guard = match guard {
+            Some(g) => g,
+            None => return Ok(None),
+        };
+        #[allow(unused_mut)]
+        let mut pending = self.check_access(guard.access.as_ref())?;
+        #[cfg(feature = "polkit")]
+        if let Some(action_id) = &guard.polkit_action {
+            let mut polkit = self.polkit_check(action_id)?;
+            pending = match pending {
+                None => match poll_now(&mut polkit) {
+                    Some(r) => { r?; None },
+                    None => Some(polkit),
+                },
+                Some(access) => Some(Box::pin(async move { access.await?; polkit.await })),
+            };
+        }
+        Ok(pending)
+    }
+
+    /// Lets a guard check that has to wait finish in the background, so that its answer is cached.
+    fn finish_in_background(&self, check: CheckFuture) {
+        if let Some(a) = &self.async_support {
+            a.spawn(Box::pin(async move { let _ = check.await; }));
+        }
+    }
+
+    /// Checks that the caller of the current method is allowed past the guard.
+    ///
+    /// Callers are denied if the check has to wait, for credentials or for polkit. This is for
+    /// filtering what several properties a call reads; use `guard_call` to let the call wait.
+    pub (crate) fn check_guard(&self, guard: Option<&Guard>) -> Result<(), MethodErr> {
+        match self.check_guard_deferred(guard)? {
+            None => Ok(()),
+            Some(check) => {
+                self.finish_in_background(check);
+                Err(denied("Caller is not authorized yet"))
+            }
+        }
+    }
+
+    /// Checks that the caller of the call is allowed past the guard.
+    ///
+    /// Returns the context if the call can go on, or Err with the context, with an error reply set,
+    /// if the caller is denied. If the check has to wait, for credentials or for polkit, the call
+    /// waits in a spawned task, and Err(None) is returned.
+    #[allow(clippy::result_large_err)]
+    pub (crate) fn guard_call(&mut self, mut ctx: Context, guard: Option<&Guard>) -> Result<Context, Option<Context>> {
+        if guard.is_some() && std::mem::take(&mut self.admitted) { return Ok(ctx) }
+        match self.check_guard_deferred(guard) {
+            Ok(None) => Ok(ctx),
+            Ok(Some(check)) => Err(self.defer(ctx, check)),
+            Err(e) => { ctx.reply_err(e); Err(Some(ctx)) },
+        }
+    }
+
+    /// True if calls can wait for their guard check in a spawned task.
+    fn can_defer(&self) -> bool {
+        self.async_support.is_some() && (self.tree.is_some() || self.deferred_dispatch.is_some())
+    }
+
+    /// Spawns a task waiting for the check, which then dispatches the call or denies the caller.
+    ///
+    /// Returns the context, with an error reply set, if the call cannot wait.
+    fn defer(&mut self, mut ctx: Context, check: CheckFuture) -> Option<Context> {
+        if !self.can_defer() {
+            self.finish_in_background(check);
+            ctx.reply_err(MethodErr::failed("Caller can not be checked yet"));
+            return Some(ctx);
+        }
+        let a = self.async_support.as_ref().unwrap();
+        let sender = match &self.reply_sender {
+            Some(s) => s.0.clone(),
+            None => a.sender.clone(),
+        };
+        let shared = self.tree.as_ref().map(|_| self.share());
+        let dispatch = self.deferred_dispatch.as_ref().map(|d| d.0.clone());
+        let connection = self.connection;
+        a.spawn(Box::pin(async move {
+            if let Err(e) = check.await {
+                ctx.reply_err(e);
+                let _ = ctx.flush_messages(&*sender);
+                return;
+            }
+            let deferred = Deferred { ctx, sender, connection };
+            match (shared, dispatch) {
+                (Some(shared), _) => Crossroads::from_shared(shared).handle_deferred(deferred),
+                (None, Some(dispatch)) => dispatch(deferred),
+                (None, None) => unreachable!(),
+            }
+        }));
+        None
+    }
+
+    /// Sets where method calls go after waiting for their caller to be checked.
+    ///
+    /// When the credentials of a caller (see `set_credentials_lookup`) or a polkit authorization
+    /// (see `set_polkit_authority`) do not arrive at once, the call waits for them in a task
+    /// spawned with async support. If the caller is allowed, the call is then handed to this
+    /// closure, which should pass it to `handle_deferred` of this instance, e g through an
+    /// `Arc<Mutex<Crossroads>>` or a channel. Callers who are not allowed get an error reply
+    /// right away.
+    ///
+    /// Not needed for a ConcurrentCrossroads, which dispatches such calls from the task.
+    pub fn set_deferred_dispatch(&mut self, dispatch: Option<DeferredDispatch>) -> Option<DeferredDispatch> {
+        let old = std::mem::replace(&mut self.deferred_dispatch, dispatch.map(|d| Dbg(Arc::new(d))));
+        old.map(|d| match Arc::try_unwrap(d.0) {
+            Ok(d) => d,
+            Err(d) => Box::new(move |x| d(x)),
+        })
+    }
+
+    /// Dispatches a method call that has waited for its caller to be checked, see `set_deferred_dispatch`.
+    ///
+    /// The reply is sent on the connection the call arrived on.
+    pub fn handle_deferred(&mut self, deferred: Deferred) {
+        let Deferred { ctx, sender, connection } = deferred;
+        self.connection = connection;
+        self.caller = ctx.message().sender().map(|s| s.into_static());
+        #[cfg(feature = "polkit")]
+        { self.allow_interaction = ctx.message().get_allow_interactive_authorization(); }
+        self.reply_sender = Some(Dbg(sender.clone()));
+        self.admitted = true;
+        if let Some(mut ctx) = self.handle_message_path(ctx) {
+            let _ = ctx.flush_messages(&*sender);
+        }
+        self.admitted = false;
+        self.reply_sender = None;
+        self.connection = None;
+        self.caller = None;
+        #[cfg(feature = "polkit")]
+        { self.allow_interaction = false; }
+    }
+
+    /// Runs f without a current caller, so that guards deny everyone.
+    ///
+    /// Used for signals that every connection receives, which must not depend on who called.
+    pub (crate) fn without_caller<R, F: FnOnce(&mut Self) -> R>(&mut self, f: F) -> R {
+        let caller = self.caller.take();
+        let r = f(self);
+        self.caller = caller;
+        r
+    }
+
+    /// Returns an emitter, which sends the signals declared in this instance through this connection.
+    ///
+    /// The emitter can be used outside method handlers, e g from another thread.
+    pub fn emitter(&self, sender: Arc<dyn Sender + Send + Sync + 'static>) -> Emitter {
+        Emitter::new(self.emit_info.clone(), sender)
+    }
+
+    /// Links the property cells in the data of an object to the object.
+    fn bind_properties(&mut self, path: &dbus::Path<'static>) {
+        let obj = match self.map.get_mut(path) { Some(obj) => obj, None => return };
+        for &t in &obj.ifaces {
+            let interface = match self.registry.get_intf_name(t) { Some(i) => i, None => continue };
+            for (name, binder) in self.registry.binders(t) {
+                let link = Link {
+                    path: path.clone(),
+                    interface: interface.clone(),
+                    name: name.into(),
+                    emits: self.registry.emits_changed(t, name),
+                    queue: self.changes.clone(),
+                };
+                let data = obj.iface_data.get_mut(&t).unwrap_or(&mut obj.data);
+                binder(&mut **data, link);
+            }
+        }
+    }
+
+    /// Sends the PropertiesChanged signals queued by property cells.
+    ///
+    /// Changes made to cells while handling a method call are sent together with its reply,
+    /// so this is only needed for changes made elsewhere, e g through `data_mut`, or after the
+    /// first await point of an async method.
+    pub fn flush_property_changes<S: Sender + ?Sized>(&self, conn: &S) -> Result<(), MethodErr> {
+        for msg in self.take_property_changes() {
+            conn.send(msg).map_err(|_| MethodErr::failed("Sending signal failed"))?;
+        }
+        Ok(())
+    }
+
+    fn take_property_changes(&self) -> Vec<dbus::Message> {
+        let msgs = std::mem::take(&mut *self.changes.lock().unwrap());
+        if msgs.is_empty() { return msgs }
+        emitter::coalesce(&self.emit_info.read().unwrap(), msgs)
+    }
+
+    /// Adds middleware that sees every method call handled by this instance.
+    ///
+    /// Middleware runs in the order it was added, see the `Middleware` trait for details.
+    pub fn add_middleware<M: Middleware>(&mut self, middleware: M) {
+        self.push_middleware(Arc::new(middleware));
+    }
+
+    pub (crate) fn push_middleware(&mut self, middleware: Arc<dyn Middleware>) {
+        self.middleware.0.push(middleware);
+    }
+
+    /// Makes a fallback object exist temporarily, or takes the object out of the shared tree.
+    ///
+    /// Returns true if the object should be removed (or given back) with `dematerialize`.
+    pub (crate) fn materialize(&mut self, path: &dbus::Path<'static>) -> bool {
+        if self.map.contains_key(path) { return false }
+        if self.check_out(path) { return true }
+        let fallback = self.fallbacks.iter_mut().filter(|f| is_in_subtree(path, &f.prefix))
+            .max_by_key(|f| f.prefix.len());
+        let (ifaces, data) = match fallback.and_then(|f| (f.resolver.0)(path)) {
+            Some(x) => x,
+            None => return false,
+        };
+        let ifaces = self.object_ifaces(ifaces);
+        self.map.insert(path.clone(), Object { ifaces, data, iface_data: HashMap::new(), temporary: true });
+        self.bind_properties(path);
+        true
+    }
+
+    /// Removes an object created by `materialize`, unless it was inserted for real since, or
+    /// gives back an object taken out of the shared tree.
+    pub (crate) fn dematerialize(&mut self, path: &dbus::Path<'static>) {
+        let temporary = match self.map.get(path) { Some(obj) => obj.temporary, None => return };
+        match &self.tree {
+            Some(tree) if !temporary => tree.check_in(path, self.map.remove(path).unwrap()),
+            _ => if temporary { self.map.remove(path); },
         }
     }
 
@@ -149,6 +796,7 @@ impl Crossroads {
     where D: Any + Send + 'static, N: Into<dbus::Path<'static>>
     {
         let name = name.into();
+        self.check_out(&name);
         if let Some(obj) = self.map.get_mut(&name) {
             if !obj.data.is::<D>() {
                 // Data type mismatch, return fail.
@@ -161,6 +809,7 @@ impl Crossroads {
             }
 
             obj.ifaces.insert(iface.0);
+            self.bind_properties(&name);
             if let Some(oms) = self.object_manager_support.as_ref() {
                 stdimpl::object_manager_interface_added(oms.0.clone(), &name, iface.0, self);
             }
@@ -170,26 +819,81 @@ impl Crossroads {
         false
     }
 
+    /// Adds an interface with its own data to an existing object path.
+    ///
+    /// The callbacks of the interface get this data instead of the data of the path, so
+    /// independent interfaces can be put on one object without a combined data type.
+    /// If the object already has the interface, its data is replaced.
+    ///
+    /// Returns false if the object does not exist.
+    pub fn add_interface_with_data<D, N>(&mut self, name: N, iface: IfaceToken<D>, data: D) -> bool
+    where D: Any + Send + 'static, N: Into<dbus::Path<'static>>
+    {
+        let name = name.into();
+        self.check_out(&name);
+        let obj = match self.map.get_mut(&name) { Some(obj) => obj, None => return false };
+        obj.iface_data.insert(iface.0, Box::new(data));
+        let added = obj.ifaces.insert(iface.0);
+        if added && self.add_standard_ifaces && self.registry.has_props(iface.0) {
+            obj.ifaces.insert(PROPERTIES);
+        }
+        self.bind_properties(&name);
+        if added {
+            if let Some(oms) = self.object_manager_support.as_ref() {
+                stdimpl::object_manager_interface_added(oms.0.clone(), &name, iface.0, self);
+            }
+        }
+        true
+    }
+
+    /// Removes an interface from an object path, and returns the data it was added with.
+    ///
+    /// Returns None if the path does not have the interface, or if the interface did not have
+    /// data of its own. In case of a type mismatch, the interface will be removed, but None
+    /// will be returned.
+    pub fn remove_interface_with_data<D, N>(&mut self, name: N, iface: IfaceToken<D>) -> Option<D>
+    where D: Any + Send + 'static, N: Into<dbus::Path<'static>>
+    {
+        let name = name.into();
+        self.check_out(&name);
+        let data = self.map.get_mut(&name)?.iface_data.remove(&iface.0);
+        self.remove_interface(name, iface);
+        let r: Box<D> = data?.downcast().ok()?;
+        Some(*r)
+    }
+
     /// Removes an interface from an object path.
+    ///
+    /// If the interface was added with its own data, the data is dropped.
     pub fn remove_interface<'z, D, N>(&mut self, name: N, iface: IfaceToken<D>)
     where D: Any + Send + 'static, N: Into<dbus::Path<'static>>
     {
         let name = name.into();
+        self.check_out(&name);
         if let Some(obj) = self.map.get_mut(&name) {
             if !obj.ifaces.contains(&iface.0) {
                 return;
             }
 
             obj.ifaces.remove(&iface.0);
+            obj.iface_data.remove(&iface.0);
             if let Some(oms) = self.object_manager_support.as_ref() {
                 stdimpl::object_manager_interface_removed(oms.0.clone(), &name, iface.0, self);
             }
         }
     }
 
+    pub (crate) fn has_path(&self, name: &dbus::Path<'static>) -> bool {
+        self.map.contains_key(name) || self.tree.as_ref().map(|t| t.contains(name)).unwrap_or(false)
+    }
+
     /// Returns true if the path exists and implements the interface
     pub fn has_interface<D: Send>(&self, name: &dbus::Path<'static>, token: IfaceToken<D>) -> bool {
-        self.map.get(name).map(|x| x.ifaces.contains(&token.0)).unwrap_or(false)
+        match (self.map.get(name), &self.tree) {
+            (Some(obj), _) => obj.ifaces.contains(&token.0),
+            (None, Some(tree)) => tree.has_interface(name, token.0),
+            (None, None) => false,
+        }
     }
 
     /// Removes an existing path.
@@ -198,12 +902,14 @@ impl Crossroads {
     /// In case of a type mismatch, the path will be removed, but None will be returned.
     pub fn remove<D>(&mut self, name: &dbus::Path<'static>) -> Option<D>
     where D: Any + Send + 'static {
+        self.check_out(name);
         if let Some(oms) = self.object_manager_support.as_ref() {
             if self.map.contains_key(name) {
                 stdimpl::object_manager_path_removed(oms.0.clone(), &name, self);
             }
         }
         let x = self.map.remove(name)?;
+        if let Some(tree) = &self.tree { tree.forget(name) }
         let r: Box<D> = x.data.downcast().ok()?;
         Some(*r)
     }
@@ -216,7 +922,7 @@ impl Crossroads {
         self.registry.find_token(interface, &obj.ifaces)
     }
 
-    pub (crate) fn registry(&mut self) -> &mut Registry { &mut self.registry }
+    pub (crate) fn registry_ref(&self) -> &Registry { &self.registry }
 
     pub (crate) fn registry_and_ifaces(&self, path: &dbus::Path<'static>)
     -> (&Registry, &HashSet<usize>) {
@@ -224,13 +930,33 @@ impl Crossroads {
         (&self.registry, &obj.ifaces)
     }
 
-    pub (crate) fn get_children(&self, path: &dbus::Path<'static>, direct_only: bool) -> Vec<&str> {
+    /// Path, interface, method name, input and output signature of every method on every path,
+    /// in a deterministic order.
+    pub (crate) fn method_sigs(&self) -> Vec<MethodSig> {
+        let mut r = vec!();
+        for (path, obj) in &self.map {
+            for &t in &obj.ifaces {
+                let iface = match self.registry.get_intf_name(t) { Some(i) => i, None => continue };
+                for (m, i, o) in self.registry.method_sigs(t) {
+                    r.push((path.clone(), iface.clone(), m.clone(), i, o));
+                }
+            }
+        }
+        r.sort();
+        r
+    }
+
+    pub (crate) fn get_children(&mut self, path: &dbus::Path<'static>, direct_only: bool) -> Vec<String> {
         use std::ops::Bound;
-        let mut range = self.map.range((Bound::Excluded(path), Bound::Unbounded));
+        let shared_paths;
+        let range: Box<dyn Iterator<Item = &dbus::Path<'static>>> = match &self.tree {
+            Some(tree) => { shared_paths = tree.paths_after(path); Box::new(shared_paths.iter()) },
+            None => Box::new(self.map.range((Bound::Excluded(path), Bound::Unbounded)).map(|(c, _)| c)),
+        };
         let p2 = path.as_bytes();
         let substart = if &p2 == &b"/" { 0 } else { p2.len() };
-        let mut r: Vec<&str> = vec!();
-        while let Some((c, _)) = range.next() {
+        let mut r: Vec<String> = vec!();
+        for c in range {
             if !c.as_bytes().starts_with(p2) { break; }
             let csub: &str = &c[substart..];
             if csub.len() == 0 || csub.as_bytes()[0] != b'/' { continue; }
@@ -240,8 +966,29 @@ impl Crossroads {
                 let b = csub1.as_bytes();
                 if b.len() > prev.len() && b.starts_with(prev) && b[prev.len()] == b'/' { continue; }
             }
-            r.push(csub1);
+            r.push(csub1.into());
         };
+        if self.fallbacks.is_empty() { return r; }
+
+        for f in self.fallbacks.iter_mut() {
+            let mut todo = vec!();
+            if is_in_subtree(path, &f.prefix) {
+                todo.push(path.clone());
+            } else if is_in_subtree(&f.prefix, path) {
+                let rel = &f.prefix[substart+1..];
+                r.push(if direct_only { rel.split('/').next().unwrap().into() } else { rel.into() });
+                if !direct_only { todo.push(f.prefix.clone()) };
+            }
+            while let Some(p) = todo.pop() {
+                for child in (f.children.0)(&p) {
+                    let child = match join_path(&p, &child) { Some(c) => c, None => continue };
+                    r.push(child[substart+1..].into());
+                    if !direct_only { todo.push(child) };
+                }
+            }
+        }
+        r.sort_unstable();
+        r.dedup();
         r
     }
 
@@ -249,14 +996,58 @@ impl Crossroads {
     where F: FnOnce(Arc<dyn Sender + Send + Sync + 'static>, &mut Crossroads) -> R,
     R: Future<Output=()> + Send + 'static
     {
-        let sender = self.async_support.as_ref().expect("Async support not set").sender.clone();
+        let sender = match &self.reply_sender {
+            Some(s) => s.0.clone(),
+            None => self.async_support.as_ref().expect("Async support not set").sender.clone(),
+        };
         let future = f(sender, self);
-        let spawner = &self.async_support.as_ref().expect("Async support not set").spawner;
+        let a = self.async_support.as_ref().expect("Async support not set");
         let boxed = Box::pin(async move { future.await });
-        (spawner)(boxed)
+        a.spawn(boxed)
+    }
+
+    fn handle_message_inner(&mut self, ctx: Context) -> Option<Context> {
+        self.caller = ctx.message().sender().map(|s| s.into_static());
+        #[cfg(feature = "polkit")]
+        { self.allow_interaction = ctx.message().get_allow_interactive_authorization(); }
+        let r = self.handle_message_caller(ctx);
+        self.caller = None;
+        #[cfg(feature = "polkit")]
+        { self.allow_interaction = false; }
+        r
+    }
+
+    fn handle_message_caller(&mut self, mut ctx: Context) -> Option<Context> {
+        ctx.set_credentials(Some(self.credentials.clone()), self.connection);
+        ctx.set_emit_info(self.emit_info.clone());
+        ctx.set_signal_sender(self.signal_sender());
+        for (i, m) in self.middleware.0.iter().enumerate() {
+            if let Err(e) = m.before(&mut ctx) {
+                ctx.set_middleware(self.middleware.0[..=i].to_vec());
+                ctx.reply_err(e);
+                return Some(ctx);
+            }
+        }
+        ctx.set_middleware(self.middleware.0.clone());
+        self.handle_message_path(ctx)
+    }
+
+    fn handle_message_path(&mut self, ctx: Context) -> Option<Context> {
+        let path = ctx.path().clone();
+        let temporary = self.materialize(&path);
+        let mut r = self.handle_message_object(ctx);
+        if temporary { self.dematerialize(&path) };
+        let changes = self.take_property_changes();
+        let signal_sender = self.signal_sender().or_else(|| self.async_support.as_ref().map(|a| a.sender.clone()));
+        match (&mut r, signal_sender) {
+            (Some(ctx), _) => for msg in changes { ctx.push_msg(msg) },
+            (None, Some(s)) => for msg in changes { let _ = s.send(msg); },
+            (None, None) => self.changes.lock().unwrap().extend(changes),
+        }
+        r
     }
 
-    fn handle_message_inner(&mut self, mut ctx: Context) -> Option<Context> {
+    fn handle_message_object(&mut self, mut ctx: Context) -> Option<Context> {
         let itoken_result = match self.find_iface_token(ctx.path(), ctx.interface()) {
             Ok(x) => Ok(x),
             Err(merr) => {
@@ -268,16 +1059,22 @@ impl Crossroads {
             }
         };
 
-        let (itoken, mut cb) = match ctx.check(|ctx| {
-            let itoken = itoken_result?;
-            let cb = self.registry.take_method(itoken, ctx.method())?;
-            Ok((itoken, cb))
-        }) {
+        let itoken = match ctx.check(|_| itoken_result) {
+            Ok(x) => x,
+            Err(_) => return Some(ctx),
+        };
+        let guard = self.registry.method_guard(itoken, ctx.method()).cloned();
+        let mut ctx = match self.guard_call(ctx, guard.as_ref()) {
+            Ok(ctx) => ctx,
+            Err(r) => return r,
+        };
+        let mut cb = match ctx.check(|ctx| self.registry.take_method(itoken, ctx.method())) {
             Ok(x) => x,
-            Err(_) => return Some(ctx)
+            Err(_) => return Some(ctx),
         };
         // No failure paths before method is given back!
         let methodname = ctx.method().clone();
+        ctx.set_iface_token(itoken);
         let ctx = cb(ctx, self);
         self.registry.give_method(itoken, &methodname, cb);
         ctx
@@ -285,8 +1082,21 @@ impl Crossroads {
 
     /// Handles an incoming message call.
     ///
-    /// Returns Err if the message is not a method call.
-    pub fn handle_message<S: dbus::channel::Sender>(&mut self, message: dbus::Message, conn: &S) -> Result<(), ()> {
+    /// "NameOwnerChanged" signals from the bus are accepted too, and used to forget the
+    /// credentials of connections that have gone away (see `set_credentials_lookup`).
+    ///
+    /// Returns Err if the message is neither a method call nor such a signal.
+    pub fn handle_message<S: dbus::channel::Sender + ?Sized>(&mut self, message: dbus::Message, conn: &S) -> Result<(), ()> {
+        if is_name_owner_changed(&message) {
+            if let Ok((name, _, new_owner)) = message.read3::<&str, &str, &str>() {
+                if new_owner.is_empty() {
+                    self.credentials.remove(self.connection, name);
+                    #[cfg(feature = "polkit")]
+                    self.polkit.remove(self.connection, name);
+                }
+            }
+            return Ok(());
+        }
         let ctx = Context::new(message).ok_or(())?;
         if let Some(mut ctx) = self.handle_message_inner(ctx) {
             let _ = ctx.flush_messages(conn);
@@ -294,6 +1104,59 @@ impl Crossroads {
         Ok(())
     }
 
+    /// Attaches a connection, so that this instance serves several connections at once.
+    ///
+    /// Signals sent from method handlers (e g with `Context::push_msg`, or when a property is set)
+    /// are then sent on all attached connections that receive signals, see `set_connection_signals`.
+    /// Signals with a destination are sent only on the connection the call arrived on.
+    /// To send ObjectManager and Emitter signals the same way, pass `connections` to
+    /// `set_object_manager_support` and `emitter`.
+    ///
+    /// Method calls are handled with `handle_message_on`, which sends the reply on the connection
+    /// the call arrived on.
+    pub fn attach_connection(&mut self, sender: Arc<dyn Sender + Send + Sync + 'static>) -> ConnectionId {
+        self.connections.attach(sender)
+    }
+
+    /// Detaches a connection. Returns false if there was no such connection.
+    pub fn detach_connection(&mut self, id: ConnectionId) -> bool {
+        self.credentials.set_lookup(Some(id), None);
+        #[cfg(feature = "polkit")]
+        self.polkit.set_authority(Some(id), None);
+        self.connections.detach(id)
+    }
+
+    /// Sets whether signals are sent on an attached connection (the default is true).
+    ///
+    /// Returns false if there was no such connection.
+    pub fn set_connection_signals(&mut self, id: ConnectionId, enabled: bool) -> bool {
+        self.connections.set_signals(id, enabled)
+    }
+
+    /// The attached connections. Sending through these sends on all connections that receive signals.
+    pub fn connections(&self) -> Connections { self.connections.clone() }
+
+    fn signal_sender(&self) -> Option<Arc<dyn Sender + Send + Sync + 'static>> {
+        if self.connections.is_empty() { None } else { Some(Arc::new(self.connections.clone())) }
+    }
+
+    /// Handles an incoming message that arrived on an attached connection.
+    ///
+    /// Like `handle_message`, but the reply is sent on that connection, also for async methods.
+    /// Callers are checked with the credentials lookup and polkit authority of that connection,
+    /// and "NameOwnerChanged" signals forget only what was cached for it.
+    /// Returns Err if there is no such connection.
+    #[allow(clippy::result_unit_err)]
+    pub fn handle_message_on(&mut self, message: dbus::Message, id: ConnectionId) -> Result<(), ()> {
+        let sender = self.connections.get(id).ok_or(())?;
+        self.reply_sender = Some(Dbg(sender.clone()));
+        self.connection = Some(id);
+        let r = self.handle_message(message, &*sender);
+        self.connection = None;
+        self.reply_sender = None;
+        r
+    }
+
     /// The token representing the built-in implementation of "org.freedesktop.DBus.Introspectable".
     pub fn introspectable<T: Send + 'static>(&self) -> IfaceToken<T> { IfaceToken(INTROSPECTABLE, PhantomData) }
 
@@ -314,9 +1177,15 @@ impl Crossroads {
         let a = self.async_support.take();
         self.async_support = x.map(|x| AsyncSupport {
             sender: x.0,
-            spawner: x.1
+            spawner: Arc::new(x.1),
         });
-        a.map(|x| (x.sender, x.spawner))
+        a.map(|x| {
+            let spawner: BoxedSpawn = match Arc::try_unwrap(x.spawner) {
+                Ok(s) => s,
+                Err(s) => Box::new(move |f| s(f)),
+            };
+            (x.sender, spawner)
+        })
     }
 
     /// Enables this crossroads instance to send signals when paths are added and removed.
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use dbus::arg::{self, ArgType, RefArg, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::EmitsChangedSignal;
+use dbus::blocking::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged as PPC;
+use dbus::message::SignalArgs;
+use dbus::channel::Sender;
+use dbus::strings::{Interface, Member};
+use crate::{IfaceToken, MethodErr};
+
+/// What an emitter needs to know about an interface.
+#[derive(Debug, Default)]
+pub (crate) struct IfaceEmitInfo {
+    pub name: Option<Interface<'static>>,
+    /// Signal name to signature.
+    pub signals: HashMap<Member<'static>, String>,
+    pub props: HashMap<String, PropEmitInfo>,
+}
+
+#[derive(Debug)]
+pub (crate) struct PropEmitInfo {
+    pub sig: String,
+    pub emits: EmitsChangedSignal,
+    /// Where the property was declared in the interface.
+    pub order: usize,
+}
+
+pub (crate) type EmitInfo = Arc<RwLock<Vec<IfaceEmitInfo>>>;
+
+/// Sends signals declared in a Crossroads instance, outside of method handlers.
+///
+/// Get one from `Crossroads::emitter`. It can be cloned and sent to other threads, e g to send
+/// signals when a hardware event happens or a timer fires.
+///
+/// To change several properties at once, use `batch`, which sends one PropertiesChanged
+/// signal per path and interface.
+#[derive(Clone)]
+pub struct Emitter {
+    info: EmitInfo,
+    sender: Arc<dyn Sender + Send + Sync + 'static>,
+}
+
+impl fmt::Debug for Emitter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Emitter") }
+}
+
+fn signature(msg: &dbus::Message) -> String {
+    let mut i = msg.iter_init();
+    let mut s = String::new();
+    while i.arg_type() != ArgType::Invalid {
+        s += &i.signature();
+        i.next();
+    }
+    s
+}
+
+impl Emitter {
+    pub (crate) fn new(info: EmitInfo, sender: Arc<dyn Sender + Send + Sync + 'static>) -> Self {
+        Emitter { info, sender }
+    }
+
+    fn send(&self, msg: dbus::Message) -> Result<(), MethodErr> {
+        self.sender.send(msg).map(|_| ()).map_err(|_| MethodErr::failed("Sending signal failed"))
+    }
+
+    fn signal_msg<T: Send + 'static, A: arg::AppendAll>(&self, token: IfaceToken<T>, path: &dbus::Path, name: Member<'static>, args: A) -> Result<dbus::Message, MethodErr> {
+        let info = self.info.read().unwrap();
+        let iface = &info[token.0];
+        let iface_name = iface.name.as_ref().ok_or_else(|| MethodErr::no_interface(""))?;
+        let sig = iface.signals.get(&name)
+            .ok_or_else(|| MethodErr::failed(&format!("Interface {} has no signal {}", iface_name, name)))?;
+        let mut msg = dbus::Message::signal(path, iface_name, &name);
+        msg.append_all(args);
+        let actual = signature(&msg);
+        if &actual != sig {
+            return Err(MethodErr::invalid_arg(&format!("Signal {} has signature '{}', not '{}'", name, sig, actual)));
+        }
+        Ok(msg)
+    }
+
+    fn property_changed_msg<T, V>(&self, token: IfaceToken<T>, path: &dbus::Path, name: &str, value: V) -> Result<Option<dbus::Message>, MethodErr>
+    where T: Send + 'static, V: arg::RefArg + arg::Arg + 'static {
+        let info = self.info.read().unwrap();
+        let iface = &info[token.0];
+        let iface_name = iface.name.as_ref().ok_or_else(|| MethodErr::no_interface(""))?;
+        let prop = iface.props.get(name).ok_or_else(|| MethodErr::no_property(name))?;
+        let actual = <V as arg::Arg>::signature();
+        if *actual != prop.sig {
+            return Err(MethodErr::invalid_arg(&format!("Property {} has signature '{}', not '{}'", name, prop.sig, actual)));
+        }
+        if prop.emits == EmitsChangedSignal::Const {
+            return Err(MethodErr::failed(&format!("Property {} is const", name)));
+        }
+        let mut ppc = PPC {
+            interface_name: iface_name.to_string(),
+            invalidated_properties: vec!(),
+            changed_properties: Default::default(),
+        };
+        if !ppc.add_prop(name, prop.emits, || Box::new(value)) { return Ok(None) }
+        Ok(Some(ppc.to_emit_message(path)))
+    }
+
+    /// Sends a signal declared on the interface.
+    ///
+    /// Returns an error if there is no such signal, if the arguments do not match the
+    /// signal's declaration, or if the signal could not be sent.
+    pub fn signal<T: Send + 'static, A: arg::AppendAll, N: Into<Member<'static>>>(&self, token: IfaceToken<T>, path: &dbus::Path, name: N, args: A) -> Result<(), MethodErr> {
+        let msg = self.signal_msg(token, path, name.into(), args)?;
+        self.send(msg)
+    }
+
+    /// Sends a PropertiesChanged signal for a property on the interface.
+    ///
+    /// Depending on the "EmitsChangedSignal" annotation of the property, the new value is included,
+    /// only the name of the property is, or no signal is sent. Returns true if a signal was sent.
+    ///
+    /// Returns an error if there is no such property, if the value is not of the property's type,
+    /// if the property is const, or if the signal could not be sent.
+    pub fn property_changed<T, V>(&self, token: IfaceToken<T>, path: &dbus::Path, name: &str, value: V) -> Result<bool, MethodErr>
+    where T: Send + 'static, V: arg::RefArg + arg::Arg + 'static {
+        match self.property_changed_msg(token, path, name, value)? {
+            Some(msg) => { self.send(msg)?; Ok(true) },
+            None => Ok(false),
+        }
+    }
+
+    /// Collects the signals and property changes made in the closure, and sends them when
+    /// the closure returns.
+    ///
+    /// All property changes of one path and interface are merged into one PropertiesChanged
+    /// signal, with the properties in the order they were declared. It is sent where the first
+    /// of these changes was made, relative to other signals.
+    pub fn batch<R, F: FnOnce(&mut Batch) -> R>(&self, f: F) -> Result<R, MethodErr> {
+        let mut batch = Batch { emitter: self, msgs: vec!() };
+        let r = f(&mut batch);
+        let msgs = coalesce(&self.info.read().unwrap(), batch.msgs);
+        for msg in msgs { self.send(msg)?; }
+        Ok(r)
+    }
+}
+
+/// Signals and property changes to be sent together, see `Emitter::batch`.
+#[derive(Debug)]
+pub struct Batch<'a> {
+    emitter: &'a Emitter,
+    msgs: Vec<dbus::Message>,
+}
+
+impl Batch<'_> {
+    /// Like `Emitter::signal`, but the signal is sent when the batch is done.
+    pub fn signal<T: Send + 'static, A: arg::AppendAll, N: Into<Member<'static>>>(&mut self, token: IfaceToken<T>, path: &dbus::Path, name: N, args: A) -> Result<(), MethodErr> {
+        let msg = self.emitter.signal_msg(token, path, name.into(), args)?;
+        self.msgs.push(msg);
+        Ok(())
+    }
+
+    /// Like `Emitter::property_changed`, but the change is sent when the batch is done.
+    ///
+    /// Returns true if the change will be part of a signal.
+    pub fn property_changed<T, V>(&mut self, token: IfaceToken<T>, path: &dbus::Path, name: &str, value: V) -> Result<bool, MethodErr>
+    where T: Send + 'static, V: arg::RefArg + arg::Arg + 'static {
+        let msg = self.emitter.property_changed_msg(token, path, name, value)?;
+        Ok(msg.map(|msg| self.msgs.push(msg)).is_some())
+    }
+}
+
+/// A property and its new value, or None if invalidated.
+type Change = (String, Option<Variant<Box<dyn RefArg>>>);
+
+/// The changed and invalidated properties of one path, interface and destination.
+struct Changes {
+    path: dbus::Path<'static>,
+    interface_name: String,
+    /// The first of the merged signals, whose header the merged signal gets.
+    first: dbus::Message,
+    props: Vec<Change>,
+}
+
+/// Copies the header fields a signal can have, except path, interface and member.
+fn copy_header(from: &dbus::Message, to: &mut dbus::Message) {
+    to.set_destination(from.destination());
+    to.set_sender(from.sender());
+    to.set_no_reply(from.get_no_reply());
+    to.set_auto_start(from.get_auto_start());
+}
+
+impl Changes {
+    fn add(&mut self, name: String, value: Option<Variant<Box<dyn RefArg>>>) {
+        self.props.retain(|(n, _)| *n != name);
+        self.props.push((name, value));
+    }
+
+    fn into_message(mut self, info: &[IfaceEmitInfo]) -> Option<dbus::Message> {
+        let iface = info.iter().find(|i| i.name.as_deref() == Some(&*self.interface_name));
+        let prop = |name: &str| iface.and_then(|i| i.props.get(name));
+        self.props.retain(|(name, _)| prop(name).map(|p| p.emits != EmitsChangedSignal::False).unwrap_or(true));
+        if self.props.is_empty() { return None }
+        self.props.sort_by_key(|(name, _)| (prop(name).map(|p| p.order).unwrap_or(usize::MAX), name.clone()));
+        let mut changed = vec!();
+        let mut invalidated = vec!();
+        for (name, value) in self.props {
+            match value {
+                Some(v) => changed.push((name, v)),
+                None => invalidated.push(name),
+            }
+        }
+        let mut msg = dbus::Message::signal(&self.path, &PPC::INTERFACE.into(), &PPC::NAME.into());
+        copy_header(&self.first, &mut msg);
+        Some(msg.append3(&self.interface_name, arg::Dict::new(changed.iter().map(|(k, v)| (k, v))), invalidated))
+    }
+}
+
+/// Merges PropertiesChanged signals for the same path, interface and destination.
+///
+/// The merged signal takes the place of the first one, and gets its header. Other messages keep
+/// their order.
+/// Properties that should not be signalled are left out.
+pub (crate) fn coalesce(info: &[IfaceEmitInfo], msgs: Vec<dbus::Message>) -> Vec<dbus::Message> {
+    enum Out { Msg(dbus::Message), Changes(usize) }
+    let mut out = vec!();
+    let mut changes: Vec<Changes> = vec!();
+    for msg in msgs {
+        let ppc = if msg.msg_type() == dbus::MessageType::Signal { PPC::from_message(&msg) } else { None };
+        let (ppc, path) = match (ppc, msg.path()) {
+            (Some(ppc), Some(path)) => (ppc, path.into_static()),
+            _ => { out.push(Out::Msg(msg)); continue },
+        };
+        let idx = match changes.iter().position(|c| c.path == path && c.interface_name == ppc.interface_name
+            && c.first.destination() == msg.destination()) {
+            Some(idx) => idx,
+            None => {
+                changes.push(Changes { path, interface_name: ppc.interface_name, first: msg, props: vec!() });
+                out.push(Out::Changes(changes.len() - 1));
+                changes.len() - 1
+            }
+        };
+        let c = &mut changes[idx];
+        for (name, value) in ppc.changed_properties { c.add(name, Some(value)) }
+        for name in ppc.invalidated_properties { c.add(name, None) }
+    }
+    let mut changes: Vec<_> = changes.into_iter().map(Some).collect();
+    out.into_iter().filter_map(|o| match o {
+        Out::Msg(msg) => Some(msg),
+        Out::Changes(idx) => changes[idx].take().unwrap().into_message(info),
+    }).collect()
+}
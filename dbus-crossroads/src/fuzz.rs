@@ -0,0 +1,123 @@
+//! Firing random method calls at a Crossroads instance.
+//!
+//! For every method on every path, calls are made with random arguments matching the method's
+//! input signature, generated with `dbus::arg::arbitrary::Generator`. Methods that panic, do not
+//! reply, or reply with something else than their declared output signature are reported.
+//! Error replies are fine, as most random arguments are expected to be rejected.
+//!
+//! # Example
+//!
+//! ```
+//! use dbus_crossroads::Crossroads;
+//! use dbus::arg::arbitrary::Generator;
+//!
+//! let mut cr = Crossroads::new();
+//! let token = cr.register("com.example.Calc", |b| {
+//!     b.method("Div", ("a", "b"), ("quotient",), |_, _, (a, b): (i32, i32)| {
+//!         a.checked_div(b).map(|q| (q,)).ok_or_else(|| dbus::MethodErr::invalid_arg("b"))
+//!     });
+//! });
+//! cr.insert("/", &[token], ());
+//!
+//! let findings = dbus_crossroads::fuzz::fuzz(&mut cr, &mut Generator::new(1), 100);
+//! assert!(findings.is_empty(), "{:?}", findings);
+//! ```
+
+use std::cell::RefCell;
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use dbus::{Message, MessageType, Path};
+use dbus::arg::arbitrary::{Generator, Source};
+use dbus::arg::messageitem::MessageItem;
+use dbus::strings::{Interface, Member};
+use crate::Crossroads;
+
+/// What went wrong with a method call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The method panicked, with this message.
+    ///
+    /// A method that panicked is not given back to the Crossroads instance, so later calls to it
+    /// fail with an error.
+    Panic(String),
+    /// Neither a method return nor an error was sent.
+    NoReply,
+    /// The method return did not have the declared output signature.
+    Malformed { expected: String, actual: String },
+}
+
+/// A method call that caused a problem.
+#[derive(Debug, Clone)]
+pub struct Finding {
+    /// The object path called.
+    pub path: Path<'static>,
+    /// The interface called.
+    pub interface: Interface<'static>,
+    /// The method called.
+    pub member: Member<'static>,
+    /// The arguments of the call.
+    pub args: Vec<MessageItem>,
+    /// What went wrong.
+    pub problem: Problem,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}.{}(", self.path, self.interface, self.member)?;
+        for (i, a) in self.args.iter().enumerate() {
+            if i > 0 { write!(f, ", ")? };
+            write!(f, "{}", dbus::arg::text::GVariantDisplay(a))?;
+        }
+        match &self.problem {
+            Problem::Panic(s) => write!(f, "): panicked: {}", s),
+            Problem::NoReply => write!(f, "): no reply"),
+            Problem::Malformed { expected, actual } => write!(f, "): expected reply signature '{}', got '{}'", expected, actual),
+        }
+    }
+}
+
+fn panic_message(e: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = e.downcast_ref::<&str>() { s.to_string() }
+    else if let Some(s) = e.downcast_ref::<String>() { s.clone() }
+    else { "(unknown panic payload)".into() }
+}
+
+/// Calls every method on every path `calls` times with random arguments.
+///
+/// The Crossroads instance must not have async support enabled, as replies from spawned tasks
+/// are not waited for. Methods might of course change the data of the Crossroads instance.
+pub fn fuzz<S: Source>(cr: &mut Crossroads, gen: &mut Generator<S>, calls: usize) -> Vec<Finding> {
+    let mut findings = vec!();
+    let mut serial = 0;
+    for (path, interface, member, in_sig, out_sig) in cr.method_sigs() {
+        for _ in 0..calls {
+            let args = match gen.items(&in_sig) {
+                Ok(args) => args,
+                Err(_) => break,
+            };
+            let mut msg = Message::method_call(&"org.freedesktop.DBus".into(), &path, &interface, &member);
+            msg.set_destination(None);
+            msg.append_items(&args);
+            serial += 1;
+            msg.set_serial(serial);
+            let sent = RefCell::new(vec!());
+            let problem = match catch_unwind(AssertUnwindSafe(|| cr.handle_message(msg, &sent))) {
+                Err(e) => Some(Problem::Panic(panic_message(&*e))),
+                Ok(_) => match sent.into_inner().into_iter().find(|m| m.msg_type() != MessageType::Signal) {
+                    None => Some(Problem::NoReply),
+                    Some(reply) if reply.msg_type() == MessageType::MethodReturn => {
+                        let actual: String = reply.get_items().iter().map(|i| i.signature().to_string()).collect();
+                        if actual != out_sig { Some(Problem::Malformed { expected: out_sig.clone(), actual }) } else { None }
+                    }
+                    Some(_) => None,
+                },
+            };
+            if let Some(problem) = problem {
+                let stop = matches!(problem, Problem::Panic(_));
+                findings.push(Finding { path: path.clone(), interface: interface.clone(), member: member.clone(), args, problem });
+                if stop { break }
+            }
+        }
+    }
+    findings
+}
@@ -1,9 +1,12 @@
 use dbus::blocking::stdintf::org_freedesktop_dbus::EmitsChangedSignal;
 use std::future::Future;
 use std::marker::PhantomData;
-use crate::{Context, PropContext, MethodErr, Crossroads, utils::Dbg};
+use crate::{Access, Context, PropContext, MethodErr, Crossroads, utils::Dbg};
+use crate::emitter::{IfaceEmitInfo, PropEmitInfo};
+use crate::property::{Binder, Property};
 use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::sync::{Arc, Mutex};
 use std::borrow::Cow;
 use dbus::{arg, strings};
 
@@ -24,16 +27,17 @@ impl Registry {
         Err(name.map(MethodErr::no_interface).unwrap_or_else(|| MethodErr::no_interface("")))
     }
 
-    pub fn take_method(&mut self, t: usize, name: &strings::Member<'static>) -> Result<Callback, MethodErr> {
-        let mdesc = self.0[t].methods.get_mut(name).ok_or_else(|| MethodErr::no_method(name))?;
-        let cb = mdesc.cb.take();
-        let cb = cb.ok_or_else(|| MethodErr::failed(&format!("Detected recursive call to {}", name)))?;
-        Ok(cb.0)
+    /// Takes the callback of a method, to call it. If it is in use, a new one is made if the
+    /// interface can be built again, see `IfaceBuilder::build_shared`.
+    pub fn take_method(&self, t: usize, name: &strings::Member<'static>) -> Result<Callback, MethodErr> {
+        let desc = &self.0[t];
+        let mdesc = desc.methods.get(name).ok_or_else(|| MethodErr::no_method(name))?;
+        let cb = mdesc.cb.take().or_else(|| desc.rebuild()?.methods.remove(name)?.cb.take());
+        cb.ok_or_else(|| MethodErr::failed(&format!("Detected recursive call to {}", name)))
     }
 
-    pub fn give_method(&mut self, t: usize, name: &strings::Member<'static>, cb: Callback) {
-        let x = self.0[t].methods.get_mut(name).unwrap();
-        x.cb = Some(CallbackDbg(cb));
+    pub fn give_method(&self, t: usize, name: &strings::Member<'static>, cb: Callback) {
+        self.0[t].methods[name].cb.give(cb);
     }
 
     pub fn prop_names_readable(&self, t: usize) -> impl Iterator<Item=&str> {
@@ -42,18 +46,27 @@ impl Registry {
         })
     }
 
-    pub fn take_prop(&mut self, t: usize, name: &str, is_set: bool) -> Result<PropCb, MethodErr> {
-        let pdesc = self.0[t].properties.get_mut(name).ok_or_else(|| MethodErr::no_property(name))?;
-        let cb = if is_set { pdesc.set_cb.take() } else { pdesc.get_cb.take() };
+    /// Like take_method, but for getting or setting a property.
+    pub fn take_prop(&self, t: usize, name: &str, is_set: bool) -> Result<PropCb, MethodErr> {
+        let desc = &self.0[t];
+        let pdesc = desc.properties.get(name).ok_or_else(|| MethodErr::no_property(name))?;
         let rw = if is_set { "writable" } else { "readable" };
-        let cb = cb.ok_or_else(|| MethodErr::failed(&format!("Property {} is not {}", name, rw)))?;
-        Ok(cb.0)
+        let pool = pdesc.cb(is_set).ok_or_else(|| MethodErr::failed(&format!("Property {} is not {}", name, rw)))?;
+        let cb = pool.take().or_else(|| desc.rebuild()?.properties.remove(name)?.cb(is_set)?.take());
+        cb.ok_or_else(|| MethodErr::failed(&format!("Detected recursive access to {}", name)))
     }
 
-    pub fn give_prop(&mut self, t: usize, name: &str, cb: PropCb, is_set: bool) {
-        let x = self.0[t].properties.get_mut(name).unwrap();
-        let cb = Some(Dbg(cb));
-        if is_set { x.set_cb = cb } else { x.get_cb = cb };
+    pub fn give_prop(&self, t: usize, name: &str, cb: PropCb, is_set: bool) {
+        if let Some(pool) = self.0[t].properties[name].cb(is_set) { pool.give(cb) }
+    }
+
+    pub fn method_guard(&self, t: usize, name: &strings::Member<'static>) -> Option<&Guard> {
+        self.0[t].methods.get(name).map(|m| &m.guard)
+    }
+
+    pub fn prop_guard(&self, t: usize, name: &str, is_set: bool) -> Option<&Guard> {
+        let pdesc = self.0[t].properties.get(name)?;
+        Some(if is_set { &pdesc.write_guard } else { &pdesc.read_guard })
     }
 
     pub fn has_props(&self, t: usize) -> bool { !self.0[t].properties.is_empty() }
@@ -125,14 +138,62 @@ impl Registry {
     pub fn get_intf_name(&self, t: usize) -> Option<&strings::Interface<'static>> {
         self.0.get(t)?.name.as_ref()
     }
+
+    /// Returns the EmitsChangedSignal annotation of a property, or of its interface if the property has none.
+    pub fn emits_changed(&self, t: usize, prop_name: &str) -> EmitsChangedSignal {
+        match self.find_annotation(t, EMITS_CHANGED, Some(prop_name)) {
+            Some("false") => EmitsChangedSignal::False,
+            Some("const") => EmitsChangedSignal::Const,
+            Some("invalidates") => EmitsChangedSignal::Invalidates,
+            _ => EmitsChangedSignal::True,
+        }
+    }
+
+    /// Returns the property cell binders of the interface, see `PropBuilder::bind`.
+    pub fn binders(&self, t: usize) -> impl Iterator<Item=(&str, &Binder)> {
+        self.0[t].properties.iter().filter_map(|(name, p)| Some((&**name, &p.binder.as_ref()?.0)))
+    }
+
+    /// Returns what an Emitter needs to know about the interface.
+    pub fn emit_info(&self, t: usize) -> IfaceEmitInfo {
+        let desc = &self.0[t];
+        let props = desc.properties.iter().map(|(name, p)| {
+            let emits = self.emits_changed(t, name);
+            (name.clone(), PropEmitInfo { sig: p.sig.to_string(), emits, order: p.order })
+        }).collect();
+        IfaceEmitInfo {
+            name: desc.name.clone(),
+            signals: desc.signals.iter().map(|(name, s)| (name.clone(), s.args.sig())).collect(),
+            props,
+        }
+    }
+
+    /// Returns name, input signature and output signature of every method.
+    pub fn method_sigs(&self, t: usize) -> impl Iterator<Item=(&strings::Member<'static>, String, String)> {
+        self.0[t].methods.iter().map(|(k, v)| (k, v.input_args.sig(), v.output_args.sig()))
+    }
 }
 
 pub type Callback = Box<dyn FnMut(Context, &mut Crossroads) -> Option<Context> + Send + 'static>;
 pub type PropCb = Box<dyn FnMut(PropContext, &mut Crossroads) -> Option<PropContext> + Send + 'static>;
 
-struct CallbackDbg(Callback);
+/// Builds an interface again, to get more callbacks.
+type Factory = Arc<dyn Fn() -> IfaceDesc + Send + Sync + 'static>;
 
-impl fmt::Debug for CallbackDbg {
+/// The callbacks of a method or property that are not running.
+///
+/// A callback is taken out while it runs, so that it can get the Crossroads instance. Usually
+/// there is one, but interfaces that can be built again get more when called from several
+/// threads at once.
+pub (crate) struct Pool<T>(Mutex<Vec<T>>);
+
+impl<T> Pool<T> {
+    fn new(x: T) -> Self { Pool(Mutex::new(vec!(x))) }
+    fn take(&self) -> Option<T> { self.0.lock().unwrap().pop() }
+    fn give(&self, x: T) { self.0.lock().unwrap().push(x) }
+}
+
+impl<T> fmt::Debug for Pool<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Callback") }
 }
 
@@ -176,6 +237,8 @@ struct Argument {
 pub struct Arguments(Vec<Argument>);
 
 impl Arguments {
+    fn sig(&self) -> String { self.0.iter().map(|a| &*a.sig).collect() }
+
     fn introspect(&self, dir: Option<&str>, prefix: &str) -> String {
         let mut r = String::new();
         for a in &self.0 {
@@ -195,10 +258,19 @@ impl Arguments {
 /// Struct used to describe a method when building an interface.
 #[derive(Debug)]
 pub struct MethodDesc {
-    cb: Option<CallbackDbg>,
+    cb: Pool<Callback>,
     input_args: Arguments,
     output_args: Arguments,
     annotations: Annotations,
+    guard: Guard,
+}
+
+/// What a caller must be allowed to call a method, or get or set a property.
+#[derive(Debug, Clone, Default)]
+pub struct Guard {
+    pub access: Option<Access>,
+    #[cfg(feature = "polkit")]
+    pub polkit_action: Option<String>,
 }
 
 impl MethodDesc {
@@ -207,6 +279,27 @@ impl MethodDesc {
         self
     }
     pub fn deprecated(&mut self) -> &mut Self { self.annotate(DEPRECATED, "true") }
+
+    /// Only lets callers that the access policy allows call this method.
+    ///
+    /// Other callers get an "org.freedesktop.DBus.Error.AccessDenied" error, without the
+    /// method handler being called. See `Crossroads::set_credentials_lookup`.
+    pub fn access<A: Into<Access>>(&mut self, access: A) -> &mut Self {
+        self.guard.access = Some(access.into());
+        self
+    }
+
+    /// Only lets callers that polkit authorizes for this action call this method.
+    ///
+    /// Other callers get an "org.freedesktop.DBus.Error.AccessDenied" error, or
+    /// "org.freedesktop.DBus.Error.InteractiveAuthorizationRequired" if they could be authorized
+    /// by authenticating, but did not allow interactive authorization.
+    /// See `Crossroads::set_polkit_authority`.
+    #[cfg(feature = "polkit")]
+    pub fn polkit_action<A: Into<String>>(&mut self, action_id: A) -> &mut Self {
+        self.guard.polkit_action = Some(action_id.into());
+        self
+    }
 }
 
 
@@ -255,8 +348,13 @@ impl<A: arg::AppendAll + 'static> SignalBuilder<'_, A> {
 pub struct PropDesc {
     annotations: Annotations,
     sig: dbus::Signature<'static>,
-    get_cb: Option<Dbg<PropCb>>,
-    set_cb: Option<Dbg<PropCb>>,
+    get_cb: Option<Pool<PropCb>>,
+    set_cb: Option<Pool<PropCb>>,
+    read_guard: Guard,
+    write_guard: Guard,
+    /// Properties are numbered in the order they were declared.
+    order: usize,
+    binder: Option<Dbg<Binder>>,
 }
 
 #[derive(Debug)]
@@ -266,6 +364,17 @@ pub struct IfaceDesc {
     methods: HashMap<strings::Member<'static>, MethodDesc>,
     signals: HashMap<strings::Member<'static>, SignalDesc>,
     properties: HashMap<String, PropDesc>,
+    factory: Option<Dbg<Factory>>,
+}
+
+impl PropDesc {
+    fn cb(&self, is_set: bool) -> Option<&Pool<PropCb>> {
+        if is_set { self.set_cb.as_ref() } else { self.get_cb.as_ref() }
+    }
+}
+
+impl IfaceDesc {
+    fn rebuild(&self) -> Option<IfaceDesc> { self.factory.as_ref().map(|f| (f.0)()) }
 }
 
 fn build_argvec<A: arg::ArgAll>(a: A::strs) -> Arguments {
@@ -297,7 +406,7 @@ impl<T: Send, A: Send + arg::RefArg + arg::Arg + arg::Append> PropBuilder<'_, T,
     pub fn get<CB>(self, mut cb: CB) -> Self
     where CB: FnMut(&mut PropContext, &mut T) -> Result<A, MethodErr> + Send + 'static {
         self.get_with_cr(move |ctx, cr| {
-            let data = cr.data_mut(ctx.path()).ok_or_else(|| MethodErr::no_path(ctx.path()))?;
+            let data = cr.object_data_mut(ctx.path(), ctx.iface_token()).ok_or_else(|| MethodErr::no_path(ctx.path()))?;
             cb(ctx, data)
         })
     }
@@ -313,7 +422,7 @@ impl<T: Send, A: Send + arg::RefArg + arg::Arg + arg::Append> PropBuilder<'_, T,
 
     pub (crate) fn get_custom<CB>(self, mut cb: CB) -> Self
     where CB: FnMut(PropContext, &mut Crossroads) -> Option<PropContext> + Send + 'static {
-        self.desc.get_cb = Some(Dbg(Box::new(move |ctx, cr| {
+        self.desc.get_cb = Some(Pool::new(Box::new(move |ctx, cr| {
             cb(ctx, cr)
         })));
         self
@@ -341,7 +450,7 @@ impl<T: Send, A: Send + arg::RefArg + arg::Arg + arg::Append> PropBuilder<'_, T,
     {
         self.get_with_cr_async(move |ctx, cr| {
             // It should be safe to unwrap here, the path has already been checked once (when dispatching the method)
-            let data = cr.data_mut(ctx.path()).unwrap();
+            let data = cr.object_data_mut(ctx.path(), ctx.iface_token()).unwrap();
             cb(ctx, data)
         })
     }
@@ -378,6 +487,29 @@ impl<T: Send, A: Send + arg::RefArg + arg::Arg + arg::Append> PropBuilder<'_, T,
     }
 }
 
+impl<T: Send, A: Send + PartialEq + Clone + arg::RefArg + arg::Arg + arg::Append> PropBuilder<'_, T, A> {
+    /// Binds a property cell in the object data to this property, for reading.
+    ///
+    /// The closure returns the cell for the object data. Reading the property returns the
+    /// value of the cell, and when the object is inserted into the tree, the cell is set up to
+    /// queue PropertiesChanged signals when its value is changed. See `Property` for details.
+    pub fn bind<F>(self, f: F) -> Self
+    where F: Fn(&mut T) -> &mut Property<A> + Send + Sync + 'static {
+        self.bind_arc(Arc::new(f))
+    }
+
+    fn bind_arc(self, f: CellFn<T, A>) -> Self {
+        let f2 = f.clone();
+        self.desc.binder = Some(Dbg(Box::new(move |data, link| {
+            if let Some(data) = data.downcast_mut::<T>() { f2(data).bind(link) }
+        })));
+        self.get(move |_, data| Ok(f(data).get().clone()))
+    }
+}
+
+/// Returns the property cell in the object data.
+type CellFn<T, A> = Arc<dyn Fn(&mut T) -> &mut Property<A> + Send + Sync + 'static>;
+
 pub const EMITS_CHANGED: &'static str = "org.freedesktop.DBus.Property.EmitsChangedSignal";
 const DEPRECATED: &'static str = "org.freedesktop.DBus.Deprecated";
 
@@ -389,7 +521,7 @@ impl<T: Send, A: arg::RefArg + Send + for<'x> arg::Get<'x> + arg::Arg + arg::App
     pub fn set<CB>(self, mut cb: CB) -> Self
     where CB: FnMut(&mut PropContext, &mut T, A) -> Result<Option<A>, MethodErr> + Send + 'static {
         self.set_with_cr(move |ctx, cr, a| {
-            let data = cr.data_mut(ctx.path()).ok_or_else(|| MethodErr::no_path(ctx.path()))?;
+            let data = cr.object_data_mut(ctx.path(), ctx.iface_token()).ok_or_else(|| MethodErr::no_path(ctx.path()))?;
             cb(ctx, data, a)
         })
     }
@@ -412,7 +544,7 @@ impl<T: Send, A: arg::RefArg + Send + for<'x> arg::Get<'x> + arg::Arg + arg::App
 
     pub fn set_custom<CB>(self, mut cb: CB) -> Self
     where CB: FnMut(PropContext, &mut Crossroads, A) -> Option<PropContext> + Send + 'static {
-        self.desc.set_cb = Some(Dbg(Box::new(move |mut ctx, cr| {
+        self.desc.set_cb = Some(Pool::new(Box::new(move |mut ctx, cr| {
             match ctx.check(|ctx| {
                 let ctx = ctx.unwrap();
                 let mut i = ctx.message().iter_init();
@@ -449,12 +581,27 @@ impl<T: Send, A: arg::RefArg + Send + for<'x> arg::Get<'x> + arg::Arg + arg::App
     {
         self.set_with_cr_async(move |ctx, cr, a| {
             // It should be safe to unwrap here, the path has already been checked once (when dispatching the method)
-            let data = cr.data_mut(ctx.path()).unwrap();
+            let data = cr.object_data_mut(ctx.path(), ctx.iface_token()).unwrap();
             cb(ctx, data, a)
         })
     }
 }
 
+impl<T: Send, A: Send + PartialEq + Clone + for<'x> arg::Get<'x> + arg::RefArg + arg::Arg + arg::Append> PropBuilder<'_, T, A> {
+    /// Binds a property cell in the object data to this property, for reading and writing.
+    ///
+    /// Like `bind`, but setting the property sets the cell, which queues a PropertiesChanged
+    /// signal if the value changed.
+    pub fn bind_writable<F>(self, f: F) -> Self
+    where F: Fn(&mut T) -> &mut Property<A> + Send + Sync + 'static {
+        let f: CellFn<T, A> = Arc::new(f);
+        self.bind_arc(f.clone()).set(move |_, data, a| {
+            f(data).set(a);
+            Ok(None)
+        })
+    }
+}
+
 impl<T: std::marker::Send, A> PropBuilder<'_, T, A> {
 
     pub fn annotate<N: Into<String>, V: Into<String>>(self, name: N, value: V) -> Self {
@@ -480,6 +627,47 @@ impl<T: std::marker::Send, A> PropBuilder<'_, T, A> {
         self.emits_changed = EmitsChangedSignal::True;
         self.annotate(EMITS_CHANGED, "true")
     }
+
+    /// Only lets callers that the access policy allows get or set this property.
+    ///
+    /// Other callers get an "org.freedesktop.DBus.Error.AccessDenied" error, and the property is
+    /// left out when they get all properties. It is always left out of "InterfacesAdded" signals.
+    /// See `Crossroads::set_credentials_lookup`.
+    pub fn access<P: Into<Access>>(self, access: P) -> Self {
+        let access = access.into();
+        self.desc.read_guard.access = Some(access.clone());
+        self.desc.write_guard.access = Some(access);
+        self
+    }
+
+    /// Only lets callers that the access policy allows set this property.
+    ///
+    /// Use this after `access` for a property that fewer callers may set than get.
+    pub fn write_access<P: Into<Access>>(self, access: P) -> Self {
+        self.desc.write_guard.access = Some(access.into());
+        self
+    }
+
+    /// Only lets callers that polkit authorizes for this action get or set this property.
+    ///
+    /// Other callers get an error, and the property is left out when they get all properties.
+    /// It is always left out of "InterfacesAdded" signals. See `MethodDesc::polkit_action`.
+    #[cfg(feature = "polkit")]
+    pub fn polkit_action<P: Into<String>>(self, action_id: P) -> Self {
+        let action_id = action_id.into();
+        self.desc.read_guard.polkit_action = Some(action_id.clone());
+        self.desc.write_guard.polkit_action = Some(action_id);
+        self
+    }
+
+    /// Only lets callers that polkit authorizes for this action set this property.
+    ///
+    /// Use this after `polkit_action` for a property that fewer callers may set than get.
+    #[cfg(feature = "polkit")]
+    pub fn write_polkit_action<P: Into<String>>(self, action_id: P) -> Self {
+        self.desc.write_guard.polkit_action = Some(action_id.into());
+        self
+    }
 }
 
 /// Struct used to build an interface.
@@ -496,6 +684,11 @@ impl<T: std::marker::Send, A> PropBuilder<'_, T, A> {
 /// other tasks with method calls can run as separate tasks. Remember to call Crossroads::set_async_support
 /// when using async methods.
 ///
+/// With `ConcurrentCrossroads::register`, the interface may be built several times, to handle calls
+/// from several threads at once. Each copy has its own handlers, so state captured by a handler
+/// closure (e g a counter moved into it) is not shared between the copies. Keep such state in the
+/// object data, or behind an `Arc` created outside the closure given to `register`.
+///
 #[derive(Debug)]
 pub struct IfaceBuilder<T: Send + 'static>(IfaceDesc, PhantomData<&'static T>);
 
@@ -503,12 +696,17 @@ impl<T: Send + 'static> IfaceBuilder<T> {
     pub fn property<A: arg::Arg, N: Into<String>>(&mut self, name: N) -> PropBuilder<'_, T, A> {
         let key = name.into();
         let prop_name = key.clone();
+        let order = self.0.properties.len();
         PropBuilder {
             desc: self.0.properties.entry(key).or_insert(PropDesc {
+                order,
                 annotations: Default::default(),
                 get_cb: None,
                 set_cb: None,
+                read_guard: Default::default(),
+                write_guard: Default::default(),
                 sig: A::signature(),
+                binder: None,
             }),
             _dummy: PhantomData,
             emits_changed: EmitsChangedSignal::True,
@@ -522,7 +720,7 @@ impl<T: Send + 'static> IfaceBuilder<T> {
     N: Into<strings::Member<'static>>,
     CB: FnMut(&mut Context, &mut T, IA) -> Result<OA, MethodErr> + Send + 'static {
         self.method_with_cr(name, input_args, output_args, move |ctx, cr, ia| {
-            let data = cr.data_mut(ctx.path()).ok_or_else(|| MethodErr::no_path(ctx.path()))?;
+            let data = cr.object_data_mut(ctx.path(), ctx.iface_token()).ok_or_else(|| MethodErr::no_path(ctx.path()))?;
             cb(ctx, data, ia)
         })
     }
@@ -544,7 +742,8 @@ impl<T: Send + 'static> IfaceBuilder<T> {
             annotations: Default::default(),
             input_args: build_argvec::<IA>(input_args),
             output_args: build_argvec::<OA>(output_args),
-            cb: Some(CallbackDbg(boxed)),
+            cb: Pool::new(boxed),
+            guard: Default::default(),
         })
     }
 
@@ -562,7 +761,8 @@ impl<T: Send + 'static> IfaceBuilder<T> {
             annotations: Default::default(),
             input_args: build_argvec::<IA>(input_args),
             output_args: build_argvec::<OA>(output_args),
-            cb: Some(CallbackDbg(boxed)),
+            cb: Pool::new(boxed),
+            guard: Default::default(),
         })
     }
 
@@ -624,8 +824,19 @@ impl<T: Send + 'static> IfaceBuilder<T> {
             methods: Default::default(),
             signals: Default::default(),
             properties: Default::default(),
+            factory: None,
         }, PhantomData);
         f(&mut b);
         b.0
     }
+
+    /// Like build, but the interface can be built again, to handle calls from several threads
+    /// at once.
+    pub (crate) fn build_shared<F>(name: Option<strings::Interface<'static>>, f: F) -> IfaceDesc
+    where F: Fn(&mut IfaceBuilder<T>) + Send + Sync + 'static {
+        let f = Arc::new(f);
+        let mut desc = Self::build(name.clone(), &*f);
+        desc.factory = Some(Dbg(Arc::new(move || Self::build(name.clone(), &*f))));
+        desc
+    }
 }
@@ -11,12 +11,29 @@ mod context;
 mod crossroads;
 mod ifacedesc;
 mod stdimpl;
+mod middleware;
+mod credentials;
+mod emitter;
+mod property;
+mod concurrent;
+mod connections;
+
+pub mod replay;
+pub mod fuzz;
+#[cfg(feature = "polkit")]
+pub mod polkit;
 
 pub use dbus::MethodErr as MethodErr;
 
 pub use context::Context;
+pub use middleware::Middleware;
+pub use emitter::{Batch, Emitter};
+pub use property::Property;
+pub use credentials::{Access, Credentials, CredentialsLookup};
 pub use stdimpl::PropContext;
-pub use crossroads::{Crossroads, IfaceToken};
+pub use crossroads::{Crossroads, Deferred, DeferredDispatch, IfaceToken};
+pub use concurrent::ConcurrentCrossroads;
+pub use connections::{ConnectionId, Connections};
 
 pub use ifacedesc::{MethodDesc, SignalDesc, IfaceBuilder, PropBuilder};
 
@@ -25,6 +42,7 @@ mod test;
 
 mod utils {
     use std::fmt;
+    #[derive(Clone)]
     pub (crate) struct Dbg<T>(pub T);
 
     impl<T> fmt::Debug for Dbg<T> {
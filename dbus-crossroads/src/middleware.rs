@@ -0,0 +1,30 @@
+use crate::{Context, MethodErr};
+
+/// Middleware sees every method call before and after it is dispatched to its handler.
+///
+/// Add middleware with `Crossroads::add_middleware`. Middleware runs in the order it was added:
+/// `before` is called in that order, and `after` in the reverse order, so the first middleware
+/// added is the outermost one. Typical uses are logging, access checks, rate limiting and metrics.
+///
+/// Per-call state (e g, a start time or a request id) can be stored in the context with
+/// `Context::insert_extension`, where both `after` and the method handler can get it back.
+pub trait Middleware: Send + Sync + 'static {
+    /// Called before the method call is dispatched.
+    ///
+    /// Returning an error short-circuits the call: later middleware and the method handler
+    /// are not called, and the error is sent back as reply.
+    fn before(&self, ctx: &mut Context) -> Result<(), MethodErr> {
+        let _ = ctx;
+        Ok(())
+    }
+
+    /// Called when the method call has completed, right before the reply is sent.
+    ///
+    /// For async methods, this is when the spawned task has finished. `reply` is the method
+    /// return or error message, or None if the caller asked for no reply.
+    /// This is called for every middleware whose `before` was called, even if a later
+    /// middleware short-circuited the call.
+    fn after(&self, ctx: &Context, reply: Option<&dbus::Message>) {
+        let _ = (ctx, reply);
+    }
+}
@@ -0,0 +1,325 @@
+//! Authorizing method calls and property access with polkit.
+//!
+//! Declare the polkit action needed with `MethodDesc::polkit_action` or `PropBuilder::polkit_action`,
+//! and set an authority with `Crossroads::set_polkit_authority`. Before such a method is
+//! dispatched, the authority is asked whether the caller is authorized for the action.
+//! This needs libdbus 1.8.10 or later, to read whether callers allow interactive authorization.
+//! Like credentials lookups, the answer is a future. If it is not ready at once, the call waits
+//! for it in a spawned task.
+//!
+//! # Example
+//!
+//! ```
+//! use dbus_crossroads::Crossroads;
+//! use dbus_crossroads::polkit::{LocalAuthority, Implicit};
+//!
+//! let mut cr = Crossroads::new();
+//! let token = cr.register("com.example.Power", |b| {
+//!     b.method("Reboot", (), (), |_, _, _: ()| Ok(())).polkit_action("com.example.power.reboot");
+//! });
+//! cr.insert("/", &[token], ());
+//!
+//! // In a real service, use SystemAuthority instead.
+//! let authority = LocalAuthority::new();
+//! authority.set("com.example.power.reboot", Implicit::AuthRequired);
+//! cr.set_polkit_authority(Some(Box::new(authority)));
+//! ```
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::fmt;
+use dbus::arg::{PropMap, Variant};
+use crate::{ConnectionId, MethodErr};
+
+/// The key in `Authorization::details` polkit uses for the temporary authorization it granted,
+/// when the user has authenticated for an action with "auth_admin_keep" or "auth_self_keep".
+pub const TEMPORARY_AUTHORIZATION_ID: &str = "polkit.temporary_authorization_id";
+
+/// The answer to an authorization check.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Authorization {
+    /// The caller is authorized.
+    pub is_authorized: bool,
+    /// The caller is not authorized, but could be if authenticating.
+    pub is_challenge: bool,
+    /// Extra details from the authority.
+    pub details: HashMap<String, String>,
+}
+
+impl Authorization {
+    /// The id of the temporary authorization granted, if any.
+    pub fn temporary_authorization_id(&self) -> Option<&str> {
+        self.details.get(TEMPORARY_AUTHORIZATION_ID).map(|s| &**s)
+    }
+}
+
+/// The answer of an `Authority`, which may arrive later.
+pub type AuthorityFuture<T> = Pin<Box<dyn Future<Output = Result<T, MethodErr>> + Send + 'static>>;
+
+/// Something that decides whether a bus name is authorized for a polkit action.
+pub trait Authority: Send + Sync + 'static {
+    /// Checks whether the connection with this unique name is authorized for the action.
+    ///
+    /// If `allow_interaction` is true, the caller may be asked to authenticate, which
+    /// can take a long time.
+    fn check_authorization(&self, bus_name: &str, action_id: &str, allow_interaction: bool) -> AuthorityFuture<Authorization>;
+
+    /// Returns when the temporary authorization with this id, granted to the connection with
+    /// this unique name, expires.
+    ///
+    /// Positive decisions are cached until then. Defaults to None, i e, nothing is cached.
+    fn temporary_authorization_expires(&self, _bus_name: &str, _authorization_id: &str) -> AuthorityFuture<Option<SystemTime>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    /// Returns true if the answers may arrive later, which needs async support, see
+    /// `Crossroads::set_polkit_authority`.
+    ///
+    /// Defaults to false, i e, the futures finish when first polled.
+    fn is_nonblock(&self) -> bool { false }
+}
+
+impl<A: Authority> Authority for Arc<A> {
+    fn check_authorization(&self, bus_name: &str, action_id: &str, allow_interaction: bool) -> AuthorityFuture<Authorization> {
+        (**self).check_authorization(bus_name, action_id, allow_interaction)
+    }
+    fn temporary_authorization_expires(&self, bus_name: &str, authorization_id: &str) -> AuthorityFuture<Option<SystemTime>> {
+        (**self).temporary_authorization_expires(bus_name, authorization_id)
+    }
+    fn is_nonblock(&self) -> bool { (**self).is_nonblock() }
+}
+
+#[derive(Clone)]
+enum Conn {
+    Blocking(Arc<dbus::blocking::SyncConnection>),
+    #[cfg(feature = "futures")]
+    Nonblock(Arc<dbus::nonblock::SyncConnection>),
+}
+
+/// The polkit authority on the system bus.
+pub struct SystemAuthority {
+    conn: Conn,
+    timeout: Duration,
+}
+
+impl fmt::Debug for SystemAuthority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "SystemAuthority") }
+}
+
+impl SystemAuthority {
+    /// Creates a new authority, talking to polkit through this blocking connection to the system bus.
+    ///
+    /// The answers are futures making a blocking call when first polled, so they finish at once,
+    /// at the cost of blocking the thread handling the message until polkit answers. If the
+    /// caller allows interactive authorization, this can take a long time.
+    ///
+    /// The connection should be a separate connection, so that it can be used while a message
+    /// is being handled. Interactive authorization can take a long time, so the timeout should be
+    /// long enough for the user to type a password.
+    pub fn new(conn: Arc<dbus::blocking::SyncConnection>, timeout: Duration) -> Self {
+        SystemAuthority { conn: Conn::Blocking(conn), timeout }
+    }
+
+    /// Creates a new authority, talking to polkit through this non-blocking connection to the
+    /// system bus.
+    ///
+    /// The checks do not block. Calls wait for the answer in a spawned task, so this needs async
+    /// support, see `Crossroads::set_polkit_authority`.
+    #[cfg(feature = "futures")]
+    pub fn nonblock(conn: Arc<dbus::nonblock::SyncConnection>, timeout: Duration) -> Self {
+        SystemAuthority { conn: Conn::Nonblock(conn), timeout }
+    }
+
+    fn call<A, R>(&self, method: &'static str, args: A) -> AuthorityFuture<R>
+    where A: dbus::arg::AppendAll + Send + 'static, R: dbus::arg::ReadAll + Send + 'static {
+        const DEST: &str = "org.freedesktop.PolicyKit1";
+        const PATH: &str = "/org/freedesktop/PolicyKit1/Authority";
+        const IFACE: &str = "org.freedesktop.PolicyKit1.Authority";
+        let timeout = self.timeout;
+        match self.conn.clone() {
+            Conn::Blocking(conn) => Box::pin(async move {
+                Ok(conn.with_proxy(DEST, PATH, timeout).method_call(IFACE, method, args)?)
+            }),
+            #[cfg(feature = "futures")]
+            Conn::Nonblock(conn) => {
+                let proxy = dbus::nonblock::Proxy::new(DEST, PATH, timeout, conn);
+                Box::pin(async move { Ok(proxy.method_call(IFACE, method, args).await?) })
+            }
+        }
+    }
+}
+
+fn subject(bus_name: &str) -> (&'static str, PropMap) {
+    let mut details = PropMap::new();
+    details.insert("name".into(), Variant(Box::new(bus_name.to_string())));
+    ("system-bus-name", details)
+}
+
+impl Authority for SystemAuthority {
+    fn check_authorization(&self, bus_name: &str, action_id: &str, allow_interaction: bool) -> AuthorityFuture<Authorization> {
+        let details: HashMap<String, String> = HashMap::new();
+        // 0x1 is AllowUserInteraction
+        let flags: u32 = if allow_interaction { 1 } else { 0 };
+        let reply = self.call::<_, ((bool, bool, HashMap<String, String>),)>("CheckAuthorization",
+            (subject(bus_name), action_id.to_string(), details, flags, ""));
+        Box::pin(async move {
+            let ((is_authorized, is_challenge, details),) = reply.await?;
+            Ok(Authorization { is_authorized, is_challenge, details })
+        })
+    }
+
+    fn temporary_authorization_expires(&self, bus_name: &str, authorization_id: &str) -> AuthorityFuture<Option<SystemTime>> {
+        let reply = self.call::<_, (Vec<(String, String, (String, PropMap), u64, u64)>,)>("EnumerateTemporaryAuthorizations",
+            (subject(bus_name),));
+        let authorization_id = authorization_id.to_string();
+        Box::pin(async move {
+            let (auths,) = reply.await?;
+            // Times are in seconds since the epoch
+            Ok(auths.into_iter().find(|a| a.0 == authorization_id).map(|a| UNIX_EPOCH + Duration::from_secs(a.4)))
+        })
+    }
+
+    fn is_nonblock(&self) -> bool {
+        match self.conn {
+            Conn::Blocking(_) => false,
+            #[cfg(feature = "futures")]
+            Conn::Nonblock(_) => true,
+        }
+    }
+}
+
+/// The implicit authorization of an action, like the `allow_any`/`allow_active` elements in a polkit policy file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Implicit {
+    /// Always authorized.
+    Yes,
+    /// Never authorized.
+    No,
+    /// Authorized after authenticating, which needs interactive authorization.
+    AuthRequired,
+    /// Like AuthRequired, but the authorization is kept for a while after authenticating.
+    AuthRequiredKeep,
+}
+
+/// A stand-in for polkit, e g for tests.
+///
+/// Actions not set are not authorized. Callers of "AuthRequired" actions are authorized if they
+/// allow interactive authorization, as if they authenticated successfully. Authorizations for
+/// "AuthRequiredKeep" actions are temporary authorizations, which expire after five minutes, like
+/// in polkit.
+#[derive(Debug, Default)]
+pub struct LocalAuthority {
+    actions: Mutex<HashMap<String, Implicit>>,
+    checks: Mutex<Vec<(String, String, bool)>>,
+    temporary: Mutex<Vec<(String, String, SystemTime)>>,
+}
+
+impl LocalAuthority {
+    /// Creates a new authority, with no actions.
+    pub fn new() -> Self { Default::default() }
+
+    /// Sets the implicit authorization of an action.
+    pub fn set<A: Into<String>>(&self, action_id: A, implicit: Implicit) {
+        self.actions.lock().unwrap().insert(action_id.into(), implicit);
+    }
+
+    /// Returns bus name, action id and whether interaction was allowed, for every check made so far.
+    pub fn checks(&self) -> Vec<(String, String, bool)> {
+        self.checks.lock().unwrap().clone()
+    }
+}
+
+impl Authority for LocalAuthority {
+    fn check_authorization(&self, bus_name: &str, action_id: &str, allow_interaction: bool) -> AuthorityFuture<Authorization> {
+        self.checks.lock().unwrap().push((bus_name.into(), action_id.into(), allow_interaction));
+        let implicit = self.actions.lock().unwrap().get(action_id).copied().unwrap_or(Implicit::No);
+        let (is_authorized, is_challenge) = match implicit {
+            Implicit::Yes => (true, false),
+            Implicit::No => (false, false),
+            Implicit::AuthRequired | Implicit::AuthRequiredKeep => (allow_interaction, !allow_interaction),
+        };
+        let mut details = HashMap::new();
+        if is_authorized && implicit == Implicit::AuthRequiredKeep {
+            let mut temporary = self.temporary.lock().unwrap();
+            let id = format!("tmpauthz{}", temporary.len());
+            temporary.push((id.clone(), bus_name.into(), SystemTime::now() + Duration::from_secs(300)));
+            details.insert(TEMPORARY_AUTHORIZATION_ID.into(), id);
+        }
+        Box::pin(std::future::ready(Ok(Authorization { is_authorized, is_challenge, details })))
+    }
+
+    fn temporary_authorization_expires(&self, bus_name: &str, authorization_id: &str) -> AuthorityFuture<Option<SystemTime>> {
+        let temporary = self.temporary.lock().unwrap();
+        let expires = temporary.iter().find(|t| t.0 == authorization_id && t.1 == bus_name).map(|t| t.2);
+        Box::pin(std::future::ready(Ok(expires)))
+    }
+}
+
+/// The authorities, and the temporary authorizations granted so far, keyed by connection, unique
+/// connection name and action id.
+///
+/// Calls that did not arrive on an attached connection use the connection None.
+#[derive(Default)]
+pub (crate) struct AuthorityCache {
+    authorities: Mutex<HashMap<Option<ConnectionId>, Arc<dyn Authority>>>,
+    cache: Mutex<HashMap<CacheKey, Instant>>,
+}
+
+// Connection, unique connection name and action id
+type CacheKey = (Option<ConnectionId>, String, String);
+
+impl fmt::Debug for AuthorityCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "AuthorityCache") }
+}
+
+impl AuthorityCache {
+    /// Sets the authority for the connection, forgetting the authorizations the old one granted.
+    pub fn set_authority(&self, conn: Option<ConnectionId>, authority: Option<Box<dyn Authority>>) {
+        let mut authorities = self.authorities.lock().unwrap();
+        match authority {
+            Some(a) => authorities.insert(conn, a.into()),
+            None => authorities.remove(&conn),
+        };
+        self.cache.lock().unwrap().retain(|k, _| k.0 != conn);
+    }
+
+    pub fn has_authority(&self, conn: Option<ConnectionId>) -> bool {
+        self.authorities.lock().unwrap().contains_key(&conn)
+    }
+
+    pub fn check(self: &Arc<Self>, conn: Option<ConnectionId>, bus_name: String, action_id: String, allow_interaction: bool) -> impl Future<Output = Result<(), MethodErr>> + Send + 'static {
+        let this = self.clone();
+        async move {
+            let key = (conn, bus_name, action_id);
+            let now = Instant::now();
+            if let Some(expires) = this.cache.lock().unwrap().get(&key) {
+                if *expires > now { return Ok(()) }
+            }
+            let authority = this.authorities.lock().unwrap().get(&conn).cloned();
+            let authority = authority.ok_or_else(|| dbus::StandardError::AccessDenied("No polkit authority available".into()))?;
+            let (bus_name, action_id) = (&key.1, &key.2);
+            let a = authority.check_authorization(bus_name, action_id, allow_interaction).await?;
+            if a.is_authorized {
+                // Other positive decisions may change at any time, e g when the session becomes inactive
+                if let Some(id) = a.temporary_authorization_id() {
+                    let expires = authority.temporary_authorization_expires(bus_name, id).await.ok().flatten();
+                    if let Some(left) = expires.and_then(|e| e.duration_since(SystemTime::now()).ok()) {
+                        this.cache.lock().unwrap().insert(key, now + left);
+                    }
+                }
+                Ok(())
+            } else if a.is_challenge && !allow_interaction {
+                Err(dbus::StandardError::InteractiveAuthorizationRequired(format!("Interactive authorization required for {}", action_id)).into())
+            } else {
+                Err(dbus::StandardError::AccessDenied(format!("Not authorized for {}", action_id)).into())
+            }
+        }
+    }
+
+    pub fn remove(&self, conn: Option<ConnectionId>, bus_name: &str) {
+        self.cache.lock().unwrap().retain(|(c, name, _), _| *c != conn || name != bus_name);
+    }
+}
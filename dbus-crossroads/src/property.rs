@@ -0,0 +1,95 @@
+use std::any::Any;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use dbus::arg::{Arg, RefArg};
+use dbus::blocking::stdintf::org_freedesktop_dbus::EmitsChangedSignal;
+use dbus::blocking::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged as PPC;
+use dbus::message::SignalArgs;
+
+/// PropertiesChanged signals queued by property cells, waiting to be sent.
+pub (crate) type ChangeQueue = Arc<Mutex<Vec<dbus::Message>>>;
+
+/// Attaches a property cell in the object data to its object path, interface and property name.
+pub (crate) type Binder = Box<dyn Fn(&mut (dyn Any + Send), Link) + Send + Sync + 'static>;
+
+/// Where a property cell lives, and where to queue its changes.
+#[derive(Clone)]
+pub (crate) struct Link {
+    pub path: dbus::Path<'static>,
+    pub interface: dbus::strings::Interface<'static>,
+    pub name: String,
+    pub emits: EmitsChangedSignal,
+    pub queue: ChangeQueue,
+}
+
+impl fmt::Debug for Link {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}.{}", self.path, self.interface, self.name)
+    }
+}
+
+/// A property value in object data, which signals PropertiesChanged when it is changed.
+///
+/// Bind the cell to a property with `PropBuilder::bind` or `PropBuilder::bind_writable`.
+/// When the object is inserted, the cell learns its path, and from then on `set` queues a
+/// PropertiesChanged signal if the value changed, honouring the property's EmitsChangedSignal
+/// annotation. Queued signals are sent together with the reply to the current method call, or by
+/// `Crossroads::flush_property_changes` for changes made outside method handlers.
+///
+/// # Example
+///
+/// ```
+/// use dbus_crossroads::{Crossroads, Property};
+///
+/// struct Sensor { temp: Property<f64> }
+///
+/// let mut cr = Crossroads::new();
+/// let token = cr.register("com.example.Sensor", |b| {
+///     b.property("Temperature").bind(|s: &mut Sensor| &mut s.temp);
+/// });
+/// cr.insert("/sensor", &[token], Sensor { temp: Property::new(20.5) });
+///
+/// let sensor: &mut Sensor = cr.data_mut(&"/sensor".into()).unwrap();
+/// assert!(sensor.temp.set(21.0));
+/// let sent = std::cell::RefCell::new(vec!());
+/// cr.flush_property_changes(&sent).unwrap();
+/// assert_eq!(sent.borrow().len(), 1);
+/// ```
+#[derive(Debug, Default)]
+pub struct Property<T> {
+    value: T,
+    link: Option<Link>,
+}
+
+impl<T> Property<T> {
+    /// Creates a new cell with this value.
+    pub fn new(value: T) -> Self { Property { value, link: None } }
+
+    /// The current value.
+    pub fn get(&self) -> &T { &self.value }
+
+    pub (crate) fn bind(&mut self, link: Link) { self.link = Some(link); }
+}
+
+impl<T: PartialEq + RefArg + Arg + Clone + 'static> Property<T> {
+    /// Changes the value, and queues a PropertiesChanged signal if it differs from the old one.
+    ///
+    /// Returns true if the value changed.
+    pub fn set(&mut self, value: T) -> bool {
+        if self.value == value { return false }
+        self.value = value;
+        if let Some(link) = &self.link {
+            if link.emits == EmitsChangedSignal::Const { return true }
+            let mut ppc = PPC {
+                interface_name: link.interface.to_string(),
+                invalidated_properties: vec!(),
+                changed_properties: Default::default(),
+            };
+            let value = &self.value;
+            if ppc.add_prop(&link.name, link.emits, || Box::new(value.clone())) {
+                link.queue.lock().unwrap().push(ppc.to_emit_message(&link.path));
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,342 @@
+//! Replaying recorded method calls, for regression testing of servers.
+//!
+//! A `Recording` is a list of method calls together with the replies they are expected to get.
+//! It can be captured from a running service (e.g. with `dbus::blocking::Monitor` or
+//! `busctl capture`) and stored in pcapng format, or built up in code.
+//! Replaying it against a `Crossroads` instance, or a service on a connection, compares the actual
+//! replies to the expected ones. Serial numbers and senders are ignored, and arguments
+//! that differ are reported one by one.
+//!
+//! # Example
+//!
+//! ```
+//! use dbus_crossroads::Crossroads;
+//! use dbus_crossroads::replay::{Recording, replay_crossroads};
+//! use dbus::Message;
+//!
+//! let mut cr = Crossroads::new();
+//! let token = cr.register("com.example.Calc", |b| {
+//!     b.method("Add", ("a", "b"), ("sum",), |_, _, (a, b): (i32, i32)| Ok((a + b,)));
+//! });
+//! cr.insert("/", &[token], ());
+//!
+//! let mut rec = Recording::new();
+//! let mut call = Message::new_method_call("com.example.Calc", "/", "com.example.Calc", "Add").unwrap().append2(2i32, 3i32);
+//! call.set_serial(1);
+//! let reply = call.method_return().append1(5i32);
+//! rec.push(call, Some(reply));
+//!
+//! let report = replay_crossroads(&mut cr, &rec);
+//! assert!(report.is_ok(), "{}", report);
+//! ```
+
+use std::{fmt, io};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::time::Duration;
+use dbus::{Message, MessageType};
+use dbus::arg::messageitem::MessageItem;
+use dbus::arg::text::GVariantDisplay;
+use dbus::blocking::BlockingSender;
+use dbus::channel::Sender;
+use dbus::strings::{BusName, ErrorName};
+use crate::Crossroads;
+
+/// A recorded method call, and the reply it got.
+#[derive(Debug)]
+pub struct Exchange {
+    /// The method call.
+    pub call: Message,
+    /// The reply (method return or error), or None if no reply is expected.
+    pub reply: Option<Message>,
+}
+
+/// A sequence of method calls and their expected replies.
+#[derive(Debug, Default)]
+pub struct Recording {
+    exchanges: Vec<Exchange>,
+}
+
+impl Recording {
+    /// Creates an empty recording.
+    pub fn new() -> Self { Default::default() }
+
+    /// Adds a method call and its expected reply.
+    ///
+    /// The reply should be created from the call, e g with `method_return`, so that they can be
+    /// matched up when the recording is written to a file and read back. This requires the call
+    /// to have a serial number. A call without one is given the next free one.
+    pub fn push(&mut self, mut call: Message, reply: Option<Message>) {
+        if call.get_serial().is_none() { call.set_serial(self.exchanges.len() as u32 + 1) };
+        self.exchanges.push(Exchange { call, reply });
+    }
+
+    /// Builds a recording from captured messages.
+    ///
+    /// Method calls are paired up with the replies that follow them, by sender and serial.
+    /// Signals, and replies to method calls that were not captured, are skipped.
+    pub fn from_messages<I: IntoIterator<Item=Message>>(msgs: I) -> Self {
+        let mut exchanges = vec!();
+        let mut pending = HashMap::new();
+        for msg in msgs {
+            match msg.msg_type() {
+                MessageType::MethodCall => {
+                    if !msg.get_no_reply() {
+                        if let Some(serial) = msg.get_serial() {
+                            pending.insert((msg.sender().map(|s| s.to_string()), serial), exchanges.len());
+                        }
+                    }
+                    exchanges.push(Exchange { call: msg, reply: None });
+                },
+                MessageType::MethodReturn | MessageType::Error => {
+                    let key = (msg.destination().map(|s| s.to_string()), msg.get_reply_serial().unwrap_or(0));
+                    if let Some(idx) = pending.remove(&key) {
+                        exchanges[idx].reply = Some(msg);
+                    }
+                },
+                _ => {},
+            }
+        }
+        Recording { exchanges }
+    }
+
+    /// Reads a recording from a pcapng capture, see `dbus::capture`.
+    pub fn read<R: io::Read>(r: R) -> Result<Self, dbus::Error> {
+        let reader = dbus::capture::Reader::new(r).map_err(|e| dbus::Error::new_failed(&e.to_string()))?;
+        let msgs = reader.map(|r| r.map(|(_, msg)| msg)).collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::from_messages(msgs))
+    }
+
+    /// Writes the recording in pcapng format, see `dbus::capture`.
+    ///
+    /// Replies without a serial number are given one, as a message cannot be marshalled without it.
+    pub fn write<W: io::Write>(&self, w: W) -> io::Result<W> {
+        let mut w = dbus::capture::Writer::new(w)?;
+        let mut serial = self.exchanges.iter().filter_map(|e| e.call.get_serial()).max().unwrap_or(0);
+        for e in &self.exchanges {
+            w.write_message(&e.call)?;
+            match &e.reply {
+                Some(reply) if reply.get_serial().is_none() => {
+                    let mut reply = reply.duplicate().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    serial += 1;
+                    reply.set_serial(serial);
+                    w.write_message(&reply)?;
+                },
+                Some(reply) => w.write_message(reply)?,
+                None => {},
+            }
+        }
+        w.flush()?;
+        Ok(w.into_inner())
+    }
+
+    /// The recorded method calls and replies.
+    pub fn exchanges(&self) -> &[Exchange] { &self.exchanges }
+}
+
+/// One way an actual reply differed from the expected one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    /// A reply was expected, but none was sent.
+    MissingReply,
+    /// A reply was sent, but none was expected.
+    UnexpectedReply,
+    /// A method return was expected and an error was sent, or vice versa.
+    MessageType { expected: MessageType, actual: MessageType },
+    /// The error replies have different error names.
+    ErrorName { expected: String, actual: String },
+    /// The replies have different signatures. Arguments are not compared in this case.
+    Signature { expected: String, actual: String },
+    /// An argument, or a part of it, differs.
+    ///
+    /// The path starts with the index of the argument, followed by `[n]` for array elements,
+    /// `[key]` for dictionary entries and `.n` for struct fields. Values are formatted
+    /// as GVariant text; a value is None if it is missing on that side.
+    Arg { path: String, expected: Option<String>, actual: Option<String> },
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn opt(x: &Option<String>) -> &str { x.as_deref().unwrap_or("(missing)") }
+        match self {
+            Difference::MissingReply => write!(f, "expected a reply, got none"),
+            Difference::UnexpectedReply => write!(f, "expected no reply, got one"),
+            Difference::MessageType { expected, actual } => write!(f, "expected {:?}, got {:?}", expected, actual),
+            Difference::ErrorName { expected, actual } => write!(f, "expected error {}, got {}", expected, actual),
+            Difference::Signature { expected, actual } => write!(f, "expected signature '{}', got '{}'", expected, actual),
+            Difference::Arg { path, expected, actual } => write!(f, "argument {}: expected {}, got {}", path, opt(expected), opt(actual)),
+        }
+    }
+}
+
+/// The differences found for one recorded method call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Index into the recording's exchanges.
+    pub index: usize,
+    /// Path, interface and member of the method call.
+    pub call: String,
+    /// What differed.
+    pub differences: Vec<Difference>,
+}
+
+/// The result of replaying a recording.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// The number of method calls replayed.
+    pub calls: usize,
+    /// The method calls that did not get the expected reply.
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl Report {
+    /// Returns true if all method calls got the expected reply.
+    pub fn is_ok(&self) -> bool { self.mismatches.is_empty() }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} of {} calls got the expected reply", self.calls - self.mismatches.len(), self.calls)?;
+        for m in &self.mismatches {
+            write!(f, "\n#{} {}:", m.index, m.call)?;
+            for d in &m.differences { write!(f, "\n    {}", d)? };
+        }
+        Ok(())
+    }
+}
+
+fn describe_call(msg: &Message) -> String {
+    format!("{} {}.{}", msg.path().as_deref().unwrap_or(""), msg.interface().as_deref().unwrap_or(""),
+        msg.member().as_deref().unwrap_or(""))
+}
+
+fn error_name(msg: &Message) -> String {
+    msg.duplicate().ok().and_then(|mut m| m.as_result().err())
+        .and_then(|e| e.name().map(|s| s.to_string())).unwrap_or_default()
+}
+
+fn display(item: &MessageItem) -> Option<String> { Some(GVariantDisplay(item).to_string()) }
+
+fn compare_items(path: String, expected: &MessageItem, actual: &MessageItem, diffs: &mut Vec<Difference>) {
+    if expected.signature() != actual.signature() {
+        diffs.push(Difference::Arg { path, expected: display(expected), actual: display(actual) });
+        return;
+    }
+    match (expected, actual) {
+        (MessageItem::Array(e), MessageItem::Array(a)) => {
+            for i in 0..e.len().max(a.len()) {
+                let p = format!("{}[{}]", path, i);
+                match (e.get(i), a.get(i)) {
+                    (Some(x), Some(y)) => compare_items(p, x, y, diffs),
+                    (x, y) => diffs.push(Difference::Arg { path: p, expected: x.and_then(display), actual: y.and_then(display) }),
+                }
+            }
+        },
+        (MessageItem::Dict(e), MessageItem::Dict(a)) => {
+            for (k, x) in e.iter() {
+                let p = format!("{}[{}]", path, GVariantDisplay(k));
+                match a.iter().find(|(k2, _)| k2 == k) {
+                    Some((_, y)) => compare_items(p, x, y, diffs),
+                    None => diffs.push(Difference::Arg { path: p, expected: display(x), actual: None }),
+                }
+            }
+            for (k, y) in a.iter().filter(|(k, _)| !e.iter().any(|(k2, _)| k2 == k)) {
+                diffs.push(Difference::Arg { path: format!("{}[{}]", path, GVariantDisplay(k)), expected: None, actual: display(y) });
+            }
+        },
+        (MessageItem::Struct(e), MessageItem::Struct(a)) => {
+            for (i, (x, y)) in e.iter().zip(a.iter()).enumerate() {
+                compare_items(format!("{}.{}", path, i), x, y, diffs);
+            }
+        },
+        (MessageItem::Variant(e), MessageItem::Variant(a)) => compare_items(path, e, a, diffs),
+        (e, a) => if e != a {
+            diffs.push(Difference::Arg { path, expected: display(e), actual: display(a) })
+        },
+    }
+}
+
+/// Compares an actual reply to the expected one.
+///
+/// Serials, senders and destinations are not compared.
+pub fn compare(expected: Option<&Message>, actual: Option<&Message>) -> Vec<Difference> {
+    let (e, a) = match (expected, actual) {
+        (None, None) => return vec!(),
+        (Some(_), None) => return vec!(Difference::MissingReply),
+        (None, Some(_)) => return vec!(Difference::UnexpectedReply),
+        (Some(e), Some(a)) => (e, a),
+    };
+    if e.msg_type() != a.msg_type() {
+        return vec!(Difference::MessageType { expected: e.msg_type(), actual: a.msg_type() });
+    }
+    let mut diffs = vec!();
+    if e.msg_type() == MessageType::Error {
+        let (en, an) = (error_name(e), error_name(a));
+        if en != an { diffs.push(Difference::ErrorName { expected: en, actual: an }) };
+    }
+    let (ei, ai) = (e.get_items(), a.get_items());
+    let sig = |items: &[MessageItem]| items.iter().map(|i| i.signature().to_string()).collect::<String>();
+    let (es, as_) = (sig(&ei), sig(&ai));
+    if es != as_ {
+        diffs.push(Difference::Signature { expected: es, actual: as_ });
+        return diffs;
+    }
+    for (i, (x, y)) in ei.iter().zip(ai.iter()).enumerate() {
+        compare_items(i.to_string(), x, y, &mut diffs);
+    }
+    diffs
+}
+
+fn replay<F: FnMut(&Exchange) -> Option<Message>>(rec: &Recording, mut f: F) -> Report {
+    let mut report = Report::default();
+    for (index, e) in rec.exchanges.iter().enumerate() {
+        let actual = f(e);
+        let differences = compare(e.reply.as_ref(), actual.as_ref());
+        report.calls += 1;
+        if !differences.is_empty() {
+            report.mismatches.push(Mismatch { index, call: describe_call(&e.call), differences });
+        }
+    }
+    report
+}
+
+/// Replays a recording against a Crossroads instance, by calling `handle_message` for every
+/// recorded method call.
+///
+/// Signals emitted while handling the calls are ignored. The Crossroads instance must not
+/// have async support enabled, as replies from spawned tasks are not waited for.
+pub fn replay_crossroads(cr: &mut Crossroads, rec: &Recording) -> Report {
+    replay(rec, |e| {
+        let mut call = e.call.duplicate().ok()?;
+        call.set_serial(e.call.get_serial().unwrap_or(1));
+        let sent = RefCell::new(vec!());
+        cr.handle_message(call, &sent).ok()?;
+        sent.into_inner().into_iter().find(|m| m.msg_type() != MessageType::Signal)
+    })
+}
+
+/// Replays a recording against a service over a connection.
+///
+/// Every recorded method call is sent again, with its destination replaced if `destination`
+/// is given, and the reply is waited for at most `timeout`. A call that times out is
+/// reported as an error reply.
+pub fn replay_connection<C: BlockingSender + Sender>(conn: &C, destination: Option<&BusName>, rec: &Recording, timeout: Duration) -> Report {
+    replay(rec, |e| {
+        let mut call = e.call.duplicate().ok()?;
+        if let Some(d) = destination { call.set_destination(Some(d.clone())) };
+        if e.call.get_no_reply() {
+            let _ = conn.send(call);
+            return None;
+        }
+        Some(match conn.send_with_reply_and_block(call, timeout) {
+            Ok(reply) => reply,
+            Err(err) => {
+                let name = ErrorName::new(err.name().unwrap_or("org.freedesktop.DBus.Error.Failed").to_string())
+                    .unwrap_or_else(|_| ErrorName::from("org.freedesktop.DBus.Error.Failed"));
+                let text = CString::new(err.message().unwrap_or("")).unwrap_or_default();
+                e.call.error(&name, &text)
+            }
+        })
+    })
+}
@@ -8,7 +8,7 @@ use dbus::arg::{Variant, RefArg, Arg, Append, PropMap};
 use std::marker::PhantomData;
 use crate::ifacedesc::EMITS_CHANGED;
 
-fn introspect(cr: &Crossroads, path: &dbus::Path<'static>) -> String {
+fn introspect(cr: &mut Crossroads, path: &dbus::Path<'static>) -> String {
     let mut children = cr.get_children(path, true);
     let mut childstr = String::new();
     children.sort_unstable();
@@ -27,7 +27,7 @@ r##"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//E
 }
 
 pub fn introspectable(cr: &mut Crossroads) -> IfaceToken<()> {
-    cr.register("org.freedesktop.DBus.Introspectable", |b| {
+    cr.register_shared("org.freedesktop.DBus.Introspectable", |b| {
         b.method_with_cr("Introspect", (), ("xml_data",), |ctx, cr, _: ()| {
             Ok((introspect(cr, ctx.path()),))
         });
@@ -76,6 +76,8 @@ impl PropContext {
     /// The current property name.
     pub fn name(&self) -> &str { &self.name }
 
+    pub (crate) fn iface_token(&self) -> Option<usize> { Some(self.iface_token) }
+
     /// The message, if any, that caused this method to be called.
     pub fn message(&self) -> Option<&dbus::Message> { self.context.as_ref().map(|ctx| ctx.message()) }
 
@@ -139,13 +141,13 @@ impl PropContext {
         let token = self.iface_token;
         let name = self.name.clone();
         let mut cb = match self.check(|_| {
-            cr.registry().take_prop(token, &name, is_set)
+            cr.registry_ref().take_prop(token, &name, is_set)
         }) {
             Ok(cb) => cb,
             Err(_) => return Some(self)
         };
         let octx = cb(self, cr);
-        cr.registry().give_prop(token, &name, cb, is_set);
+        cr.registry_ref().give_prop(token, &name, cb, is_set);
         // dbg!(&name, octx.is_some());
         octx
     }
@@ -158,15 +160,19 @@ impl PropContext {
             donefn: Some(Dbg(Box::new(f))),
             propctx: Some(self),
         }));
+        // Properties the caller may not read are left out, and guarded properties are left out
+        // of signals, which have no caller
+        let names: Vec<String> = cr.registry_ref().prop_names_readable(token).map(String::from).collect();
+        let names = names.into_iter().filter(|name| cr.check_guard(cr.registry_ref().prop_guard(token, name, false)).is_ok());
         let mut pb = pactx.lock().unwrap();
-        let pctxs: Vec<_> = cr.registry().prop_names_readable(token).map(|prop_name| {
+        let pctxs: Vec<_> = names.map(|prop_name| {
             pb.remaining += 1;
             let parent = pb.propctx.as_ref().unwrap();
             PropContext {
                 path: parent.path.clone(),
                 iface_token: parent.iface_token,
                 interface: parent.interface().clone(),
-                name: prop_name.into(),
+                name: prop_name,
                 get_all: Some(pactx.clone()),
                 context: None,
                 emits_changed: None
@@ -227,10 +233,15 @@ impl PropAllCtx {
 }
 
 fn get(mut ctx: Context, cr: &mut Crossroads, (interface_name, property_name): (String, String)) -> Option<Context> {
-    let mut propctx = match ctx.check(|ctx| { PropContext::new(cr, ctx.path().clone(), interface_name, property_name)}) {
+    let mut propctx = match ctx.check(|ctx| PropContext::new(cr, ctx.path().clone(), interface_name, property_name)) {
         Ok(p) => p,
         Err(_) => return Some(ctx),
     };
+    let guard = cr.registry_ref().prop_guard(propctx.iface_token, &propctx.name, false).cloned();
+    let ctx = match cr.guard_call(ctx, guard.as_ref()) {
+        Ok(ctx) => ctx,
+        Err(r) => return r,
+    };
     propctx.context = Some(ctx);
     propctx.call_prop(cr, false).map(|propctx| { propctx.context.unwrap() })
 }
@@ -263,11 +274,16 @@ fn getall(mut ctx: Context, cr: &mut Crossroads, (interface_name,): (String,)) -
 }
 
 fn set(mut ctx: Context, cr: &mut Crossroads, (interface_name, property_name, _value): (String, String, Variant<Box<dyn RefArg>>)) -> Option<Context> {
-    let mut propctx = match ctx.check(|ctx| { PropContext::new(cr, ctx.path().clone(), interface_name, property_name) }) {
+    let mut propctx = match ctx.check(|ctx| PropContext::new(cr, ctx.path().clone(), interface_name, property_name)) {
         Ok(p) => p,
         Err(_) => return Some(ctx),
     };
-    let ann = cr.registry()
+    let guard = cr.registry_ref().prop_guard(propctx.iface_token, &propctx.name, true).cloned();
+    let ctx = match cr.guard_call(ctx, guard.as_ref()) {
+        Ok(ctx) => ctx,
+        Err(r) => return r,
+    };
+    let ann = cr.registry_ref()
         .find_annotation(propctx.iface_token, EMITS_CHANGED, Some(&propctx.name));
     propctx.emits_changed = match ann {
         Some("const") => Some("const"),
@@ -280,7 +296,7 @@ fn set(mut ctx: Context, cr: &mut Crossroads, (interface_name, property_name, _v
 }
 
 pub fn properties(cr: &mut Crossroads) -> IfaceToken<()> {
-    cr.register("org.freedesktop.DBus.Properties", |b| {
+    cr.register_shared("org.freedesktop.DBus.Properties", |b| {
         b.method_with_cr_custom::<_, (Variant<u8>,), _, _>("Get", ("interface_name", "property_name"), ("value",), get);
         b.method_with_cr_custom::<_, (PropMap,), _, _>("GetAll", ("interface_name",), ("properties",), getall);
         b.method_with_cr_custom::<_, (), _, _>("Set", ("interface_name", "property_name", "value"), (), set);
@@ -363,7 +379,7 @@ fn for_each_interface_with_properties<F: FnOnce(&mut IfaceContext, &mut Option<C
 }
 
 //
-fn get_managed_objects(mut ctx: Context, cr: &mut Crossroads, _: ()) -> Option<Context> {
+fn get_managed_objects(ctx: Context, cr: &mut Crossroads, _: ()) -> Option<Context> {
     // HashMap<dbus::Path<'static>, IfacePropMap>
     let parent = ctx.path();
     let children: Vec<dbus::Path<'static>> =
@@ -372,57 +388,59 @@ fn get_managed_objects(mut ctx: Context, cr: &mut Crossroads, _: ()) -> Option<C
             if !x.ends_with('/') {
                 x.push_str("/");
             }
-            x.push_str(child_path);
+            x.push_str(&child_path);
             dbus::Path::from(x).into_static()
         }).collect();
-
-    if children.len() == 0 {
-        ctx.do_reply(|msg| {
-            let x: PathPropMap = Default::default();
-            msg.append_all((x,));
-        });
-        return Some(ctx);
-    }
-
     #[derive(Debug)]
     struct Temp {
+        // One more than the objects still being read, until all have been started
         remaining: usize,
         temp_map: PathPropMap,
         octx: Option<Context>,
     }
+    fn done(rr: &mut Temp) {
+        rr.remaining -= 1;
+        if rr.remaining > 0 { return; }
+        let mut octx = rr.octx.take().unwrap();
+        octx.do_reply(|msg| {
+            msg.append_all((&rr.temp_map,));
+        });
+        rr.octx = Some(octx);
+    }
     let r = Arc::new(Mutex::new(Temp {
-        remaining: children.len(),
+        remaining: 1,
         temp_map: HashMap::new(),
         octx: Some(ctx),
     }));
     for subpath in children {
-        let rclone = r.clone();
-        let subpath_clone = subpath.clone();
-        let octx = r.lock().unwrap().octx.take();
-        get_all_for_path(&subpath, cr, octx, move |ictx, octx| {
-            let mut rr = rclone.lock().unwrap();
-            if rr.octx.is_none() { rr.octx = octx.take(); }
-            let ifaces = std::mem::replace(&mut ictx.ifaces, HashMap::new());
-            rr.temp_map.insert(subpath_clone, ifaces);
-            rr.remaining -= 1;
-            // dbg!(&rr);
-            if rr.remaining > 0 { return; }
-            let mut octx = rr.octx.take().unwrap();
-            octx.do_reply(|msg| {
-                msg.append_all((&rr.temp_map,));
+        // Objects below a fallback exist only while they are being called, and objects of a
+        // ConcurrentCrossroads are taken out of its tree one at a time while they are read
+        let temporary = cr.materialize(&subpath);
+        if cr.has_path(&subpath) {
+            r.lock().unwrap().remaining += 1;
+            let rclone = r.clone();
+            let subpath_clone = subpath.clone();
+            let octx = r.lock().unwrap().octx.take();
+            get_all_for_path(&subpath, cr, octx, move |ictx, octx| {
+                let mut rr = rclone.lock().unwrap();
+                if rr.octx.is_none() { rr.octx = octx.take(); }
+                let ifaces = std::mem::replace(&mut ictx.ifaces, HashMap::new());
+                rr.temp_map.insert(subpath_clone, ifaces);
+                done(&mut rr);
+            }).map(|octx| {
+                let mut rr = r.lock().unwrap();
+                if rr.octx.is_none() { rr.octx = Some(octx); }
             });
-            rr.octx = Some(octx);
-        }).map(|octx| {
-            let mut rr = r.lock().unwrap();
-            if rr.octx.is_none() { rr.octx = Some(octx); }
-        });
+        }
+        if temporary { cr.dematerialize(&subpath) }
     }
     let mut rr = r.lock().unwrap();
+    done(&mut rr);
     rr.octx.take()
 }
 
 pub fn object_manager(cr: &mut Crossroads) -> IfaceToken<()> {
-    cr.register("org.freedesktop.DBus.ObjectManager", |b| {
+    cr.register_shared("org.freedesktop.DBus.ObjectManager", |b| {
         b.method_with_cr_custom::<(), (PathPropMap,), _, _>
             ("GetManagedObjects", (), ("objpath_interfaces_and_properties",), get_managed_objects);
         b.signal::<(dbus::Path<'static>, IfacePropMap), _>("InterfacesAdded",
@@ -445,13 +463,13 @@ pub fn object_manager_path_added(sender: Arc<dyn Sender + Send + Sync>, name: &d
     object_manager_parents(name, cr, |parent, cr| {
         let n = name.clone();
         let s = sender.clone();
-        get_all_for_path(&name, cr, None, move |ictx, _| {
+        cr.without_caller(|cr| get_all_for_path(&name, cr, None, move |ictx, _| {
             let x = dbus::blocking::stdintf::org_freedesktop_dbus::ObjectManagerInterfacesAdded {
                 object: n,
                 interfaces: std::mem::replace(&mut ictx.ifaces, HashMap::new()),
             };
             let _ = s.send(dbus::message::SignalArgs::to_emit_message(&x, &parent));
-        });
+        }));
     });
 }
 
@@ -475,13 +493,13 @@ pub fn object_manager_interface_added(sender: Arc<dyn Sender + Send + Sync>, nam
         let n = name.clone();
         let s = sender.clone();
 
-        for_each_interface_with_properties(&name, vec![itoken], cr, None, move |ictx, _| {
+        cr.without_caller(|cr| for_each_interface_with_properties(&name, vec![itoken], cr, None, move |ictx, _| {
             let x = dbus::blocking::stdintf::org_freedesktop_dbus::ObjectManagerInterfacesAdded {
                 object: n,
                 interfaces: std::mem::replace(&mut ictx.ifaces, HashMap::new()),
             };
             let _ = s.send(dbus::message::SignalArgs::to_emit_message(&x, &parent));
-        });
+        }));
     });
 }
 
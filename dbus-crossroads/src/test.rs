@@ -445,3 +445,1108 @@ async fn properties_multi_interface_get_all_async() {
     assert_eq!(response.get("OtherAsync").unwrap().as_i64(), Some(4));
     assert_eq!(response.len(), 4);
 }
+
+#[test]
+fn replay() {
+    use crate::replay::*;
+    let mut cr = Crossroads::new();
+    let iface = cr.register("com.example.dbusrs.replay", |b| {
+        b.method("Lookup", ("key",), ("values", "info"), |_, _, (key,): (String,)| {
+            if key == "missing" { return Err(MethodErr::no_arg()) }
+            let mut info: PropMap = HashMap::new();
+            info.insert("len".into(), Variant(Box::new(key.len() as u32)));
+            Ok((vec!(key.clone(), key), info))
+        });
+    });
+    cr.insert("/", &[iface], ());
+
+    let mut rec = Recording::new();
+    let call = |key: &str, serial| {
+        let mut m = Message::new_method_call("com.example.dbusrs", "/", "com.example.dbusrs.replay", "Lookup").unwrap().append1(key);
+        m.set_serial(serial);
+        m
+    };
+    let mut info: PropMap = HashMap::new();
+    info.insert("len".into(), Variant(Box::new(3u32)));
+    let m = call("abc", 1);
+    let r = m.method_return().append2(vec!("abc", "abc"), &info);
+    rec.push(m, Some(r));
+    info.insert("len".into(), Variant(Box::new(4u32)));
+    info.insert("extra".into(), Variant(Box::new(true)));
+    let m = call("def", 2);
+    let r = m.method_return().append2(vec!("def", "xyz", "def"), &info);
+    rec.push(m, Some(r));
+    let m = call("missing", 3);
+    let r = m.method_return().append2(Vec::<String>::new(), PropMap::new());
+    rec.push(m, Some(r));
+
+    // Round trip through a capture file
+    let data = rec.write(vec!()).unwrap();
+    let rec = Recording::read(&data[..]).unwrap();
+    assert_eq!(rec.exchanges().len(), 3);
+    assert!(rec.exchanges().iter().all(|e| e.reply.is_some()));
+
+    let report = replay_crossroads(&mut cr, &rec);
+    let text = report.to_string();
+    assert!(text.starts_with("1 of 3 calls got the expected reply\n#1 / com.example.dbusrs.replay.Lookup:\n"), "{}", text);
+    assert!(text.contains("\n    argument 0[1]: expected 'xyz', got 'def'"), "{}", text);
+    assert!(text.contains("\n    argument 0[2]: expected 'def', got (missing)"), "{}", text);
+    assert!(text.ends_with("\n#2 / com.example.dbusrs.replay.Lookup:\n    expected MethodReturn, got Error"), "{}", text);
+    assert_eq!(report.calls, 3);
+    assert_eq!(report.mismatches.len(), 2);
+    let m = &report.mismatches[0];
+    assert_eq!(m.index, 1);
+    assert_eq!(m.call, "/ com.example.dbusrs.replay.Lookup");
+    // Dictionary order is not deterministic
+    assert_eq!(m.differences.len(), 4);
+    for d in &[
+        Difference::Arg { path: "0[1]".into(), expected: Some("'xyz'".into()), actual: Some("'def'".into()) },
+        Difference::Arg { path: "0[2]".into(), expected: Some("'def'".into()), actual: None },
+        Difference::Arg { path: "1['len']".into(), expected: Some("uint32 4".into()), actual: Some("uint32 3".into()) },
+        Difference::Arg { path: "1['extra']".into(), expected: Some("<true>".into()), actual: None },
+    ] { assert!(m.differences.contains(d), "{:?}", d) }
+    assert_eq!(report.mismatches[1].differences, vec!(Difference::MessageType {
+        expected: dbus::MessageType::MethodReturn, actual: dbus::MessageType::Error
+    }));
+}
+
+#[test]
+fn fuzz() {
+    use crate::fuzz::*;
+    let mut cr = Crossroads::new();
+    let iface = cr.register("com.example.dbusrs.fuzz", |b| {
+        b.method("Fine", ("a", "b"), ("c",), |_, _, (a, b): (String, Vec<u32>)| Ok((a.len() as u32 + b.len() as u32,)));
+        b.method("Panic", ("a",), (), |_, _, (a,): (u8,)| { assert!(a != 255, "Overflow"); Ok(()) });
+        b.method_with_cr_custom::<(u32,), (String,), _, _>("Wrong", ("a",), ("b",), |mut ctx, _, (a,)| {
+            let m = ctx.message().method_return().append1(a);
+            ctx.push_msg(m);
+            Some(ctx)
+        });
+        b.method_with_cr_custom::<(), (), _, _>("Silent", (), (), |_, _, _| None);
+    });
+    cr.insert("/", &[iface], ());
+    cr.insert("/sub", &[iface], ());
+
+    let findings = fuzz(&mut cr, &mut dbus::arg::arbitrary::Generator::new(3), 50);
+    let mut problems: Vec<_> = findings.iter().map(|f| (&*f.path, &*f.member, &f.problem)).collect();
+    problems.dedup_by(|a, b| a.0 == b.0 && a.1 == b.1);
+    assert_eq!(problems, vec!(
+        ("/", "Panic", &Problem::Panic("Overflow".into())),
+        ("/", "Silent", &Problem::NoReply),
+        ("/", "Wrong", &Problem::Malformed { expected: "s".into(), actual: "u".into() }),
+        ("/sub", "Silent", &Problem::NoReply),
+        ("/sub", "Wrong", &Problem::Malformed { expected: "s".into(), actual: "u".into() }),
+    ));
+}
+
+#[test]
+fn fallback() {
+    let mut cr = Crossroads::new();
+    let token = cr.register("com.example.dbusrs.row", |b| {
+        b.method("Id", (), ("id",), |_, row: &mut u32, _: ()| Ok((*row,)));
+        b.property("Name").get(|_, row: &mut u32| Ok(format!("Row {}", row)));
+    });
+    let om = cr.object_manager::<()>();
+    cr.insert("/", &[om], ());
+    cr.insert("/rows/special", &[], ());
+    cr.insert_fallback("/rows", move |path| {
+        let id: u32 = path.strip_prefix("/rows/")?.parse().ok()?;
+        if id == 0 || id > 3 { return None };
+        Some((vec!(token), id))
+    }, |path| if &**path == "/rows" { vec!("1".into(), "2".into(), "3".into()) } else { vec!() });
+
+    let call = |path: &str, iface: &str, method: &str| Message::new_method_call("com.example.dbusrs.row", path, iface, method).unwrap();
+    let r = dispatch_helper(&mut cr, call("/rows/2", "com.example.dbusrs.row", "Id"));
+    assert_eq!(r.read1::<u32>().unwrap(), 2);
+    let r = dispatch_helper(&mut cr, call("/rows/3", "org.freedesktop.DBus.Properties", "Get").append2("com.example.dbusrs.row", "Name"));
+    assert_eq!(r.read1::<Variant<String>>().unwrap().0, "Row 3");
+    let mut r = dispatch_helper2(&mut cr, call("/rows/4", "com.example.dbusrs.row", "Id"));
+    assert!(r[0].as_result().is_err());
+    // The objects only existed during the calls
+    assert!(cr.data_mut::<u32>(&"/rows/2".into()).is_none());
+
+    let r = dispatch_helper(&mut cr, call("/", "org.freedesktop.DBus.Introspectable", "Introspect"));
+    let xml: &str = r.read1().unwrap();
+    assert!(xml.contains(r#"<node name="rows"/>"#));
+    let r = dispatch_helper(&mut cr, call("/rows/special", "org.freedesktop.DBus.Introspectable", "Introspect"));
+    let xml: &str = r.read1().unwrap();
+    assert!(!xml.contains("  <node name="));
+
+    let r = dispatch_helper(&mut cr, call("/", "org.freedesktop.DBus.ObjectManager", "GetManagedObjects"));
+    let objs: HashMap<dbus::Path<'static>, HashMap<String, PropMap>> = r.read1().unwrap();
+    let mut paths: Vec<_> = objs.keys().map(|p| p.to_string()).collect();
+    paths.sort();
+    assert_eq!(paths, vec!("/rows/1", "/rows/2", "/rows/3", "/rows/special"));
+    let name = &objs[&"/rows/1".into()]["com.example.dbusrs.row"]["Name"];
+    assert_eq!(name.as_str(), Some("Row 1"));
+    assert!(cr.data_mut::<u32>(&"/rows/1".into()).is_none());
+}
+
+#[tokio::test]
+async fn middleware() {
+    use std::sync::{Arc, Mutex};
+    use crate::Middleware;
+
+    struct RequestId(u32);
+    struct Audit(Arc<Mutex<Vec<String>>>, &'static str);
+    impl Middleware for Audit {
+        fn before(&self, ctx: &mut Context) -> Result<(), MethodErr> {
+            ctx.insert_extension(RequestId(7));
+            self.0.lock().unwrap().push(format!("{} before {}", self.1, ctx.method()));
+            Ok(())
+        }
+        fn after(&self, ctx: &Context, reply: Option<&Message>) {
+            let e = reply.filter(|r| r.msg_type() == dbus::MessageType::Error).and_then(|r| r.get1::<String>());
+            self.0.lock().unwrap().push(format!("{} after {} {:?}", self.1, ctx.method(), e));
+        }
+    }
+    struct Deny;
+    impl Middleware for Deny {
+        fn before(&self, ctx: &mut Context) -> Result<(), MethodErr> {
+            if &**ctx.method() == "Secret" { Err(MethodErr::failed("Access denied")) } else { Ok(()) }
+        }
+    }
+
+    let log = Arc::new(Mutex::new(vec!()));
+    let sent = Arc::new(Mutex::new(vec!()));
+    let mut cr = Crossroads::new();
+    let spawner = Box::new(|fut| { tokio::spawn(fut); });
+    cr.set_async_support(Some((sent.clone(), spawner)));
+    cr.add_middleware(Audit(log.clone(), "outer"));
+    cr.add_middleware(Deny);
+    cr.add_middleware(Audit(log.clone(), "inner"));
+    let iface = cr.register("com.example.dbusrs.middleware", |b| {
+        b.method("Id", (), ("id",), |ctx, _, _: ()| Ok((ctx.extension::<RequestId>().unwrap().0,)));
+        b.method("Secret", (), (), |_, _, _: ()| -> Result<(), _> { unreachable!() });
+        b.method_with_cr_async("Later", (), ("x",), |mut ctx, _, _: ()| async move {
+            tokio::task::yield_now().await;
+            ctx.reply(Ok((5u8,)))
+        });
+    });
+    cr.insert("/", &[iface], ());
+
+    let msg = Message::new_method_call("com.example.dbusrs.middleware", "/", "com.example.dbusrs.middleware", "Id").unwrap();
+    let r: u32 = dispatch_helper(&mut cr, msg).read1().unwrap();
+    assert_eq!(r, 7);
+
+    let msg = Message::new_method_call("com.example.dbusrs.middleware", "/", "com.example.dbusrs.middleware", "Secret").unwrap();
+    let mut r = dispatch_helper2(&mut cr, msg);
+    assert_eq!(r[0].as_result().unwrap_err().message(), Some("Access denied"));
+
+    let mut msg = Message::new_method_call("com.example.dbusrs.middleware", "/", "com.example.dbusrs.middleware", "Later").unwrap();
+    msg.set_serial(58);
+    cr.handle_message(msg, &*sent).unwrap();
+    while sent.lock().unwrap().is_empty() { tokio::task::yield_now().await; }
+    let r: u8 = sent.lock().unwrap()[0].read1().unwrap();
+    assert_eq!(r, 5);
+
+    assert_eq!(&*log.lock().unwrap(), &[
+        "outer before Id", "inner before Id", "inner after Id None", "outer after Id None",
+        "outer before Secret", "outer after Secret Some(\"Access denied\")",
+        "outer before Later", "inner before Later", "inner after Later None", "outer after Later None",
+    ]);
+}
+
+#[test]
+fn access() {
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::{Access, Credentials, CredentialsLookup};
+
+    let lookups = Arc::new(AtomicUsize::new(0));
+    let lookups2 = lookups.clone();
+    let mut cr = Crossroads::new();
+    cr.set_credentials_lookup(Some(CredentialsLookup::blocking(move |name| {
+        lookups2.fetch_add(1, Ordering::SeqCst);
+        let c = match &*name {
+            ":1.1" => Credentials { uid: Some(0), groups: Some(vec!(0)), ..Default::default() },
+            ":1.2" => Credentials { uid: Some(1000), groups: Some(vec!(100, 1000)), ..Default::default() },
+            _ => Credentials { uid: Some(1001), groups: Some(vec!(1001)), ..Default::default() },
+        };
+        Box::pin(async { Ok(c) })
+    })));
+    let child = cr.register("com.example.dbusrs.access.Child", |b: &mut IfaceBuilder<()>| {
+        b.property("Secret").get(|_, _| Ok(1)).access(Access::root());
+        b.property("Public").get(|_, _| Ok(2));
+    });
+    let iface = cr.register("com.example.dbusrs.access", move |b| {
+        b.method("Reboot", (), ("uid",), |ctx, _, _: ()| {
+            Ok((ctx.cached_credentials().unwrap().uid.unwrap(),))
+        }).access(Access::root());
+        b.method("Hello", (), (), |_, _, _: ()| Ok(()));
+        b.method_with_cr("Add", (), (), move |_, cr, _: ()| {
+            cr.insert("/child", &[child], ());
+            Ok(())
+        }).access(Access::root());
+        b.property("Staff").get(|_, _| Ok(1)).access(Access::group(100).or(Access::root())).write_access(Access::root())
+            .set(|_, _, _: i32| Ok(None));
+        b.property("Public").get(|_, _| Ok(2));
+    });
+    let signals = Arc::new(Mutex::new(vec!()));
+    cr.set_object_manager_support(Some(signals.clone()));
+    cr.insert("/", &[iface, cr.object_manager()], ());
+
+    let call = |cr: &mut Crossroads, sender: &str, iface: &str, method: &str, args: &[&str]| {
+        let mut msg = Message::new_method_call("com.example.dbusrs.access", "/", iface, method).unwrap();
+        msg.set_sender(Some(sender.into()));
+        for a in args { msg = msg.append1(*a); }
+        if method == "Set" { msg = msg.append1(Variant(3)); }
+        let mut r = dispatch_helper2(cr, msg);
+        assert_eq!(r.len(), 1);
+        let mut r = r.pop().unwrap();
+        match r.as_result() {
+            Ok(_) => Ok(r),
+            Err(e) => Err(e.name().unwrap().to_string()),
+        }
+    };
+    const DENIED: &str = "org.freedesktop.DBus.Error.AccessDenied";
+
+    let r = call(&mut cr, ":1.1", "com.example.dbusrs.access", "Reboot", &[]).unwrap();
+    assert_eq!(r.read1::<u32>().unwrap(), 0);
+    assert_eq!(call(&mut cr, ":1.2", "com.example.dbusrs.access", "Reboot", &[]).unwrap_err(), DENIED);
+    call(&mut cr, ":1.2", "com.example.dbusrs.access", "Hello", &[]).unwrap();
+    assert_eq!(lookups.load(Ordering::SeqCst), 2);
+
+    let props = ["com.example.dbusrs.access", "Staff"];
+    call(&mut cr, ":1.2", "org.freedesktop.DBus.Properties", "Get", &props).unwrap();
+    assert_eq!(call(&mut cr, ":1.2", "org.freedesktop.DBus.Properties", "Set", &props).unwrap_err(), DENIED);
+    call(&mut cr, ":1.1", "org.freedesktop.DBus.Properties", "Set", &props).unwrap();
+    assert_eq!(call(&mut cr, ":1.3", "org.freedesktop.DBus.Properties", "Get", &props).unwrap_err(), DENIED);
+    let r = call(&mut cr, ":1.3", "org.freedesktop.DBus.Properties", "GetAll", &props[..1]).unwrap();
+    let r: HashMap<String, Variant<Box<dyn RefArg>>> = r.read1().unwrap();
+    assert_eq!(r.keys().collect::<Vec<_>>(), vec!("Public"));
+    assert_eq!(lookups.load(Ordering::SeqCst), 3);
+
+    // Signals go to everyone, so guarded properties are left out, whoever caused them
+    call(&mut cr, ":1.1", "com.example.dbusrs.access", "Add", &[]).unwrap();
+    let added = signals.lock().unwrap().pop().unwrap();
+    let (_, ifaces): (dbus::Path, HashMap<String, PropMap>) = added.read2().unwrap();
+    assert_eq!(ifaces["com.example.dbusrs.access.Child"].keys().collect::<Vec<_>>(), vec!("Public"));
+
+    // Credentials are cached until the connection goes away
+    let noc = Message::signal(&"/org/freedesktop/DBus".into(), &"org.freedesktop.DBus".into(), &"NameOwnerChanged".into())
+        .append3(":1.1", ":1.1", "");
+    let mut noc2 = noc.duplicate().unwrap();
+    noc2.set_sender(Some("org.freedesktop.DBus".into()));
+    assert!(cr.handle_message(noc, &RefCell::new(vec!())).is_err());
+    cr.handle_message(noc2, &RefCell::new(vec!())).unwrap();
+    call(&mut cr, ":1.1", "com.example.dbusrs.access", "Reboot", &[]).unwrap();
+    call(&mut cr, ":1.2", "com.example.dbusrs.access", "Reboot", &[]).unwrap_err();
+    assert_eq!(lookups.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test]
+async fn access_async_lookup() {
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::{Access, ConcurrentCrossroads, Credentials, CredentialsLookup};
+
+    // The credentials arrive later, like from a lookup over a non-blocking connection
+    let lookup = || CredentialsLookup::nonblock(move |name| Box::pin(async move {
+        tokio::task::yield_now().await;
+        let uid = if name.starts_with(":1.1") { 0 } else { 1000 };
+        Ok(Credentials { uid: Some(uid), ..Default::default() })
+    }));
+    let sent = Arc::new(Mutex::new(vec!()));
+    let spawner = || -> crate::crossroads::BoxedSpawn { Box::new(|fut| { tokio::spawn(fut); }) };
+
+    // Calls can only wait for the lookup with async support and somewhere to dispatch them
+    let mut cr = Crossroads::new();
+    let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cr.set_credentials_lookup(Some(lookup()))));
+    assert!(r.is_err());
+    cr.set_async_support(Some((sent.clone(), spawner())));
+    let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cr.set_credentials_lookup(Some(lookup()))));
+    assert!(r.is_err());
+
+    let ran = Arc::new(AtomicUsize::new(0));
+    let ran2 = ran.clone();
+    let cr = Arc::new(Mutex::new(cr));
+    let weak = Arc::downgrade(&cr);
+    {
+        let mut c = cr.lock().unwrap();
+        c.set_deferred_dispatch(Some(Box::new(move |d| {
+            if let Some(cr) = weak.upgrade() { cr.lock().unwrap().handle_deferred(d) }
+        })));
+        c.set_credentials_lookup(Some(lookup()));
+        let iface = c.register("com.example.dbusrs.access", |b| {
+            b.method_with_cr_async("Reboot", (), ("x",), move |mut ctx, _, _: ()| {
+                ran2.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    ctx.reply(Ok((ctx.cached_credentials().unwrap().uid.unwrap(),)))
+                }
+            }).access(Access::root());
+            b.method("Halt", (), (), |_, _, _: ()| Ok(())).access(Access::root());
+        });
+        c.insert("/", &[iface], ());
+    }
+
+    let msg = |sender: &str, method: &str, serial: u32| {
+        let mut msg = Message::new_method_call("com.example.dbusrs.access", "/", "com.example.dbusrs.access", method).unwrap();
+        msg.set_sender(Some(sender.into()));
+        msg.set_serial(serial);
+        msg
+    };
+    let call = |sender: &str, method: &str, serial: u32| {
+        cr.lock().unwrap().handle_message(msg(sender, method, serial), &*sent).unwrap();
+    };
+    let reply_to = |serial: u32| {
+        let sent = sent.clone();
+        async move {
+            loop {
+                let found = {
+                    let mut v = sent.lock().unwrap();
+                    v.iter().position(|m: &Message| m.get_reply_serial() == Some(serial)).map(|i| v.remove(i))
+                };
+                if let Some(mut r) = found {
+                    return r.as_result().map(|r| r.get1::<u32>()).map_err(|e| e.name().unwrap().to_string());
+                }
+                tokio::task::yield_now().await;
+            }
+        }
+    };
+    const DENIED: &str = "org.freedesktop.DBus.Error.AccessDenied";
+
+    // Calls wait for the lookup, and are then dispatched or denied
+    call(":1.10", "Reboot", 1);
+    call(":1.20", "Reboot", 2);
+    assert!(sent.lock().unwrap().is_empty());
+    assert_eq!(ran.load(Ordering::SeqCst), 0);
+    assert_eq!(reply_to(1).await, Ok(Some(0)));
+    assert_eq!(reply_to(2).await.unwrap_err(), DENIED);
+    assert_eq!(ran.load(Ordering::SeqCst), 1);
+
+    // The same goes for synchronous methods, and cached credentials are checked right away
+    call(":1.11", "Halt", 3);
+    assert!(sent.lock().unwrap().is_empty());
+    assert_eq!(reply_to(3).await, Ok(None));
+    call(":1.10", "Halt", 4);
+    assert_eq!(sent.lock().unwrap().pop().unwrap().get_reply_serial(), Some(4));
+
+    // A ConcurrentCrossroads dispatches the call from the task
+    let mut ccr = ConcurrentCrossroads::new();
+    ccr.set_async_support(Some((sent.clone(), spawner())));
+    ccr.set_credentials_lookup(Some(lookup()));
+    let iface = ccr.register("com.example.dbusrs.access", |b| {
+        b.method("Halt", (), (), |_, _, _: ()| Ok(())).access(Access::root());
+    });
+    ccr.insert("/", &[iface], ());
+    ccr.handle_message(msg(":1.12", "Halt", 5), &*sent).unwrap();
+    ccr.handle_message(msg(":1.22", "Halt", 6), &*sent).unwrap();
+    assert!(sent.lock().unwrap().is_empty());
+    assert_eq!(reply_to(5).await, Ok(None));
+    assert_eq!(reply_to(6).await.unwrap_err(), DENIED);
+}
+
+#[tokio::test]
+async fn credentials_from_bus() {
+    let bus = std::sync::Arc::new(dbus::blocking::SyncConnection::new_session().unwrap());
+    let name = bus.unique_name().clone().into_static();
+    let lookup = crate::Credentials::blocking_lookup(bus, Duration::from_secs(5));
+    let c = lookup.lookup(name).await.unwrap();
+    assert_eq!(c.pid, Some(std::process::id()));
+    assert!(c.uid.is_some());
+}
+
+#[cfg(feature = "polkit")]
+#[test]
+fn polkit() {
+    use std::sync::Arc;
+    use crate::polkit::{LocalAuthority, Implicit};
+
+    let authority = Arc::new(LocalAuthority::new());
+    authority.set("com.example.power.reboot", Implicit::AuthRequiredKeep);
+    authority.set("com.example.power.suspend", Implicit::AuthRequired);
+    authority.set("com.example.power.status", Implicit::Yes);
+    let mut cr = Crossroads::new();
+    cr.set_polkit_authority(Some(Box::new(authority.clone())));
+    let iface = cr.register("com.example.dbusrs.polkit", |b| {
+        b.method("Reboot", (), (), |_, _, _: ()| Ok(())).polkit_action("com.example.power.reboot");
+        b.method("Shutdown", (), (), |_, _, _: ()| Ok(())).polkit_action("com.example.power.shutdown");
+        b.method("Suspend", (), (), |_, _, _: ()| Ok(())).polkit_action("com.example.power.suspend");
+        b.property("Status").get(|_, _| Ok(1)).polkit_action("com.example.power.status")
+            .write_polkit_action("com.example.power.reboot").set(|_, _, _: i32| Ok(None));
+    });
+    cr.insert("/", &[iface], ());
+
+    let call = |cr: &mut Crossroads, sender: &str, method: &str, interactive: bool| {
+        let mut msg = Message::new_method_call("com.example.dbusrs.polkit", "/", "com.example.dbusrs.polkit", method).unwrap();
+        msg.set_sender(Some(sender.into()));
+        msg.set_allow_interactive_authorization(interactive);
+        let mut r = dispatch_helper2(cr, msg);
+        let mut r = r.pop().unwrap();
+        match r.as_result() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.name().unwrap().to_string()),
+        }
+    };
+
+    assert_eq!(call(&mut cr, ":1.1", "Reboot", false).unwrap_err(), "org.freedesktop.DBus.Error.InteractiveAuthorizationRequired");
+    call(&mut cr, ":1.1", "Reboot", true).unwrap();
+    // The temporary authorization is cached
+    call(&mut cr, ":1.1", "Reboot", false).unwrap();
+    assert_eq!(call(&mut cr, ":1.1", "Shutdown", true).unwrap_err(), "org.freedesktop.DBus.Error.AccessDenied");
+    // Other decisions are not
+    call(&mut cr, ":1.1", "Suspend", true).unwrap();
+    assert!(call(&mut cr, ":1.1", "Suspend", false).is_err());
+    assert_eq!(authority.checks(), vec!(
+        (":1.1".into(), "com.example.power.reboot".into(), false),
+        (":1.1".into(), "com.example.power.reboot".into(), true),
+        (":1.1".into(), "com.example.power.shutdown".into(), true),
+        (":1.1".into(), "com.example.power.suspend".into(), true),
+        (":1.1".into(), "com.example.power.suspend".into(), false),
+    ));
+
+    let mut msg = Message::call_with_args("com.example.dbusrs.polkit", "/", "org.freedesktop.DBus.Properties", "Set",
+        ("com.example.dbusrs.polkit", "Status", Variant(5i32)));
+    msg.set_sender(Some(":1.2".into()));
+    let mut r = dispatch_helper2(&mut cr, msg);
+    assert_eq!(r[0].as_result().unwrap_err().name(), Some("org.freedesktop.DBus.Error.InteractiveAuthorizationRequired"));
+    let mut msg = Message::call_with_args("com.example.dbusrs.polkit", "/", "org.freedesktop.DBus.Properties", "Get",
+        ("com.example.dbusrs.polkit", "Status"));
+    msg.set_sender(Some(":1.2".into()));
+    dispatch_helper(&mut cr, msg);
+
+    // Forget decisions when the caller goes away
+    let mut noc = Message::signal(&"/org/freedesktop/DBus".into(), &"org.freedesktop.DBus".into(), &"NameOwnerChanged".into())
+        .append3(":1.1", ":1.1", "");
+    noc.set_sender(Some("org.freedesktop.DBus".into()));
+    cr.handle_message(noc, &RefCell::new(vec!())).unwrap();
+    assert!(call(&mut cr, ":1.1", "Reboot", false).is_err());
+
+    // Calls on an attached connection are checked with the authority of that connection only
+    let sent = Arc::new(std::sync::Mutex::new(vec!()));
+    let id = cr.attach_connection(sent.clone());
+    let call_on = |cr: &mut Crossroads| {
+        let mut msg = Message::new_method_call("com.example.dbusrs.polkit", "/", "com.example.dbusrs.polkit", "Suspend").unwrap();
+        msg.set_sender(Some(":1.1".into()));
+        msg.set_allow_interactive_authorization(true);
+        msg.set_serial(57);
+        cr.handle_message_on(msg, id).unwrap();
+        let mut r = sent.lock().unwrap().pop().unwrap();
+        r.as_result().map(|_| ()).map_err(|e| e.name().unwrap().to_string())
+    };
+    assert_eq!(call_on(&mut cr).unwrap_err(), "org.freedesktop.DBus.Error.AccessDenied");
+    let remote = Arc::new(LocalAuthority::new());
+    remote.set("com.example.power.suspend", Implicit::Yes);
+    assert!(cr.set_connection_polkit_authority(id, Some(Box::new(remote.clone()))));
+    call_on(&mut cr).unwrap();
+    assert_eq!(remote.checks().len(), 1);
+    assert_eq!(authority.checks().len(), 8);
+}
+
+#[cfg(feature = "polkit")]
+#[tokio::test]
+async fn polkit_async() {
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::SystemTime;
+    use crate::polkit::{Authority, AuthorityFuture, Authorization, TEMPORARY_AUTHORIZATION_ID};
+
+    // Answers later, like polkit over a non-blocking connection
+    #[derive(Default)]
+    struct Slow(AtomicUsize);
+    impl Authority for Slow {
+        fn check_authorization(&self, _: &str, action_id: &str, _: bool) -> AuthorityFuture<Authorization> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            let is_authorized = action_id == "com.example.power.reboot";
+            Box::pin(async move {
+                tokio::task::yield_now().await;
+                let mut details = HashMap::new();
+                details.insert(TEMPORARY_AUTHORIZATION_ID.to_string(), "tmpauthz0".to_string());
+                Ok(Authorization { is_authorized, is_challenge: false, details })
+            })
+        }
+        fn temporary_authorization_expires(&self, _: &str, _: &str) -> AuthorityFuture<Option<SystemTime>> {
+            Box::pin(async { Ok(Some(SystemTime::now() + Duration::from_secs(300))) })
+        }
+        fn is_nonblock(&self) -> bool { true }
+    }
+
+    let authority = Arc::new(Slow::default());
+    let ran = Arc::new(AtomicUsize::new(0));
+    let (ran2, ran3) = (ran.clone(), ran.clone());
+    let sent = Arc::new(Mutex::new(vec!()));
+    let mut cr = Crossroads::new();
+    cr.set_async_support(Some((sent.clone(), Box::new(|fut| { tokio::spawn(fut); }))));
+    // Calls need somewhere to go after waiting
+    let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cr.set_polkit_authority(Some(Box::new(authority.clone())))));
+    assert!(r.is_err());
+
+    let cr = Arc::new(Mutex::new(cr));
+    let weak = Arc::downgrade(&cr);
+    {
+        let mut c = cr.lock().unwrap();
+        c.set_deferred_dispatch(Some(Box::new(move |d| {
+            if let Some(cr) = weak.upgrade() { cr.lock().unwrap().handle_deferred(d) }
+        })));
+        c.set_polkit_authority(Some(Box::new(authority.clone())));
+        let iface = c.register("com.example.dbusrs.polkit", |b| {
+            b.method("Reboot", (), (), move |_, _, _: ()| { ran2.fetch_add(1, Ordering::SeqCst); Ok(()) })
+                .polkit_action("com.example.power.reboot");
+            b.method("Halt", (), (), move |_, _, _: ()| { ran3.fetch_add(1, Ordering::SeqCst); Ok(()) })
+                .polkit_action("com.example.power.halt");
+        });
+        c.insert("/", &[iface], ());
+    }
+
+    let call = |method: &str, serial: u32| {
+        let mut msg = Message::new_method_call("com.example.dbusrs.polkit", "/", "com.example.dbusrs.polkit", method).unwrap();
+        msg.set_sender(Some(":1.1".into()));
+        msg.set_serial(serial);
+        cr.lock().unwrap().handle_message(msg, &*sent).unwrap();
+    };
+    let reply = || async {
+        loop {
+            let r = sent.lock().unwrap().pop();
+            if let Some(mut r) = r { return r.as_result().map(|_| ()).map_err(|e| e.name().unwrap().to_string()) }
+            tokio::task::yield_now().await;
+        }
+    };
+
+    // The call waits for polkit, and is dispatched once authorized
+    call("Reboot", 1);
+    assert!(sent.lock().unwrap().is_empty());
+    assert_eq!(ran.load(Ordering::SeqCst), 0);
+    reply().await.unwrap();
+    assert_eq!(ran.load(Ordering::SeqCst), 1);
+    assert_eq!(authority.0.load(Ordering::SeqCst), 1);
+
+    // The temporary authorization is cached, so the next call is dispatched right away
+    call("Reboot", 2);
+    sent.lock().unwrap().pop().unwrap().as_result().unwrap();
+    assert_eq!(ran.load(Ordering::SeqCst), 2);
+    assert_eq!(authority.0.load(Ordering::SeqCst), 1);
+
+    // Callers who are not authorized are denied when polkit answers
+    call("Halt", 3);
+    assert!(sent.lock().unwrap().is_empty());
+    assert_eq!(reply().await.unwrap_err(), "org.freedesktop.DBus.Error.AccessDenied");
+    assert_eq!(ran.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn emitter() {
+    use std::sync::{Arc, Mutex};
+    use dbus::blocking::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged as PPC;
+    use dbus::message::SignalArgs;
+
+    let mut cr = Crossroads::new();
+    let iface = cr.register("com.example.dbusrs.emitter", |b: &mut IfaceBuilder<()>| {
+        b.signal::<(u32, String), _>("Alarm", ("level", "text"));
+        b.property("Temp").get(|_, _| Ok(20.5));
+        b.property("Mode").get(|_, _| Ok(0u8)).emits_changed_invalidates();
+        b.property("Noise").get(|_, _| Ok(0u8)).emits_changed_false();
+        b.property("Serial").get(|_, _| Ok(String::new())).emits_changed_const();
+    });
+    cr.insert("/sensor", &[iface], ());
+    let sent = Arc::new(Mutex::new(vec!()));
+    let emitter = cr.emitter(sent.clone());
+
+    let path: dbus::Path = "/sensor".into();
+    let e2 = emitter.clone();
+    let p2 = path.clone();
+    std::thread::spawn(move || {
+        e2.signal(iface, &p2, "Alarm", (3u32, "Too hot")).unwrap();
+    }).join().unwrap();
+    assert!(emitter.signal(iface, &path, "Alarm", ("Too hot",)).is_err());
+    assert!(emitter.signal(iface, &path, "Alarms", (3u32, "Too hot")).is_err());
+
+    assert!(emitter.property_changed(iface, &path, "Temp", 21.0).unwrap());
+    assert!(emitter.property_changed(iface, &path, "Temp", 21).is_err());
+    assert!(emitter.property_changed(iface, &path, "Mode", 1u8).unwrap());
+    assert!(!emitter.property_changed(iface, &path, "Noise", 1u8).unwrap());
+    assert!(emitter.property_changed(iface, &path, "Serial", String::from("x")).is_err());
+
+    let sent = sent.lock().unwrap();
+    assert_eq!(sent.len(), 3);
+    let (level, text): (u32, &str) = sent[0].read2().unwrap();
+    assert_eq!((&*sent[0].member().unwrap(), level, text), ("Alarm", 3, "Too hot"));
+    assert_eq!(&*sent[0].path().unwrap(), "/sensor");
+    let ppc = PPC::from_message(&sent[1]).unwrap();
+    assert_eq!(ppc.changed_properties["Temp"].0.as_f64(), Some(21.0));
+    let ppc = PPC::from_message(&sent[2]).unwrap();
+    assert_eq!(ppc.invalidated_properties, vec!("Mode"));
+    assert!(ppc.changed_properties.is_empty());
+}
+
+#[test]
+fn coalesce_properties_changed() {
+    use std::sync::{Arc, Mutex};
+    use dbus::blocking::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged as PPC;
+    use dbus::message::SignalArgs;
+
+    let mut cr = Crossroads::new();
+    let mut fns = vec!();
+    let iface = cr.register("com.example.dbusrs.coalesce", |b: &mut IfaceBuilder<()>| {
+        b.signal::<(), _>("Updated", ());
+        fns.push(b.property::<u8, _>("Zeta").get(|_, _| Ok(0)).changed_msg_fn());
+        fns.push(b.property::<u8, _>("Beta").get(|_, _| Ok(0)).changed_msg_fn());
+        fns.push(b.property::<u8, _>("Alpha").get(|_, _| Ok(0)).emits_changed_invalidates().changed_msg_fn());
+        fns.push(b.property::<u8, _>("Mid").get(|_, _| Ok(0)).emits_changed_false().changed_msg_fn());
+        b.method("Update", (), (), move |ctx, _, _: ()| {
+            for (i, f) in fns.iter().enumerate().rev() {
+                if let Some(msg) = f(ctx.path(), &(i as u8)) { ctx.push_msg(msg) }
+            }
+            ctx.push_msg(ctx.make_signal("Updated", ()));
+            // Mid is emits_changed_false, so is left out even if signalled by hand
+            let mut ppc = PPC { interface_name: "com.example.dbusrs.coalesce".into(), changed_properties: Default::default(), invalidated_properties: vec!() };
+            ppc.add_prop("Mid", dbus::blocking::stdintf::org_freedesktop_dbus::EmitsChangedSignal::True, || Box::new(2u8));
+            ppc.add_prop("Zeta", dbus::blocking::stdintf::org_freedesktop_dbus::EmitsChangedSignal::True, || Box::new(7u8));
+            ctx.push_msg(ppc.to_emit_message(ctx.path()));
+            // Signals to another destination are kept apart
+            let mut directed = ppc.to_emit_message(ctx.path());
+            directed.set_destination(Some(":1.54".into()));
+            ctx.push_msg(directed);
+            Ok(())
+        });
+    });
+    cr.insert("/a", &[iface], ());
+    cr.insert("/b", &[iface], ());
+
+    let msg = Message::new_method_call("com.example.dbusrs.coalesce", "/a", "com.example.dbusrs.coalesce", "Update").unwrap();
+    let r = dispatch_helper2(&mut cr, msg);
+    assert_eq!(r.len(), 4);
+    let ppc = PPC::from_message(&r[1]).unwrap();
+    assert_eq!(r[1].destination(), None);
+    assert_eq!(ppc.changed_properties.len(), 2);
+    assert_eq!(ppc.changed_properties["Zeta"].0.as_u64(), Some(7));
+    assert_eq!(ppc.changed_properties["Beta"].0.as_u64(), Some(1));
+    assert_eq!(ppc.invalidated_properties, vec!("Alpha"));
+    assert_eq!(&*r[2].member().unwrap(), "Updated");
+    let directed = PPC::from_message(&r[3]).unwrap();
+    assert_eq!(&*r[3].destination().unwrap(), ":1.54");
+    assert_eq!(directed.changed_properties.len(), 1);
+    assert_eq!(directed.changed_properties["Zeta"].0.as_u64(), Some(7));
+
+    // Declaration order is kept on the wire
+    let sent = Arc::new(Mutex::new(vec!()));
+    let emitter = cr.emitter(sent.clone());
+    let n = emitter.batch(|b| {
+        let (a, bb): (dbus::Path, dbus::Path) = ("/a".into(), "/b".into());
+        b.property_changed(iface, &a, "Beta", 1u8).unwrap();
+        b.property_changed(iface, &a, "Alpha", 1u8).unwrap();
+        b.signal(iface, &bb, "Updated", ()).unwrap();
+        b.property_changed(iface, &bb, "Zeta", 2u8).unwrap();
+        assert!(!b.property_changed(iface, &a, "Mid", 3u8).unwrap());
+        b.property_changed(iface, &a, "Zeta", 4u8).unwrap();
+        5
+    }).unwrap();
+    assert_eq!(n, 5);
+    let sent = sent.lock().unwrap();
+    assert_eq!(sent.len(), 3);
+    assert_eq!(&*sent[0].path().unwrap(), "/a");
+    let (_, changed): (&str, dbus::arg::Dict<&str, Variant<u8>, _>) = sent[0].read2().unwrap();
+    assert_eq!(changed.map(|(k, v)| (k, v.0)).collect::<Vec<_>>(), vec!(("Zeta", 4), ("Beta", 1)));
+    let ppc = PPC::from_message(&sent[0]).unwrap();
+    assert_eq!(ppc.invalidated_properties, vec!("Alpha"));
+    assert_eq!(&*sent[1].member().unwrap(), "Updated");
+    assert_eq!(&*sent[2].path().unwrap(), "/b");
+}
+
+#[test]
+fn property_cells() {
+    use dbus::blocking::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged as PPC;
+    use dbus::message::SignalArgs;
+    use crate::Property;
+
+    struct Light { on: Property<bool>, level: Property<u8>, name: Property<String> }
+    const IFACE: &str = "com.example.dbusrs.light";
+
+    let mut cr = Crossroads::new();
+    let iface = cr.register(IFACE, |b: &mut IfaceBuilder<Light>| {
+        b.property("On").bind_writable(|l: &mut Light| &mut l.on);
+        b.property("Level").bind(|l: &mut Light| &mut l.level).emits_changed_invalidates();
+        b.property("Name").bind(|l: &mut Light| &mut l.name).emits_changed_false();
+        b.method("Dim", ("level",), (), |_, l, (level,): (u8,)| {
+            l.level.set(level);
+            l.name.set("dimmed".into());
+            Ok(())
+        });
+    });
+    let light = Light { on: Property::new(false), level: Property::new(10), name: Property::new("light".into()) };
+    cr.insert("/light", &[iface], light);
+
+    let set = |on: bool| Message::call_with_args(IFACE, "/light", "org.freedesktop.DBus.Properties", "Set", (IFACE, "On", Variant(on)));
+    let mut r = dispatch_helper2(&mut cr, set(true));
+    assert_eq!(r.len(), 2);
+    if r[0].msg_type() == dbus::message::MessageType::Signal { r.swap(0, 1); }
+    r[0].as_result().unwrap();
+    let ppc = PPC::from_message(&r[1]).unwrap();
+    assert_eq!(&*ppc.interface_name, IFACE);
+    assert_eq!(ppc.changed_properties["On"].0.as_u64(), Some(1));
+
+    // Setting the same value again changes nothing
+    dispatch_helper(&mut cr, set(true));
+
+    let msg = Message::call_with_args(IFACE, "/light", "org.freedesktop.DBus.Properties", "Get", (IFACE, "On"));
+    let r = dispatch_helper(&mut cr, msg);
+    let v: Variant<bool> = r.read1().unwrap();
+    assert!(v.0);
+
+    // Only the name of Level is signalled, and Name not at all
+    let msg = Message::call_with_args(IFACE, "/light", IFACE, "Dim", (3u8,));
+    let mut r = dispatch_helper2(&mut cr, msg);
+    assert_eq!(r.len(), 2);
+    if r[0].msg_type() == dbus::message::MessageType::Signal { r.swap(0, 1); }
+    let ppc = PPC::from_message(&r[1]).unwrap();
+    assert!(ppc.changed_properties.is_empty());
+    assert_eq!(ppc.invalidated_properties, vec!("Level"));
+
+    // Changes made outside method handlers are queued until flushed
+    let light: &mut Light = cr.data_mut(&"/light".into()).unwrap();
+    assert!(light.on.set(false));
+    assert!(!light.on.set(false));
+    assert!(light.level.set(5));
+    let sent = RefCell::new(vec!());
+    cr.flush_property_changes(&sent).unwrap();
+    let sent = sent.into_inner();
+    assert_eq!(sent.len(), 1);
+    let ppc = PPC::from_message(&sent[0]).unwrap();
+    assert_eq!(ppc.changed_properties["On"].0.as_u64(), Some(0));
+    assert_eq!(ppc.invalidated_properties, vec!("Level"));
+    assert_eq!(*cr.data_mut::<Light>(&"/light".into()).unwrap().level.get(), 5);
+}
+
+#[test]
+fn concurrent() {
+    use std::sync::{Arc, Mutex, mpsc};
+
+    fn is_sync<T: Send + Sync>(_: &T) {}
+
+    struct Gate { tx: Mutex<Vec<mpsc::Sender<()>>>, rx: Mutex<mpsc::Receiver<()>>, entered: Mutex<mpsc::Sender<()>> }
+    const IFACE: &str = "com.example.dbusrs.concurrent";
+    const MANAGER: &str = "com.example.dbusrs.concurrent.Manager";
+
+    let mut cr = ConcurrentCrossroads::new();
+    let token = cr.register(IFACE, |b: &mut IfaceBuilder<Gate>| {
+        // Blocks until Open is called on another object
+        b.method("Wait", (), ("opened",), |_, gate, _: ()| {
+            let _ = gate.entered.lock().unwrap().send(());
+            Ok((gate.rx.lock().unwrap().recv_timeout(Duration::from_secs(10)).is_ok(),))
+        });
+        b.method("Open", (), (), |_, gate, _: ()| {
+            for tx in gate.tx.lock().unwrap().iter() { tx.send(()).unwrap() }
+            Ok(())
+        });
+        // Blocks like Wait, and then gets another object
+        b.method_with_cr("Peek", ("other",), (), |ctx, cr, (other,): (String,)| {
+            let gate: &mut Gate = cr.data_mut(ctx.path()).unwrap();
+            let _ = gate.entered.lock().unwrap().send(());
+            let _ = gate.rx.lock().unwrap().recv_timeout(Duration::from_secs(10));
+            cr.data_mut::<Gate>(&other.clone().into()).ok_or_else(|| MethodErr::no_path(&other))?;
+            Ok(())
+        });
+    });
+    let manager = cr.register(MANAGER, move |b: &mut IfaceBuilder<()>| {
+        b.method_with_cr("Add", ("name",), (), move |_, cr, (name,): (String,)| {
+            let (tx, rx) = mpsc::channel();
+            cr.insert(format!("/gate/{}", name), &[token], Gate { tx: Mutex::new(vec!()), rx: Mutex::new(rx), entered: Mutex::new(tx) });
+            Ok(())
+        });
+        b.method_with_cr("Remove", ("name",), (), |_, cr, (name,): (String,)| {
+            cr.remove::<Gate>(&format!("/gate/{}", name).into()).ok_or_else(|| MethodErr::no_path(&name))?;
+            Ok(())
+        });
+    });
+    let signals = Arc::new(Mutex::new(vec!()));
+    cr.set_object_manager_support(Some(signals.clone()));
+    cr.insert("/", &[cr.object_manager(), manager], ());
+
+    let (tx_a, rx_a) = mpsc::channel();
+    let (tx_b, rx_b) = mpsc::channel();
+    let (entered_tx, entered) = mpsc::channel();
+    let gate = |tx, rx| Gate { tx: Mutex::new(tx), rx: Mutex::new(rx), entered: Mutex::new(entered_tx.clone()) };
+    cr.insert("/gate/a", &[token], gate(vec!(), rx_a));
+    cr.insert("/gate/b", &[token], gate(vec!(), rx_b));
+    cr.insert("/gate/c", &[token], gate(vec!(tx_a, tx_b), mpsc::channel().1));
+    let cr = Arc::new(cr);
+    is_sync(&*cr);
+
+    let call = |path: &str, iface: &str, method: &str| {
+        let mut msg = Message::new_method_call(IFACE, path, iface, method).unwrap();
+        msg.set_serial(57);
+        msg
+    };
+    let handle = |cr: &ConcurrentCrossroads, msg: Message| {
+        let r = RefCell::new(vec!());
+        cr.handle_message(msg, &r).unwrap();
+        r.into_inner().remove(0)
+    };
+
+    // The same method is called on two objects at once
+    let waiters: Vec<_> = ["/gate/a", "/gate/b"].iter().map(|path| {
+        let cr2 = cr.clone();
+        let msg = call(path, IFACE, "Wait");
+        std::thread::spawn(move || handle(&cr2, msg).read1::<bool>().unwrap())
+    }).collect();
+    for _ in 0..2 { entered.recv_timeout(Duration::from_secs(10)).unwrap(); }
+    handle(&cr, call("/gate/c", IFACE, "Open")).as_result().unwrap();
+    for w in waiters { assert!(w.join().unwrap()); }
+
+    // GetManagedObjects takes one object at a time, so it does not hold one that a handler
+    // waits for, while waiting for the object of that handler
+    let cr2 = cr.clone();
+    let msg = call("/gate/b", IFACE, "Peek").append1("/gate/a");
+    let peek = std::thread::spawn(move || handle(&cr2, msg));
+    entered.recv_timeout(Duration::from_secs(10)).unwrap();
+    let (gmo_tx, gmo_rx) = mpsc::channel();
+    let cr2 = cr.clone();
+    let msg = call("/", "org.freedesktop.DBus.ObjectManager", "GetManagedObjects");
+    std::thread::spawn(move || gmo_tx.send(handle(&cr2, msg)).unwrap());
+    std::thread::sleep(Duration::from_millis(100));
+    handle(&cr, call("/gate/c", IFACE, "Open")).as_result().unwrap();
+    let gmo = gmo_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    let objects: HashMap<dbus::Path, HashMap<String, PropMap>> = gmo.read1().unwrap();
+    assert_eq!(objects.len(), 3);
+    peek.join().unwrap().as_result().unwrap();
+
+    // Introspection sees the other objects
+    let xml: String = handle(&cr, call("/", "org.freedesktop.DBus.Introspectable", "Introspect")).read1().unwrap();
+    assert!(xml.contains(r#"<node name="gate/a"/>"#));
+
+    // Unknown paths get an error reply
+    let mut r = handle(&cr, call("/gate/d", IFACE, "Open"));
+    assert_eq!(r.as_result().unwrap_err().name(), Some("org.freedesktop.DBus.Error.UnknownMethod"));
+
+    // Handlers change the tree, which sends ObjectManager signals
+    signals.lock().unwrap().clear();
+    handle(&cr, call("/", MANAGER, "Add").append1("d")).as_result().unwrap();
+    handle(&cr, call("/gate/d", IFACE, "Open")).as_result().unwrap();
+    let gmo = handle(&cr, call("/", "org.freedesktop.DBus.ObjectManager", "GetManagedObjects"));
+    let objects: HashMap<dbus::Path, HashMap<String, PropMap>> = gmo.read1().unwrap();
+    let mut paths: Vec<_> = objects.keys().map(|p| p.to_string()).collect();
+    paths.sort();
+    assert_eq!(paths, vec!("/gate/a", "/gate/b", "/gate/c", "/gate/d"));
+    assert!(objects[&"/gate/d".into()].contains_key(IFACE));
+    handle(&cr, call("/", MANAGER, "Remove").append1("d")).as_result().unwrap();
+    let members: Vec<_> = signals.lock().unwrap().iter().map(|m: &Message| m.member().unwrap().to_string()).collect();
+    assert_eq!(members, vec!("InterfacesAdded", "InterfacesRemoved"));
+    assert!(handle(&cr, call("/", MANAGER, "Remove").append1("d")).as_result().is_err());
+
+    assert!(cr.with_data(&"/gate/a".into(), |_: &mut Gate| ()).is_some());
+    assert!(cr.remove::<Gate>(&"/gate/a".into()).is_some());
+    assert!(cr.with_data(&"/gate/a".into(), |_: &mut Gate| ()).is_none());
+}
+
+#[tokio::test]
+async fn multiple_connections() {
+    use std::sync::{Arc, Mutex};
+    const IFACE: &str = "com.example.dbusrs.multi";
+
+    let mut cr = Crossroads::new();
+    let unattached = Arc::new(Mutex::new(vec!()));
+    let spawner = Box::new(|fut| { tokio::spawn(fut); });
+    cr.set_async_support(Some((unattached.clone(), spawner)));
+    let token = cr.register(IFACE, |b: &mut IfaceBuilder<()>| {
+        b.signal::<(), _>("Pinged", ());
+        b.method("Ping", (), (), |ctx, _, _: ()| {
+            ctx.push_msg(ctx.make_signal("Pinged", ()));
+            Ok(())
+        });
+        b.method("DirectPing", (), (), |ctx, _, _: ()| {
+            let mut msg = ctx.make_signal("Pinged", ());
+            msg.set_destination(Some(":1.54".into()));
+            ctx.push_msg(msg);
+            Ok(())
+        });
+        b.method_with_cr_async("AsyncPing", (), (), |mut ctx, _, _: ()| async move {
+            ctx.reply(Ok(()))
+        });
+    });
+
+    let conns: Vec<Arc<Mutex<Vec<Message>>>> = (0..3).map(|_| Default::default()).collect();
+    let ids: Vec<_> = conns.iter().map(|c| cr.attach_connection(c.clone())).collect();
+    assert!(cr.set_connection_signals(ids[2], false));
+    cr.set_object_manager_support(Some(Arc::new(cr.connections())));
+    cr.insert("/", &[cr.object_manager()], ());
+    cr.insert("/obj", &[token], ());
+
+    let take = |i: usize| -> Vec<(dbus::MessageType, String)> {
+        conns[i].lock().unwrap().drain(..).map(|m| (m.msg_type(), m.member().map(|m| m.to_string()).unwrap_or_default())).collect()
+    };
+    let added = (dbus::MessageType::Signal, "InterfacesAdded".to_string());
+    assert_eq!(take(0), vec!(added.clone()));
+    assert_eq!(take(1), vec!(added));
+    assert_eq!(take(2), vec!());
+
+    // The reply goes back on the connection of the call, the signal to all that receive signals
+    let call = |method: &str| {
+        let mut msg = Message::new_method_call(IFACE, "/obj", IFACE, method).unwrap();
+        msg.set_serial(57);
+        msg
+    };
+    cr.handle_message_on(call("Ping"), ids[2]).unwrap();
+    let pinged = (dbus::MessageType::Signal, "Pinged".to_string());
+    assert_eq!(take(0), vec!(pinged.clone()));
+    assert_eq!(take(1), vec!(pinged.clone()));
+    assert_eq!(take(2), vec!((dbus::MessageType::MethodReturn, String::new())));
+
+    // A signal with a destination goes only to the connection of the call
+    cr.handle_message_on(call("DirectPing"), ids[2]).unwrap();
+    assert_eq!(take(0), vec!());
+    assert_eq!(take(1), vec!());
+    assert_eq!(take(2), vec!((dbus::MessageType::MethodReturn, String::new()), pinged.clone()));
+
+    cr.handle_message_on(call("AsyncPing"), ids[1]).unwrap();
+    tokio::task::yield_now().await;
+    assert_eq!(take(1), vec!((dbus::MessageType::MethodReturn, String::new())));
+    assert!(unattached.lock().unwrap().is_empty());
+
+    // Emitting on a selected subset
+    let emitter = cr.emitter(Arc::new(cr.connections().only(&[ids[0], ids[2]])));
+    emitter.signal(token, &"/obj".into(), "Pinged", ()).unwrap();
+    assert_eq!(take(0), vec!(pinged.clone()));
+    assert_eq!(take(1), vec!());
+    assert_eq!(take(2), vec!(pinged.clone()));
+
+    assert!(cr.detach_connection(ids[0]));
+    assert!(cr.handle_message_on(call("Ping"), ids[0]).is_err());
+    cr.handle_message_on(call("Ping"), ids[1]).unwrap();
+    assert_eq!(take(0), vec!());
+    assert_eq!(take(1).len(), 2);
+}
+
+#[test]
+fn connection_credentials() {
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::{Access, Credentials, CredentialsLookup};
+    const IFACE: &str = "com.example.dbusrs.multi";
+
+    let mut cr = Crossroads::new();
+    let token = cr.register(IFACE, |b: &mut IfaceBuilder<()>| {
+        b.method("Reboot", (), (), |_, _, _: ()| Ok(())).access(Access::root());
+    });
+    cr.insert("/", &[token], ());
+    let conns: Vec<Arc<Mutex<Vec<Message>>>> = (0..2).map(|_| Default::default()).collect();
+    let ids: Vec<_> = conns.iter().map(|c| cr.attach_connection(c.clone())).collect();
+
+    let lookups = Arc::new(AtomicUsize::new(0));
+    let lookup = |uid: u32| {
+        let lookups = lookups.clone();
+        CredentialsLookup::blocking(move |_| {
+            lookups.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { Ok(Credentials { uid: Some(uid), ..Default::default() }) })
+        })
+    };
+    let call = |cr: &mut Crossroads, i: usize| {
+        let mut msg = Message::new_method_call(IFACE, "/", IFACE, "Reboot").unwrap();
+        msg.set_sender(Some(":1.5".into()));
+        msg.set_serial(57);
+        cr.handle_message_on(msg, ids[i]).unwrap();
+        let mut r = conns[i].lock().unwrap().pop().unwrap();
+        r.as_result().map(|_| ()).map_err(|e| e.name().unwrap().to_string())
+    };
+    const DENIED: &str = "org.freedesktop.DBus.Error.AccessDenied";
+
+    // The lookup for unattached calls is not used for attached connections
+    cr.set_credentials_lookup(Some(lookup(0)));
+    assert_eq!(call(&mut cr, 0).unwrap_err(), DENIED);
+    assert_eq!(lookups.load(Ordering::SeqCst), 0);
+
+    // The same unique name is a root process on one bus, and a user process on the other
+    assert!(cr.set_connection_credentials_lookup(ids[0], Some(lookup(0))));
+    assert!(cr.set_connection_credentials_lookup(ids[1], Some(lookup(1000))));
+    call(&mut cr, 0).unwrap();
+    assert_eq!(call(&mut cr, 1).unwrap_err(), DENIED);
+    call(&mut cr, 0).unwrap();
+    assert_eq!(lookups.load(Ordering::SeqCst), 2);
+
+    // The name going away on one bus does not affect the other
+    let mut noc = Message::signal(&"/org/freedesktop/DBus".into(), &"org.freedesktop.DBus".into(), &"NameOwnerChanged".into())
+        .append3(":1.5", ":1.5", "");
+    noc.set_sender(Some("org.freedesktop.DBus".into()));
+    cr.handle_message_on(noc, ids[1]).unwrap();
+    call(&mut cr, 0).unwrap();
+    assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    assert_eq!(call(&mut cr, 1).unwrap_err(), DENIED);
+    assert_eq!(lookups.load(Ordering::SeqCst), 3);
+
+    assert!(cr.detach_connection(ids[1]));
+    assert!(!cr.set_connection_credentials_lookup(ids[1], Some(lookup(0))));
+}
+
+#[test]
+fn reentrant_spawner() {
+    use std::sync::{Arc, Mutex, OnceLock};
+    use std::future::Future;
+    use std::pin::Pin;
+    const IFACE: &str = "com.example.dbusrs.reentrant";
+
+    // The spawner handles a call to another object before keeping the future
+    let cr: Arc<OnceLock<ConcurrentCrossroads>> = Default::default();
+    let spawned: Arc<Mutex<Vec<Pin<Box<dyn Future<Output = ()> + Send>>>>> = Default::default();
+    let sent: Arc<Mutex<Vec<Message>>> = Default::default();
+    let call = |path: &str| {
+        let mut msg = Message::new_method_call(IFACE, path, IFACE, "AsyncPing").unwrap();
+        msg.set_serial(57);
+        msg
+    };
+    let (cr2, spawned2, sent2) = (cr.clone(), spawned.clone(), sent.clone());
+    let spawner = Box::new(move |fut| {
+        let first = spawned2.lock().unwrap().is_empty();
+        spawned2.lock().unwrap().push(fut);
+        if first { cr2.get().unwrap().handle_message(call("/b"), &*sent2).unwrap(); }
+    });
+
+    let mut c = ConcurrentCrossroads::new();
+    c.set_async_support(Some((sent.clone(), spawner)));
+    let token = c.register(IFACE, |b: &mut IfaceBuilder<()>| {
+        b.method_with_cr_async("AsyncPing", (), (), |mut ctx, _, _: ()| async move {
+            ctx.reply(Ok(()))
+        });
+    });
+    c.insert("/a", &[token], ());
+    c.insert("/b", &[token], ());
+    cr.set(c).ok().unwrap();
+
+    cr.get().unwrap().handle_message(call("/a"), &*sent).unwrap();
+    assert_eq!(spawned.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn interface_data() {
+    use crate::Property;
+
+    struct Battery { level: Property<u8> }
+    struct Name(String);
+
+    let mut cr = Crossroads::new();
+    let battery = cr.register("com.example.dbusrs.Battery", |b: &mut IfaceBuilder<Battery>| {
+        b.property("Level").bind(|b: &mut Battery| &mut b.level);
+        b.method("Drain", (), (), |_, b, _: ()| {
+            let level = *b.level.get();
+            b.level.set(level - 1);
+            Ok(())
+        });
+    });
+    let name = cr.register("com.example.dbusrs.Name", |b: &mut IfaceBuilder<Name>| {
+        b.property("Name").get(|_, n| Ok(n.0.clone()));
+        b.method("Rename", ("name",), (), |_, n, (name,): (String,)| { n.0 = name; Ok(()) });
+    });
+    let path: dbus::Path = "/dev".into();
+    assert!(!cr.add_interface_with_data(path.clone(), name, Name("none".into())));
+    cr.insert(path.clone(), &[], ());
+    assert!(cr.add_interface_with_data(path.clone(), battery, Battery { level: Property::new(50) }));
+    assert!(cr.add_interface_with_data(path.clone(), name, Name("phone".into())));
+
+    let call = |iface: &str, method: &str| Message::new_method_call("com.example.dbusrs", "/dev", iface, method).unwrap();
+    let r = dispatch_helper2(&mut cr, call("com.example.dbusrs.Battery", "Drain"));
+    assert_eq!(r.len(), 2);
+    dispatch_helper(&mut cr, call("com.example.dbusrs.Name", "Rename").append1("tablet"));
+
+    let msg = Message::call_with_args("com.example.dbusrs", "/dev", "org.freedesktop.DBus.Properties", "GetAll", ("com.example.dbusrs.Battery",));
+    let r = dispatch_helper(&mut cr, msg);
+    let props: PropMap = r.read1().unwrap();
+    assert_eq!(props["Level"].0.as_u64(), Some(49));
+    let msg = Message::call_with_args("com.example.dbusrs", "/dev", "org.freedesktop.DBus.Properties", "Get", ("com.example.dbusrs.Name", "Name"));
+    let r = dispatch_helper(&mut cr, msg);
+    assert_eq!(r.read1::<Variant<&str>>().unwrap().0, "tablet");
+
+    assert_eq!(cr.interface_data_mut(&path, name).unwrap().0, "tablet");
+    assert!(cr.data_mut::<()>(&path).is_some());
+    let b = cr.remove_interface_with_data(path.clone(), battery).unwrap();
+    assert_eq!(*b.level.get(), 49);
+    assert!(!cr.has_interface(&path, battery));
+    assert!(cr.remove_interface_with_data(path.clone(), battery).is_none());
+}
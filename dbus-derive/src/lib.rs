@@ -0,0 +1,176 @@
+//! Derive macros for the dbus crate.
+//!
+//! Use these through the `derive` feature of the dbus crate, rather than depending on this crate directly.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::{parse_macro_input, Attribute, Data, DeriveInput, Fields, LitStr};
+
+/// Derives `dbus::DBusError` for an enum, where every variant corresponds to a D-Bus error name.
+///
+/// The enum needs a `#[dbus_error(prefix = "...")]` attribute. The error name of a variant is
+/// the prefix, a dot, and the name of the variant. This can be overridden with
+/// `#[dbus_error(name = "...")]` on the variant, giving the full error name.
+///
+/// A variant can either be a unit variant, or have one field which holds the error message.
+/// The field can be of any type implementing `FromStr` and `Display`: when converting from a
+/// `dbus::Error`, the message is parsed into the field, and if that fails, the error is not
+/// recognised. The message of a unit variant is its name, unless set with
+/// `#[dbus_error(message = "...")]`.
+///
+/// Besides `DBusError`, this derives `Display`, `std::error::Error`, `TryFrom<dbus::Error>`,
+/// and conversion into `dbus::Error` and `dbus::MethodErr`, so the enum can be returned
+/// from method handlers.
+///
+/// # Example
+///
+/// ```
+/// use dbus::DBusError;
+/// use std::convert::TryFrom;
+///
+/// #[derive(DBusError, Debug, PartialEq)]
+/// #[dbus_error(prefix = "com.example.Error")]
+/// enum MyError {
+///     NotFound(String),
+///     #[dbus_error(message = "Try again later")]
+///     Busy,
+///     #[dbus_error(name = "com.example.OtherError.TooLarge")]
+///     TooLarge(u32),
+/// }
+///
+/// let e = dbus::Error::new_custom("com.example.OtherError.TooLarge", "42");
+/// assert_eq!(MyError::try_from(e).unwrap(), MyError::TooLarge(42));
+///
+/// let m: dbus::MethodErr = MyError::Busy.into();
+/// assert_eq!(&**m.errorname(), "com.example.Error.Busy");
+/// assert_eq!(m.description(), "Try again later");
+/// ```
+///
+/// Error names are checked at compile time:
+///
+/// ```compile_fail
+/// #[derive(dbus::DBusError, Debug)]
+/// #[dbus_error(prefix = "com.example.")]
+/// enum MyError {
+///     Busy,
+/// }
+/// ```
+#[proc_macro_derive(DBusError, attributes(dbus_error))]
+pub fn derive_dbus_error(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match dbus_error(input) {
+        Ok(x) => x.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+#[derive(Default)]
+struct Attrs {
+    prefix: Option<LitStr>,
+    name: Option<LitStr>,
+    message: Option<LitStr>,
+}
+
+fn parse_attrs(attrs: &[Attribute]) -> syn::Result<Attrs> {
+    let mut r = Attrs::default();
+    for attr in attrs.iter().filter(|a| a.path().is_ident("dbus_error")) {
+        attr.parse_nested_meta(|meta| {
+            let field = if meta.path.is_ident("prefix") { &mut r.prefix }
+                else if meta.path.is_ident("name") { &mut r.name }
+                else if meta.path.is_ident("message") { &mut r.message }
+                else { return Err(meta.error("expected `prefix`, `name` or `message`")) };
+            *field = Some(meta.value()?.parse()?);
+            Ok(())
+        })?;
+    }
+    Ok(r)
+}
+
+fn dbus_error(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let data = match &input.data {
+        Data::Enum(data) => data,
+        _ => return Err(syn::Error::new_spanned(&input.ident, "DBusError can only be derived for enums")),
+    };
+    let attrs = parse_attrs(&input.attrs)?;
+    if attrs.name.is_some() || attrs.message.is_some() {
+        return Err(syn::Error::new_spanned(&input.ident, "`name` and `message` are only allowed on variants"));
+    }
+    let prefix = attrs.prefix.ok_or_else(|| syn::Error::new(Span::call_site(), "missing #[dbus_error(prefix = \"...\")] attribute"))?;
+
+    let mut from_arms = vec!();
+    let mut name_arms = vec!();
+    let mut message_arms = vec!();
+    for v in &data.variants {
+        let vattrs = parse_attrs(&v.attrs)?;
+        if vattrs.prefix.is_some() {
+            return Err(syn::Error::new_spanned(&v.ident, "`prefix` is only allowed on the enum"));
+        }
+        let ident = &v.ident;
+        let (name, span) = match vattrs.name {
+            Some(n) => (n.value(), n.span()),
+            None => (format!("{}.{}", prefix.value(), ident), prefix.span()),
+        };
+        if dbus_strings::validity::is_valid_error_name(name.as_bytes()).is_err() {
+            return Err(syn::Error::new(span, format!("invalid D-Bus error name: {:?}", name)));
+        }
+        match &v.fields {
+            Fields::Unit => {
+                let message = vattrs.message.map(|m| m.value()).unwrap_or_else(|| ident.to_string());
+                from_arms.push(quote! { #name => ::std::option::Option::Some(Self::#ident), });
+                name_arms.push(quote! { Self::#ident => ::dbus::error_name!(#name), });
+                message_arms.push(quote! { Self::#ident => #message.into(), });
+            },
+            Fields::Unnamed(f) if f.unnamed.len() == 1 && vattrs.message.is_none() => {
+                from_arms.push(quote! { #name => message.parse().ok().map(Self::#ident), });
+                name_arms.push(quote! { Self::#ident(_) => ::dbus::error_name!(#name), });
+                message_arms.push(quote! { Self::#ident(m) => m.to_string(), });
+            },
+            _ => return Err(syn::Error::new_spanned(v, "DBusError variants must either be unit variants, or have one unnamed field for the message")),
+        }
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::dbus::DBusError for #ident #ty_generics #where_clause {
+            fn from_name_and_message(name: &str, message: &str) -> ::std::option::Option<Self> {
+                match name {
+                    #(#from_arms)*
+                    _ => ::std::option::Option::None,
+                }
+            }
+            fn error_name(&self) -> ::dbus::strings::ErrorName<'static> {
+                match self { #(#name_arms)* }
+            }
+            fn error_message(&self) -> ::std::string::String {
+                match self { #(#message_arms)* }
+            }
+        }
+
+        impl #impl_generics ::std::fmt::Display for #ident #ty_generics #where_clause {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                f.write_str(&::dbus::DBusError::error_message(self))
+            }
+        }
+
+        impl #impl_generics ::std::error::Error for #ident #ty_generics #where_clause {}
+
+        impl #impl_generics ::std::convert::From<#ident #ty_generics> for ::dbus::MethodErr #where_clause {
+            fn from(e: #ident #ty_generics) -> Self { ::dbus::DBusError::to_method_err(&e) }
+        }
+
+        impl #impl_generics ::std::convert::From<#ident #ty_generics> for ::dbus::Error #where_clause {
+            fn from(e: #ident #ty_generics) -> Self { ::dbus::DBusError::to_error(&e) }
+        }
+
+        impl #impl_generics ::std::convert::TryFrom<::dbus::Error> for #ident #ty_generics #where_clause {
+            type Error = ::dbus::Error;
+            fn try_from(e: ::dbus::Error) -> ::std::result::Result<Self, ::dbus::Error> {
+                <Self as ::dbus::DBusError>::from_error(&e).ok_or(e)
+            }
+        }
+    })
+}
@@ -1,4 +1,6 @@
 use std::os::unix::net::UnixStream;
+use std::os::unix::io::{RawFd, OwnedFd, FromRawFd};
+use std::io;
 
 pub fn getuid() -> u32 {
     let x = unsafe { libc::getuid() };
@@ -32,3 +34,71 @@ pub fn connect_blocking(addr: &libc::sockaddr_un) -> Result<UnixStream, Box<dyn
     let u = unsafe { UnixStream::from_raw_fd(fd) };
     Ok(u)
 }
+
+// This is the max number of fds the Linux kernel allows in a single SCM_RIGHTS message,
+// and also what libdbus uses as its per-message limit.
+const MAX_FDS: usize = 253;
+
+// Returns a zeroed buffer large enough for a SCM_RIGHTS message with fd_count fds.
+// We use u64 storage so that the buffer is sufficiently aligned for cmsghdr.
+fn cmsg_buf(fd_count: usize) -> (Vec<u64>, usize) {
+    let space = unsafe { libc::CMSG_SPACE((fd_count * std::mem::size_of::<RawFd>()) as u32) } as usize;
+    (vec![0u64; space / 8 + 1], space)
+}
+
+pub fn send_with_fds(fd: RawFd, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+    if fds.len() > MAX_FDS { Err(io::Error::new(io::ErrorKind::InvalidInput, "Too many file descriptors"))? }
+    let mut iov = libc::iovec { iov_base: buf.as_ptr() as *mut libc::c_void, iov_len: buf.len() };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+
+    let (mut cmsg, space) = cmsg_buf(fds.len());
+    if !fds.is_empty() {
+        let fds_len = std::mem::size_of_val(fds) as u32;
+        msg.msg_control = cmsg.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        unsafe {
+            let hdr = libc::CMSG_FIRSTHDR(&msg);
+            (*hdr).cmsg_level = libc::SOL_SOCKET;
+            (*hdr).cmsg_type = libc::SCM_RIGHTS;
+            (*hdr).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(hdr) as *mut RawFd, fds.len());
+        }
+    }
+
+    let r = unsafe { libc::sendmsg(fd, &msg, libc::MSG_NOSIGNAL) };
+    if r < 0 { Err(io::Error::last_os_error()) } else { Ok(r as usize) }
+}
+
+pub fn recv_with_fds(fd: RawFd, buf: &mut [u8], fds: &mut Vec<OwnedFd>) -> io::Result<usize> {
+    let mut iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+    let (mut cmsg, space) = cmsg_buf(MAX_FDS);
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    let r = unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if r < 0 { return Err(io::Error::last_os_error()) }
+
+    // Take ownership of all received fds first, so that they get closed if something goes wrong.
+    unsafe {
+        let mut hdr = libc::CMSG_FIRSTHDR(&msg);
+        while !hdr.is_null() {
+            if (*hdr).cmsg_level == libc::SOL_SOCKET && (*hdr).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(hdr) as *const RawFd;
+                let data_len = (*hdr).cmsg_len as usize - (data as usize - hdr as usize);
+                for i in 0..(data_len / std::mem::size_of::<RawFd>()) {
+                    fds.push(OwnedFd::from_raw_fd(std::ptr::read_unaligned(data.add(i))));
+                }
+            }
+            hdr = libc::CMSG_NXTHDR(&msg, hdr);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "File descriptors were truncated"))?
+    }
+    Ok(r as usize)
+}
@@ -0,0 +1,40 @@
+//! Sending and receiving file descriptors as SCM_RIGHTS ancillary data.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+
+/// Writes data to a unix socket, attaching the file descriptors as ancillary data.
+///
+/// Returns the number of bytes written, which might be less than the length of buf.
+/// If so, the file descriptors were still sent (together with the first byte).
+pub fn send_with_fds<S: AsRawFd>(s: &S, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+    crate::sys::send_with_fds(s.as_raw_fd(), buf, fds)
+}
+
+/// Reads data from a unix socket. File descriptors received are appended to fds.
+///
+/// Returns the number of bytes read.
+pub fn recv_with_fds<S: AsRawFd>(s: &S, buf: &mut [u8], fds: &mut Vec<OwnedFd>) -> io::Result<usize> {
+    crate::sys::recv_with_fds(s.as_raw_fd(), buf, fds)
+}
+
+#[test]
+fn socketpair() {
+    use std::io::{Read, Write};
+    let (a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+    let f = std::fs::File::from(OwnedFd::from(a.try_clone().unwrap()));
+    assert_eq!(send_with_fds(&a, b"Hello", &[f.as_raw_fd()]).unwrap(), 5);
+    let mut buf = [0u8; 16];
+    let mut fds = vec!();
+    assert_eq!(recv_with_fds(&b, &mut buf, &mut fds).unwrap(), 5);
+    assert_eq!(&buf[..5], b"Hello");
+    assert_eq!(fds.len(), 1);
+    assert!(fds[0].as_raw_fd() != f.as_raw_fd());
+
+    // Write through the received fd and check that it ends up on the other side.
+    let mut f2 = std::fs::File::from(fds.pop().unwrap());
+    f2.write_all(b"World").unwrap();
+    let mut buf = [0u8; 5];
+    (&b).read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"World");
+}
@@ -0,0 +1,467 @@
+//! A D-Bus connection state machine which does not do any I/O.
+//!
+//! Bytes (and file descriptors) read from the socket are fed into the connection with
+//! `receive_data`, and the connection tells what to write back through `outgoing`.
+//! This makes it possible to use in any event loop, as well as in tests and fuzzers.
+//!
+//! The native channel of the dbus crate does not use this state machine yet; porting it
+//! over is not part of this module.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::num::NonZeroU32;
+use std::os::unix::io::OwnedFd;
+use std::sync::Arc;
+use std::time::Instant;
+use std::fmt;
+use dbus_native_channel::authentication::Authentication;
+use crate::message::{Message, MessageReader, METHOD_RETURN, ERROR, self};
+use crate::marshalled::Parsed;
+use crate::types::DemarshalError;
+use crate::strings::{BusName, BusNameBuf, StringLike};
+
+/// Something that happened on the connection.
+#[derive(Debug)]
+pub enum Event {
+    /// Authentication and the Hello call are done, and the unique name is now available.
+    Connected,
+    /// A message that is not a reply to a call made with `send_with_reply`.
+    Message(Message<'static>),
+    /// A reply (method return or error) to a call made with `send_with_reply`.
+    Reply(NonZeroU32, Message<'static>),
+    /// No reply to the call with this serial was received before its deadline.
+    Timeout(NonZeroU32),
+    /// The message with this serial, queued before authentication was done, could not be sent.
+    SendFailed(NonZeroU32, SendError),
+    /// The connection is closed and can no longer be used. Contains a description of why.
+    Disconnected(String),
+}
+
+/// Why a message could not be queued for sending.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The connection is closed.
+    Disconnected,
+    /// The message has file descriptors, but unix fd passing was not negotiated.
+    UnixFdNotSupported,
+    /// The message could not be marshalled.
+    Marshal(DemarshalError),
+}
+
+impl std::error::Error for SendError {}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SendError::Disconnected => write!(f, "Connection is closed"),
+            SendError::UnixFdNotSupported => write!(f, "Unix fd passing is not enabled on this connection"),
+            SendError::Marshal(e) => write!(f, "Marshalling failed: {}", e),
+        }
+    }
+}
+
+impl From<DemarshalError> for SendError {
+    fn from(e: DemarshalError) -> SendError { SendError::Marshal(e) }
+}
+
+#[derive(Debug)]
+enum State {
+    Authenticating(Authentication),
+    WaitingForHello(NonZeroU32),
+    Connected,
+    Disconnected,
+}
+
+#[derive(Debug)]
+struct OutChunk {
+    data: Vec<u8>,
+    pos: usize,
+    fds: Vec<Arc<OwnedFd>>,
+}
+
+/// The protocol state of a D-Bus connection, without the socket.
+///
+/// Typical usage is:
+///  * Write what `outgoing` returns to the socket, then call `advance_output`.
+///  * Feed what is read from the socket into `receive_data`, and call `receive_eof` if the socket is closed.
+///  * Call `handle_timeout` when the instant returned by `poll_timeout` has passed.
+///  * Call `poll_event` until it returns None.
+#[derive(Debug)]
+pub struct Connection {
+    state: State,
+    unix_fd: bool,
+    line: Vec<u8>,
+    reader: MessageReader,
+    out: VecDeque<OutChunk>,
+    // Messages queued by the user before authentication was done, and their serials.
+    waiting: Vec<(NonZeroU32, OutChunk)>,
+    last_serial: u32,
+    replies: BTreeMap<NonZeroU32, Option<Instant>>,
+    events: VecDeque<Event>,
+    unique_name: Option<BusNameBuf>,
+}
+
+impl Connection {
+    /// Creates a new connection, which will start by authenticating.
+    ///
+    /// If do_unix_fd is true, the connection will try to negotiate unix fd passing.
+    pub fn new(do_unix_fd: bool) -> Self {
+        let (auth, s) = Authentication::new(do_unix_fd);
+        let mut c = Connection {
+            state: State::Authenticating(auth),
+            unix_fd: false,
+            line: vec!(),
+            reader: MessageReader::new(),
+            out: VecDeque::new(),
+            waiting: vec!(),
+            // Serial 1 is reserved for the Hello call.
+            last_serial: 1,
+            replies: BTreeMap::new(),
+            events: VecDeque::new(),
+            unique_name: None,
+        };
+        c.push_out(s.into_bytes(), vec!());
+        c
+    }
+
+    /// The unique name of this connection, e g ":1.54". Available after `Event::Connected`.
+    pub fn unique_name(&self) -> Option<&BusName> { self.unique_name.as_deref() }
+
+    /// Whether unix file descriptors can be sent and received on this connection.
+    pub fn unix_fd_enabled(&self) -> bool { self.unix_fd }
+
+    /// Whether the connection is closed.
+    pub fn is_disconnected(&self) -> bool { matches!(self.state, State::Disconnected) }
+
+    fn push_out(&mut self, data: Vec<u8>, fds: Vec<Arc<OwnedFd>>) {
+        self.out.push_back(OutChunk { data, pos: 0, fds });
+    }
+
+    fn next_serial(&mut self) -> NonZeroU32 {
+        self.last_serial = self.last_serial.wrapping_add(1);
+        if self.last_serial == 0 { self.last_serial = 1; }
+        NonZeroU32::new(self.last_serial).unwrap()
+    }
+
+    fn disconnect(&mut self, reason: String) {
+        if self.is_disconnected() { return; }
+        self.state = State::Disconnected;
+        self.out.clear();
+        self.waiting.clear();
+        self.replies.clear();
+        self.events.push_back(Event::Disconnected(reason));
+    }
+
+    /// Queues a message for sending, and returns its serial.
+    ///
+    /// Messages sent before authentication is done are held back until the Hello call has been sent.
+    /// If such a message has file descriptors, but unix fd passing was not negotiated,
+    /// it is dropped and `Event::SendFailed` is returned instead.
+    pub fn send(&mut self, mut msg: Message) -> Result<NonZeroU32, SendError> {
+        if self.is_disconnected() { Err(SendError::Disconnected)? }
+        let fds = msg.take_unix_fds();
+        if !fds.is_empty() && !self.unix_fd && !matches!(self.state, State::Authenticating(_)) {
+            Err(SendError::UnixFdNotSupported)?
+        }
+        let serial = self.next_serial();
+        msg.set_serial(Some(serial));
+        let data = msg.marshal(serial, false)?;
+        let chunk = OutChunk { data, pos: 0, fds };
+        if let State::Authenticating(_) = self.state { self.waiting.push((serial, chunk)) } else { self.out.push_back(chunk) };
+        Ok(serial)
+    }
+
+    /// Queues a method call for sending, and returns its serial.
+    ///
+    /// The reply will be returned as `Event::Reply`. If no reply has arrived at the deadline,
+    /// `Event::Timeout` will be returned instead.
+    pub fn send_with_reply(&mut self, msg: Message, deadline: Option<Instant>) -> Result<NonZeroU32, SendError> {
+        let serial = self.send(msg)?;
+        self.replies.insert(serial, deadline);
+        Ok(serial)
+    }
+
+    /// Stops waiting for a reply. A reply arriving later will be returned as `Event::Message`.
+    pub fn cancel_reply(&mut self, serial: NonZeroU32) {
+        self.replies.remove(&serial);
+    }
+
+    /// Returns the bytes that should be written to the socket next, and file descriptors
+    /// that should be sent together with them, if any.
+    ///
+    /// Returns None if there is nothing to write.
+    pub fn outgoing(&self) -> Option<(&[u8], &[Arc<OwnedFd>])> {
+        self.out.front().map(|c| (&c.data[c.pos..], &*c.fds))
+    }
+
+    /// Tells the connection that this many bytes returned from `outgoing` were written.
+    ///
+    /// The file descriptors were sent together with the first byte, so they are closed here.
+    pub fn advance_output(&mut self, count: usize) {
+        let mut count = count;
+        while count > 0 {
+            let c = if let Some(c) = self.out.front_mut() { c } else { return };
+            c.fds.clear();
+            let n = std::cmp::min(count, c.data.len() - c.pos);
+            c.pos += n;
+            count -= n;
+            if c.pos >= c.data.len() { self.out.pop_front(); }
+        }
+    }
+
+    /// Feeds file descriptors read from the socket into the connection.
+    ///
+    /// They will be attached to the message they belong to when that message is complete.
+    pub fn receive_fds<I: IntoIterator<Item=OwnedFd>>(&mut self, fds: I) {
+        if self.is_disconnected() { return; }
+        self.reader.push_fds(fds);
+    }
+
+    /// Feeds data read from the socket into the connection.
+    pub fn receive_data(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            data = match self.state {
+                State::Disconnected => return,
+                State::Authenticating(_) => self.receive_auth(data),
+                _ => self.receive_msg(data),
+            };
+        }
+    }
+
+    /// Tells the connection that the socket was closed by the other side.
+    pub fn receive_eof(&mut self) {
+        self.disconnect("Connection closed by peer".into());
+    }
+
+    fn receive_auth<'b>(&mut self, data: &'b [u8]) -> &'b [u8] {
+        let (line, rest) = match data.iter().position(|&b| b == b'\n') {
+            Some(pos) => data.split_at(pos + 1),
+            None => (data, &[][..]),
+        };
+        self.line.extend_from_slice(line);
+        if self.line.last() != Some(&b'\n') {
+            if self.line.len() > 16384 { self.disconnect("D-Bus authentication error (line too long)".into()) }
+            return rest;
+        }
+        let line = std::mem::take(&mut self.line);
+        let auth = if let State::Authenticating(auth) = &mut self.state { auth } else { unreachable!() };
+        match auth.handle(&line) {
+            Ok(s) => self.push_out(s.as_bytes().into(), vec!()),
+            Err(e) => { self.disconnect(e.to_string()); return rest; },
+        };
+        if let State::Authenticating(Authentication::Begin(unix_fd)) = self.state {
+            self.unix_fd = unix_fd;
+            let serial = NonZeroU32::new(1).unwrap();
+            let data = message::get_hello_message().marshal(serial, false)
+                .expect("Failed to marshal Hello message");
+            self.push_out(data, vec!());
+            self.state = State::WaitingForHello(serial);
+            let waiting = std::mem::take(&mut self.waiting);
+            for (serial, chunk) in waiting {
+                if !chunk.fds.is_empty() && !unix_fd {
+                    self.replies.remove(&serial);
+                    self.events.push_back(Event::SendFailed(serial, SendError::UnixFdNotSupported));
+                    continue;
+                }
+                self.out.push_back(chunk);
+            }
+        }
+        rest
+    }
+
+    fn receive_msg<'b>(&mut self, data: &'b [u8]) -> &'b [u8] {
+        let buf = self.reader.get_buf();
+        let n = std::cmp::min(buf.len(), data.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        match self.reader.buf_written_to(n) {
+            Ok(None) => {},
+            Ok(Some(v)) => if let Err(e) = self.handle_msg(v) {
+                self.disconnect(format!("D-Bus protocol error ({})", e));
+            },
+            Err(e) => self.disconnect(format!("D-Bus protocol error ({})", e)),
+        }
+        &data[n..]
+    }
+
+    fn handle_msg(&mut self, v: Vec<u8>) -> Result<(), DemarshalError> {
+        let fds = self.reader.take_fds(&v)?;
+        let msg = match Message::demarshal_with_fds(&v, fds)? {
+            Some(msg) => msg.into_owned(),
+            None => return Ok(()),
+        };
+        let is_reply = msg.msg_type() == METHOD_RETURN || msg.msg_type() == ERROR;
+        let reply_serial = if is_reply { msg.reply_serial() } else { None };
+        if let State::WaitingForHello(serial) = self.state {
+            if reply_serial == Some(serial) {
+                if msg.msg_type() == ERROR { Err(DemarshalError::InvalidProtocol)? }
+                let name = match msg.read_body().iter().next() {
+                    Some(s) => s?.parse()?,
+                    None => Err(DemarshalError::NotEnoughData)?,
+                };
+                let name = if let Parsed::String(name) = name { name } else { Err(DemarshalError::WrongType)? };
+                self.unique_name = Some(BusName::new_owned(name.to_string())?);
+                self.state = State::Connected;
+                self.events.push_back(Event::Connected);
+                return Ok(());
+            }
+        }
+        match reply_serial {
+            Some(serial) if self.replies.remove(&serial).is_some() => self.events.push_back(Event::Reply(serial, msg)),
+            _ => self.events.push_back(Event::Message(msg)),
+        }
+        Ok(())
+    }
+
+    /// Returns the earliest deadline of the calls waiting for a reply, if any.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        self.replies.values().filter_map(|x| *x).min()
+    }
+
+    /// Times out all calls whose deadline is at or before now.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        let expired: Vec<_> = self.replies.iter()
+            .filter(|(_, d)| matches!(d, Some(d) if *d <= now))
+            .map(|(s, _)| *s).collect();
+        for serial in expired {
+            self.replies.remove(&serial);
+            self.events.push_back(Event::Timeout(serial));
+        }
+    }
+
+    /// Returns the next event, if any.
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+}
+
+#[cfg(test)]
+fn make_reply(reply_serial: u32, serial: u32, body: &str) -> Vec<u8> {
+    use crate::marshalled::MultiBuf;
+    let mut m = Message::new_method_return(NonZeroU32::new(reply_serial).unwrap());
+    let mut b = MultiBuf::new();
+    b.append(crate::strings::DBusStr::new(body).unwrap()).unwrap();
+    m.set_body(b);
+    m.marshal(NonZeroU32::new(serial).unwrap(), false).unwrap()
+}
+
+#[cfg(test)]
+fn take_output(c: &mut Connection) -> Vec<u8> {
+    let mut r = vec!();
+    while let Some((data, _)) = c.outgoing() {
+        let n = data.len();
+        r.extend_from_slice(data);
+        c.advance_output(n);
+    }
+    r
+}
+
+#[test]
+fn auth_and_hello() {
+    let mut c = Connection::new(true);
+    let out = take_output(&mut c);
+    assert!(out.starts_with(b"\0AUTH EXTERNAL "));
+
+    // Calls made before authentication are held back
+    let ping = Message::new_method_call(crate::strings::ObjectPath::new("/").unwrap().into(),
+        crate::strings::MemberName::new("Ping").unwrap().into()).unwrap();
+    let ping_serial = c.send_with_reply(ping, None).unwrap();
+    assert!(c.outgoing().is_none());
+
+    c.receive_data(b"OK 1234deadbeef\r");
+    assert!(c.outgoing().is_none());
+    c.receive_data(b"\n");
+    assert_eq!(take_output(&mut c), b"NEGOTIATE_UNIX_FD\r\n");
+    c.receive_data(b"AGREE_UNIX_FD\r\n");
+    assert!(c.unix_fd_enabled());
+    let out = take_output(&mut c);
+    assert!(out.starts_with(b"BEGIN\r\n"));
+    let hello = &out[7..];
+    let hello_len = message::total_message_size(hello).unwrap();
+    let m = Message::demarshal(&hello[..hello_len]).unwrap().unwrap();
+    assert_eq!(m.serial().unwrap().get(), 1);
+    let m = Message::demarshal(&hello[hello_len..]).unwrap().unwrap();
+    assert_eq!(m.serial(), Some(ping_serial));
+    assert_eq!(ping_serial.get(), 2);
+
+    // Feed the Hello reply, one byte at a time
+    for b in make_reply(1, 1, ":1.54") { c.receive_data(&[b]); }
+    assert!(matches!(c.poll_event(), Some(Event::Connected)));
+    assert_eq!(&**c.unique_name().unwrap(), ":1.54");
+
+    // A message with an unknown reply serial, followed by the reply to our ping
+    let mut v = make_reply(42, 2, "");
+    v.extend(make_reply(2, 3, ""));
+    c.receive_data(&v);
+    assert!(matches!(c.poll_event(), Some(Event::Message(_))));
+    match c.poll_event() {
+        Some(Event::Reply(s, m)) => { assert_eq!(s, ping_serial); assert_eq!(m.reply_serial(), Some(ping_serial)) },
+        x => panic!("Unexpected event {:?}", x),
+    }
+    assert!(c.poll_event().is_none());
+}
+
+#[test]
+fn timeout_and_disconnect() {
+    use std::time::Duration;
+    let mut c = Connection::new(false);
+    c.receive_data(b"OK 1234deadbeef\r\n");
+    for b in make_reply(1, 1, ":1.7").chunks(5) { c.receive_data(b); }
+    assert!(matches!(c.poll_event(), Some(Event::Connected)));
+    assert!(!c.unix_fd_enabled());
+
+    let now = Instant::now();
+    let msg = message::get_hello_message();
+    let s1 = c.send_with_reply(msg, Some(now + Duration::from_secs(5))).unwrap();
+    let msg = message::get_hello_message();
+    let s2 = c.send_with_reply(msg, Some(now + Duration::from_secs(1))).unwrap();
+    assert_eq!(c.poll_timeout(), Some(now + Duration::from_secs(1)));
+    c.handle_timeout(now + Duration::from_secs(2));
+    match c.poll_event() { Some(Event::Timeout(s)) => assert_eq!(s, s2), x => panic!("Unexpected event {:?}", x) }
+    assert!(c.poll_event().is_none());
+    assert_eq!(c.poll_timeout(), Some(now + Duration::from_secs(5)));
+
+    // The late reply is just a message now
+    c.receive_data(&make_reply(s2.get(), 2, ""));
+    assert!(matches!(c.poll_event(), Some(Event::Message(_))));
+
+    // Garbage data causes a disconnect
+    c.receive_data(b"garbage data, not a D-Bus message");
+    assert!(matches!(c.poll_event(), Some(Event::Disconnected(_))));
+    assert!(c.is_disconnected());
+    assert!(c.poll_timeout().is_none());
+    assert_eq!(c.send_with_reply(message::get_hello_message(), None).unwrap_err(), SendError::Disconnected);
+    assert_ne!(s1, s2);
+}
+
+#[test]
+fn fds_without_unix_fd() {
+    let mut c = Connection::new(false);
+    take_output(&mut c);
+    let mut m = message::get_hello_message();
+    m.add_unix_fd(OwnedFd::from(std::fs::File::open("/dev/null").unwrap()));
+    let s1 = c.send_with_reply(m, Some(Instant::now())).unwrap();
+    let s2 = c.send(message::get_hello_message()).unwrap();
+
+    // Only the message with fds fails, the rest is sent
+    c.receive_data(b"OK 1234deadbeef\r\n");
+    match c.poll_event() {
+        Some(Event::SendFailed(s, e)) => { assert_eq!(s, s1); assert_eq!(e, SendError::UnixFdNotSupported) },
+        x => panic!("Unexpected event {:?}", x),
+    }
+    assert!(!c.is_disconnected());
+    assert!(c.poll_timeout().is_none());
+    let out = take_output(&mut c);
+    let hello = &out[7..];
+    let hello_len = message::total_message_size(hello).unwrap();
+    let m = Message::demarshal(&hello[hello_len..]).unwrap().unwrap();
+    assert_eq!(m.serial(), Some(s2));
+    assert_eq!(hello.len(), hello_len + message::total_message_size(&hello[hello_len..]).unwrap());
+}
+
+#[test]
+fn auth_rejected() {
+    let mut c = Connection::new(false);
+    c.receive_data(b"REJECTED EXTERNAL\r\n");
+    assert!(matches!(c.poll_event(), Some(Event::Disconnected(_))));
+    assert!(c.outgoing().is_none());
+}
@@ -0,0 +1,452 @@
+//! Conversion between D-Bus marshalling and the GVariant serialization format.
+//!
+//! GVariant is the format GLib uses for dconf databases, ostree metadata and GDBus peer-to-peer
+//! connections. It has the same type system as D-Bus, but different wire rules: booleans are
+//! one byte, variants are 8-byte aligned and store their signature after the value, and
+//! containers of variable-size values store framing offsets at their end instead of a length
+//! in front. The size of each framing offset (1, 2, 4 or 8 bytes) depends on the size of the
+//! container.
+//!
+//! Values are converted to and from the D-Bus representation: `GVariant` implements `Marshal`,
+//! so it can be appended to a `MultiBuf`, and `GVariantBuf::from_single` serializes a
+//! demarshalled `Single`.
+
+use std::ops::Range;
+use std::str::from_utf8;
+use std::convert::TryInto;
+use dbus_strings::{BasicType, Type, SignatureSingle, SignatureSingleBuf, SignatureMulti, StringLike, DBusStr, ObjectPath};
+use crate::marshalled::{Marshal, MultiBuf, Single, Parsed, UnixFd, align_buf, align_up, ARRAY_MAX_LEN, MAX_TOTAL_DEPTH};
+use crate::types::DemarshalError;
+
+fn alignment(t: &Type) -> usize {
+    match t {
+        Type::Basic(BasicType::Boolean) | Type::Basic(BasicType::String) | Type::Basic(BasicType::ObjectPath) => 1,
+        Type::Basic(b) => b.alignment(),
+        Type::Array(e) => alignment(e),
+        Type::Dict(k, v) => k.alignment().max(alignment(v)),
+        Type::Struct(fields) => fields.iter().map(alignment).max().unwrap_or(1),
+        Type::Variant => 8,
+    }
+}
+
+fn fixed_size(t: &Type) -> Option<usize> {
+    match t {
+        Type::Basic(BasicType::Boolean) => Some(1),
+        Type::Basic(b) => b.fixed_size(),
+        Type::Struct(fields) => {
+            let size = fields.iter().try_fold(0, |pos, f| Some(align_up(pos, alignment(f)) + fixed_size(f)?))?;
+            Some(align_up(size, alignment(t)))
+        }
+        Type::Array(_) | Type::Dict(_, _) | Type::Variant => None,
+    }
+}
+
+// A dict entry has the same serialization as a struct with two fields.
+fn dict_entry(k: BasicType, v: &Type) -> Type {
+    Type::Struct(vec!(Type::Basic(k), v.clone()))
+}
+
+// The size of the framing offsets in a container of `len` bytes.
+fn offset_size(len: usize) -> usize {
+    match len {
+        0 => 0,
+        0x1..=0xff => 1,
+        0x100..=0xffff => 2,
+        _ if len as u64 <= 0xffff_ffff => 4,
+        _ => 8,
+    }
+}
+
+// Determines the offset size for a container with `body` bytes of data and `n` framing offsets.
+fn offset_size_for(body: usize, n: usize) -> usize {
+    if n == 0 { return 0 }
+    [1, 2, 4].iter().copied().find(|&s| ((body + n * s) as u64) < 1u64 << (s * 8)).unwrap_or(8)
+}
+
+/// A borrowed, validated GVariant value of a single complete type.
+#[derive(Debug, Clone, Copy)]
+pub struct GVariant<'a> {
+    sig: &'a SignatureSingle,
+    data: &'a [u8],
+    is_big_endian: bool,
+}
+
+impl<'a> GVariant<'a> {
+    /// Checks that `data` is a valid GVariant serialization of a value of type `sig`.
+    ///
+    /// This is strict: values that the GVariant specification says should be read as the
+    /// default value of their type (e g because their framing offsets are out of range)
+    /// are rejected instead.
+    pub fn new(sig: &'a SignatureSingle, data: &'a [u8], is_big_endian: bool) -> Result<Self, DemarshalError> {
+        let r = GVariant { sig, data, is_big_endian };
+        // Converting is the only way to check everything, e g that the strings are valid.
+        r.convert(&sig.parse(), data, &mut vec!(), 0)?;
+        Ok(r)
+    }
+
+    pub fn data(&self) -> &'a [u8] { self.data }
+
+    pub fn is_big_endian(&self) -> bool { self.is_big_endian }
+
+    /// Converts this value to D-Bus marshalling.
+    ///
+    /// Use `MultiBuf::multi` on the result to get at the `Parsed` value.
+    pub fn to_multibuf(&self) -> MultiBuf {
+        let mut r = MultiBuf::new();
+        r.append(self).expect("a single complete type always fits");
+        r
+    }
+
+    fn read_uint(&self, d: &[u8]) -> u64 {
+        let mut x = [0; 8];
+        if self.is_big_endian {
+            x[8 - d.len()..].copy_from_slice(d);
+            u64::from_be_bytes(x)
+        } else {
+            x[..d.len()].copy_from_slice(d);
+            u64::from_le_bytes(x)
+        }
+    }
+
+    fn read_offset(&self, d: &[u8], idx_from_end: usize, osize: usize) -> Result<usize, DemarshalError> {
+        let start = d.len().checked_sub(idx_from_end * osize).ok_or(DemarshalError::InvalidFraming)?;
+        let x = self.read_uint(&d[start..start + osize]);
+        x.try_into().map_err(|_| DemarshalError::InvalidFraming)
+    }
+
+    fn array_frames(&self, elem: &Type, d: &[u8]) -> Result<Vec<Range<usize>>, DemarshalError> {
+        if let Some(size) = fixed_size(elem) {
+            let n = d.len() / size;
+            if n * size != d.len() { Err(DemarshalError::InvalidFraming)? }
+            return Ok((0..n).map(|i| i * size..(i + 1) * size).collect());
+        }
+        if d.is_empty() { return Ok(vec!()) }
+        let osize = offset_size(d.len());
+        let offsets_start = self.read_offset(d, 1, osize)?;
+        if offsets_start > d.len() { Err(DemarshalError::InvalidFraming)? }
+        let n = (d.len() - offsets_start) / osize;
+        if offsets_start + n * osize != d.len() { Err(DemarshalError::InvalidFraming)? }
+        let mut r = Vec::with_capacity(n);
+        let mut pos = 0;
+        for i in 0..n {
+            let start = align_up(pos, alignment(elem));
+            let end = self.read_offset(d, n - i, osize)?;
+            if start > end || end > offsets_start { Err(DemarshalError::InvalidFraming)? }
+            r.push(start..end);
+            pos = end;
+        }
+        Ok(r)
+    }
+
+    fn struct_frames(&self, fields: &[Type], d: &[u8]) -> Result<Vec<Range<usize>>, DemarshalError> {
+        let t = Type::Struct(fields.into());
+        if let Some(size) = fixed_size(&t) {
+            if d.len() != size { Err(DemarshalError::InvalidFraming)? }
+        }
+        let osize = offset_size(d.len());
+        let mut used = 0;
+        let mut pos = 0;
+        let mut r = Vec::with_capacity(fields.len());
+        for (i, f) in fields.iter().enumerate() {
+            let start = align_up(pos, alignment(f));
+            let end = if let Some(size) = fixed_size(f) { start + size }
+            else if i + 1 == fields.len() {
+                d.len().checked_sub(used * osize).ok_or(DemarshalError::InvalidFraming)?
+            } else {
+                used += 1;
+                self.read_offset(d, used, osize)?
+            };
+            if start > end || end + used * osize > d.len() { Err(DemarshalError::InvalidFraming)? }
+            r.push(start..end);
+            pos = end;
+        }
+        Ok(r)
+    }
+
+    fn convert_str(&self, b: BasicType, d: &[u8], v: &mut Vec<u8>) -> Result<(), DemarshalError> {
+        let (&last, s) = d.split_last().ok_or(DemarshalError::InvalidFraming)?;
+        if last != 0 || s.contains(&0) { Err(DemarshalError::InvalidNul)? }
+        let s = from_utf8(s).map_err(|_| DemarshalError::InvalidUtf8)?;
+        match b {
+            BasicType::String => DBusStr::new(s)?.append_data_to(v),
+            BasicType::ObjectPath => ObjectPath::new(s).map_err(|_| DemarshalError::InvalidObjectPath)?.append_data_to(v),
+            _ => SignatureMulti::new(s).map_err(|_| DemarshalError::InvalidSignature)?.append_data_to(v),
+        }
+        Ok(())
+    }
+
+    // Writes the D-Bus marshalling of the GVariant value in d, of type t, to v.
+    fn convert(&self, t: &Type, d: &[u8], v: &mut Vec<u8>, depth: u8) -> Result<(), DemarshalError> {
+        if let Type::Basic(b) = t {
+            let x = match fixed_size(t) {
+                Some(size) if d.len() != size => Err(DemarshalError::InvalidFraming)?,
+                Some(_) => self.read_uint(d),
+                None => 0,
+            };
+            match b {
+                BasicType::Byte => (x as u8).append_data_to(v),
+                BasicType::Boolean => {
+                    if x > 1 { Err(DemarshalError::InvalidBoolean)? }
+                    (x as u32).append_data_to(v)
+                }
+                BasicType::Int16 => (x as i16).append_data_to(v),
+                BasicType::UInt16 => (x as u16).append_data_to(v),
+                BasicType::Int32 => (x as i32).append_data_to(v),
+                BasicType::UInt32 => (x as u32).append_data_to(v),
+                BasicType::Int64 => (x as i64).append_data_to(v),
+                BasicType::UInt64 => x.append_data_to(v),
+                BasicType::Double => f64::from_bits(x).append_data_to(v),
+                BasicType::UnixFd => UnixFd(x as u32).append_data_to(v),
+                BasicType::String | BasicType::ObjectPath | BasicType::Signature => self.convert_str(*b, d, v)?,
+            }
+            return Ok(());
+        }
+        if depth >= MAX_TOTAL_DEPTH { Err(DemarshalError::NestingTooDeep)? }
+        match t {
+            Type::Variant => {
+                let zero = d.iter().rposition(|&b| b == 0).ok_or(DemarshalError::InvalidFraming)?;
+                let sig = from_utf8(&d[zero + 1..]).ok().and_then(|s| SignatureSingle::new(s).ok())
+                    .ok_or(DemarshalError::InvalidSignature)?;
+                sig.append_data_to(v);
+                self.convert(&sig.parse(), &d[..zero], v, depth + 1)?;
+            }
+            Type::Struct(fields) => {
+                align_buf(v, 8);
+                for (f, r) in fields.iter().zip(self.struct_frames(fields, d)?) {
+                    self.convert(f, &d[r], v, depth + 1)?;
+                }
+            }
+            Type::Array(_) | Type::Dict(_, _) => {
+                let elem = match t {
+                    Type::Dict(k, val) => dict_entry(*k, val),
+                    Type::Array(e) => (**e).clone(),
+                    _ => unreachable!(),
+                };
+                0u32.append_data_to(v);
+                let len_pos = v.len() - 4;
+                align_buf(v, if let Type::Struct(_) = elem { 8 } else { elem.alignment() });
+                let start = v.len();
+                for r in self.array_frames(&elem, d)? {
+                    self.convert(&elem, &d[r], v, depth + 1)?;
+                }
+                let len = v.len() - start;
+                if len > ARRAY_MAX_LEN { Err(DemarshalError::ArrayTooLong)? }
+                v[len_pos..len_pos + 4].copy_from_slice(&(len as u32).to_ne_bytes());
+            }
+            Type::Basic(_) => unreachable!(),
+        }
+        Ok(())
+    }
+}
+
+impl Marshal for GVariant<'_> {
+    fn signature(&self) -> &SignatureSingle { self.sig }
+    fn append_data_to(&self, v: &mut Vec<u8>) {
+        self.convert(&self.sig.parse(), self.data, v, 0).expect("GVariant data was validated in GVariant::new");
+    }
+}
+
+/// An owned GVariant value of a single complete type.
+#[derive(Debug, Clone)]
+pub struct GVariantBuf {
+    sig: SignatureSingleBuf,
+    data: Vec<u8>,
+    is_big_endian: bool,
+}
+
+impl GVariantBuf {
+    /// Serializes a D-Bus value in GVariant format.
+    pub fn new<T: Marshal + ?Sized>(value: &T, is_big_endian: bool) -> Result<Self, DemarshalError> {
+        let mut m = MultiBuf::new();
+        m.append(value)?;
+        let m = m.multi();
+        let s = m.iter().next().ok_or(DemarshalError::NotEnoughData)??;
+        Self::from_single(&s, is_big_endian)
+    }
+
+    /// Serializes a demarshalled D-Bus value in GVariant format.
+    pub fn from_single(value: &Single, is_big_endian: bool) -> Result<Self, DemarshalError> {
+        let mut w = Writer { out: vec!(), is_big_endian };
+        w.write(&value.signature().parse(), value)?;
+        Ok(GVariantBuf { sig: value.signature().into(), data: w.out, is_big_endian })
+    }
+
+    pub fn gvariant(&self) -> GVariant<'_> {
+        GVariant { sig: &self.sig, data: &self.data, is_big_endian: self.is_big_endian }
+    }
+
+    pub fn into_inner(self) -> (SignatureSingleBuf, Vec<u8>) {
+        (self.sig, self.data)
+    }
+}
+
+struct Writer {
+    out: Vec<u8>,
+    is_big_endian: bool,
+}
+
+impl Writer {
+    fn uint(&mut self, x: u64, size: usize) {
+        if self.is_big_endian {
+            self.out.extend_from_slice(&x.to_be_bytes()[8 - size..]);
+        } else {
+            self.out.extend_from_slice(&x.to_le_bytes()[..size]);
+        }
+    }
+
+    // Appends framing offsets (relative to start) to a container that started at start.
+    fn offsets<I: ExactSizeIterator<Item=usize>>(&mut self, start: usize, ends: I) {
+        let osize = offset_size_for(self.out.len() - start, ends.len());
+        for e in ends { self.uint(e as u64, osize) }
+    }
+
+    fn write_struct<'a, I>(&mut self, t: &Type, fields: &[Type], values: I) -> Result<(), DemarshalError>
+    where I: Iterator<Item=Result<Single<'a>, DemarshalError>> {
+        let start = self.out.len();
+        let mut ends = vec!();
+        let mut count = 0;
+        for (i, (f, value)) in fields.iter().zip(values).enumerate() {
+            self.write(f, &value?)?;
+            if fixed_size(f).is_none() && i + 1 < fields.len() { ends.push(self.out.len() - start) }
+            count += 1;
+        }
+        if count != fields.len() { Err(DemarshalError::NotEnoughData)? }
+        if fixed_size(t).is_some() {
+            align_buf(&mut self.out, alignment(t));
+        } else {
+            self.offsets(start, ends.into_iter().rev());
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, t: &Type, value: &Single) -> Result<(), DemarshalError> {
+        align_buf(&mut self.out, alignment(t));
+        match (t, value.parse()?) {
+            (_, Parsed::Byte(x)) => self.out.push(x),
+            (_, Parsed::Boolean(x)) => self.out.push(x as u8),
+            (_, Parsed::Int16(x)) => self.uint(x as u16 as u64, 2),
+            (_, Parsed::UInt16(x)) => self.uint(x as u64, 2),
+            (_, Parsed::Int32(x)) => self.uint(x as u32 as u64, 4),
+            (_, Parsed::UInt32(x)) => self.uint(x as u64, 4),
+            (_, Parsed::Int64(x)) => self.uint(x as u64, 8),
+            (_, Parsed::UInt64(x)) => self.uint(x, 8),
+            (_, Parsed::Double(x)) => self.uint(x.to_bits(), 8),
+            (_, Parsed::UnixFd(x)) => self.uint(x as u64, 4),
+            (_, p @ Parsed::String(_)) | (_, p @ Parsed::ObjectPath(_)) | (_, p @ Parsed::Signature(_)) => {
+                self.out.extend_from_slice(p.as_dbus_str()?.as_bytes());
+                self.out.push(0);
+            }
+            (_, Parsed::Variant(inner)) => {
+                self.write(&inner.signature().parse(), &inner)?;
+                self.out.push(0);
+                self.out.extend_from_slice(inner.signature().as_bytes());
+            }
+            (Type::Struct(fields), Parsed::Struct(m)) => self.write_struct(t, fields, m.iter())?,
+            (Type::Array(elem), Parsed::Array(a)) => {
+                let start = self.out.len();
+                let mut ends = vec!();
+                for x in a {
+                    self.write(elem, &x?)?;
+                    ends.push(self.out.len() - start);
+                }
+                if fixed_size(elem).is_none() { self.offsets(start, ends.into_iter()) }
+            }
+            (Type::Dict(k, v), Parsed::Dict(d)) => {
+                let entry = dict_entry(*k, v);
+                let fields = if let Type::Struct(f) = &entry { f } else { unreachable!() };
+                let start = self.out.len();
+                let mut ends = vec!();
+                for x in d {
+                    let (k, v) = x?;
+                    align_buf(&mut self.out, alignment(&entry));
+                    self.write_struct(&entry, fields, vec!(Ok(k), Ok(v)).into_iter())?;
+                    ends.push(self.out.len() - start);
+                }
+                if fixed_size(&entry).is_none() { self.offsets(start, ends.into_iter()) }
+            }
+            _ => Err(DemarshalError::WrongType)?,
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn spec_examples() {
+    use crate::marshalled::{ArrayBuf, DictBuf, StructBuf, VariantBuf};
+    let s = |x| DBusStr::new(x).unwrap();
+    let gv = |m: &dyn Marshal| GVariantBuf::new(m, false).unwrap().into_inner().1;
+
+    let mut st = MultiBuf::new();
+    st.append(s("foo")).unwrap();
+    st.append(&-1i32).unwrap();
+    let st = StructBuf::new(st).unwrap();
+    assert_eq!(gv(&st), b"foo\0\xff\xff\xff\xff\x04");
+
+    let mut a = ArrayBuf::new(SignatureSingle::new("s").unwrap()).unwrap();
+    for x in &["i", "can", "has", "strings?"] { a.append(s(x)).unwrap() }
+    assert_eq!(gv(&a), b"i\0can\0has\0strings?\0\x02\x06\x0a\x13");
+
+    let mut a = ArrayBuf::new(SignatureSingle::new("(si)").unwrap()).unwrap();
+    for &(x, y) in &[("hi", -2i32), ("bye", -1)] {
+        let mut m = MultiBuf::new();
+        m.append(s(x)).unwrap();
+        m.append(&y).unwrap();
+        a.append(&StructBuf::new(m).unwrap()).unwrap();
+    }
+    assert_eq!(gv(&a), b"hi\0\0\xfe\xff\xff\xff\x03\0\0\0bye\0\xff\xff\xff\xff\x04\x09\x15");
+
+    assert_eq!(gv(&VariantBuf::new(s("hi")).unwrap()), b"hi\0\0s");
+    assert_eq!(gv(&VariantBuf::new(&5u16).unwrap()), b"\x05\0\0q");
+
+    let mut d = DictBuf::new(SignatureSingle::new_owned("s").unwrap(), SignatureSingle::new_owned("i").unwrap()).unwrap();
+    d.append(s("a"), &1i32).unwrap();
+    assert_eq!(gv(&d), b"a\0\0\0\x01\0\0\0\x02\x09");
+
+    let mut a = ArrayBuf::new(SignatureSingle::new("t").unwrap()).unwrap();
+    a.append(&0x0102u64).unwrap();
+    assert_eq!(GVariantBuf::new(&a, true).unwrap().into_inner().1, b"\0\0\0\0\0\0\x01\x02");
+}
+
+#[test]
+fn roundtrip() {
+    use crate::marshalled::{ArrayBuf, DictBuf, StructBuf, VariantBuf};
+    let mut d = DictBuf::new(SignatureSingle::new_owned("s").unwrap(), SignatureSingle::new_owned("v").unwrap()).unwrap();
+    d.append(DBusStr::new("x").unwrap(), &VariantBuf::new(&7u64).unwrap()).unwrap();
+    d.append(DBusStr::new("long").unwrap(), &VariantBuf::new(DBusStr::new(&"y".repeat(300)).unwrap()).unwrap()).unwrap();
+    let mut inner = MultiBuf::new();
+    inner.append(&3u8).unwrap();
+    inner.append(&d).unwrap();
+    inner.append(ObjectPath::new("/a/b").unwrap()).unwrap();
+    inner.append(&ArrayBuf::new(SignatureSingle::new("q").unwrap()).unwrap()).unwrap();
+    let st = StructBuf::new(inner).unwrap();
+
+    let mut orig = MultiBuf::new();
+    orig.append(&st).unwrap();
+    for &be in &[false, true] {
+        let g = GVariantBuf::new(&st, be).unwrap();
+        let back = GVariant::new(st.signature(), g.gvariant().data(), be).unwrap().to_multibuf();
+        assert_eq!(back.into_inner(), orig.clone().into_inner());
+    }
+
+    let g = GVariant::new(SignatureSingle::new("ab").unwrap(), b"\x01\x00", false).unwrap();
+    let m = g.to_multibuf();
+    let s = m.multi().iter().next().unwrap().unwrap();
+    assert_eq!(GVariantBuf::from_single(&s, false).unwrap().into_inner().1, b"\x01\x00");
+    let mut a = match s.parse().unwrap() { Parsed::Array(a) => a, _ => panic!() };
+    assert!(matches!(a.next().unwrap().unwrap().parse().unwrap(), Parsed::Boolean(true)));
+}
+
+#[test]
+fn invalid() {
+    let sig = |s| SignatureSingle::new(s).unwrap();
+    assert!(GVariant::new(sig("s"), b"abc", false).is_err());
+    assert!(GVariant::new(sig("s"), b"a\0c\0", false).is_err());
+    assert!(GVariant::new(sig("u"), b"\0\0\0", false).is_err());
+    assert!(GVariant::new(sig("b"), b"\x02", false).is_err());
+    assert!(GVariant::new(sig("as"), b"a\0\x05", false).is_err());
+    assert!(GVariant::new(sig("v"), b"\x05\0\0(", false).is_err());
+    assert!(GVariant::new(sig("(yy)"), b"\x05", false).is_err());
+    assert!(GVariant::new(sig("o"), b"a\0", false).is_err());
+    assert!(GVariant::new(sig("as"), b"", false).is_ok());
+    assert!(GVariant::new(sig("v"), &b"\0\0\0\0\0\0\0\0".iter().chain(&[b'v'; 70]).copied().collect::<Vec<_>>(), false).is_err());
+}
@@ -5,7 +5,7 @@
 ///
 /// No stability guarantees for this crate.
 
-pub use dbus_native_channel::{machineid, address, authentication};
+pub use dbus_native_channel::{machineid, address, authentication, unixfd};
 
 pub mod message;
 
@@ -13,6 +13,10 @@ pub mod types;
 
 pub mod marshalled;
 
+pub mod gvariant;
+
+pub mod connection;
+
 pub mod strings {
     //! Re-export of the dbus_strings crate
     pub use dbus_strings::*;
@@ -5,7 +5,7 @@ const IS_BIG_ENDIAN: bool = false;
 #[cfg(target_endian="big")]
 const IS_BIG_ENDIAN: bool = true;
 
-const ARRAY_MAX_LEN: usize = 67108864;
+pub(crate) const ARRAY_MAX_LEN: usize = 67108864;
 
 use std::str::from_utf8;
 use std::mem;
@@ -93,22 +93,22 @@ pub fn align_of(c: u8) -> usize {
 
 impl<'a> Single<'a> {
     fn read_f64(&self) -> Result<f64, DemarshalError> {
-        let x: [u8; 8] = self.data[0..8].try_into().map_err(|_| DemarshalError::NotEnoughData)?;
+        let x: [u8; 8] = self.data.get(0..8).and_then(|x| x.try_into().ok()).ok_or(DemarshalError::NotEnoughData)?;
         Ok(if self.is_big_endian { f64::from_be_bytes(x) } else { f64::from_le_bytes(x) })
     }
 
     fn read8(&self) -> Result<u64, DemarshalError> {
-        let x: [u8; 8] = self.data[0..8].try_into().map_err(|_| DemarshalError::NotEnoughData)?;
+        let x: [u8; 8] = self.data.get(0..8).and_then(|x| x.try_into().ok()).ok_or(DemarshalError::NotEnoughData)?;
         Ok(if self.is_big_endian { u64::from_be_bytes(x) } else { u64::from_le_bytes(x) })
     }
 
     fn read4(&self) -> Result<u32, DemarshalError> {
-        let x: [u8; 4] = self.data[0..4].try_into().map_err(|_| DemarshalError::NotEnoughData)?;
+        let x: [u8; 4] = self.data.get(0..4).and_then(|x| x.try_into().ok()).ok_or(DemarshalError::NotEnoughData)?;
         Ok(if self.is_big_endian { u32::from_be_bytes(x) } else { u32::from_le_bytes(x) })
     }
 
     fn read2(&self) -> Result<u16, DemarshalError> {
-        let x: [u8; 2] = self.data[0..1].try_into().map_err(|_| DemarshalError::NotEnoughData)?;
+        let x: [u8; 2] = self.data.get(0..2).and_then(|x| x.try_into().ok()).ok_or(DemarshalError::NotEnoughData)?;
         Ok(if self.is_big_endian { u16::from_be_bytes(x) } else { u16::from_le_bytes(x) })
     }
 
@@ -166,7 +166,9 @@ impl<'a> Single<'a> {
             b'a' => {
                 let x = self.read4()? as usize;
                 if x > 67108864 { Err(DemarshalError::NumberTooBig)? };
-                x + 4
+                // Padding between the length and the first element is not included in x.
+                let elem_align = align_of(self.sig.as_bytes()[1]);
+                align_up(self.start_pos + 4, elem_align) - self.start_pos + x
             },
             b'v' => {
                 let x = self.inner_variant()?;
@@ -234,6 +236,8 @@ impl<'a> Single<'a> {
     pub fn new(sig: &'a SignatureSingle, data: &'a [u8], start_pos: usize, is_big_endian: bool) -> Self {
         Single { sig, data, start_pos, is_big_endian }
     }
+
+    pub fn signature(&self) -> &'a SignatureSingle { self.sig }
 }
 
 /// Contains multiple values of the same type.
@@ -260,8 +264,9 @@ impl<'a> Iterator for Array<'a> {
             _ => return Some(Err(DemarshalError::NotEnoughData)),
         };
         s.data = &s.data[0..len];
-        if len < s.data.len() {
+        if len < self.data.len() {
             len = align_up(len + self.start_pos, align_of(self.inner_sig.as_bytes()[0])) - self.start_pos;
+            if len > self.data.len() { return Some(Err(DemarshalError::ArrayLengthMismatch)) }
             self.start_pos += len;
             self.data = &self.data[len..];
         } else {
@@ -287,7 +292,9 @@ impl<'a> Iterator for Dict<'a> {
             (Some(Ok(k)), Some(Ok(v))) => {
                 let len = self.data.len() - mi.inner.data.len();
                 if len < self.data.len() {
-                    self.data = &self.data[align_up(len, 8)..];
+                    let len = align_up(len, 8);
+                    if len > self.data.len() { return Some(Err(DemarshalError::ArrayLengthMismatch)) }
+                    self.data = &self.data[len..];
                 } else {
                     self.data = &[];
                 }
@@ -505,7 +512,7 @@ pub enum Parsed<'a> {
     /// D-Bus allows for sending file descriptors, which can be used to
     /// set up SHM, unix pipes, or other communication channels.
     ///
-    /// The usize is an index that can need to be used with the message to get the actual file descriptor out.
+    /// The usize is an index into the message's list of file descriptors, see `Message::unix_fds`.
     UnixFd(usize),
 }
 
@@ -603,6 +610,20 @@ marshal_impl!(i32, "i", 4);
 marshal_impl!(i64, "x", 8);
 marshal_impl!(f64, "d", 8);
 
+/// A D-Bus unix file descriptor, represented as an index into the message's
+/// list of file descriptors.
+///
+/// Get one by calling `Message::add_unix_fd`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UnixFd(pub u32);
+
+impl Marshal for UnixFd {
+    fn signature(&self) -> &SignatureSingle { SignatureSingle::new_unchecked("h") }
+    fn append_data_to(&self, v: &mut Vec<u8>) {
+        self.0.append_data_to(v);
+    }
+}
+
 impl Marshal for DBusStr {
     fn signature(&self) -> &SignatureSingle { SignatureSingle::new_unchecked("s") }
     fn append_data_to(&self, v: &mut Vec<u8>) {
@@ -637,3 +658,198 @@ impl Marshal for SignatureSingle {
         v.push(0);
     }
 }
+
+// Variants, arrays, structs and dict entries all count towards this limit.
+pub(crate) const MAX_TOTAL_DEPTH: u8 = 64;
+// Arrays, and structs (including dict entries), are also limited separately.
+const MAX_ARRAY_DEPTH: u8 = 32;
+const MAX_STRUCT_DEPTH: u8 = 32;
+
+/// Container nesting at some point in the data.
+#[derive(Debug, Clone, Copy, Default)]
+struct Depth {
+    arrays: u8,
+    structs: u8,
+    total: u8,
+}
+
+impl Depth {
+    fn enter(self, arrays: u8, structs: u8) -> Result<Depth, DemarshalError> {
+        let d = Depth { arrays: self.arrays + arrays, structs: self.structs + structs, total: self.total + 1 };
+        if d.arrays > MAX_ARRAY_DEPTH || d.structs > MAX_STRUCT_DEPTH || d.total > MAX_TOTAL_DEPTH {
+            Err(DemarshalError::NestingTooDeep)?
+        }
+        Ok(d)
+    }
+
+    fn variant(self) -> Result<Depth, DemarshalError> { self.enter(0, 0) }
+    fn array(self) -> Result<Depth, DemarshalError> { self.enter(1, 0) }
+    fn structure(self) -> Result<Depth, DemarshalError> { self.enter(0, 1) }
+}
+
+const BASIC_FIXED: &[(u8, usize)] = &[(b'y', 1), (b'n', 2), (b'q', 2), (b'i', 4), (b'u', 4), (b'x', 8), (b't', 8), (b'd', 8)];
+
+// Returns the length of the first complete type in an already validated signature.
+fn sig_single_len(sig: &[u8]) -> usize {
+    match sig[0] {
+        b'a' => 1 + sig_single_len(&sig[1..]),
+        b'(' | b'{' => {
+            let mut pos = 1;
+            while sig[pos] != b')' && sig[pos] != b'}' { pos += sig_single_len(&sig[pos..]); }
+            pos + 1
+        }
+        _ => 1,
+    }
+}
+
+/// Strictly checks marshalled data against its signature, according to the D-Bus specification.
+///
+/// Positions are relative to the start of data, which must be 8-byte aligned in the message.
+struct Validator<'a> {
+    data: &'a [u8],
+    is_big_endian: bool,
+    unix_fds: usize,
+}
+
+impl<'a> Validator<'a> {
+    fn overrun(&self, end: usize) -> DemarshalError {
+        if end < self.data.len() { DemarshalError::ArrayLengthMismatch } else { DemarshalError::NotEnoughData }
+    }
+
+    fn pad(&self, pos: usize, align: usize, end: usize) -> Result<usize, DemarshalError> {
+        let p = align_up(pos, align);
+        if p > end { Err(self.overrun(end))? }
+        if self.data[pos..p].iter().any(|&b| b != 0) { Err(DemarshalError::NonZeroPadding)? }
+        Ok(p)
+    }
+
+    fn fixed(&self, pos: usize, size: usize, end: usize) -> Result<(&'a [u8], usize), DemarshalError> {
+        let p = self.pad(pos, size, end)?;
+        if p + size > end { Err(self.overrun(end))? }
+        Ok((&self.data[p..p+size], p + size))
+    }
+
+    fn u32(&self, pos: usize, end: usize) -> Result<(u32, usize), DemarshalError> {
+        let (x, p) = self.fixed(pos, 4, end)?;
+        let x: [u8; 4] = x.try_into().unwrap();
+        Ok((if self.is_big_endian { u32::from_be_bytes(x) } else { u32::from_le_bytes(x) }, p))
+    }
+
+    // Checks string data of len bytes followed by a nul byte.
+    fn str_data(&self, pos: usize, len: usize, end: usize) -> Result<(&'a str, usize), DemarshalError> {
+        if pos + len + 1 > end { Err(self.overrun(end))? }
+        let s = &self.data[pos..pos+len];
+        if self.data[pos+len] != 0 || s.contains(&0) { Err(DemarshalError::InvalidNul)? }
+        let s = from_utf8(s).map_err(|_| DemarshalError::InvalidUtf8)?;
+        Ok((s, pos + len + 1))
+    }
+
+    fn sig_data(&self, pos: usize, end: usize) -> Result<(&'a str, usize), DemarshalError> {
+        if pos >= end { Err(self.overrun(end))? }
+        self.str_data(pos + 1, self.data[pos] as usize, end)
+    }
+
+    fn multi(&self, sig: &[u8], mut pos: usize, end: usize, depth: Depth) -> Result<usize, DemarshalError> {
+        let mut s = 0;
+        while s < sig.len() {
+            let (slen, p) = self.single(&sig[s..], pos, end, depth)?;
+            s += slen;
+            pos = p;
+        }
+        Ok(pos)
+    }
+
+    // Returns the number of signature bytes consumed, and the position after the value.
+    fn single(&self, sig: &[u8], pos: usize, end: usize, depth: Depth) -> Result<(usize, usize), DemarshalError> {
+        let c = sig[0];
+        if let Some(&(_, size)) = BASIC_FIXED.iter().find(|x| x.0 == c) {
+            return Ok((1, self.fixed(pos, size, end)?.1));
+        }
+        Ok(match c {
+            b'b' => {
+                let (x, p) = self.u32(pos, end)?;
+                if x > 1 { Err(DemarshalError::InvalidBoolean)? }
+                (1, p)
+            }
+            b'h' => {
+                let (x, p) = self.u32(pos, end)?;
+                if x as usize >= self.unix_fds { Err(DemarshalError::UnixFdOutOfRange)? }
+                (1, p)
+            }
+            b's' | b'o' => {
+                let (len, p) = self.u32(pos, end)?;
+                let (s, p) = self.str_data(p, len as usize, end)?;
+                if c == b'o' && dbus_strings::ObjectPath::is_valid(s).is_err() { Err(DemarshalError::InvalidObjectPath)? }
+                (1, p)
+            }
+            b'g' => {
+                let (s, p) = self.sig_data(pos, end)?;
+                if SignatureMulti::is_valid(s).is_err() { Err(DemarshalError::InvalidSignature)? }
+                (1, p)
+            }
+            b'v' => {
+                let depth = depth.variant()?;
+                let (s, p) = self.sig_data(pos, end)?;
+                if SignatureSingle::is_valid(s).is_err() { Err(DemarshalError::InvalidSignature)? }
+                (1, self.single(s.as_bytes(), p, end, depth)?.1)
+            }
+            b'(' => {
+                let depth = depth.structure()?;
+                let slen = sig_single_len(sig);
+                let p = self.pad(pos, 8, end)?;
+                (slen, self.multi(&sig[1..slen-1], p, end, depth)?)
+            }
+            b'a' => {
+                let depth = depth.array()?;
+                let elem_sig = &sig[1..];
+                let elen = sig_single_len(elem_sig);
+                let (len, p) = self.u32(pos, end)?;
+                let len = len as usize;
+                if len > ARRAY_MAX_LEN { Err(DemarshalError::ArrayTooLong)? }
+                // Padding to the first element is there even if the array is empty.
+                let p = self.pad(p, align_of(elem_sig[0]), end)?;
+                let arr_end = p + len;
+                if arr_end > end { Err(self.overrun(end))? }
+                if let Some(&(_, size)) = BASIC_FIXED.iter().find(|x| x.0 == elem_sig[0]) {
+                    // Fast path: no need to check every element (sizes are powers of two)
+                    if len & (size - 1) != 0 { Err(DemarshalError::ArrayLengthMismatch)? }
+                } else {
+                    let mut q = p;
+                    while q < arr_end {
+                        q = if elem_sig[0] == b'{' {
+                            let depth = depth.structure()?;
+                            let q = self.pad(q, 8, arr_end)?;
+                            self.multi(&elem_sig[1..elen-1], q, arr_end, depth)?
+                        } else {
+                            self.single(elem_sig, q, arr_end, depth)?.1
+                        };
+                    }
+                }
+                (1 + elen, arr_end)
+            }
+            _ => Err(DemarshalError::InvalidSignature)?,
+        })
+    }
+}
+
+/// Strictly validates data against a signature, and returns the position after the last value.
+///
+/// Data must start at an 8-byte aligned position in the message, and validation starts at `pos`.
+/// The `unix_fds` parameter is the number of file descriptors sent with the message.
+pub(crate) fn validate(sig: &SignatureMulti, data: &[u8], pos: usize, is_big_endian: bool, unix_fds: usize) -> Result<usize, DemarshalError> {
+    let v = Validator { data, is_big_endian, unix_fds };
+    v.multi(sig.as_bytes(), pos, data.len(), Depth::default())
+}
+
+impl Multi<'_> {
+    /// Strictly checks that the data is valid according to the signature and the D-Bus specification.
+    ///
+    /// This checks string encodings, padding, nesting depth, array lengths and so on, and
+    /// also that there is no data left after the last value.
+    /// The `unix_fds` parameter is the number of file descriptors sent with the message.
+    pub fn validate(&self, unix_fds: usize) -> Result<(), DemarshalError> {
+        let pos = validate(self.sig, self.data, 0, self.is_big_endian, unix_fds)?;
+        if pos != self.data.len() { Err(DemarshalError::BodySignatureMismatch)? }
+        Ok(())
+    }
+}
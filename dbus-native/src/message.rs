@@ -5,13 +5,22 @@ use crate::types::{Marshal, DemarshalError};
 use std::convert::TryInto;
 use std::num::NonZeroU32;
 use std::io;
-use crate::marshalled::{Multi, MultiBuf, DictBuf, VariantBuf, Parsed, Single};
+use std::collections::VecDeque;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use crate::marshalled::{Multi, MultiBuf, DictBuf, VariantBuf, Parsed, Single, UnixFd};
 
 const FIXED_HEADER_SIZE: usize = 16;
 
+// The Linux kernel does not allow more than this number of fds in a single sendmsg call.
+const MAX_UNIX_FDS: usize = 253;
+
+const MAX_MESSAGE_SIZE: usize = 134217728;
+const MAX_ARRAY_SIZE: usize = 67108864;
+
 const METHOD_CALL: u8 = 1;
-const METHOD_RETURN: u8 = 2;
-const ERROR: u8 = 3;
+pub(crate) const METHOD_RETURN: u8 = 2;
+pub(crate) const ERROR: u8 = 3;
 const SIGNAL: u8 = 4;
 
 #[cfg(target_endian = "little")]
@@ -19,7 +28,7 @@ const ENDIAN: u8 = b'l';
 #[cfg(target_endian = "big")]
 const ENDIAN: u8 = b'B';
 
-#[derive(Clone, Debug)]
+#[derive(Debug, Clone)]
 pub struct Message<'a> {
     msg_type: u8,
     flags: u8,
@@ -32,7 +41,8 @@ pub struct Message<'a> {
     destination: Option<Cow<'a, strings::BusName>>,
     sender: Option<Cow<'a, strings::BusName>>,
     signature: Option<Cow<'a, strings::SignatureMulti>>,
-//    unix_fds: Option<u32>,
+    // Shared with clones of this message
+    unix_fds: Vec<Arc<OwnedFd>>,
     body: Cow<'a, [u8]>,
     is_big_endian: bool,
 }
@@ -51,7 +61,7 @@ impl<'a> Message<'a> {
             destination: None,
             sender: None,
             signature: None,
-//            unix_fds: None,
+            unix_fds: vec!(),
             body: Cow::Borrowed(&[]),
             #[cfg(target_endian = "little")]
             is_big_endian: false,
@@ -61,7 +71,48 @@ impl<'a> Message<'a> {
     }
 
     pub fn into_owned(self) -> Message<'static> {
-        todo!()
+        fn owned<T: ?Sized + ToOwned>(x: Option<Cow<'_, T>>) -> Option<Cow<'static, T>> {
+            x.map(|x| Cow::Owned(x.into_owned()))
+        }
+        Message {
+            msg_type: self.msg_type,
+            flags: self.flags,
+            serial: self.serial,
+            path: owned(self.path),
+            interface: owned(self.interface),
+            member: owned(self.member),
+            error_name: owned(self.error_name),
+            reply_serial: self.reply_serial,
+            destination: owned(self.destination),
+            sender: owned(self.sender),
+            signature: owned(self.signature),
+            unix_fds: self.unix_fds,
+            body: Cow::Owned(self.body.into_owned()),
+            is_big_endian: self.is_big_endian,
+        }
+    }
+
+    /// Creates a replica of this message, duplicating its file descriptors.
+    ///
+    /// Unlike `clone`, which shares the file descriptors with the original message,
+    /// this gives the replica file descriptors of its own. May fail if out of file descriptors.
+    pub fn duplicate(&self) -> io::Result<Self> {
+        Ok(Message {
+            msg_type: self.msg_type,
+            flags: self.flags,
+            serial: self.serial,
+            path: self.path.clone(),
+            interface: self.interface.clone(),
+            member: self.member.clone(),
+            error_name: self.error_name.clone(),
+            reply_serial: self.reply_serial,
+            destination: self.destination.clone(),
+            sender: self.sender.clone(),
+            signature: self.signature.clone(),
+            unix_fds: self.unix_fds.iter().map(|fd| fd.try_clone().map(Arc::new)).collect::<io::Result<_>>()?,
+            body: self.body.clone(),
+            is_big_endian: self.is_big_endian,
+        })
     }
 
     pub fn msg_type(&self) -> u8 { self.msg_type }
@@ -141,6 +192,23 @@ impl<'a> Message<'a> {
 
     pub fn flags(&self) -> u8 { self.flags }
 
+    /// The file descriptors attached to this message.
+    ///
+    /// A "h" value in the body is an index into this list. Clones of this message share the
+    /// file descriptors, so they are closed when the last message referring to them is dropped.
+    pub fn unix_fds(&self) -> &[Arc<OwnedFd>] { &self.unix_fds }
+
+    /// Attaches a file descriptor to this message.
+    ///
+    /// The returned value should be appended to the body to refer to the file descriptor.
+    pub fn add_unix_fd(&mut self, fd: OwnedFd) -> UnixFd {
+        self.unix_fds.push(Arc::new(fd));
+        UnixFd((self.unix_fds.len() - 1) as u32)
+    }
+
+    /// Removes all file descriptors from this message, and returns them.
+    pub fn take_unix_fds(&mut self) -> Vec<Arc<OwnedFd>> { std::mem::take(&mut self.unix_fds) }
+
     pub fn write_header<B: io::Write + io::Seek>(&self, serial: std::num::NonZeroU32, buf: &mut B) -> io::Result<()> {
 
         fn add_header_field<B, Z, Y: Marshal, F>(b: &mut types::MarshalState<B>, header_type: u8, field: Option<Z>, f: F) -> io::Result<()>
@@ -155,6 +223,8 @@ impl<'a> Message<'a> {
         let mut b = types::MarshalState::new(buf);
         let body_len = self.body.len();
         if body_len >= 134217728 { Err(io::ErrorKind::InvalidData)? }
+        if self.unix_fds.len() > MAX_UNIX_FDS { Err(io::ErrorKind::InvalidData)? }
+        let unix_fds = if self.unix_fds.is_empty() { None } else { Some(self.unix_fds.len() as u32) };
 
         b.write_single(&[ENDIAN, self.msg_type, self.flags, 1])?;
         b.write_fixed(4, &(body_len as u32).to_ne_bytes())?;
@@ -168,6 +238,7 @@ impl<'a> Message<'a> {
             add_header_field(b, 6, self.destination.as_ref(), |x| x.as_dbus_str())?;
             add_header_field(b, 7, self.sender.as_ref(), |x| x.as_dbus_str())?;
             add_header_field(b, 8, self.signature.as_ref(), |x| &**x)?;
+            add_header_field(b, 9, unix_fds, |x| x)?;
             Ok(())
         })?;
         b.write_single(b.align_buf(8))?;
@@ -186,6 +257,8 @@ impl<'a> Message<'a> {
 
         let body_len = self.body.len();
         if body_len >= 134217728 { Err(types::DemarshalError::NumberTooBig)? }
+        if self.unix_fds.len() > MAX_UNIX_FDS { Err(types::DemarshalError::NumberTooBig)? }
+        let unix_fds = if self.unix_fds.is_empty() { None } else { Some(self.unix_fds.len() as u32) };
         let mut buf = Vec::with_capacity(256);
         buf.extend_from_slice(&[ENDIAN, self.msg_type, self.flags, 1]);
         buf.extend_from_slice(&(body_len as u32).to_ne_bytes());
@@ -200,6 +273,7 @@ impl<'a> Message<'a> {
         add_header_field(&mut arr, 6, self.destination.as_ref(), |x| VariantBuf::new(x.as_dbus_str()).unwrap());
         add_header_field(&mut arr, 7, self.sender.as_ref(), |x| VariantBuf::new(x.as_dbus_str()).unwrap());
         add_header_field(&mut arr, 8, self.signature.as_ref(), |x| VariantBuf::new(&**x).unwrap());
+        add_header_field(&mut arr, 9, unix_fds, |x| VariantBuf::new(&x).unwrap());
         crate::marshalled::Marshal::append_data_to(&arr, &mut buf);
         crate::marshalled::align_buf(&mut buf, 8);
         if !header_only {
@@ -213,57 +287,109 @@ impl<'a> Message<'a> {
     pub fn is_big_endian(&self) -> bool { self.is_big_endian }
 
     // Should disconnect on error. If Ok(None) is returned, its a message that should be ignored.
+    //
+    // A message that came with file descriptors is returned without them: its "h" values then
+    // refer to file descriptors that are not in unix_fds. Use demarshal_with_fds to keep them.
     pub fn demarshal(buf: &'a [u8]) -> Result<Option<Self>, types::DemarshalError> {
+        Self::demarshal_header(buf, true).map(|x| x.0)
+    }
+
+    /// Like demarshal, but also attaches the file descriptors received together with the message.
+    ///
+    /// The number of file descriptors must match the UNIX_FDS header field. If the message is
+    /// to be ignored, the file descriptors are closed.
+    pub fn demarshal_with_fds(buf: &'a [u8], fds: Vec<Arc<OwnedFd>>) -> Result<Option<Self>, types::DemarshalError> {
+        let (m, unix_fds) = Self::demarshal_header(buf, true)?;
+        if unix_fds as usize != fds.len() { Err(DemarshalError::WrongUnixFdCount)? }
+        Ok(m.map(|mut m| { m.unix_fds = fds; m }))
+    }
+
+    fn demarshal_header(buf: &'a [u8], validate_body: bool) -> Result<(Option<Self>, u32), types::DemarshalError> {
         let start = message_start_parse(buf)?;
         if buf.len() < start.total_size { Err(DemarshalError::NotEnoughData)? }
         let msg_type = buf[1];
-        if msg_type < 1 || msg_type > 4 { return Ok(None) };
         let mut m = Self::new_internal(msg_type);
+        let mut unix_fds = 0;
         m.is_big_endian = start.is_big_endian;
         m.flags = buf[2] & 0x7;
         m.serial = Some(start.serial);
         m.body = Cow::Borrowed(&buf[start.body_start..start.total_size]);
 
         use strings::StringLike;
+        let header_end = crate::marshalled::validate(strings::SignatureMulti::new_unchecked("a(yv)"),
+            &buf[..start.body_start], 12, m.is_big_endian, 0)?;
+        if buf[header_end..start.body_start].iter().any(|&b| b != 0) { Err(DemarshalError::NonZeroPadding)? }
+
         let dictsig = strings::SignatureSingle::new_unchecked("a{yv}");
         let single = Single::new(dictsig, &buf[12..start.body_start], 12, m.is_big_endian);
         let parsed = single.parse()?;
         let dict = if let Parsed::Dict(dict) = parsed { dict } else { Err(DemarshalError::InvalidProtocol)? };
+        let mut seen = 0u32;
         for entry in dict {
             let (key, value) = entry?;
             let (key, value) = (key.parse()?, value.parse()?);
             let key = if let Parsed::Byte(key) = key { key } else { Err(DemarshalError::InvalidProtocol)? };
             let value = if let Parsed::Variant(value) = value { value } else { Err(DemarshalError::InvalidProtocol)? };
             let value = value.parse()?;
+            if (1..=9).contains(&key) {
+                if seen & (1 << key) != 0 { Err(DemarshalError::DuplicateHeaderField(key))? }
+                seen |= 1 << key;
+            }
+            let wrong_type = DemarshalError::WrongHeaderFieldType(key);
             match key {
                 1 => if let Parsed::ObjectPath(x) = value {
+                    if &**x == "/org/freedesktop/DBus/Local" { Err(DemarshalError::ReservedLocalName)? }
                     m.path = Some(Cow::Borrowed(x))
-                } else { Err(DemarshalError::WrongType)? },
+                } else { Err(wrong_type)? },
                 2 => if let Parsed::String(x) = value {
-                    m.interface = Some(Cow::Borrowed(x.try_into()?))
-                } else { Err(DemarshalError::WrongType)? },
+                    if &**x == "org.freedesktop.DBus.Local" { Err(DemarshalError::ReservedLocalName)? }
+                    m.interface = Some(Cow::Borrowed(x.try_into().map_err(|_| DemarshalError::InvalidInterfaceName)?))
+                } else { Err(wrong_type)? },
                 3 => if let Parsed::String(x) = value {
-                    m.member = Some(Cow::Borrowed(x.try_into()?))
-                } else { Err(DemarshalError::WrongType)? },
+                    m.member = Some(Cow::Borrowed(x.try_into().map_err(|_| DemarshalError::InvalidMemberName)?))
+                } else { Err(wrong_type)? },
                 4 => if let Parsed::String(x) = value {
-                    m.error_name = Some(Cow::Borrowed(x.try_into()?))
-                } else { Err(DemarshalError::WrongType)? },
+                    m.error_name = Some(Cow::Borrowed(x.try_into().map_err(|_| DemarshalError::InvalidErrorName)?))
+                } else { Err(wrong_type)? },
                 5 => if let Parsed::UInt32(x) = value {
-                    m.reply_serial = NonZeroU32::new(x)
-                } else { Err(DemarshalError::WrongType)? }
+                    m.reply_serial = Some(NonZeroU32::new(x).ok_or(DemarshalError::ZeroSerial)?)
+                } else { Err(wrong_type)? }
                 6 => if let Parsed::String(x) = value {
-                    m.destination = Some(Cow::Borrowed(x.try_into()?))
-                } else { Err(DemarshalError::WrongType)? },
+                    m.destination = Some(Cow::Borrowed(x.try_into().map_err(|_| DemarshalError::InvalidBusName)?))
+                } else { Err(wrong_type)? },
                 7 => if let Parsed::String(x) = value {
-                    m.sender = Some(Cow::Borrowed(x.try_into()?))
-                } else { Err(DemarshalError::WrongType)? },
+                    m.sender = Some(Cow::Borrowed(x.try_into().map_err(|_| DemarshalError::InvalidBusName)?))
+                } else { Err(wrong_type)? },
                 8 => if let Parsed::Signature(x) = value {
                     m.signature = Some(Cow::Borrowed(x))
-                } else { Err(DemarshalError::WrongType)? }
+                } else { Err(wrong_type)? }
+                9 => if let Parsed::UInt32(x) = value {
+                    if x as usize > MAX_UNIX_FDS { Err(DemarshalError::TooManyUnixFds)? }
+                    unix_fds = x
+                } else { Err(wrong_type)? }
                 _ => {},
             }
         }
-        Ok(Some(m))
+
+        let required: &[u8] = match msg_type {
+            METHOD_CALL => &[1, 3],
+            METHOD_RETURN => &[5],
+            ERROR => &[4, 5],
+            SIGNAL => &[1, 2, 3],
+            _ => return Ok((None, unix_fds)),
+        };
+        if let Some(&key) = required.iter().find(|&&key| seen & (1 << key) == 0) {
+            Err(DemarshalError::MissingHeaderField(key))?
+        }
+
+        if validate_body {
+            // The entire message is here, so running out of data means the body is too short.
+            m.read_body().validate(unix_fds as usize).map_err(|e| match e {
+                DemarshalError::NotEnoughData => DemarshalError::BodySignatureMismatch,
+                e => e,
+            })?;
+        }
+        Ok((Some(m), unix_fds))
     }
 
     pub fn read_body<'b>(&'b self) -> Multi<'b> {
@@ -297,22 +423,21 @@ struct MsgStart {
 
 fn message_start_parse(buf: &[u8]) -> Result<MsgStart, DemarshalError> {
     if buf.len() < FIXED_HEADER_SIZE { Err(DemarshalError::NotEnoughData)? };
-    if buf[3] != 1 { Err(DemarshalError::InvalidProtocol)? };
+    if buf[3] != 1 { Err(DemarshalError::InvalidProtocolVersion)? };
     let body_len = buf[4..8].try_into().unwrap();
     let serial = buf[8..12].try_into().unwrap();
     let arr_len = buf[12..16].try_into().unwrap();
     let (is_big_endian, body_len, serial, arr_len) = match buf[0] {
         b'l' => (false, u32::from_le_bytes(body_len), u32::from_le_bytes(serial), u32::from_le_bytes(arr_len)),
         b'B' => (true, u32::from_be_bytes(body_len), u32::from_be_bytes(serial), u32::from_be_bytes(arr_len)),
-        _ => Err(DemarshalError::InvalidProtocol)?
+        _ => Err(DemarshalError::InvalidEndianness)?
     };
+    if arr_len as usize > MAX_ARRAY_SIZE { Err(DemarshalError::ArrayTooLong)? }
     let body_len = body_len as usize;
     let body_start = types::align_up(arr_len as usize, 8) + FIXED_HEADER_SIZE;
     let total_size = body_start + body_len;
-    if body_len >= 134217728 || arr_len >= 67108864 || total_size >= 134217728 {
-        Err(DemarshalError::NumberTooBig)?
-    }
-    let serial = NonZeroU32::new(serial).ok_or(DemarshalError::NotEnoughData)?;
+    if total_size > MAX_MESSAGE_SIZE { Err(DemarshalError::MessageTooLarge)? }
+    let serial = NonZeroU32::new(serial).ok_or(DemarshalError::ZeroSerial)?;
     Ok(MsgStart { total_size, serial, body_start, is_big_endian })
 }
 
@@ -320,11 +445,19 @@ pub fn total_message_size(buf: &[u8]) -> Result<usize, DemarshalError> {
     message_start_parse(buf).map(|x| x.total_size)
 }
 
+/// Returns the number of file descriptors that belong to this message, according to its header.
+pub fn unix_fd_count(buf: &[u8]) -> Result<usize, DemarshalError> {
+    Message::demarshal_header(buf, false).map(|x| x.1 as usize)
+}
+
 #[derive(Debug, Clone)]
 pub struct MessageReader {
     storage: Vec<u8>,
     read_bytes: usize,
     total_size: Option<usize>,
+    max_size: usize,
+    // Shared with clones of this reader
+    fds: VecDeque<Arc<OwnedFd>>,
 }
 
 impl MessageReader {
@@ -333,8 +466,33 @@ impl MessageReader {
             storage:  vec![0u8; 256],
             read_bytes: 0,
             total_size: None,
+            max_size: MAX_MESSAGE_SIZE,
+            fds: VecDeque::new(),
         }
     }
+
+    /// Sets the maximum size of a message. Larger messages cause a MessageTooLarge error.
+    ///
+    /// The default, and the highest value allowed, is the 128 MiB limit of the D-Bus specification.
+    pub fn set_max_message_size(&mut self, max_size: usize) {
+        self.max_size = std::cmp::min(max_size, MAX_MESSAGE_SIZE);
+    }
+
+    /// Adds file descriptors received from the socket.
+    ///
+    /// They are kept until the message they belong to is complete, even if that message
+    /// arrives over several reads.
+    pub fn push_fds<I: IntoIterator<Item=OwnedFd>>(&mut self, fds: I) {
+        self.fds.extend(fds.into_iter().map(Arc::new))
+    }
+
+    /// Removes the file descriptors belonging to a message returned from buf_written_to.
+    pub fn take_fds(&mut self, msg: &[u8]) -> Result<Vec<Arc<OwnedFd>>, DemarshalError> {
+        let count = unix_fd_count(msg)?;
+        if count > self.fds.len() { Err(DemarshalError::WrongUnixFdCount)? }
+        Ok(self.fds.drain(..count).collect())
+    }
+
     pub fn clear(&mut self) {
         if self.storage.capacity() < 256 {
             self.storage = vec![0u8; 256];
@@ -345,9 +503,10 @@ impl MessageReader {
         self.read_bytes = 0;
         self.total_size = None;
     }
+
     pub fn get_buf(&mut self) -> &mut [u8] {
-        if let Some(ts) = self.total_size {
-            &mut self.storage[self.read_bytes..ts]
+        if self.total_size.is_some() {
+            &mut self.storage[self.read_bytes..]
         } else {
             &mut self.storage[self.read_bytes..FIXED_HEADER_SIZE]
         }
@@ -357,8 +516,17 @@ impl MessageReader {
         self.read_bytes += count;
         if self.total_size.is_none() && self.read_bytes >= FIXED_HEADER_SIZE {
             let start = message_start_parse(&self.storage)?;
+            if start.total_size > self.max_size { Err(DemarshalError::MessageTooLarge)? }
             self.total_size = Some(start.total_size);
-            self.storage.resize(start.total_size, 0);
+            // Don't trust the size in the header until the data is actually there,
+            // grow the buffer as data comes in instead.
+            self.storage.resize(std::cmp::min(start.total_size, self.storage.len()), 0);
+        }
+        if let Some(ts) = self.total_size {
+            if self.read_bytes == self.storage.len() && self.read_bytes < ts {
+                let new_len = std::cmp::min(ts, self.storage.len() * 2);
+                self.storage.resize(new_len, 0);
+            }
         }
         if Some(self.read_bytes) == self.total_size {
             let r = std::mem::replace(&mut self.storage, vec!());
@@ -381,6 +549,77 @@ impl MessageReader {
                 .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))? { return Ok(v); }
         };
     }
+
+    /// Like block_until_next_message, but also receives file descriptors sent
+    /// as SCM_RIGHTS ancillary data.
+    pub fn block_until_next_message_with_fds<S: AsRawFd>(&mut self, s: &S) -> io::Result<(Vec<u8>, Vec<Arc<OwnedFd>>)> {
+        let mut fds = vec!();
+        loop {
+            let count = match dbus_native_channel::unixfd::recv_with_fds(s, self.get_buf(), &mut fds) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                x => x?,
+            };
+            self.push_fds(fds.drain(..));
+            if count == 0 { Err(io::ErrorKind::UnexpectedEof)? }
+            if let Some(v) = self.buf_written_to(count)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))? {
+                let fds = self.take_fds(&v).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                return Ok((v, fds));
+            }
+        }
+    }
+}
+
+/// Writes a marshalled message to the socket, sending the file descriptors as SCM_RIGHTS
+/// ancillary data together with the first byte.
+///
+/// Blocking: until the entire message is written.
+pub fn block_until_message_sent<S: AsRawFd, F: AsRawFd>(s: &S, mut data: &[u8], fds: &[F]) -> io::Result<()> {
+    let mut fds: Vec<RawFd> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+    while !data.is_empty() {
+        let count = match dbus_native_channel::unixfd::send_with_fds(s, data, &fds) {
+            Ok(0) => Err(io::ErrorKind::WriteZero)?,
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => Err(e)?,
+        };
+        fds.clear();
+        data = &data[count..];
+    }
+    Ok(())
+}
+
+/// Parses data as a message, including every value in the body.
+///
+/// This is meant as an entry point for fuzzers: for any input, it should return an error
+/// rather than panic or allocate excessive amounts of memory.
+pub fn fuzz_demarshal(data: &[u8]) -> Result<(), DemarshalError> {
+    fn walk(p: Parsed) -> Result<(), DemarshalError> {
+        match p {
+            Parsed::Array(a) => for x in a { walk(x?.parse()?)? },
+            Parsed::Dict(d) => for x in d { let (k, v) = x?; walk(k.parse()?)?; walk(v.parse()?)? },
+            Parsed::Struct(s) => for x in s.iter() { walk(x?.parse()?)? },
+            Parsed::Variant(v) => walk(v.parse()?)?,
+            _ => {},
+        }
+        Ok(())
+    }
+
+    let mut mr = MessageReader::new();
+    let mut data = data;
+    let v = loop {
+        let buf = mr.get_buf();
+        let n = std::cmp::min(buf.len(), data.len());
+        if n == 0 { Err(DemarshalError::NotEnoughData)? }
+        buf[..n].copy_from_slice(&data[..n]);
+        data = &data[n..];
+        if let Some(v) = mr.buf_written_to(n)? { break v; }
+    };
+    // No file descriptors are attached, but the body is validated as if they were.
+    if let (Some(m), _) = Message::demarshal_header(&v, true)? {
+        for x in m.read_body().iter() { walk(x?.parse()?)? }
+    }
+    Ok(())
 }
 
 pub fn get_hello_message() -> Message<'static> {
@@ -423,3 +662,199 @@ fn hello() {
         6, 1, 115, 0, 20, 0, 0, 0, 111, 114, 103, 46, 102, 114, 101, 101, 100, 101, 115, 107, 116, 111, 112, 46, 68, 66, 117, 115, 0, 0, 0, 0
     ][..]);
 }
+
+#[test]
+fn unix_fds() {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    let (a, b) = UnixStream::pair().unwrap();
+    let (c, mut d) = UnixStream::pair().unwrap();
+
+    let mut m = get_hello_message();
+    let fd = m.add_unix_fd(OwnedFd::from(c));
+    let mut body = MultiBuf::new();
+    body.append(&fd).unwrap();
+    m.set_body(body);
+    let v = m.marshal(NonZeroU32::new(5).unwrap(), false).unwrap();
+    block_until_message_sent(&a, &v, m.unix_fds()).unwrap();
+    block_until_message_sent(&a, &v, m.unix_fds()).unwrap();
+    drop(m);
+
+    let mut mr = MessageReader::new();
+    let (v2, fds) = mr.block_until_next_message_with_fds(&b).unwrap();
+    assert_eq!(v, v2);
+    assert_eq!(fds.len(), 1);
+    assert!(Message::demarshal(&v2).unwrap().unwrap().unix_fds().is_empty());
+    let mut m2 = Message::demarshal_with_fds(&v2, fds).unwrap().unwrap();
+    let idx = match m2.read_body().iter().next().unwrap().unwrap().parse().unwrap() {
+        Parsed::UnixFd(idx) => idx,
+        _ => panic!("Expected a unix fd"),
+    };
+    let m2c = m2.clone();
+    assert_eq!(m2c.unix_fds()[idx].as_raw_fd(), m2.unix_fds()[idx].as_raw_fd());
+    drop(m2c);
+    let mut c2 = UnixStream::from(Arc::try_unwrap(m2.take_unix_fds().remove(idx)).unwrap());
+    c2.write_all(b"Hi").unwrap();
+    drop(c2);
+    let mut buf = [0u8; 2];
+    d.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"Hi");
+
+    // Dropping the second message unread should close its fd, and thus the other end sees EOF.
+    let (v3, fds) = mr.block_until_next_message_with_fds(&b).unwrap();
+    let m3 = Message::demarshal_with_fds(&v3, fds).unwrap().unwrap();
+    drop(m3);
+    assert_eq!(d.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn strict_demarshal() {
+    use crate::marshalled::{ArrayBuf, StructBuf, VariantBuf};
+    use dbus_strings::{StringLike, DBusStr, ObjectPath, MemberName};
+    let serial = NonZeroU32::new(1u32).unwrap();
+    let check = |v: &[u8], e| assert_eq!(Message::demarshal(v).unwrap_err(), e);
+    let hello = get_hello_message().marshal(serial, false).unwrap();
+    assert!(Message::demarshal(&hello).unwrap().is_some());
+
+    let mut v = hello.clone(); v[0] = b'x'; check(&v, DemarshalError::InvalidEndianness);
+    let mut v = hello.clone(); v[3] = 2; check(&v, DemarshalError::InvalidProtocolVersion);
+    let mut v = hello.clone(); v[8] = 0; check(&v, DemarshalError::ZeroSerial);
+    let mut v = hello.clone(); v[46] = 1; check(&v, DemarshalError::NonZeroPadding);
+    let mut v = hello.clone(); v[96] = 2; check(&v, DemarshalError::DuplicateHeaderField(2));
+    let mut v = hello.clone(); v[80] = 5; check(&v, DemarshalError::WrongHeaderFieldType(5));
+    let mut v = hello.clone(); v[90] = b'.'; check(&v, DemarshalError::InvalidMemberName);
+    let mut v = hello.clone(); v[90] = 0xff; check(&v, DemarshalError::InvalidUtf8);
+    let mut v = hello.clone(); v[90] = 0; check(&v, DemarshalError::InvalidNul);
+
+    // Required header fields
+    let ret = Message::new_method_return(serial).marshal(serial, false).unwrap();
+    let mut v = ret.clone(); v[1] = METHOD_CALL; check(&v, DemarshalError::MissingHeaderField(1));
+    let mut v = ret.clone(); v[1] = ERROR; check(&v, DemarshalError::MissingHeaderField(4));
+    let mut v = ret.clone(); v[1] = 17; assert!(Message::demarshal(&v).unwrap().is_none());
+
+    let m = Message::new_method_call(ObjectPath::new("/org/freedesktop/DBus/Local").unwrap().into(),
+        MemberName::new("Hello").unwrap().into()).unwrap();
+    check(&m.marshal(serial, false).unwrap(), DemarshalError::ReservedLocalName);
+
+    // Body checks. Header is 8 bytes of reply serial, 8 bytes of signature, and then the body.
+    let mut m = Message::new_method_return(serial);
+    let mut b = MultiBuf::new();
+    b.append(&2u32).unwrap();
+    m.set_body(b);
+    let v = m.marshal(serial, false).unwrap();
+    assert_eq!(&v[24..32], &[8, 1, b'g', 0, 1, b'u', 0, 0]);
+    assert!(Message::demarshal(&v).unwrap().is_some());
+    let mut v2 = v.clone(); v2[29] = b'b'; check(&v2, DemarshalError::InvalidBoolean);
+    let mut v2 = v.clone(); v2[29] = b'h'; check(&v2, DemarshalError::UnixFdOutOfRange);
+    let mut v2 = v.clone(); v2[29] = b'x'; check(&v2, DemarshalError::BodySignatureMismatch);
+    let mut v2 = v.clone(); v2[4] = 8; v2.extend_from_slice(&[0; 4]); check(&v2, DemarshalError::BodySignatureMismatch);
+    let mut v2 = v.clone(); v2[29] = b'{'; check(&v2, DemarshalError::InvalidSignature);
+
+    let mut m = Message::new_method_return(serial);
+    let mut a = ArrayBuf::new(dbus_strings::SignatureSingle::new("s").unwrap()).unwrap();
+    a.append(DBusStr::new("Hello").unwrap()).unwrap();
+    let mut b = MultiBuf::new();
+    b.append(&a).unwrap();
+    m.set_body(b);
+    let v = m.marshal(serial, false).unwrap();
+    assert!(Message::demarshal(&v).unwrap().is_some());
+    let mut v2 = v.clone(); v2[35] = 4; check(&v2, DemarshalError::ArrayTooLong);
+    let mut v2 = v.clone(); v2[32] = 9; check(&v2, DemarshalError::ArrayLengthMismatch);
+
+    // Nested variants
+    let nested = |depth| {
+        let mut x = VariantBuf::new(&5u8).unwrap();
+        for _ in 1..depth { x = VariantBuf::new(&x).unwrap(); }
+        let mut m = Message::new_method_return(serial);
+        let mut b = MultiBuf::new();
+        b.append(&x).unwrap();
+        m.set_body(b);
+        m.marshal(serial, false).unwrap()
+    };
+    assert!(Message::demarshal(&nested(64)).unwrap().is_some());
+    check(&nested(65), DemarshalError::NestingTooDeep);
+
+    // Arrays and structs are limited to 32 each, also when split up by variants
+    let body = |x: VariantBuf| {
+        let mut m = Message::new_method_return(serial);
+        let mut b = MultiBuf::new();
+        b.append(&x).unwrap();
+        m.set_body(b);
+        m.marshal(serial, false).unwrap()
+    };
+    let arrays = |levels| {
+        let mut x = VariantBuf::new(&5u8).unwrap();
+        for _ in 0..levels {
+            let mut a = ArrayBuf::new(crate::marshalled::Marshal::signature(&x)).unwrap();
+            a.append(&x).unwrap();
+            for _ in 1..4 {
+                let mut b = ArrayBuf::new(crate::marshalled::Marshal::signature(&a)).unwrap();
+                b.append(&a).unwrap();
+                a = b;
+            }
+            x = VariantBuf::new(&a).unwrap();
+        }
+        body(x)
+    };
+    assert!(Message::demarshal(&arrays(8)).unwrap().is_some());
+    check(&arrays(9), DemarshalError::NestingTooDeep);
+    let structs = |levels| {
+        let mut x = VariantBuf::new(&5u8).unwrap();
+        for _ in 0..levels {
+            let mut b = MultiBuf::new();
+            b.append(&x).unwrap();
+            let mut st = StructBuf::new(b).unwrap();
+            for _ in 1..8 {
+                let mut b = MultiBuf::new();
+                b.append(&st).unwrap();
+                st = StructBuf::new(b).unwrap();
+            }
+            x = VariantBuf::new(&st).unwrap();
+        }
+        body(x)
+    };
+    assert!(Message::demarshal(&structs(4)).unwrap().is_some());
+    check(&structs(5), DemarshalError::NestingTooDeep);
+
+    // Message size limit on the reader
+    let mut mr = MessageReader::new();
+    mr.set_max_message_size(64);
+    mr.get_buf().copy_from_slice(&hello[..16]);
+    assert_eq!(mr.buf_written_to(16).unwrap_err(), DemarshalError::MessageTooLarge);
+}
+
+#[test]
+fn fuzz_no_panic() {
+    use crate::marshalled::{ArrayBuf, DictBuf, VariantBuf, StructBuf};
+    use dbus_strings::{StringLike, DBusStr, SignatureSingle};
+    let mut d = DictBuf::new(SignatureSingle::new_owned("s").unwrap(), SignatureSingle::new_owned("v").unwrap()).unwrap();
+    d.append(DBusStr::new("Key").unwrap(), &VariantBuf::new(&7u16).unwrap()).unwrap();
+    d.append(DBusStr::new("Key2").unwrap(), &VariantBuf::new(&1.5f64).unwrap()).unwrap();
+    let mut a = ArrayBuf::new(SignatureSingle::new("x").unwrap()).unwrap();
+    a.append(&-5i64).unwrap();
+    let mut s = MultiBuf::new();
+    s.append(&3u8).unwrap();
+    s.append(&d).unwrap();
+    let mut b = MultiBuf::new();
+    b.append(&StructBuf::new(s).unwrap()).unwrap();
+    b.append(&a).unwrap();
+    b.append(&UnixFd(0)).unwrap();
+    let mut m = get_hello_message();
+    m.set_body(b);
+    m.add_unix_fd(OwnedFd::from(std::fs::File::open("/dev/null").unwrap()));
+    let v = m.marshal(NonZeroU32::new(1u32).unwrap(), false).unwrap();
+    fuzz_demarshal(&v).unwrap();
+
+    // Simple xorshift PRNG, so that the test is reproducible.
+    let mut x = 0x2545F491u32;
+    let mut rand = || { x ^= x << 13; x ^= x >> 17; x ^= x << 5; x as usize };
+    for _ in 0..20000 {
+        let mut v2 = v.clone();
+        for _ in 0..(rand() % 4 + 1) {
+            let pos = rand() % v2.len();
+            v2[pos] = rand() as u8;
+        }
+        let len = if rand() % 4 == 0 { rand() % v2.len() } else { v2.len() };
+        let _ = fuzz_demarshal(&v2[..len]);
+    }
+}
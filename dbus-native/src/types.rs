@@ -38,7 +38,8 @@ pub struct DemarshalState<'a> {
     pub is_big_endian: bool,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DemarshalError {
     NotEnoughData,
     InvalidString,
@@ -46,6 +47,55 @@ pub enum DemarshalError {
     InvalidBoolean,
     WrongType,
     NumberTooBig,
+    WrongUnixFdCount,
+    /// The first byte of the message is neither 'l' nor 'B'.
+    InvalidEndianness,
+    /// The major protocol version is not 1.
+    InvalidProtocolVersion,
+    /// The message serial is zero.
+    ZeroSerial,
+    /// The message is larger than 128 MiB (or the limit set on the reader).
+    MessageTooLarge,
+    /// An array is larger than 64 MiB.
+    ArrayTooLong,
+    /// The elements of an array do not add up to the array's length.
+    ArrayLengthMismatch,
+    /// More than 32 nested arrays, 32 nested structs, or 64 nested containers in total.
+    NestingTooDeep,
+    /// A padding byte is not zero.
+    NonZeroPadding,
+    /// A string is not valid UTF-8.
+    InvalidUtf8,
+    /// A string contains a nul byte, or is not terminated by one.
+    InvalidNul,
+    /// An object path is not valid.
+    InvalidObjectPath,
+    /// A type signature is not valid, or a variant does not contain a single complete type.
+    InvalidSignature,
+    /// An interface name is not valid.
+    InvalidInterfaceName,
+    /// A member name is not valid.
+    InvalidMemberName,
+    /// An error name is not valid.
+    InvalidErrorName,
+    /// A bus name is not valid.
+    InvalidBusName,
+    /// A header field occurs more than once. Contains the header field code.
+    DuplicateHeaderField(u8),
+    /// A header field has the wrong type. Contains the header field code.
+    WrongHeaderFieldType(u8),
+    /// A header field required for this message type is missing. Contains the header field code.
+    MissingHeaderField(u8),
+    /// The path or interface is the reserved "org.freedesktop.DBus.Local".
+    ReservedLocalName,
+    /// The body does not match the signature header field.
+    BodySignatureMismatch,
+    /// More than 253 unix file descriptors.
+    TooManyUnixFds,
+    /// A unix fd value refers to a file descriptor that was not sent with the message.
+    UnixFdOutOfRange,
+    /// GVariant data does not have the size required by its type, or its framing offsets are inconsistent.
+    InvalidFraming,
 }
 
 impl std::error::Error for DemarshalError {
@@ -37,3 +37,52 @@ fn connect_to_session_bus() {
     println!("Our ID is {}", r2.as_dbus_str().unwrap());
 
 }
+
+#[test]
+fn sans_io_connection() {
+    use dbus::connection::{Connection, Event};
+    use dbus::strings::{StringLike, ObjectPath, MemberName, BusName, InterfaceName};
+    use std::os::unix::io::AsRawFd;
+
+    fn next_event(c: &mut Connection, s: &std::os::unix::net::UnixStream) -> Event {
+        loop {
+            if let Some(ev) = c.poll_event() { return ev; }
+            while let Some((data, fds)) = c.outgoing() {
+                let fds: Vec<_> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+                let n = dbus::unixfd::send_with_fds(s, data, &fds).unwrap();
+                c.advance_output(n);
+            }
+            let mut buf = [0u8; 4096];
+            let mut fds = vec!();
+            let n = dbus::unixfd::recv_with_fds(s, &mut buf, &mut fds).unwrap();
+            c.receive_fds(fds);
+            if n == 0 { c.receive_eof() } else { c.receive_data(&buf[..n]) }
+        }
+    }
+
+    let addr = address::read_session_address().unwrap();
+    let stream = address::connect_blocking(&addr).unwrap();
+    let mut c = Connection::new(true);
+
+    let mut m = message::Message::new_method_call(ObjectPath::new("/org/freedesktop/DBus").unwrap().into(),
+        MemberName::new("GetId").unwrap().into()).unwrap();
+    m.set_destination(Some(BusName::new("org.freedesktop.DBus").unwrap().into())).unwrap();
+    m.set_interface(Some(InterfaceName::new("org.freedesktop.DBus").unwrap().into())).unwrap();
+    let serial = c.send_with_reply(m, None).unwrap();
+
+    assert!(matches!(next_event(&mut c, &stream), Event::Connected));
+    assert!(c.unique_name().unwrap().starts_with(":1."));
+    assert!(c.unix_fd_enabled());
+    loop {
+        match next_event(&mut c, &stream) {
+            Event::Message(_) => {}, // Probably the NameAcquired signal
+            Event::Reply(s, reply) => {
+                assert_eq!(s, serial);
+                let id = reply.read_body().iter().next().unwrap().unwrap().parse().unwrap();
+                assert_eq!(id.as_dbus_str().unwrap().len(), 32);
+                break;
+            }
+            ev => panic!("Unexpected event {:?}", ev),
+        }
+    }
+}
@@ -10,7 +10,10 @@ use std::error::Error;
 use std::ops::Deref;
 use std::convert::TryFrom;
 
-mod validity;
+pub mod validity;
+mod signature;
+
+pub use signature::{Type, BasicType, SignatureIter};
 
 /// The supplied string was not a valid string of the desired type.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
@@ -272,7 +275,7 @@ impl SignatureMulti {
     ///
     /// Returns none if the signature is empty.
     pub fn single(&self) -> Option<(&SignatureSingle, &SignatureMulti)> {
-        validity::sig_single(self.as_bytes(), 0, 0).map(|x|
+        validity::sig_single(self.as_bytes(), 0, 0, 0).map(|x|
             (SignatureSingle::new_unchecked(&self[0..x]), SignatureMulti::new_unchecked(&self[x..]))
         )
     }
@@ -0,0 +1,287 @@
+//! A parsed representation of D-Bus type signatures.
+
+use std::fmt;
+use crate::{StringLike, SignatureSingle, SignatureSingleBuf, SignatureMulti};
+
+/// One of the basic (non-container) D-Bus types.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum BasicType {
+    /// "y", an unsigned 8-bit integer.
+    Byte,
+    /// "b", a boolean, marshalled as a 32-bit integer.
+    Boolean,
+    /// "n", a signed 16-bit integer.
+    Int16,
+    /// "q", an unsigned 16-bit integer.
+    UInt16,
+    /// "i", a signed 32-bit integer.
+    Int32,
+    /// "u", an unsigned 32-bit integer.
+    UInt32,
+    /// "x", a signed 64-bit integer.
+    Int64,
+    /// "t", an unsigned 64-bit integer.
+    UInt64,
+    /// "d", an IEEE 754 double.
+    Double,
+    /// "h", an index into the message's array of unix file descriptors.
+    UnixFd,
+    /// "s", a string.
+    String,
+    /// "o", an object path.
+    ObjectPath,
+    /// "g", a signature.
+    Signature,
+}
+
+impl BasicType {
+    /// Looks up the basic type for a type code, e g `b's'`.
+    pub fn from_code(c: u8) -> Option<BasicType> {
+        use BasicType::*;
+        Some(match c {
+            b'y' => Byte,
+            b'b' => Boolean,
+            b'n' => Int16,
+            b'q' => UInt16,
+            b'i' => Int32,
+            b'u' => UInt32,
+            b'x' => Int64,
+            b't' => UInt64,
+            b'd' => Double,
+            b'h' => UnixFd,
+            b's' => String,
+            b'o' => ObjectPath,
+            b'g' => Signature,
+            _ => return None,
+        })
+    }
+
+    /// The type code of this type, e g `b's'`.
+    pub fn code(&self) -> u8 {
+        use BasicType::*;
+        match self {
+            Byte => b'y',
+            Boolean => b'b',
+            Int16 => b'n',
+            UInt16 => b'q',
+            Int32 => b'i',
+            UInt32 => b'u',
+            Int64 => b'x',
+            UInt64 => b't',
+            Double => b'd',
+            UnixFd => b'h',
+            String => b's',
+            ObjectPath => b'o',
+            Signature => b'g',
+        }
+    }
+
+    /// The alignment of this type on the wire, in bytes.
+    pub fn alignment(&self) -> usize {
+        use BasicType::*;
+        match self {
+            Byte | Signature => 1,
+            Int16 | UInt16 => 2,
+            Boolean | Int32 | UInt32 | UnixFd | String | ObjectPath => 4,
+            Int64 | UInt64 | Double => 8,
+        }
+    }
+
+    /// The size of this type on the wire, or None for the string-like types.
+    pub fn fixed_size(&self) -> Option<usize> {
+        use BasicType::*;
+        match self {
+            String | ObjectPath | Signature => None,
+            _ => Some(self.alignment()),
+        }
+    }
+}
+
+impl fmt::Display for BasicType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.code() as char)
+    }
+}
+
+/// A complete D-Bus type, as described by a single type signature.
+///
+/// Create one by calling `SignatureSingle::parse`. Its `Display` implementation writes the
+/// signature back out again.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Type {
+    /// A basic type, e g "s".
+    Basic(BasicType),
+    /// An array of the inner type, e g "ai". Dictionaries are represented by `Dict` instead.
+    Array(Box<Type>),
+    /// A dictionary, i e an array of dict entries, e g "a{sv}".
+    Dict(BasicType, Box<Type>),
+    /// A struct, e g "(ss)". There is always at least one field.
+    Struct(Vec<Type>),
+    /// "v", a variant.
+    Variant,
+}
+
+fn align_up(pos: usize, align: usize) -> usize {
+    (pos + align - 1) & !(align - 1)
+}
+
+impl Type {
+    /// The alignment of this type on the wire, in bytes.
+    pub fn alignment(&self) -> usize {
+        match self {
+            Type::Basic(b) => b.alignment(),
+            Type::Array(_) | Type::Dict(_, _) => 4,
+            Type::Struct(_) => 8,
+            Type::Variant => 1,
+        }
+    }
+
+    /// The size of this type on the wire, if it is the same for every value.
+    ///
+    /// This is the case for all basic types except the string-like ones, and for structs
+    /// containing only such types. The size of a struct includes padding between its fields,
+    /// but not any trailing padding.
+    pub fn fixed_size(&self) -> Option<usize> {
+        match self {
+            Type::Basic(b) => b.fixed_size(),
+            Type::Struct(fields) => fields.iter().try_fold(0, |pos, t|
+                Some(align_up(pos, t.alignment()) + t.fixed_size()?)
+            ),
+            Type::Array(_) | Type::Dict(_, _) | Type::Variant => None,
+        }
+    }
+
+    /// Whether this is a basic (non-container) type.
+    pub fn is_basic(&self) -> bool { matches!(self, Type::Basic(_)) }
+
+    /// Returns the signature of this type.
+    pub fn signature(&self) -> SignatureSingleBuf {
+        SignatureSingle::new_unchecked_owned(self.to_string())
+    }
+
+    fn parse_one(s: &[u8], pos: &mut usize) -> Type {
+        let c = s[*pos];
+        *pos += 1;
+        if let Some(b) = BasicType::from_code(c) { return Type::Basic(b) };
+        match c {
+            b'v' => Type::Variant,
+            b'a' if s[*pos] == b'{' => {
+                let key = BasicType::from_code(s[*pos + 1]).unwrap();
+                *pos += 2;
+                let value = Type::parse_one(s, pos);
+                *pos += 1;
+                Type::Dict(key, Box::new(value))
+            },
+            b'a' => Type::Array(Box::new(Type::parse_one(s, pos))),
+            b'(' => {
+                let mut fields = vec!();
+                while s[*pos] != b')' { fields.push(Type::parse_one(s, pos)) }
+                *pos += 1;
+                Type::Struct(fields)
+            }
+            _ => unreachable!("invalid signature {:?}", std::str::from_utf8(s)),
+        }
+    }
+}
+
+impl From<BasicType> for Type {
+    fn from(b: BasicType) -> Type { Type::Basic(b) }
+}
+
+impl From<&Type> for SignatureSingleBuf {
+    fn from(t: &Type) -> SignatureSingleBuf { t.signature() }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Basic(b) => b.fmt(f),
+            Type::Array(t) => write!(f, "a{}", t),
+            Type::Dict(k, v) => write!(f, "a{{{}{}}}", k, v),
+            Type::Struct(fields) => {
+                f.write_str("(")?;
+                for t in fields { t.fmt(f)? }
+                f.write_str(")")
+            }
+            Type::Variant => f.write_str("v"),
+        }
+    }
+}
+
+impl SignatureSingle {
+    /// Parses this signature into a type tree.
+    pub fn parse(&self) -> Type {
+        let mut pos = 0;
+        Type::parse_one(self.as_bytes(), &mut pos)
+    }
+}
+
+impl SignatureMulti {
+    /// Iterates over the top-level types of this signature.
+    pub fn iter(&self) -> SignatureIter<'_> {
+        SignatureIter(self)
+    }
+
+    /// Parses all top-level types of this signature.
+    pub fn parse(&self) -> Vec<Type> {
+        self.iter().map(|s| s.parse()).collect()
+    }
+}
+
+/// Iterator over the single types of a `SignatureMulti`, created by `SignatureMulti::iter`.
+#[derive(Debug, Clone)]
+pub struct SignatureIter<'a>(&'a SignatureMulti);
+
+impl<'a> Iterator for SignatureIter<'a> {
+    type Item = &'a SignatureSingle;
+    fn next(&mut self) -> Option<Self::Item> {
+        let (first, rest) = self.0.single()?;
+        self.0 = rest;
+        Some(first)
+    }
+}
+
+impl<'a> IntoIterator for &'a SignatureMulti {
+    type Item = &'a SignatureSingle;
+    type IntoIter = SignatureIter<'a>;
+    fn into_iter(self) -> SignatureIter<'a> { self.iter() }
+}
+
+#[test]
+fn parse_and_display() {
+    for s in &["y", "v", "ai", "a{sv}", "(ia{oa{sv}}(yy)ah)", "aav", "a{ta(dd)}"] {
+        let t = SignatureSingle::new(s).unwrap().parse();
+        assert_eq!(&t.to_string(), s);
+        assert_eq!(&**t.signature(), *s);
+    }
+    let t = SignatureSingle::new("a{s(iv)}").unwrap().parse();
+    assert_eq!(t, Type::Dict(BasicType::String, Box::new(Type::Struct(vec!(
+        Type::Basic(BasicType::Int32), Type::Variant)))));
+}
+
+#[test]
+fn alignment_and_size() {
+    let parse = |s| SignatureSingle::new(s).unwrap().parse();
+    assert_eq!(parse("y").fixed_size(), Some(1));
+    assert_eq!(parse("b").fixed_size(), Some(4));
+    assert_eq!(parse("s").fixed_size(), None);
+    assert_eq!(parse("(yx)").fixed_size(), Some(16));
+    assert_eq!(parse("(uy)").fixed_size(), Some(5));
+    assert_eq!(parse("(y(yt))").fixed_size(), Some(24));
+    assert_eq!(parse("(ys)").fixed_size(), None);
+    assert_eq!(parse("ay").fixed_size(), None);
+    assert_eq!(parse("g").alignment(), 1);
+    assert_eq!(parse("q").alignment(), 2);
+    assert_eq!(parse("a(t)").alignment(), 4);
+    assert_eq!(parse("(y)").alignment(), 8);
+    assert_eq!(parse("v").alignment(), 1);
+}
+
+#[test]
+fn multi_iter() {
+    let s = SignatureMulti::new("ua{sv}(ss)").unwrap();
+    let v: Vec<_> = s.iter().map(|x| &**x).collect();
+    assert_eq!(v, vec!("u", "a{sv}", "(ss)"));
+    assert_eq!(s.parse()[0], Type::Basic(BasicType::UInt32));
+    assert_eq!(SignatureMulti::new("").unwrap().iter().count(), 0);
+}
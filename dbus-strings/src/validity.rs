@@ -1,174 +1,205 @@
-fn is_az_(b: u8) -> Result<(), ()> {
-    match b {
-        b'A'..=b'Z' | b'a'..=b'z' | b'_' => Ok(()),
-        _ => Err(()),
-    }
+//! Validity checks for the different D-Bus string types.
+//!
+//! These are `const fn`s, so they can be used to check string literals at compile time.
+
+#![allow(clippy::result_unit_err)]
+
+const fn is_az_(b: u8) -> bool {
+    matches!(b, b'A'..=b'Z' | b'a'..=b'z' | b'_')
 }
 
-fn is_az09_(b: u8) -> Result<(), ()> {
-    match b {
-        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'_' => Ok(()),
-        _ => Err(()),
-    }
+const fn is_az09_(b: u8) -> bool {
+    matches!(b, b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'_')
 }
 
-fn is_az_hyphen(b: u8) -> Result<(), ()> {
-    match b {
-        b'A'..=b'Z' | b'a'..=b'z' | b'_' | b'-' => Ok(()),
-        _ => Err(()),
-    }
+const fn is_az_hyphen(b: u8) -> bool {
+    matches!(b, b'A'..=b'Z' | b'a'..=b'z' | b'_' | b'-')
 }
 
-fn is_az09_hyphen(b: u8) -> Result<(), ()> {
-    match b {
-        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'_' | b'-' => Ok(()),
-        _ => Err(()),
-    }
+const fn is_az09_hyphen(b: u8) -> bool {
+    matches!(b, b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'_' | b'-')
+}
+
+const fn ok_if(b: bool) -> Result<(), ()> {
+    if b { Ok(()) } else { Err(()) }
 }
 
-pub fn is_valid_string(s: &str) -> Result<(), ()> {
+/// Checks that a string is a valid D-Bus string, i e, contains no nul bytes.
+pub const fn is_valid_string(s: &str) -> Result<(), ()> {
     let s = s.as_bytes();
     // 134217728 (128 MiB) is the maximum length of a message, so it follows that
     // a string can't be longer.
-    if s.len() >= 134217728 { Err(()) }
-    else if s.iter().any(|&b| b == 0) { Err(()) } else { Ok(()) }
+    if s.len() >= 134217728 { return Err(()) }
+    let mut i = 0;
+    while i < s.len() {
+        if s[i] == 0 { return Err(()) }
+        i += 1;
+    }
+    Ok(())
 }
 
-pub fn is_valid_member_name(s: &[u8]) -> Result<(), ()> {
-    if s.len() > 255 { Err(())? }
-    let mut x = s.into_iter();
-    let c = *x.next().ok_or(())?;
-    is_az_(c)?;
-    for c in x { is_az09_(*c)? };
+/// Checks that a string is a valid member (method or signal) name.
+pub const fn is_valid_member_name(s: &[u8]) -> Result<(), ()> {
+    if s.len() > 255 || s.is_empty() || !is_az_(s[0]) { return Err(()) }
+    let mut i = 1;
+    while i < s.len() {
+        if !is_az09_(s[i]) { return Err(()) }
+        i += 1;
+    }
     Ok(())
 }
 
-pub fn is_valid_error_name(s: &[u8]) -> Result<(), ()> {
-    return is_valid_interface_name(s)
+/// Checks that a string is a valid error name.
+pub const fn is_valid_error_name(s: &[u8]) -> Result<(), ()> {
+    is_valid_interface_name(s)
 }
 
-pub fn is_valid_interface_name(s: &[u8]) -> Result<(), ()> {
-    if s.len() > 255 { Err(())? }
-    let mut x = s.into_iter();
+/// Checks that a string is a valid interface name.
+pub const fn is_valid_interface_name(s: &[u8]) -> Result<(), ()> {
+    if s.len() > 255 { return Err(()) }
     let mut elements = 1;
-    'outer: loop {
-        let c = *x.next().ok_or(())?;
-        is_az_(c)?;
-        while let Some(&c) = x.next() {
-            if c == b'.' {
-                elements += 1;
-                continue 'outer;
-            }
-            is_az09_(c)?;
-        }
-        return if elements > 1 { Ok(()) } else { Err(()) }
+    let mut element_start = true;
+    let mut i = 0;
+    while i < s.len() {
+        let c = s[i];
+        if element_start {
+            if !is_az_(c) { return Err(()) }
+            element_start = false;
+        } else if c == b'.' {
+            elements += 1;
+            element_start = true;
+        } else if !is_az09_(c) { return Err(()) }
+        i += 1;
     }
+    ok_if(!element_start && elements > 1)
 }
 
-fn is_valid_unique_conn_name(mut x: std::slice::Iter<u8>) -> Result<(), ()> {
+const fn is_valid_unique_conn_name(s: &[u8]) -> Result<(), ()> {
     let mut elements = 1;
-    'outer: loop {
-        let c = *x.next().ok_or(())?;
-        is_az09_hyphen(c)?;
-        while let Some(&c) = x.next() {
-            if c == b'.' {
-                elements += 1;
-                continue 'outer;
-            }
-            is_az09_hyphen(c)?;
-        }
-        return if elements > 1 { Ok(()) } else { Err(()) }
+    let mut element_start = true;
+    let mut i = 1;
+    while i < s.len() {
+        let c = s[i];
+        if element_start {
+            if !is_az09_hyphen(c) { return Err(()) }
+            element_start = false;
+        } else if c == b'.' {
+            elements += 1;
+            element_start = true;
+        } else if !is_az09_hyphen(c) { return Err(()) }
+        i += 1;
     }
+    ok_if(!element_start && elements > 1)
 }
 
-pub fn is_valid_bus_name(s: &[u8]) -> Result<(), ()> {
-    if s.len() > 255 { return Err(()); }
-    let mut x = s.into_iter();
-    let mut c_first = *x.next().ok_or(())?;
-    if c_first == b':' { return is_valid_unique_conn_name(x); }
+/// Checks that a string is a valid bus name, either unique (":1.54") or well-known.
+pub const fn is_valid_bus_name(s: &[u8]) -> Result<(), ()> {
+    if s.len() > 255 || s.is_empty() { return Err(()); }
+    if s[0] == b':' { return is_valid_unique_conn_name(s); }
     let mut elements = 1;
-    'outer: loop {
-        is_az_hyphen(c_first)?;
-        while let Some(&c) = x.next() {
-            if c == b'.' {
-                elements += 1;
-                c_first = *x.next().ok_or(())?;
-                continue 'outer;
-            }
-            is_az09_hyphen(c)?;
-        }
-        return if elements > 1 { Ok(()) } else { Err(()) }
+    let mut element_start = true;
+    let mut i = 0;
+    while i < s.len() {
+        let c = s[i];
+        if element_start {
+            if !is_az_hyphen(c) { return Err(()) }
+            element_start = false;
+        } else if c == b'.' {
+            elements += 1;
+            element_start = true;
+        } else if !is_az09_hyphen(c) { return Err(()) }
+        i += 1;
     }
+    ok_if(!element_start && elements > 1)
 }
 
-pub fn is_valid_object_path(s: &[u8]) -> Result<(), ()> {
-    let mut x = s.into_iter();
-    let c = x.next();
-    if c != Some(&b'/') { Err(())? };
+/// Checks that a string is a valid object path.
+pub const fn is_valid_object_path(s: &[u8]) -> Result<(), ()> {
+    if s.is_empty() || s[0] != b'/' { return Err(()) };
     if s.len() == 1 { return Ok(()) };
-
-    'outer: loop {
-        let c = *x.next().ok_or(())?;
-        is_az09_(c)?;
-        while let Some(&c) = x.next() {
-            if c == b'/' { continue 'outer; }
-            is_az09_(c)?;
-        }
-        return Ok(());
+    let mut element_start = true;
+    let mut i = 1;
+    while i < s.len() {
+        let c = s[i];
+        if c == b'/' {
+            if element_start { return Err(()) }
+            element_start = true;
+        } else if !is_az09_(c) { return Err(()) }
+        else { element_start = false; }
+        i += 1;
     }
+    ok_if(!element_start)
 }
 
-const BASIC_TYPES: &[u8] = b"ybnqiuxtdhsog";
+const fn is_basic_type(c: u8) -> bool {
+    matches!(c, b'y' | b'b' | b'n' | b'q' | b'i' | b'u' | b'x' | b't' | b'd' | b'h' | b's' | b'o' | b'g')
+}
 
-fn sig_multi(s: &[u8], arrs: u8, structs: u8) -> Option<usize> {
-    let mut pos = 0;
+const fn sig_multi(s: &[u8], start: usize, arrs: u8, structs: u8) -> Option<usize> {
+    let mut pos = start;
     while pos < s.len() {
-        if s.get(pos) == Some(&b')') { return Some(pos) }
-        pos += sig_single(&s[pos..], arrs, structs)?;
+        if s[pos] == b')' { return Some(pos - start) }
+        pos += match sig_single(s, pos, arrs, structs) {
+            Some(x) => x,
+            None => return None,
+        };
+    }
+    Some(pos - start)
+}
+
+/// Returns the length of the single complete type starting at `start`, if any.
+pub (crate) const fn sig_single(s: &[u8], start: usize, arrs: u8, structs: u8) -> Option<usize> {
+    if start >= s.len() { return None }
+    let c = s[start];
+    if is_basic_type(c) { return Some(1) }
+    match c {
+        b'v' => Some(1), // Variant
+        b'a' => { // Array
+            if arrs >= 32 { return None };
+            if start + 1 < s.len() && s[start + 1] == b'{' { // Dict
+                if start + 2 >= s.len() || !is_basic_type(s[start + 2]) { return None };
+                let pos = match sig_single(s, start + 3, arrs+1, structs) {
+                    Some(x) => 3 + x,
+                    None => return None,
+                };
+                if start + pos >= s.len() || s[start + pos] != b'}' { return None }
+                Some(pos + 1)
+            } else {
+                match sig_single(s, start + 1, arrs+1, structs) {
+                    Some(x) => Some(1 + x),
+                    None => None,
+                }
+            }
+        },
+        b'(' => {
+            if structs >= 32 { return None };
+            let pos = match sig_multi(s, start + 1, arrs, structs+1) {
+                Some(x) => 1 + x,
+                None => return None,
+            };
+            if pos == 1 || start + pos >= s.len() || s[start + pos] != b')' { return None }
+            Some(pos + 1)
+        },
+        _ => None,
+    }
+}
+
+/// Checks that a string is a valid signature of exactly one complete type.
+pub const fn is_valid_signature_single(s: &[u8]) -> Result<(), ()> {
+    if s.len() > 255 { return Err(()) }
+    match sig_single(s, 0, 0, 0) {
+        Some(pos) => ok_if(pos == s.len()),
+        None => Err(()),
+    }
+}
+
+/// Checks that a string is a valid signature of zero or more complete types.
+pub const fn is_valid_signature_multi(s: &[u8]) -> Result<(), ()> {
+    if s.len() > 255 { return Err(()) }
+    match sig_multi(s, 0, 0, 0) {
+        Some(pos) => ok_if(pos == s.len()),
+        None => Err(()),
     }
-    Some(pos)
-}
-
-pub (crate) fn sig_single(s: &[u8], arrs: u8, structs: u8) -> Option<usize> {
-    s.first().and_then(|c| {
-        if BASIC_TYPES.into_iter().any(|x| x == c) { Some(1) }
-        else {
-            Some(1 + match c {
-                b'v' => 0, // Variant
-                b'a' => { // Array
-                    if arrs >= 32 { None? };
-                    if s.get(1) == Some(&b'{') { // Dict
-                        let c = s.get(2)?;
-                        if !BASIC_TYPES.into_iter().any(|x| x == c) { None? };
-                        let pos = 3 + sig_single(&s[3..], arrs+1, structs)?;
-                        if s.get(pos)? != &b'}' { None? }
-                        pos
-                    } else {
-                        sig_single(&s[1..], arrs+1, structs)?
-                    }
-                },
-                b'(' => {
-                    if structs >= 32 { None? };
-                    let pos = 1 + sig_multi(&s[1..], arrs, structs+1)?;
-                    if pos == 1 || s.get(pos)? != &b')' { None? }
-                    pos
-                },
-                _ => None?,
-            })
-        }
-    })
-}
-
-pub fn is_valid_signature_single(s: &[u8]) -> Result<(), ()> {
-    if s.len() > 255 { Err(())? }
-    let pos = sig_single(s, 0, 0).ok_or(())?;
-    return if pos == s.len() { Ok(()) } else { Err(()) }
-}
-
-pub fn is_valid_signature_multi(s: &[u8]) -> Result<(), ()> {
-    if s.len() > 255 { Err(())? }
-    let pos = sig_multi(s, 0, 0).ok_or(())?;
-    return if pos == s.len() { Ok(()) } else { Err(()) }
 }
 
 #[test]
@@ -247,3 +278,11 @@ fn signature() {
     assert!(is_valid_signature_multi(b"dbus)").is_err());
 
 }
+
+#[test]
+fn const_eval() {
+    const PATH_OK: bool = is_valid_object_path(b"/org/example").is_ok();
+    const SIG_OK: bool = is_valid_signature_single(b"a{sv}").is_ok();
+    const BUS_OK: bool = is_valid_bus_name(b":1.54").is_ok();
+    assert!(PATH_OK && SIG_OK && BUS_OK);
+}
@@ -426,4 +426,23 @@ async fn large_message() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[tokio::test]
+async fn monitor() {
+    use futures::StreamExt;
+    use dbus::nonblock::{Monitor, MonitorMode};
+    use dbus::channel::Sender;
+
+    let (res, conn) = new_session_sync().unwrap();
+    tokio::spawn(async move { panic!("{}", res.await);});
+    let rule = dbus::message::MatchRule::new_signal("com.example.dbusrs.MonitorTest", "Ping");
+    let mut monitor = Monitor::new(conn, &[rule]).await.unwrap();
+    assert_eq!(monitor.mode(), MonitorMode::BecomeMonitor);
+
+    let c = dbus::blocking::Connection::new_session().unwrap();
+    c.send(dbus::Message::new_signal("/", "com.example.dbusrs.MonitorTest", "Ping").unwrap().append1("hi")).unwrap();
+    c.channel().flush();
+    let msg = monitor.next().await.unwrap();
+    assert_eq!(msg.read1::<&str>().unwrap(), "hi");
+}
+
 }
@@ -0,0 +1,336 @@
+//! Generation of random values of any D-Bus type, for property based testing.
+//!
+//! The `Generator` creates `MessageItem` values for a given signature. It is biased towards
+//! edge cases: empty arrays and strings, minimum and maximum integers, infinite and subnormal
+//! doubles, non-ASCII strings and deeply nested variants. All values are valid D-Bus values,
+//! i e strings contain no NUL characters and object paths and signatures are well-formed.
+//! Doubles are never NaN, so that generated values compare equal to themselves.
+//! Unix fds are opened to `/dev/null`.
+//!
+//! With the `proptest` feature, `strategy` returns a proptest `Strategy` for a signature.
+//! With the `quickcheck` feature, `quickcheck::Gen` can be used as the random source,
+//! and `MessageItem` implements `quickcheck::Arbitrary`.
+//!
+//! # Example
+//!
+//! ```
+//! use dbus::arg::arbitrary::Generator;
+//! use dbus::arg::messageitem::MessageItem;
+//!
+//! let mut g = Generator::new(42);
+//! let items = g.items("sa{sv}").unwrap();
+//! assert_eq!(items.len(), 2);
+//! assert!(matches!(items[1], MessageItem::Dict(_)));
+//! ```
+
+use std::fs::File;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use dbus_strings::{BasicType, SignatureMulti, SignatureSingle, StringLike, Type};
+use crate::{Error, Signature, Path};
+use crate::arg::OwnedFd;
+use crate::arg::messageitem::{MessageItem, MessageItemArray, MessageItemDict};
+
+/// The maximum nesting of containers (including variants) that a Generator can be set to.
+pub const MAX_DEPTH: usize = 32;
+
+const BASIC_TYPES: &[BasicType] = &[BasicType::Byte, BasicType::Boolean, BasicType::Int16, BasicType::UInt16,
+    BasicType::Int32, BasicType::UInt32, BasicType::Int64, BasicType::UInt64, BasicType::Double, BasicType::String,
+    BasicType::ObjectPath, BasicType::Signature, BasicType::UnixFd];
+
+// Characters that tend to trigger bugs: quoting, escapes, control characters and multi-byte UTF-8.
+const SPECIAL_CHARS: &[char] = &[' ', '_', '-', '/', '.', '\'', '"', '\\', '\n', '\t', '\u{7f}',
+    'é', '€', '\u{fffd}', '\u{feff}', '😀'];
+
+const NAME_CHARS: &[u8] = b"abcxyzABCXYZ0189_";
+
+/// A source of random numbers for the Generator.
+///
+/// This is implemented for closures returning u64, so any random number generator can be used.
+pub trait Source {
+    /// Returns the next random number.
+    fn next_u64(&mut self) -> u64;
+}
+
+impl<F: FnMut() -> u64> Source for F {
+    fn next_u64(&mut self) -> u64 { self() }
+}
+
+/// A simple seeded pseudo-random number generator (SplitMix64).
+///
+/// The same seed always gives the same sequence, which makes failures reproducible.
+#[derive(Debug, Clone)]
+pub struct Seeded(u64);
+
+impl Seeded {
+    /// Creates a new random number generator from a seed.
+    pub fn new(seed: u64) -> Self { Seeded(seed) }
+}
+
+impl Source for Seeded {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+fn invalid(e: dbus_strings::InvalidStringError) -> Error {
+    Error::new_failed(&format!("Invalid signature: {}", e))
+}
+
+fn to_sig(t: &Type) -> Signature<'static> {
+    Signature::new(t.to_string()).unwrap()
+}
+
+macro_rules! edgy {
+    ($self: ident, $t: ty) => {
+        match $self.below(6) {
+            0 => 0 as $t,
+            1 => 1 as $t,
+            2 => <$t>::MIN,
+            3 => <$t>::MAX,
+            _ => $self.source.next_u64() as $t,
+        }
+    }
+}
+
+/// Generates random values for D-Bus signatures.
+#[derive(Debug, Clone)]
+pub struct Generator<S> {
+    source: S,
+    max_len: usize,
+    max_depth: usize,
+}
+
+impl Generator<Seeded> {
+    /// Creates a new generator with a seeded random number generator.
+    pub fn new(seed: u64) -> Self { Generator::with_source(Seeded::new(seed)) }
+}
+
+impl<S: Source> Generator<S> {
+    /// Creates a new generator with a custom random number source.
+    pub fn with_source(source: S) -> Self { Generator { source, max_len: 8, max_depth: 8 } }
+
+    /// Sets the maximum number of elements in arrays and dicts, and characters in strings.
+    /// The default is 8.
+    pub fn max_len(mut self, max_len: usize) -> Self { self.max_len = max_len; self }
+
+    /// Sets the maximum nesting of variants in generated values. The default is 8, and values
+    /// above `MAX_DEPTH` are capped.
+    ///
+    /// Containers in the signature asked for are always generated, even if they are nested deeper.
+    pub fn max_depth(mut self, max_depth: usize) -> Self { self.max_depth = max_depth.min(MAX_DEPTH); self }
+
+    fn below(&mut self, n: usize) -> usize { (self.source.next_u64() % n as u64) as usize }
+
+    fn chance(&mut self, one_in: usize) -> bool { self.below(one_in) == 0 }
+
+    fn len(&mut self) -> usize {
+        match self.below(8) {
+            0 | 1 => 0,
+            2 => self.max_len,
+            _ => self.below(self.max_len + 1),
+        }
+    }
+
+    fn double(&mut self) -> f64 {
+        const EDGES: &[f64] = &[0.0, -0.0, 1.0, -1.5, f64::MAX, f64::MIN, f64::MIN_POSITIVE,
+            f64::EPSILON, 5e-324, f64::INFINITY, f64::NEG_INFINITY];
+        if self.chance(2) { return EDGES[self.below(EDGES.len())] }
+        let d = f64::from_bits(self.source.next_u64());
+        if d.is_nan() { 0.0 } else { d }
+    }
+
+    fn string(&mut self) -> String {
+        (0..self.len()).map(|_| match self.below(3) {
+            0 => SPECIAL_CHARS[self.below(SPECIAL_CHARS.len())],
+            1 => std::char::from_u32(self.below(0x11000) as u32).filter(|&c| c != '\0').unwrap_or('x'),
+            _ => (b' ' + self.below(95) as u8) as char,
+        }).collect()
+    }
+
+    fn object_path(&mut self) -> Path<'static> {
+        if self.chance(4) { return Path::from("/") }
+        let mut s = String::new();
+        for _ in 0..1 + self.below(3) {
+            s.push('/');
+            for _ in 0..1 + self.below(self.max_len.max(1)) {
+                s.push(NAME_CHARS[self.below(NAME_CHARS.len())] as char);
+            }
+        }
+        Path::new(s).unwrap()
+    }
+
+    fn basic_type(&mut self) -> BasicType { BASIC_TYPES[self.below(BASIC_TYPES.len())] }
+
+    fn random_type(&mut self, depth: usize) -> Type {
+        if depth == 0 { return Type::Basic(self.basic_type()) }
+        match self.below(8) {
+            0 => Type::Variant,
+            1 => Type::Array(Box::new(self.random_type(depth - 1))),
+            2 => Type::Dict(self.basic_type(), Box::new(self.random_type(depth - 1))),
+            3 => Type::Struct((0..1 + self.below(3)).map(|_| self.random_type(depth - 1)).collect()),
+            _ => Type::Basic(self.basic_type()),
+        }
+    }
+
+    /// Returns a random signature of a single complete type.
+    pub fn signature(&mut self) -> Signature<'static> {
+        let depth = self.max_depth.min(3);
+        to_sig(&self.random_type(depth))
+    }
+
+    fn fd(&mut self) -> Result<MessageItem, Error> {
+        let f = File::open("/dev/null").map_err(|e| Error::new_failed(&e.to_string()))?;
+        Ok(MessageItem::from(unsafe { OwnedFd::from_raw_fd(f.into_raw_fd()) }))
+    }
+
+    fn basic(&mut self, b: BasicType) -> Result<MessageItem, Error> {
+        Ok(match b {
+            BasicType::Byte => MessageItem::Byte(edgy!(self, u8)),
+            BasicType::Boolean => MessageItem::Bool(self.chance(2)),
+            BasicType::Int16 => MessageItem::Int16(edgy!(self, i16)),
+            BasicType::UInt16 => MessageItem::UInt16(edgy!(self, u16)),
+            BasicType::Int32 => MessageItem::Int32(edgy!(self, i32)),
+            BasicType::UInt32 => MessageItem::UInt32(edgy!(self, u32)),
+            BasicType::Int64 => MessageItem::Int64(edgy!(self, i64)),
+            BasicType::UInt64 => MessageItem::UInt64(edgy!(self, u64)),
+            BasicType::Double => MessageItem::Double(self.double()),
+            BasicType::String => MessageItem::Str(self.string()),
+            BasicType::ObjectPath => MessageItem::ObjectPath(self.object_path()),
+            BasicType::Signature => MessageItem::Signature(self.signature()),
+            BasicType::UnixFd => self.fd()?,
+        })
+    }
+
+    fn value(&mut self, t: &Type, depth: usize) -> Result<MessageItem, Error> {
+        Ok(match t {
+            Type::Basic(b) => self.basic(*b)?,
+            Type::Variant => {
+                let left = self.max_depth.saturating_sub(depth + 1);
+                // Nest variants all the way down every now and then
+                let inner = if left > 0 && self.chance(8) { Type::Variant } else { self.random_type(left.min(3)) };
+                MessageItem::Variant(Box::new(self.value(&inner, depth + 1)?))
+            },
+            Type::Dict(kt, vt) => {
+                let mut v: Vec<(MessageItem, MessageItem)> = vec!();
+                for _ in 0..self.len() {
+                    let k = self.basic(*kt)?;
+                    if v.iter().any(|(k2, _)| *k2 == k) { continue }
+                    let val = self.value(vt, depth + 1)?;
+                    v.push((k, val));
+                }
+                let d = MessageItemDict::new(v, to_sig(&Type::Basic(*kt)), to_sig(vt));
+                MessageItem::Dict(d.map_err(|e| Error::new_failed(&format!("{:?}", e)))?)
+            },
+            Type::Array(et) => {
+                let v = (0..self.len()).map(|_| self.value(et, depth + 1)).collect::<Result<_, _>>()?;
+                let a = MessageItemArray::new(v, to_sig(t));
+                MessageItem::Array(a.map_err(|e| Error::new_failed(&format!("{:?}", e)))?)
+            },
+            Type::Struct(fields) => {
+                MessageItem::Struct(fields.iter().map(|f| self.value(f, depth + 1)).collect::<Result<_, _>>()?)
+            },
+        })
+    }
+
+    /// Generates a value for a signature of a single complete type.
+    pub fn item(&mut self, sig: &Signature) -> Result<MessageItem, Error> {
+        let t = SignatureSingle::new(sig).map_err(invalid)?.parse();
+        self.value(&t, 0)
+    }
+
+    /// Generates a value for every complete type in the signature, e g method call arguments.
+    pub fn items(&mut self, sig: &str) -> Result<Vec<MessageItem>, Error> {
+        let types = SignatureMulti::new(sig).map_err(invalid)?.parse();
+        types.iter().map(|t| self.value(t, 0)).collect()
+    }
+}
+
+/// Returns a proptest strategy generating arguments for a signature.
+///
+/// Values are not shrunk, but the seed of a failing case is reported by proptest.
+///
+/// Panics if the signature is invalid.
+#[cfg(feature = "proptest")]
+pub fn strategy(sig: &str) -> proptest::strategy::BoxedStrategy<Vec<MessageItem>> {
+    use proptest::strategy::Strategy;
+    SignatureMulti::new(sig).unwrap();
+    let sig = sig.to_string();
+    proptest::arbitrary::any::<u64>().prop_map(move |seed| Generator::new(seed).items(&sig).unwrap()).boxed()
+}
+
+#[cfg(feature = "quickcheck")]
+impl Source for quickcheck::Gen {
+    fn next_u64(&mut self) -> u64 { <u64 as quickcheck::Arbitrary>::arbitrary(self) }
+}
+
+/// Generates a value of a random type, using `Gen::size` as the maximum length.
+#[cfg(feature = "quickcheck")]
+impl quickcheck::Arbitrary for MessageItem {
+    fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+        let max_len = g.size();
+        let mut g = Generator::with_source(g).max_len(max_len);
+        let sig = g.signature();
+        g.item(&sig).unwrap()
+    }
+}
+
+#[cfg(feature = "quickcheck")]
+impl Source for &mut quickcheck::Gen {
+    fn next_u64(&mut self) -> u64 { (**self).next_u64() }
+}
+
+#[test]
+fn generate() {
+    use crate::Message;
+    fn has_fd(i: &MessageItem) -> bool {
+        match i {
+            MessageItem::UnixFd(_) => true,
+            MessageItem::Variant(v) => has_fd(v),
+            MessageItem::Struct(v) => v.iter().any(has_fd),
+            MessageItem::Array(a) => a.iter().any(has_fd),
+            MessageItem::Dict(d) => d.iter().any(|(k, v)| has_fd(k) || has_fd(v)),
+            _ => false,
+        }
+    }
+    let sigs = ["y", "b", "n", "q", "i", "u", "x", "t", "d", "s", "o", "g", "h", "v", "as", "a{sv}",
+        "(ia{ub}av)", "aaay", "a{oa{sa{sv}}}", "a(oa{sv})", "sv"];
+    let mut g = Generator::new(1).max_depth(MAX_DEPTH);
+    let (mut empty, mut deep) = (false, false);
+    for sig in sigs.iter() {
+        for _ in 0..50 {
+            let items = g.items(sig).unwrap();
+            let s: String = items.iter().map(|i| i.signature().to_string()).collect();
+            assert_eq!(*sig, s);
+            for i in &items {
+                if let MessageItem::Array(a) = i { empty |= a.is_empty() }
+                let mut v = i;
+                let mut n = 0;
+                while let MessageItem::Variant(x) = v { v = x; n += 1 }
+                deep |= n >= 4;
+            }
+            // Must be accepted by libdbus. Unix fds are not marshalled, so skip those.
+            if items.iter().any(has_fd) { continue }
+            let mut m = Message::new_method_call("a.b", "/", "a.b", "c").unwrap();
+            m.append_items(&items);
+            m.set_serial(1);
+            let mut data = vec!();
+            m.marshal(|b| { data.extend_from_slice(b); Ok::<_, ()>(()) }).unwrap();
+            let m = Message::demarshal(&data).unwrap();
+            assert_eq!(m.get_items().len(), items.len());
+        }
+    }
+    assert!(empty);
+    assert!(deep);
+
+    // Signatures that are not valid give an error
+    assert!(g.items("a{(i)s}").is_err());
+    assert!(g.items("a{vs}").is_err());
+
+    // Same seed, same values
+    let a = Generator::new(7).items("a{s(dv)}").unwrap();
+    assert_eq!(a, Generator::new(7).items("a{s(dv)}").unwrap());
+}
@@ -585,6 +585,54 @@ impl arg::RefArg for MessageItem {
     fn as_any(&self) -> &dyn any::Any where Self: 'static { self }
     #[inline]
     fn as_any_mut(&mut self) -> &mut dyn any::Any where Self: 'static { self }
+    fn as_i64(&self) -> Option<i64> {
+        match self {
+            MessageItem::Bool(a) => Some(*a as i64),
+            MessageItem::Byte(a) => Some(*a as i64),
+            MessageItem::Int16(a) => Some(*a as i64),
+            MessageItem::UInt16(a) => Some(*a as i64),
+            MessageItem::Int32(a) => Some(*a as i64),
+            MessageItem::UInt32(a) => Some(*a as i64),
+            MessageItem::Int64(a) => Some(*a),
+            _ => None,
+        }
+    }
+    fn as_u64(&self) -> Option<u64> {
+        match self {
+            MessageItem::Bool(a) => Some(*a as u64),
+            MessageItem::Byte(a) => Some(*a as u64),
+            MessageItem::UInt16(a) => Some(*a as u64),
+            MessageItem::UInt32(a) => Some(*a as u64),
+            MessageItem::UInt64(a) => Some(*a),
+            _ => None,
+        }
+    }
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            MessageItem::Double(a) => Some(*a),
+            MessageItem::Bool(_) | MessageItem::Byte(_) | MessageItem::Int16(_) |
+            MessageItem::UInt16(_) | MessageItem::Int32(_) | MessageItem::UInt32(_) => self.as_i64().map(|a| a as f64),
+            _ => None,
+        }
+    }
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            MessageItem::Str(a) => Some(a),
+            MessageItem::ObjectPath(a) => Some(a),
+            MessageItem::Signature(a) => Some(a),
+            _ => None,
+        }
+    }
+    fn as_iter<'a>(&'a self) -> Option<Box<dyn Iterator<Item=&'a dyn arg::RefArg> + 'a>> {
+        match self {
+            MessageItem::Array(a) => Some(Box::new(a.iter().map(|x| x as &dyn arg::RefArg))),
+            MessageItem::Dict(a) => Some(Box::new(a.iter().flat_map(|(k, v)|
+                std::iter::once(k as &dyn arg::RefArg).chain(std::iter::once(v as &dyn arg::RefArg))))),
+            MessageItem::Struct(a) => Some(Box::new(a.iter().map(|x| x as &dyn arg::RefArg))),
+            MessageItem::Variant(a) => Some(Box::new(std::iter::once(&**a as &dyn arg::RefArg))),
+            _ => None,
+        }
+    }
     #[inline]
     fn box_clone(&self) -> Box<dyn arg::RefArg + 'static> { Box::new(self.clone()) }
 }
@@ -14,6 +14,11 @@ mod array_impl;
 
 pub mod messageitem;
 
+pub mod text;
+
+#[cfg(unix)]
+pub mod arbitrary;
+
 pub use self::msgarg::{Arg, FixedArray, Get, DictKey, Append, RefArg, AppendAll, ReadAll, ArgAll,
     cast, cast_mut, prop_cast, PropMap};
 pub use self::array_impl::{Array, Dict};
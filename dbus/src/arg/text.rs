@@ -0,0 +1,847 @@
+//! Conversion between D-Bus values and textual command line arguments.
+//!
+//! Three syntaxes are supported: the one used by `dbus-send`, where every argument is prefixed by its
+//! type (e g `string:hello` or `array:int32:1,2,3`), the one used by `busctl`, where the
+//! signature is given separately and containers are written as a length followed by their
+//! elements (e g `as 2 hello world` or `a{sv} 1 key s value`), and the GVariant text format
+//! used by GLib tools such as `gdbus call` (e g `['hello', 'world']` or `{'key': <uint32 5>}`).
+//!
+//! # Example
+//!
+//! ```
+//! use dbus::arg::text::{parse, format, Syntax};
+//!
+//! let args = parse("sa{sv}", &["hello", "1", "key", "u", "5"], Syntax::Busctl)?;
+//! let msg = dbus::Message::new_signal("/", "com.example.Foo", "Bar")?.append_ref(&args);
+//! let text = format(&mut msg.iter_init(), Syntax::DBusSend)?;
+//! assert_eq!(text, vec!["string:hello", "dict:string:variant:key,uint32:5"]);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::{error, fmt};
+use std::convert::TryFrom;
+use dbus_strings::{BasicType, Type, SignatureMulti, SignatureSingle, StringLike};
+use crate::arg::{Iter, Get, RefArg};
+use crate::arg::messageitem::{MessageItem, MessageItemArray, MessageItemDict};
+use crate::strings::{Path, Signature};
+
+/// Which command line tool's argument syntax to use.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Syntax {
+    /// The syntax of `dbus-send`, e g `string:hello` or `dict:string:int32:one,1,two,2`.
+    ///
+    /// This syntax can only express basic types, variants of basic types, arrays of basic types,
+    /// and dictionaries with basic keys and basic or variant values.
+    DBusSend,
+    /// The syntax of `busctl`, e g `hello` or `2 one 1 two 2` for signature `s` and `a{si}`.
+    Busctl,
+    /// The GVariant text format, e g `'hello'` or `{'one': 1, 'two': 2}`, one value per argument.
+    ///
+    /// When formatting, type annotations such as `uint32 5` or `@as []` are added wherever
+    /// the type could not otherwise be inferred from the text.
+    GVariant,
+}
+
+/// Error returned when arguments could not be parsed or formatted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextError(String);
+
+impl fmt::Display for TextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(&self.0) }
+}
+
+impl error::Error for TextError {}
+
+impl From<TextError> for crate::Error {
+    fn from(e: TextError) -> crate::Error {
+        crate::Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs", &e.0)
+    }
+}
+
+fn err<T>(s: String) -> Result<T, TextError> { Err(TextError(s)) }
+
+fn dbus_send_name(b: BasicType) -> Option<&'static str> {
+    use BasicType::*;
+    Some(match b {
+        Byte => "byte",
+        Boolean => "boolean",
+        Int16 => "int16",
+        UInt16 => "uint16",
+        Int32 => "int32",
+        UInt32 => "uint32",
+        Int64 => "int64",
+        UInt64 => "uint64",
+        Double => "double",
+        String => "string",
+        ObjectPath => "objpath",
+        Signature => "signature",
+        UnixFd => return None,
+    })
+}
+
+fn dbus_send_type(s: &str) -> Result<BasicType, TextError> {
+    use BasicType::*;
+    [Byte, Boolean, Int16, UInt16, Int32, UInt32, Int64, UInt64, Double, String, ObjectPath, Signature].iter()
+        .find(|&&b| dbus_send_name(b) == Some(s)).copied()
+        .ok_or_else(|| TextError(format!("Unknown type name {:?}", s)))
+}
+
+fn parse_basic(b: BasicType, s: &str) -> Result<MessageItem, TextError> {
+    fn num<T: std::str::FromStr>(b: BasicType, s: &str) -> Result<T, TextError> {
+        s.parse().map_err(|_| TextError(format!("Invalid value {:?} for type '{}'", s, b)))
+    }
+    use BasicType::*;
+    Ok(match b {
+        Byte => MessageItem::Byte(num(b, s)?),
+        Boolean => MessageItem::Bool(match s {
+            "true" | "yes" | "on" | "1" => true,
+            "false" | "no" | "off" | "0" => false,
+            _ => return err(format!("Invalid value {:?} for type 'b'", s)),
+        }),
+        Int16 => MessageItem::Int16(num(b, s)?),
+        UInt16 => MessageItem::UInt16(num(b, s)?),
+        Int32 => MessageItem::Int32(num(b, s)?),
+        UInt32 => MessageItem::UInt32(num(b, s)?),
+        Int64 => MessageItem::Int64(num(b, s)?),
+        UInt64 => MessageItem::UInt64(num(b, s)?),
+        Double => MessageItem::Double(num(b, s)?),
+        String => {
+            if s.contains('\0') { return err(format!("String {:?} contains a nul byte", s)) }
+            MessageItem::Str(s.into())
+        }
+        ObjectPath => MessageItem::ObjectPath(Path::new(s).map_err(TextError)?),
+        Signature => MessageItem::Signature(crate::strings::Signature::new(s).map_err(TextError)?),
+        UnixFd => return err("Unix file descriptors cannot be given as text".into()),
+    })
+}
+
+fn sig_of(t: &Type) -> Signature<'static> { Signature::from(t) }
+
+fn parse_dbus_send(t: &Type, arg: &str) -> Result<MessageItem, TextError> {
+    let mut parts = arg.splitn(2, ':');
+    let tn = parts.next().unwrap();
+    let rest = parts.next().ok_or_else(|| TextError(format!("Expected 'type:value', got {:?}", arg)))?;
+    let mismatch = |got: &str| TextError(format!("Expected an argument of type '{}', got {:?}", t, got));
+    match (tn, t) {
+        ("array", Type::Array(et)) => {
+            let mut p = rest.splitn(2, ':');
+            let b = dbus_send_type(p.next().unwrap())?;
+            if **et != Type::Basic(b) { return Err(mismatch(arg)) }
+            let values = p.next().unwrap_or("");
+            let v = if values.is_empty() { vec!() } else {
+                values.split(',').map(|s| parse_basic(b, s)).collect::<Result<_, _>>()?
+            };
+            Ok(MessageItem::Array(MessageItemArray::new(v, sig_of(t)).unwrap()))
+        }
+        ("dict", Type::Dict(kt, vt)) => {
+            let mut p = rest.splitn(3, ':');
+            let k = dbus_send_type(p.next().unwrap())?;
+            let vn = p.next().ok_or_else(|| mismatch(arg))?;
+            let v = if vn == "variant" { Type::Variant } else { Type::Basic(dbus_send_type(vn)?) };
+            if *kt != k || **vt != v { return Err(mismatch(arg)) }
+            let values = p.next().unwrap_or("");
+            let mut items = vec!();
+            let mut values = values.split(',').filter(|s| !s.is_empty());
+            while let Some(key) = values.next() {
+                let value = values.next().ok_or_else(|| TextError(format!("Dict key {:?} has no value", key)))?;
+                let value = if let Type::Basic(vb) = v { parse_basic(vb, value)? } else { parse_dbus_send_variant(value)? };
+                items.push((parse_basic(k, key)?, value));
+            }
+            Ok(MessageItem::Dict(MessageItemDict::new(items, sig_of(&Type::Basic(k)), sig_of(&v)).unwrap()))
+        }
+        ("variant", Type::Variant) => parse_dbus_send_variant(rest),
+        ("array", _) | ("dict", _) | ("variant", _) => Err(mismatch(arg)),
+        (tn, Type::Basic(b)) if dbus_send_type(tn)? == *b => parse_basic(*b, rest),
+        _ => Err(mismatch(arg)),
+    }
+}
+
+fn parse_dbus_send_variant(s: &str) -> Result<MessageItem, TextError> {
+    let mut p = s.splitn(2, ':');
+    let b = dbus_send_type(p.next().unwrap())?;
+    let value = p.next().ok_or_else(|| TextError(format!("Expected 'type:value', got {:?}", s)))?;
+    Ok(MessageItem::Variant(Box::new(parse_basic(b, value)?)))
+}
+
+fn parse_busctl<'b, I: Iterator<Item=&'b str>>(t: &Type, args: &mut I) -> Result<MessageItem, TextError> {
+    let mut next = || args.next().ok_or_else(|| TextError(format!("Missing value for type '{}'", t)));
+    Ok(match t {
+        Type::Basic(b) => parse_basic(*b, next()?)?,
+        Type::Array(et) => {
+            let n = parse_count(next()?)?;
+            let v = (0..n).map(|_| parse_busctl(et, args)).collect::<Result<_, _>>()?;
+            MessageItem::Array(MessageItemArray::new(v, sig_of(t)).unwrap())
+        }
+        Type::Dict(kt, vt) => {
+            let n = parse_count(next()?)?;
+            let kt = Type::Basic(*kt);
+            let v = (0..n).map(|_| Ok((parse_busctl(&kt, args)?, parse_busctl(vt, args)?)))
+                .collect::<Result<_, TextError>>()?;
+            MessageItem::Dict(MessageItemDict::new(v, sig_of(&kt), sig_of(vt)).unwrap())
+        }
+        Type::Struct(fields) => MessageItem::Struct(
+            fields.iter().map(|f| parse_busctl(f, args)).collect::<Result<_, _>>()?
+        ),
+        Type::Variant => {
+            let s = next()?;
+            let vt = SignatureSingle::new(s).map_err(|_| TextError(format!("Invalid variant signature {:?}", s)))?.parse();
+            MessageItem::Variant(Box::new(parse_busctl(&vt, args)?))
+        }
+    })
+}
+
+fn parse_count(s: &str) -> Result<usize, TextError> {
+    s.parse().map_err(|_| TextError(format!("Invalid element count {:?}", s)))
+}
+
+/// Parses textual arguments according to the signature `sig`, which may contain zero or more
+/// complete types.
+///
+/// With `Syntax::DBusSend` and `Syntax::GVariant`, there must be one textual argument per type
+/// in `sig`, and any type given in the text must match. With `Syntax::Busctl`, the types in
+/// `sig` decide how the arguments are consumed.
+pub fn parse<S: AsRef<str>>(sig: &str, args: &[S], syntax: Syntax) -> Result<Vec<Box<dyn RefArg>>, TextError> {
+    let types = SignatureMulti::new(sig).map_err(|_| TextError(format!("Invalid signature {:?}", sig)))?.parse();
+    let mut args = args.iter().map(|s| s.as_ref());
+    let mut r: Vec<Box<dyn RefArg>> = vec!();
+    for (idx, t) in types.iter().enumerate() {
+        let item = match syntax {
+            Syntax::DBusSend => {
+                let arg = args.next().ok_or_else(|| TextError(format!("Missing argument {} of type '{}'", idx, t)))?;
+                parse_dbus_send(t, arg)
+            }
+            Syntax::Busctl => parse_busctl(t, &mut args),
+            Syntax::GVariant => {
+                let arg = args.next().ok_or_else(|| TextError(format!("Missing argument {} of type '{}'", idx, t)))?;
+                GvParser { s: arg, pos: 0, depth: 0 }.parse_all(t)
+            }
+        };
+        let item = item.map_err(|e| TextError(format!("Argument {}: {}", idx, e.0)))?;
+        r.push(Box::new(item));
+    }
+    if let Some(extra) = args.next() {
+        return err(format!("Unexpected extra argument {:?}", extra));
+    }
+    Ok(r)
+}
+
+fn format_basic(m: &MessageItem) -> Result<String, TextError> {
+    Ok(match m {
+        MessageItem::Str(s) => s.clone(),
+        MessageItem::Bool(b) => b.to_string(),
+        MessageItem::Byte(b) => b.to_string(),
+        MessageItem::Int16(b) => b.to_string(),
+        MessageItem::Int32(b) => b.to_string(),
+        MessageItem::Int64(b) => b.to_string(),
+        MessageItem::UInt16(b) => b.to_string(),
+        MessageItem::UInt32(b) => b.to_string(),
+        MessageItem::UInt64(b) => b.to_string(),
+        MessageItem::Double(b) => b.to_string(),
+        MessageItem::ObjectPath(b) => b.to_string(),
+        MessageItem::Signature(b) => b.to_string(),
+        MessageItem::UnixFd(_) => return err("Unix file descriptors cannot be written as text".into()),
+        _ => unreachable!(),
+    })
+}
+
+fn basic_type(m: &MessageItem) -> Option<BasicType> {
+    let sig = m.signature();
+    BasicType::from_code(sig.as_bytes()[0]).filter(|_| sig.len() == 1)
+}
+
+fn format_dbus_send_basic(m: &MessageItem, in_list: bool) -> Result<String, TextError> {
+    let s = format_basic(m)?;
+    if in_list && s.contains(',') {
+        return err(format!("Value {:?} contains a comma, which cannot be written in dbus-send syntax", s));
+    }
+    Ok(s)
+}
+
+fn format_dbus_send_variant(m: &MessageItem, in_list: bool) -> Result<String, TextError> {
+    let inner = match m { MessageItem::Variant(v) => &**v, _ => unreachable!() };
+    let name = basic_type(inner).and_then(dbus_send_name).ok_or_else(|| unsupported(m))?;
+    Ok(format!("{}:{}", name, format_dbus_send_basic(inner, in_list)?))
+}
+
+fn unsupported(m: &MessageItem) -> TextError {
+    TextError(format!("Type '{}' cannot be written in dbus-send syntax", m.signature()))
+}
+
+fn format_dbus_send(m: &MessageItem) -> Result<String, TextError> {
+    let sig = m.signature();
+    let t = SignatureSingle::new(&sig).unwrap().parse();
+    match (m, &t) {
+        (MessageItem::Array(a), Type::Array(et)) => {
+            let b = match **et { Type::Basic(b) => b, _ => return Err(unsupported(m)) };
+            let name = dbus_send_name(b).ok_or_else(|| unsupported(m))?;
+            let v = a.iter().map(|i| format_dbus_send_basic(i, true)).collect::<Result<Vec<_>, _>>()?;
+            Ok(format!("array:{}:{}", name, v.join(",")))
+        }
+        (MessageItem::Dict(d), Type::Dict(kt, vt)) => {
+            let kname = dbus_send_name(*kt).ok_or_else(|| unsupported(m))?;
+            let vname = match **vt {
+                Type::Basic(b) => dbus_send_name(b).ok_or_else(|| unsupported(m))?,
+                Type::Variant => "variant",
+                _ => return Err(unsupported(m)),
+            };
+            let mut v = vec!();
+            for (key, value) in d.iter() {
+                v.push(format_dbus_send_basic(key, true)?);
+                v.push(if **vt == Type::Variant { format_dbus_send_variant(value, true)? }
+                    else { format_dbus_send_basic(value, true)? });
+            }
+            Ok(format!("dict:{}:{}:{}", kname, vname, v.join(",")))
+        }
+        (MessageItem::Variant(_), _) => Ok(format!("variant:{}", format_dbus_send_variant(m, false)?)),
+        (_, Type::Basic(b)) => {
+            let name = dbus_send_name(*b).ok_or_else(|| unsupported(m))?;
+            Ok(format!("{}:{}", name, format_dbus_send_basic(m, false)?))
+        }
+        _ => Err(unsupported(m)),
+    }
+}
+
+fn format_busctl(m: &MessageItem, r: &mut Vec<String>) -> Result<(), TextError> {
+    match m {
+        MessageItem::Array(a) => {
+            r.push(a.len().to_string());
+            for i in a.iter() { format_busctl(i, r)? }
+        }
+        MessageItem::Dict(d) => {
+            r.push(d.len().to_string());
+            for (k, v) in d.iter() {
+                format_busctl(k, r)?;
+                format_busctl(v, r)?;
+            }
+        }
+        MessageItem::Struct(s) => for i in s { format_busctl(i, r)? },
+        MessageItem::Variant(v) => {
+            r.push(v.signature().to_string());
+            format_busctl(v, r)?;
+        }
+        _ => r.push(format_basic(m)?),
+    }
+    Ok(())
+}
+
+/// Formats the remaining arguments of `i` as text, in a way that `parse` can read back.
+///
+/// The signature of the arguments is not included in the output; use `Message::signature` to
+/// get it if needed, e g for the `Busctl` syntax.
+/// Unix file descriptors have no text form, so arguments containing them give an error.
+pub fn format(i: &mut Iter, syntax: Syntax) -> Result<Vec<String>, TextError> {
+    let mut r = vec!();
+    while let Some(m) = MessageItem::get(i) {
+        if syntax == Syntax::GVariant && m.signature().contains('h') {
+            return err("Unix file descriptors cannot be written as text".into());
+        }
+        match syntax {
+            Syntax::DBusSend => r.push(format_dbus_send(&m)?),
+            Syntax::Busctl => format_busctl(&m, &mut r)?,
+            Syntax::GVariant => r.push(GVariantDisplay(&m).to_string()),
+        }
+        i.next();
+    }
+    Ok(r)
+}
+
+/// Displays a value in GVariant text format, with type annotations where needed.
+///
+/// ```
+/// use dbus::arg::{Variant, text::GVariantDisplay};
+/// let v = (vec!["a".to_string()], Variant(5u32), 2.0f64);
+/// assert_eq!(GVariantDisplay(&v).to_string(), "(['a'], <uint32 5>, 2.0)");
+/// ```
+///
+/// Unix file descriptors have no text form. So that values containing them can still be
+/// displayed (e g in logs), they are written as `handle -1`, which `parse` does not accept;
+/// `format` returns an error for them instead.
+pub struct GVariantDisplay<'a>(pub &'a dyn RefArg);
+
+impl fmt::Display for GVariantDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sig = self.0.signature();
+        let t = SignatureSingle::new(&sig).map_err(|_| fmt::Error)?.parse();
+        gv_write(f, self.0, &t, true)
+    }
+}
+
+fn gv_keyword(b: BasicType) -> &'static str {
+    use BasicType::*;
+    match b {
+        Byte => "byte",
+        Boolean => "boolean",
+        Int16 => "int16",
+        UInt16 => "uint16",
+        Int32 => "int32",
+        UInt32 => "uint32",
+        Int64 => "int64",
+        UInt64 => "uint64",
+        Double => "double",
+        UnixFd => "handle",
+        String => "string",
+        ObjectPath => "objectpath",
+        Signature => "signature",
+    }
+}
+
+fn gv_write_str(f: &mut dyn fmt::Write, s: &str) -> fmt::Result {
+    let q = if s.contains('\'') && !s.contains('"') { '"' } else { '\'' };
+    f.write_char(q)?;
+    for c in s.chars() {
+        match c {
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\t' => f.write_str("\\t")?,
+            '\r' => f.write_str("\\r")?,
+            '\x07' => f.write_str("\\a")?,
+            '\x08' => f.write_str("\\b")?,
+            '\x0b' => f.write_str("\\v")?,
+            '\x0c' => f.write_str("\\f")?,
+            c if c == q => { f.write_char('\\')?; f.write_char(c)? },
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char(q)
+}
+
+fn gv_write_basic(f: &mut dyn fmt::Write, a: &dyn RefArg, b: BasicType, annotate: bool) -> fmt::Result {
+    use BasicType::*;
+    if annotate && !matches!(b, Boolean | Int32 | Double | String) {
+        write!(f, "{} ", gv_keyword(b))?;
+    }
+    match b {
+        Boolean => f.write_str(if a.as_i64().ok_or(fmt::Error)? != 0 { "true" } else { "false" }),
+        Byte => write!(f, "0x{:02x}", a.as_u64().ok_or(fmt::Error)?),
+        Int16 | Int32 | Int64 => write!(f, "{}", a.as_i64().ok_or(fmt::Error)?),
+        UInt16 | UInt32 | UInt64 => write!(f, "{}", a.as_u64().ok_or(fmt::Error)?),
+        Double => {
+            let d = a.as_f64().ok_or(fmt::Error)?;
+            if d.is_nan() { f.write_str("nan") }
+            else if d.is_infinite() { f.write_str(if d > 0.0 { "inf" } else { "-inf" }) }
+            else { write!(f, "{:?}", d) }
+        }
+        UnixFd => f.write_str("-1"),
+        String | ObjectPath | Signature => gv_write_str(f, a.as_str().ok_or(fmt::Error)?),
+    }
+}
+
+fn gv_write(f: &mut dyn fmt::Write, a: &dyn RefArg, t: &Type, annotate: bool) -> fmt::Result {
+    match t {
+        Type::Basic(b) => gv_write_basic(f, a, *b, annotate),
+        Type::Array(et) => {
+            let mut items = a.as_iter().ok_or(fmt::Error)?.peekable();
+            if items.peek().is_none() {
+                if annotate { write!(f, "@{} ", t)? }
+                return f.write_str("[]");
+            }
+            f.write_char('[')?;
+            for (idx, i) in items.enumerate() {
+                if idx > 0 { f.write_str(", ")? }
+                gv_write(f, i, et, annotate && idx == 0)?;
+            }
+            f.write_char(']')
+        }
+        Type::Dict(kt, vt) => {
+            let kt = Type::Basic(*kt);
+            let mut items = a.as_iter().ok_or(fmt::Error)?.peekable();
+            if items.peek().is_none() {
+                if annotate { write!(f, "@{} ", t)? }
+                return f.write_str("{}");
+            }
+            f.write_char('{')?;
+            let mut idx = 0;
+            while let Some(k) = items.next() {
+                let v = items.next().ok_or(fmt::Error)?;
+                if idx > 0 { f.write_str(", ")? }
+                gv_write(f, k, &kt, annotate && idx == 0)?;
+                f.write_str(": ")?;
+                gv_write(f, v, vt, annotate && idx == 0)?;
+                idx += 1;
+            }
+            f.write_char('}')
+        }
+        Type::Struct(fields) => {
+            let items = a.as_iter().ok_or(fmt::Error)?;
+            f.write_char('(')?;
+            for (idx, (i, ft)) in items.zip(fields).enumerate() {
+                if idx > 0 { f.write_str(", ")? }
+                gv_write(f, i, ft, annotate)?;
+            }
+            f.write_str(if fields.len() == 1 { ",)" } else { ")" })
+        }
+        Type::Variant => {
+            let inner = a.as_iter().ok_or(fmt::Error)?.next().ok_or(fmt::Error)?;
+            let sig = inner.signature();
+            let it = SignatureSingle::new(&sig).map_err(|_| fmt::Error)?.parse();
+            f.write_char('<')?;
+            gv_write(f, inner, &it, true)?;
+            f.write_char('>')
+        }
+    }
+}
+
+// Nesting of values allowed in GVariant text, deeper nesting is rejected instead of overflowing the stack.
+const GV_MAX_DEPTH: usize = 64;
+
+struct GvParser<'s> {
+    s: &'s str,
+    pos: usize,
+    depth: usize,
+}
+
+impl GvParser<'_> {
+    fn rest(&self) -> &str { &self.s[self.pos..] }
+
+    fn skip_ws(&mut self) {
+        let r = self.rest();
+        self.pos += r.len() - r.trim_start().len();
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_ws();
+        self.rest().chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) { self.pos += c.len_utf8(); true } else { false }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), TextError> {
+        if self.eat(c) { Ok(()) } else { Err(self.error(&format!("expected '{}'", c))) }
+    }
+
+    fn error(&self, msg: &str) -> TextError {
+        TextError(format!("At position {}: {}", self.pos, msg))
+    }
+
+    /// Reads a run of characters that can be part of a keyword or number.
+    fn number_token(&mut self) -> &str {
+        self.skip_ws();
+        let start = self.pos;
+        let len = self.rest().find(|c: char| !(c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.'))
+            .unwrap_or(self.rest().len());
+        self.pos += len;
+        &self.s[start..self.pos]
+    }
+
+    fn parse_all(mut self, t: &Type) -> Result<MessageItem, TextError> {
+        let r = self.value(Some(t))?;
+        self.skip_ws();
+        if !self.rest().is_empty() { return Err(self.error("unexpected trailing text")) }
+        Ok(r)
+    }
+
+    fn type_annotation(&mut self) -> Result<Option<Type>, TextError> {
+        if self.eat('@') {
+            self.skip_ws();
+            let start = self.pos;
+            let len = self.rest().find(char::is_whitespace).unwrap_or(self.rest().len());
+            let sig = &self.s[start..start + len];
+            let t = SignatureSingle::new(sig).map_err(|_| self.error(&format!("invalid type {:?}", sig)))?.parse();
+            self.pos += len;
+            return Ok(Some(t));
+        }
+        let start = self.pos;
+        let w = self.number_token();
+        use BasicType::*;
+        let b = [Byte, Boolean, Int16, UInt16, Int32, UInt32, Int64, UInt64, Double, UnixFd, String, ObjectPath, Signature]
+            .iter().find(|&&b| gv_keyword(b) == w).copied();
+        if b.is_none() { self.pos = start; }
+        Ok(b.map(Type::Basic))
+    }
+
+    fn value(&mut self, expected: Option<&Type>) -> Result<MessageItem, TextError> {
+        if self.depth >= GV_MAX_DEPTH { return Err(self.error("values nested too deeply")) }
+        self.depth += 1;
+        let r = self.value_inner(expected);
+        self.depth -= 1;
+        r
+    }
+
+    fn value_inner(&mut self, expected: Option<&Type>) -> Result<MessageItem, TextError> {
+        self.skip_ws();
+        let start = self.pos;
+        if let Some(at) = self.type_annotation()? {
+            if let Some(t) = expected {
+                if *t != at {
+                    self.pos = start;
+                    return Err(self.error(&format!("type '{}' given where '{}' was expected", at, t)));
+                }
+            }
+            return self.typed_value(&at);
+        }
+        match expected {
+            Some(t) => self.typed_value(t),
+            None => self.infer_value(),
+        }
+    }
+
+    fn item_type(&self, item: &MessageItem) -> Result<Type, TextError> {
+        item.signature().parse_single().ok_or_else(|| self.error("invalid element type"))
+    }
+
+    fn infer_value(&mut self) -> Result<MessageItem, TextError> {
+        let t = match self.peek() {
+            Some('\'') | Some('"') => Type::Basic(BasicType::String),
+            Some('<') => Type::Variant,
+            Some('t') | Some('f') => Type::Basic(BasicType::Boolean),
+            Some('[') => {
+                self.pos += 1;
+                if self.eat(']') { return Err(self.error("cannot infer the type of an empty array")) }
+                let first = self.value(None)?;
+                let et = self.item_type(&first)?;
+                return self.array_rest(vec!(first), &et);
+            }
+            Some('{') => {
+                self.pos += 1;
+                if self.eat('}') { return Err(self.error("cannot infer the type of an empty dictionary")) }
+                let k = self.value(None)?;
+                self.expect(':')?;
+                let v = self.value(None)?;
+                let kt = self.item_type(&k)?;
+                let vt = self.item_type(&v)?;
+                return self.dict_rest(vec!((k, v)), &kt, &vt);
+            }
+            Some('(') => {
+                self.pos += 1;
+                let mut v = vec!();
+                while !self.eat(')') {
+                    if !v.is_empty() {
+                        self.expect(',')?;
+                        if self.eat(')') { break }
+                    }
+                    v.push(self.value(None)?);
+                }
+                if v.is_empty() { return Err(self.error("empty structs are not allowed")) }
+                return Ok(MessageItem::Struct(v));
+            }
+            Some(_) => {
+                let start = self.pos;
+                let w = self.number_token().to_ascii_lowercase();
+                self.pos = start;
+                let is_hex = w.trim_start_matches(&['-', '+'][..]).starts_with("0x");
+                if !is_hex && (w.contains('.') || w.contains('e') || w.contains("inf") || w.contains("nan")) {
+                    Type::Basic(BasicType::Double)
+                } else { Type::Basic(BasicType::Int32) }
+            }
+            None => return Err(self.error("unexpected end of text")),
+        };
+        self.typed_value(&t)
+    }
+
+    fn array_rest(&mut self, mut v: Vec<MessageItem>, et: &Type) -> Result<MessageItem, TextError> {
+        while !self.eat(']') {
+            self.expect(',')?;
+            v.push(self.value(Some(et))?);
+        }
+        Ok(MessageItem::Array(MessageItemArray::new(v, Signature::new(format!("a{}", et)).unwrap()).unwrap()))
+    }
+
+    fn dict_rest(&mut self, mut v: Vec<(MessageItem, MessageItem)>, kt: &Type, vt: &Type) -> Result<MessageItem, TextError> {
+        if !kt.is_basic() { return Err(self.error(&format!("dictionary key type '{}' is not basic", kt))) }
+        while !self.eat('}') {
+            self.expect(',')?;
+            let k = self.value(Some(kt))?;
+            self.expect(':')?;
+            v.push((k, self.value(Some(vt))?));
+        }
+        Ok(MessageItem::Dict(MessageItemDict::new(v, sig_of(kt), sig_of(vt)).unwrap()))
+    }
+
+    fn integer(&mut self) -> Result<i128, TextError> {
+        let start = self.pos;
+        let w = self.number_token();
+        let (neg, digits) = match w.as_bytes().first() {
+            Some(b'-') => (true, &w[1..]),
+            Some(b'+') => (false, &w[1..]),
+            _ => (false, w),
+        };
+        let r = if digits.starts_with("0x") || digits.starts_with("0X") {
+            i128::from_str_radix(&digits[2..], 16)
+        } else { digits.parse() };
+        match r {
+            Ok(r) => Ok(if neg { -r } else { r }),
+            Err(_) => { self.pos = start; Err(self.error("expected an integer")) }
+        }
+    }
+
+    fn quoted(&mut self) -> Result<std::string::String, TextError> {
+        let q = match self.peek() {
+            Some(q @ '\'') | Some(q @ '"') => q,
+            _ => return Err(self.error("expected a quoted string")),
+        };
+        self.pos += 1;
+        let mut r = std::string::String::new();
+        let mut chars = self.rest().char_indices();
+        loop {
+            let (i, c) = chars.next().ok_or_else(|| self.error("unterminated string"))?;
+            if c == q {
+                self.pos += i + 1;
+                return Ok(r);
+            }
+            if c != '\\' { r.push(c); continue }
+            let (_, e) = chars.next().ok_or_else(|| self.error("unterminated string"))?;
+            r.push(match e {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                'a' => '\x07',
+                'b' => '\x08',
+                'v' => '\x0b',
+                'f' => '\x0c',
+                'u' | 'U' => {
+                    let n = if e == 'u' { 4 } else { 8 };
+                    let hex: std::string::String = (0..n).filter_map(|_| chars.next().map(|x| x.1)).collect();
+                    u32::from_str_radix(&hex, 16).ok().and_then(std::char::from_u32)
+                        .ok_or_else(|| self.error(&format!("invalid escape \\{}{}", e, hex)))?
+                }
+                e => e,
+            });
+        }
+    }
+
+    fn typed_value(&mut self, t: &Type) -> Result<MessageItem, TextError> {
+        use BasicType::*;
+        macro_rules! int {
+            ($v: ident, $t: ty) => {{
+                let start = self.pos;
+                let i = self.integer()?;
+                MessageItem::$v(<$t>::try_from(i).map_err(|_| { self.pos = start; self.error(&format!("{} is out of range for type '{}'", i, t)) })?)
+            }}
+        }
+        Ok(match t {
+            Type::Basic(Boolean) => match self.number_token() {
+                "true" => MessageItem::Bool(true),
+                "false" => MessageItem::Bool(false),
+                _ => return Err(self.error("expected 'true' or 'false'")),
+            },
+            Type::Basic(Byte) => int!(Byte, u8),
+            Type::Basic(Int16) => int!(Int16, i16),
+            Type::Basic(UInt16) => int!(UInt16, u16),
+            Type::Basic(Int32) => int!(Int32, i32),
+            Type::Basic(UInt32) => int!(UInt32, u32),
+            Type::Basic(Int64) => int!(Int64, i64),
+            Type::Basic(UInt64) => int!(UInt64, u64),
+            Type::Basic(Double) => {
+                let start = self.pos;
+                let w = self.number_token();
+                MessageItem::Double(w.parse().map_err(|_| { self.pos = start; self.error("expected a number") })?)
+            }
+            Type::Basic(UnixFd) => return Err(self.error("Unix file descriptors cannot be given as text")),
+            Type::Basic(b) => {
+                let start = self.pos;
+                let s = self.quoted()?;
+                parse_basic(*b, &s).map_err(|e| { self.pos = start; self.error(&e.0) })?
+            }
+            Type::Array(et) => {
+                self.expect('[')?;
+                if self.eat(']') { return Ok(MessageItem::Array(MessageItemArray::new(vec!(), sig_of(t)).unwrap())) }
+                let first = self.value(Some(et))?;
+                self.array_rest(vec!(first), et)?
+            }
+            Type::Dict(kt, vt) => {
+                let kt = Type::Basic(*kt);
+                self.expect('{')?;
+                if self.eat('}') { return Ok(MessageItem::Dict(MessageItemDict::new(vec!(), sig_of(&kt), sig_of(vt)).unwrap())) }
+                let k = self.value(Some(&kt))?;
+                self.expect(':')?;
+                let v = self.value(Some(vt))?;
+                self.dict_rest(vec!((k, v)), &kt, vt)?
+            }
+            Type::Struct(fields) => {
+                self.expect('(')?;
+                let mut v = vec!();
+                for (idx, ft) in fields.iter().enumerate() {
+                    if idx > 0 { self.expect(',')? }
+                    v.push(self.value(Some(ft))?);
+                }
+                self.eat(',');
+                self.expect(')')?;
+                MessageItem::Struct(v)
+            }
+            Type::Variant => {
+                self.expect('<')?;
+                let v = self.value(None)?;
+                self.expect('>')?;
+                MessageItem::Variant(Box::new(v))
+            }
+        })
+    }
+}
+
+#[test]
+fn parse_dbus_send_args() {
+    let args = ["string:hello", "int32:-5", "array:uint16:1,2,3", "array:string:",
+        "dict:string:variant:a,boolean:true,b,double:1.5", "variant:objpath:/a/b", "byte:255"];
+    let v = parse("siaqasa{sv}vy", &args, Syntax::DBusSend).unwrap();
+    assert_eq!(v.len(), 7);
+    assert_eq!(&*v[2].signature(), "aq");
+    assert_eq!(&*v[3].signature(), "as");
+    let m = crate::Message::new_signal("/", "a.b", "C").unwrap().append_ref(&v);
+    assert_eq!(format(&mut m.iter_init(), Syntax::DBusSend).unwrap(), args);
+
+    assert!(parse("s", &["int32:5"], Syntax::DBusSend).is_err());
+    assert!(parse("s", &["hello"], Syntax::DBusSend).is_err());
+    assert!(parse("i", &["int32:five"], Syntax::DBusSend).is_err());
+    assert!(parse("i", &["int32:5", "int32:6"], Syntax::DBusSend).is_err());
+    assert!(parse("ii", &["int32:5"], Syntax::DBusSend).is_err());
+    assert!(parse("a{ss}", &["dict:string:string:a"], Syntax::DBusSend).is_err());
+    assert!(parse("ai", &["array:uint32:1"], Syntax::DBusSend).is_err());
+}
+
+#[test]
+fn parse_busctl_args() {
+    let args = ["hello", "2", "a", "b", "1", "key", "s", "val", "42", "x", "-7", "true", "as", "0", "1", "2", "8", "9"];
+    let v = parse("sasa{sv}(ivb)vaa(u)", &args, Syntax::Busctl).unwrap();
+    assert_eq!(v.len(), 6);
+    assert_eq!(&*v[3].signature(), "(ivb)");
+    let m = crate::Message::new_signal("/", "a.b", "C").unwrap().append_ref(&v);
+    assert_eq!(format(&mut m.iter_init(), Syntax::Busctl).unwrap(), args);
+    assert!(format(&mut m.iter_init(), Syntax::DBusSend).is_err());
+
+    let e = parse("as", &["2", "a"], Syntax::Busctl).unwrap_err();
+    assert_eq!(e.to_string(), "Argument 0: Missing value for type 's'");
+    assert!(parse("s", &["a", "b"], Syntax::Busctl).is_err());
+    assert!(parse("v", &["ii", "1", "2"], Syntax::Busctl).is_err());
+    assert!(parse("y", &["256"], Syntax::Busctl).is_err());
+    assert!(parse("o", &["not/a/path"], Syntax::Busctl).is_err());
+}
+
+#[test]
+fn gvariant_args() {
+    let args = ["\"it's\"", "@as []", "<uint32 5>", "{'k': <true>, 'l': <@a{sv} {}>}", "(byte 0x01, -2.5, objectpath '/a')",
+        "[int64 1, 2]", "('x',)", "<[<'a'>, <0x7fffffff>]>", "<(int16 3, \"q\")>"];
+    let sig = "sasva{sv}(ydo)ax(s)vv";
+    let v = parse(sig, &args, Syntax::GVariant).unwrap();
+    assert_eq!(v.len(), 9);
+    let m = crate::Message::new_signal("/", "a.b", "C").unwrap().append_ref(&v);
+    let f = format(&mut m.iter_init(), Syntax::GVariant).unwrap();
+    assert_eq!(f, ["\"it's\"", "@as []", "<uint32 5>", "{'k': <true>, 'l': <@a{sv} {}>}",
+        "(byte 0x01, -2.5, objectpath '/a')", "[int64 1, 2]", "('x',)", "<[<'a'>, <2147483647>]>", "<(int16 3, 'q')>"]);
+    assert_eq!(parse(sig, &f, Syntax::GVariant).unwrap().len(), 9);
+
+    let v = parse("sdy", &["'a\\n\\u00e5\\''", "3", "255"], Syntax::GVariant).unwrap();
+    assert_eq!(v[0].as_str(), Some("a\n\u{e5}'"));
+    assert_eq!(v[1].as_f64(), Some(3.0));
+    assert_eq!(GVariantDisplay(&*v[0]).to_string(), "\"a\\n\u{e5}'\"");
+
+    let fd = std::fs::File::open("/dev/null").unwrap();
+    assert_eq!(GVariantDisplay(&fd).to_string(), "handle -1");
+    let m = crate::Message::new_signal("/", "a.b", "C").unwrap().append1(fd);
+    assert!(format(&mut m.iter_init(), Syntax::GVariant).is_err());
+
+    assert!(parse("s", &["'unterminated"], Syntax::GVariant).is_err());
+    assert!(parse("u", &["int32 5"], Syntax::GVariant).is_err());
+    assert!(parse("y", &["256"], Syntax::GVariant).is_err());
+    assert!(parse("v", &["<[]>"], Syntax::GVariant).is_err());
+    assert!(parse("(ii)", &["(1)"], Syntax::GVariant).is_err());
+    assert!(parse("i", &["1 2"], Syntax::GVariant).is_err());
+
+    let nested = |n| format!("{}1{}", "<".repeat(n), ">".repeat(n));
+    assert!(parse("v", &[&nested(63)], Syntax::GVariant).is_ok());
+    assert!(parse("v", &[&nested(64)], Syntax::GVariant).unwrap_err().to_string().contains("nested too deeply"));
+    assert!(parse("v", &[&"<".repeat(100000)], Syntax::GVariant).is_err());
+    assert!(parse("v", &[&format!("<{}", "[".repeat(100000))], Syntax::GVariant).is_err());
+}
@@ -15,6 +15,9 @@ mod generated_org_freedesktop_standard_interfaces;
 #[allow(dead_code)]
 mod generated_org_freedesktop_dbus;
 
+pub (crate) mod monitor;
+pub use monitor::{Monitor, MonitorMode};
+
 /// This module contains some standard interfaces and an easy way to call them.
 ///
 /// See the [D-Bus specification](https://dbus.freedesktop.org/doc/dbus-specification.html#standard-interfaces) for more information about these standard interfaces.
@@ -0,0 +1,179 @@
+use std::time::Duration;
+use crate::{Error, Message, MessageType};
+use crate::channel::{Channel, BusType};
+use crate::message::MatchRule;
+use super::Proxy;
+
+/// How a monitor receives the messages passing through the bus.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MonitorMode {
+    /// The connection became a monitor by calling `org.freedesktop.DBus.Monitoring.BecomeMonitor`.
+    BecomeMonitor,
+    /// `BecomeMonitor` is not supported by the bus (or not allowed), so match rules with
+    /// `eavesdrop='true'` were added instead.
+    Eavesdrop,
+    /// Eavesdropping was not allowed either, so only broadcast signals and messages sent to
+    /// this connection are received.
+    Match,
+}
+
+// Returns messages caused by setting up the monitor, rather than by others on the bus.
+pub (crate) fn is_own_message(msg: &Message, unique_name: Option<&str>) -> bool {
+    msg.msg_type() == MessageType::Signal && msg.sender().as_deref() == Some("org.freedesktop.DBus")
+        && unique_name.is_some() && msg.destination().as_deref() == unique_name
+        && msg.interface().as_deref() == Some("org.freedesktop.DBus")
+        && matches!(msg.member().as_deref(), Some("NameAcquired") | Some("NameLost"))
+}
+
+pub (crate) fn is_disconnected(msg: &Message) -> bool {
+    msg.msg_type() == MessageType::Signal && msg.interface().as_deref() == Some("org.freedesktop.DBus.Local")
+        && msg.member().as_deref() == Some("Disconnected")
+}
+
+pub (crate) fn eavesdrop_rules(rules: &[MatchRule]) -> Vec<String> {
+    if rules.is_empty() { return eavesdrop_rules(&[MatchRule::new()]) };
+    rules.iter().map(|r| {
+        let mut r = r.clone();
+        r.eavesdrop = true;
+        r.match_str()
+    }).collect()
+}
+
+pub (crate) fn plain_rules(rules: &[MatchRule]) -> Vec<String> {
+    if rules.is_empty() { return plain_rules(&[MatchRule::new()]) };
+    rules.iter().map(|r| {
+        let mut r = r.clone();
+        r.eavesdrop = false;
+        r.match_str()
+    }).collect()
+}
+
+/// A connection that receives a copy of the messages passing through the bus, like `dbus-monitor`.
+///
+/// When created, the connection tries to become a monitor by calling
+/// `org.freedesktop.DBus.Monitoring.BecomeMonitor` with the given match rules. For older
+/// buses that do not support this, it falls back to adding the rules with `eavesdrop='true'`,
+/// and if that is not allowed either, to adding them as they are. An empty list of match rules
+/// means every message.
+///
+/// A bus does not allow a monitor to send messages, and will disconnect it if it tries.
+/// Therefore the monitor takes ownership of its connection.
+///
+/// # Example
+///
+/// ```no_run
+/// use dbus::blocking::Monitor;
+/// use dbus::capture::Writer;
+///
+/// let monitor = Monitor::new_session(&[])?;
+/// let mut file = Writer::new(std::fs::File::create("session.pcapng")?)?;
+/// for msg in monitor {
+///     println!("{:?}", msg);
+///     file.write_message(&msg)?;
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct Monitor {
+    channel: Channel,
+    mode: MonitorMode,
+}
+
+impl Monitor {
+    /// Connects to the session bus and starts monitoring it.
+    pub fn new_session(rules: &[MatchRule]) -> Result<Self, Error> {
+        Self::new(Channel::get_private(BusType::Session)?, rules)
+    }
+
+    /// Connects to the system bus and starts monitoring it.
+    pub fn new_system(rules: &[MatchRule]) -> Result<Self, Error> {
+        Self::new(Channel::get_private(BusType::System)?, rules)
+    }
+
+    /// Starts monitoring the bus on an already registered connection.
+    pub fn new(channel: Channel, rules: &[MatchRule]) -> Result<Self, Error> {
+        let proxy = Proxy::new("org.freedesktop.DBus", "/org/freedesktop/DBus", Duration::from_secs(5), &channel);
+        let strs: Vec<_> = rules.iter().map(|r| r.match_str()).collect();
+        let r: Result<(), _> = proxy.method_call("org.freedesktop.DBus.Monitoring", "BecomeMonitor", (strs, 0u32));
+        let mode = if r.is_ok() { MonitorMode::BecomeMonitor } else {
+            let add_all = |strs: Vec<String>| {
+                for (i, s) in strs.iter().enumerate() {
+                    if let Err(e) = proxy.method_call::<(), _, _, _>("org.freedesktop.DBus", "AddMatch", (s,)) {
+                        // Otherwise messages matching these would arrive twice, after falling back
+                        for s in &strs[..i] {
+                            let _: Result<(), _> = proxy.method_call("org.freedesktop.DBus", "RemoveMatch", (s,));
+                        }
+                        return Err(e);
+                    }
+                }
+                Ok(())
+            };
+            if add_all(eavesdrop_rules(rules)).is_ok() { MonitorMode::Eavesdrop } else {
+                add_all(plain_rules(rules))?;
+                MonitorMode::Match
+            }
+        };
+        Ok(Monitor { channel, mode })
+    }
+
+    /// How messages are received.
+    pub fn mode(&self) -> MonitorMode { self.mode }
+
+    /// Gets the underlying channel.
+    ///
+    /// Do not send messages on it, if the mode is `MonitorMode::BecomeMonitor`.
+    pub fn channel(&self) -> &Channel { &self.channel }
+
+    /// Returns the next message, or None if no message arrived within the timeout.
+    ///
+    /// Returns an error if the connection has been closed.
+    pub fn next_timeout(&self, timeout: Duration) -> Result<Option<Message>, Error> {
+        loop {
+            let msg = match self.channel.blocking_pop_message(timeout)? {
+                Some(msg) => msg,
+                None if self.channel.is_connected() => return Ok(None),
+                None => break,
+            };
+            if is_disconnected(&msg) { break }
+            if !is_own_message(&msg, self.channel.unique_name()) { return Ok(Some(msg)) }
+        }
+        Err(Error::new_custom("org.freedesktop.DBus.Error.Disconnected", "The monitor was disconnected from the bus"))
+    }
+
+    /// Returns the underlying channel.
+    pub fn into_channel(self) -> Channel { self.channel }
+}
+
+/// Iterating over a monitor blocks until the next message arrives, and ends when the
+/// connection is closed.
+impl Iterator for Monitor {
+    type Item = Message;
+    fn next(&mut self) -> Option<Message> {
+        loop {
+            match self.next_timeout(Duration::from_secs(3600)) {
+                Ok(Some(msg)) => return Some(msg),
+                Ok(None) => {},
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+#[test]
+fn monitor_session() {
+    let mut rule = MatchRule::new_signal("com.example.MonitorTest", "Ping");
+    rule.path = Some("/test".into());
+    let monitor = Monitor::new_session(&[rule]).unwrap();
+    assert_ne!(monitor.mode(), MonitorMode::Match);
+
+    let c = super::Connection::new_session().unwrap();
+    use crate::channel::Sender;
+    c.send(Message::new_signal("/other", "com.example.MonitorTest", "Ping").unwrap()).unwrap();
+    c.send(Message::new_signal("/test", "com.example.MonitorTest", "Ping").unwrap().append1(5u32)).unwrap();
+    c.channel().flush();
+
+    let msg = monitor.next_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    assert_eq!(&*msg.path().unwrap(), "/test");
+    assert_eq!(msg.read1::<u32>().unwrap(), 5);
+    assert_eq!(msg.sender().as_deref(), c.channel().unique_name());
+    assert!(monitor.next_timeout(Duration::from_millis(200)).unwrap().is_none());
+}
@@ -0,0 +1,343 @@
+//! Reading and writing captured D-Bus traffic in pcapng format.
+//!
+//! This is the format written by `busctl capture` and `dbus-monitor --pcap`, and it can be
+//! opened in Wireshark. Every message is stored in an enhanced packet block with a timestamp,
+//! on an interface of link type `LINKTYPE_DBUS` (231).
+//!
+//! # Example
+//!
+//! ```
+//! use dbus::capture::{Writer, Reader};
+//!
+//! let mut w = Writer::new(vec!())?;
+//! let mut msg = dbus::Message::new_signal("/", "com.example.Foo", "Bar")?;
+//! msg.set_serial(1);
+//! w.write_message(&msg)?;
+//!
+//! let data = w.into_inner();
+//! let mut r = Reader::new(&data[..])?;
+//! let (_timestamp, msg2) = r.next().unwrap()?;
+//! assert_eq!(&*msg2.member().unwrap(), "Bar");
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::io::{self, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::{Message, Error};
+use crate::channel::Channel;
+
+/// The pcap link type for D-Bus messages.
+pub const LINKTYPE_DBUS: u16 = 231;
+
+const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_IF_TSRESOL: u16 = 9;
+
+// Blocks larger than this are rejected by the reader, rather than allocated.
+const MAX_BLOCK_LEN: usize = 256 * 1024 * 1024;
+
+fn pad4(len: usize) -> usize { (len + 3) & !3 }
+
+fn io_error(e: io::Error) -> Error { Error::new_failed(&format!("Capture I/O error: {}", e)) }
+
+fn invalid_data(s: &str) -> io::Error { io::Error::new(io::ErrorKind::InvalidData, s) }
+
+/// Writes D-Bus messages to a pcapng file.
+///
+/// Timestamps are written with nanosecond resolution.
+#[derive(Debug)]
+pub struct Writer<W: Write> {
+    w: W,
+}
+
+impl<W: Write> Writer<W> {
+    /// Starts a new capture, writing the section header and interface description.
+    pub fn new(mut w: W) -> io::Result<Self> {
+        let mut b = vec!();
+        // Section header: version 1.0, unknown section length.
+        b.extend_from_slice(&BYTE_ORDER_MAGIC.to_ne_bytes());
+        b.extend_from_slice(&1u16.to_ne_bytes());
+        b.extend_from_slice(&0u16.to_ne_bytes());
+        b.extend_from_slice(&(-1i64).to_ne_bytes());
+        write_block(&mut w, SECTION_HEADER, &b)?;
+
+        b.clear();
+        b.extend_from_slice(&LINKTYPE_DBUS.to_ne_bytes());
+        b.extend_from_slice(&0u16.to_ne_bytes());
+        b.extend_from_slice(&0u32.to_ne_bytes());
+        b.extend_from_slice(&OPT_IF_TSRESOL.to_ne_bytes());
+        b.extend_from_slice(&1u16.to_ne_bytes());
+        b.extend_from_slice(&[9, 0, 0, 0]);
+        b.extend_from_slice(&OPT_ENDOFOPT.to_ne_bytes());
+        b.extend_from_slice(&0u16.to_ne_bytes());
+        write_block(&mut w, INTERFACE_DESCRIPTION, &b)?;
+        Ok(Writer { w })
+    }
+
+    /// Writes a marshalled message, e g from `Message::marshal` or a `dbus_native` message.
+    pub fn write_bytes(&mut self, data: &[u8], timestamp: SystemTime) -> io::Result<()> {
+        let ts = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let ts = ts.as_secs() * 1_000_000_000 + u64::from(ts.subsec_nanos());
+        let len = data.len() as u32;
+        let mut b = Vec::with_capacity(20 + pad4(data.len()));
+        b.extend_from_slice(&0u32.to_ne_bytes());
+        b.extend_from_slice(&((ts >> 32) as u32).to_ne_bytes());
+        b.extend_from_slice(&(ts as u32).to_ne_bytes());
+        b.extend_from_slice(&len.to_ne_bytes());
+        b.extend_from_slice(&len.to_ne_bytes());
+        b.extend_from_slice(data);
+        b.resize(20 + pad4(data.len()), 0);
+        write_block(&mut self.w, ENHANCED_PACKET, &b)
+    }
+
+    /// Writes a message with the given timestamp.
+    ///
+    /// The message must have a serial number, i e it must have been sent or received,
+    /// or had `Message::set_serial` called on it.
+    pub fn write_message_at(&mut self, msg: &Message, timestamp: SystemTime) -> io::Result<()> {
+        msg.marshal(|data| self.write_bytes(data, timestamp))
+    }
+
+    /// Writes a message with the current time as timestamp.
+    pub fn write_message(&mut self, msg: &Message) -> io::Result<()> {
+        self.write_message_at(msg, SystemTime::now())
+    }
+
+    /// Writes all messages received on the channel, until no message has arrived for `timeout`.
+    ///
+    /// The channel would typically be a monitor, i e a connection that has called
+    /// `org.freedesktop.DBus.Monitoring.BecomeMonitor`. Returns the number of messages written.
+    pub fn record(&mut self, c: &Channel, timeout: Duration) -> Result<usize, Error> {
+        let mut count = 0;
+        while let Some(msg) = c.blocking_pop_message(timeout)? {
+            self.write_message(&msg).map_err(io_error)?;
+            count += 1;
+        }
+        self.flush().map_err(io_error)?;
+        Ok(count)
+    }
+
+    /// Flushes the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> { self.w.flush() }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W { self.w }
+}
+
+fn write_block<W: Write>(w: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let len = (body.len() + 12) as u32;
+    w.write_all(&block_type.to_ne_bytes())?;
+    w.write_all(&len.to_ne_bytes())?;
+    w.write_all(body)?;
+    w.write_all(&len.to_ne_bytes())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Interface {
+    link_type: u16,
+    // Timestamp units per second
+    units: u64,
+}
+
+/// Reads D-Bus messages from a pcapng file.
+///
+/// Iterating over the reader yields the timestamp and demarshalled message of every packet.
+/// Use `next_packet` to get at the raw message bytes instead. Packets on interfaces with
+/// other link types than `LINKTYPE_DBUS`, and blocks other than enhanced packet blocks, are
+/// skipped.
+#[derive(Debug)]
+pub struct Reader<R: Read> {
+    r: R,
+    big_endian: bool,
+    interfaces: Vec<Interface>,
+}
+
+impl<R: Read> Reader<R> {
+    /// Starts reading a capture, which must begin with a section header.
+    pub fn new(r: R) -> io::Result<Self> {
+        let mut reader = Reader { r, big_endian: cfg!(target_endian = "big"), interfaces: vec!() };
+        match reader.read_block()? {
+            Some((SECTION_HEADER, _)) => Ok(reader),
+            _ => Err(invalid_data("Not a pcapng file")),
+        }
+    }
+
+    fn u16(&self, b: &[u8]) -> u16 {
+        let b = [b[0], b[1]];
+        if self.big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) }
+    }
+
+    fn u32(&self, b: &[u8]) -> u32 {
+        let b = [b[0], b[1], b[2], b[3]];
+        if self.big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) }
+    }
+
+    // Returns the block type and body. Section headers also set the byte order.
+    fn read_block(&mut self) -> io::Result<Option<(u32, Vec<u8>)>> {
+        let mut head = [0u8; 8];
+        match self.r.read_exact(&mut head[..4]) {
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            x => x?,
+        }
+        self.r.read_exact(&mut head[4..])?;
+        let block_type = self.u32(&head);
+        if block_type == SECTION_HEADER {
+            let mut magic = [0u8; 4];
+            self.r.read_exact(&mut magic)?;
+            self.big_endian = match u32::from_be_bytes(magic) {
+                BYTE_ORDER_MAGIC => true,
+                0x4D3C_2B1A => false,
+                _ => return Err(invalid_data("Invalid pcapng byte order magic")),
+            };
+            self.interfaces.clear();
+            let len = self.u32(&head[4..]) as usize;
+            if !(28..=MAX_BLOCK_LEN).contains(&len) || len & 3 != 0 { return Err(invalid_data("Invalid pcapng block length")) }
+            let mut body = magic.to_vec();
+            self.read_body(&mut body, len - 12)?;
+            body.truncate(len - 12);
+            return Ok(Some((block_type, body)))
+        }
+        let len = self.u32(&head[4..]) as usize;
+        if !(12..=MAX_BLOCK_LEN).contains(&len) || len & 3 != 0 { return Err(invalid_data("Invalid pcapng block length")) }
+        let mut body = vec!();
+        self.read_body(&mut body, len - 8)?;
+        body.truncate(len - 12);
+        Ok(Some((block_type, body)))
+    }
+
+    // Appends len bytes to body. The buffer grows with the data actually read, so that a
+    // corrupt block length does not make us allocate up to MAX_BLOCK_LEN in advance.
+    fn read_body(&mut self, body: &mut Vec<u8>, len: usize) -> io::Result<()> {
+        let n = (&mut self.r).take(len as u64).read_to_end(body)?;
+        if n < len { return Err(io::ErrorKind::UnexpectedEof.into()) }
+        Ok(())
+    }
+
+    fn add_interface(&mut self, b: &[u8]) -> io::Result<()> {
+        if b.len() < 8 { return Err(invalid_data("Interface description block too short")) }
+        let mut iface = Interface { link_type: self.u16(b), units: 1_000_000 };
+        let mut opts = &b[8..];
+        while opts.len() >= 4 {
+            let (code, len) = (self.u16(opts), self.u16(&opts[2..]) as usize);
+            if code == OPT_ENDOFOPT || opts.len() < 4 + len { break }
+            if code == OPT_IF_TSRESOL && len >= 1 {
+                let r = opts[4];
+                iface.units = if r & 0x80 == 0 { 10u64.checked_pow(r.into()) } else { 1u64.checked_shl((r & 0x7f).into()) }
+                    .filter(|&x| x > 0).ok_or_else(|| invalid_data("Unsupported timestamp resolution"))?;
+            }
+            opts = &opts[(4 + pad4(len)).min(opts.len())..];
+        }
+        self.interfaces.push(iface);
+        Ok(())
+    }
+
+    /// Reads the next D-Bus packet, returning its timestamp and data, or None at end of file.
+    pub fn next_packet(&mut self) -> io::Result<Option<(SystemTime, Vec<u8>)>> {
+        while let Some((block_type, body)) = self.read_block()? {
+            match block_type {
+                INTERFACE_DESCRIPTION => self.add_interface(&body)?,
+                ENHANCED_PACKET => {
+                    if body.len() < 20 { return Err(invalid_data("Enhanced packet block too short")) }
+                    let iface = self.interfaces.get(self.u32(&body) as usize).copied()
+                        .ok_or_else(|| invalid_data("Packet refers to an unknown interface"))?;
+                    if iface.link_type != LINKTYPE_DBUS { continue }
+                    let ts = (u64::from(self.u32(&body[4..])) << 32) | u64::from(self.u32(&body[8..]));
+                    let len = self.u32(&body[12..]) as usize;
+                    if 20 + len > body.len() { return Err(invalid_data("Packet length exceeds block length")) }
+                    let d = Duration::from_secs(ts / iface.units) +
+                        Duration::from_nanos(((ts % iface.units) as u128 * 1_000_000_000 / iface.units as u128) as u64);
+                    return Ok(Some((UNIX_EPOCH + d, body[20..20 + len].to_vec())));
+                }
+                _ => {},
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<(SystemTime, Message), Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_packet() {
+            Ok(Some((ts, data))) => Some(Message::demarshal(&data).map(|m| (ts, m))),
+            Ok(None) => None,
+            Err(e) => Some(Err(io_error(e))),
+        }
+    }
+}
+
+#[test]
+fn write_and_read() {
+    let mut w = Writer::new(vec!()).unwrap();
+    let mut m1 = Message::new_method_call("com.example.Foo", "/", "com.example.Foo", "Bar").unwrap().append2("Hello", 5u32);
+    m1.set_serial(7);
+    let mut m2 = Message::new_signal("/a/b", "com.example.Foo", "Baz").unwrap();
+    m2.set_serial(8);
+    let t1 = UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789);
+    w.write_message_at(&m1, t1).unwrap();
+    w.write_message(&m2).unwrap();
+    let data = w.into_inner();
+    assert_eq!(data.len() % 4, 0);
+    assert_eq!(&data[..4], &[0x0a, 0x0d, 0x0d, 0x0a]);
+
+    let mut r = Reader::new(&data[..]).unwrap();
+    let (ts, m) = r.next().unwrap().unwrap();
+    assert_eq!(ts, t1);
+    assert_eq!(m.get_serial(), Some(7));
+    assert_eq!(m.read2::<&str, u32>().unwrap(), ("Hello", 5));
+    let (_, m) = r.next().unwrap().unwrap();
+    assert_eq!(&*m.path().unwrap(), "/a/b");
+    assert!(r.next().is_none());
+
+    assert!(Reader::new(&b"not a pcapng file"[..]).is_err());
+    let mut r = Reader::new(&data[..data.len() - 3]).unwrap();
+    assert!(r.next().unwrap().is_ok());
+    assert!(r.next().unwrap().is_err());
+
+    // A truncated block claiming the maximum length
+    let mut d = data.clone();
+    d.extend_from_slice(&ENHANCED_PACKET.to_ne_bytes());
+    d.extend_from_slice(&(MAX_BLOCK_LEN as u32).to_ne_bytes());
+    d.extend_from_slice(&[0; 32]);
+    let mut r = Reader::new(&d[..]).unwrap();
+    assert!(r.next().unwrap().is_ok());
+    assert!(r.next().unwrap().is_ok());
+    assert!(r.next().unwrap().is_err());
+}
+
+#[test]
+fn read_big_endian() {
+    // A big endian file with microsecond timestamps (the default resolution).
+    let mut m = Message::new_signal("/", "com.example.Foo", "Bar").unwrap();
+    m.set_serial(1);
+    let mut data = vec!();
+    m.marshal(|d| { data.extend_from_slice(d); Ok::<_, ()>(()) }).unwrap();
+    let mut f = vec!();
+    let mut block = |t: u32, body: &[u8]| {
+        f.extend_from_slice(&t.to_be_bytes());
+        f.extend_from_slice(&(body.len() as u32 + 12).to_be_bytes());
+        f.extend_from_slice(body);
+        f.extend_from_slice(&(body.len() as u32 + 12).to_be_bytes());
+    };
+    block(SECTION_HEADER, &[0x1a, 0x2b, 0x3c, 0x4d, 0, 1, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    block(INTERFACE_DESCRIPTION, &[0, 1, 0, 0, 0, 0, 0, 0]);
+    block(INTERFACE_DESCRIPTION, &[0, 231, 0, 0, 0, 0, 0, 0]);
+    let mut epb = vec!(0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0);
+    block(ENHANCED_PACKET, &{ let mut e = epb.clone(); e.extend_from_slice(&[0, 0, 0, 4, 0, 0, 0, 4, 1, 2, 3, 4]); e });
+    epb[3] = 1;
+    epb[11] = 5;
+    epb.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    epb.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    epb.extend_from_slice(&data);
+    epb.resize(20 + pad4(data.len()), 0);
+    block(ENHANCED_PACKET, &epb);
+
+    let mut r = Reader::new(&f[..]).unwrap();
+    let (ts, m) = r.next().unwrap().unwrap();
+    assert_eq!(ts, UNIX_EPOCH + Duration::from_micros(5));
+    assert_eq!(&*m.member().unwrap(), "Bar");
+    assert!(r.next().is_none());
+}
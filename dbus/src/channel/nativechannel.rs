@@ -10,7 +10,11 @@ use futures_util::io as fio;
 use std::pin::Pin;
 use std::os::unix::net::UnixStream;
 use std::collections::VecDeque;
-use std::os::unix::io::{RawFd, AsRawFd};
+use std::os::unix::io::{RawFd, AsRawFd, OwnedFd};
+use std::fs::File;
+use std::convert::TryInto;
+use crate::arg::{ArgType, Iter, IterAppend};
+use crate::strings::{Signature, Path};
 
 /// Low-level connection - handles read/write to the socket
 ///
@@ -28,6 +32,252 @@ pub struct Channel {
     writer: FMutex<Pin<Box<dyn fio::AsyncWrite + Send>>>,
     raw_fd: RawFd,
     unix_fd: bool,
+    // File descriptors received, but not yet matched with a message
+    in_fds: Mutex<VecDeque<OwnedFd>>,
+}
+
+/// Collects the file descriptors in the arguments, in the order they are marshalled.
+fn collect_fds(i: &mut Iter, fds: &mut Vec<File>) {
+    loop {
+        match i.arg_type() {
+            ArgType::Invalid => return,
+            ArgType::UnixFd => if let Some(f) = i.get::<File>() { fds.push(f) },
+            t @ (ArgType::Array | ArgType::Struct | ArgType::Variant | ArgType::DictEntry) => {
+                if let Some(mut sub) = i.recurse(t) { collect_fds(&mut sub, fds) }
+            },
+            _ => {},
+        }
+        i.next();
+    }
+}
+
+/// Copies the arguments of a message, taking the unix fds from fds in the order they are marshalled.
+///
+/// Returns None if a value could not be read, or there are too few file descriptors.
+fn copy_args<'a, I: Iterator<Item=File>>(from: &mut Iter, to: &mut IterAppend<'a>, fds: &mut I) -> Option<()> {
+    loop {
+        match from.arg_type() {
+            ArgType::Invalid => return Some(()),
+            ArgType::UnixFd => to.append(fds.next()?),
+            ArgType::Boolean => to.append(from.get::<bool>()?),
+            ArgType::Byte => to.append(from.get::<u8>()?),
+            ArgType::Int16 => to.append(from.get::<i16>()?),
+            ArgType::UInt16 => to.append(from.get::<u16>()?),
+            ArgType::Int32 => to.append(from.get::<i32>()?),
+            ArgType::UInt32 => to.append(from.get::<u32>()?),
+            ArgType::Int64 => to.append(from.get::<i64>()?),
+            ArgType::UInt64 => to.append(from.get::<u64>()?),
+            ArgType::Double => to.append(from.get::<f64>()?),
+            ArgType::String => to.append(from.get::<&str>()?),
+            ArgType::ObjectPath => to.append(from.get::<Path>()?),
+            ArgType::Signature => to.append(from.get::<Signature>()?),
+            t => {
+                let sig = if t == ArgType::Array { Some(from.signature()) } else { None };
+                let mut sub = from.recurse(t)?;
+                let mut r = None;
+                match sig {
+                    // Dict entry signatures are only valid inside an array signature
+                    Some(sig) if sig.starts_with("a{") => {
+                        let key = Signature::new(&sig[2..3]).ok()?;
+                        let value = Signature::new(&sig[3..sig.len() - 1]).ok()?;
+                        to.append_dict(&key, &value, |s| r = copy_args(&mut sub, s, fds));
+                    },
+                    Some(sig) => {
+                        let inner = Signature::new(&sig[1..]).ok()?;
+                        to.append_array(&inner, |s| r = copy_args(&mut sub, s, fds));
+                    },
+                    None if t == ArgType::Variant => {
+                        let inner = sub.signature();
+                        to.append_variant(&inner, |s| r = copy_args(&mut sub, s, fds));
+                    },
+                    None if t == ArgType::Struct => to.append_struct(|s| r = copy_args(&mut sub, s, fds)),
+                    None => to.append_dict_entry(|s| r = copy_args(&mut sub, s, fds)),
+                }
+                r?;
+            },
+        }
+        from.next();
+    }
+}
+
+/// Returns the alignment of values of the type starting with c.
+fn alignment(c: u8) -> usize {
+    match c {
+        b'y' | b'g' | b'v' => 1,
+        b'n' | b'q' => 2,
+        b'x' | b't' | b'd' | b'(' | b'{' => 8,
+        _ => 4,
+    }
+}
+
+/// Splits a signature into its first single complete type and the rest.
+fn split_single(sig: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut depth = 0isize;
+    for (i, &c) in sig.iter().enumerate() {
+        match c {
+            b'a' => continue,
+            b'(' | b'{' => depth += 1,
+            b')' | b'}' => depth -= 1,
+            _ => {},
+        }
+        if depth == 0 { return Some(sig.split_at(i + 1)) }
+    }
+    None
+}
+
+/// Walks over a marshalled message, collecting the values of unix fd arguments, i e the
+/// indices into the file descriptors sent along with the message.
+struct Walker<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+    depth: usize,
+    fd_indices: Vec<u32>,
+}
+
+impl<'a> Walker<'a> {
+    fn align(&mut self, n: usize) { self.pos = (self.pos + n - 1) & !(n - 1) }
+
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let b = self.buf.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(b)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.align(4);
+        let b: [u8; 4] = self.bytes(4)?.try_into().ok()?;
+        Some(if self.big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) })
+    }
+
+    fn signature(&mut self) -> Option<&'a [u8]> {
+        let len = self.bytes(1)?[0] as usize;
+        let s = self.bytes(len)?;
+        self.bytes(1)?;
+        Some(s)
+    }
+
+    /// Walks over a value of the single complete type sig.
+    fn value(&mut self, sig: &[u8]) -> Option<()> {
+        // The header is walked before libdbus has validated it, so limit the nesting
+        // like the D-Bus specification does.
+        if self.depth >= 64 { return None }
+        self.depth += 1;
+        let r = self.value_inner(sig);
+        self.depth -= 1;
+        r
+    }
+
+    fn value_inner(&mut self, sig: &[u8]) -> Option<()> {
+        match *sig.first()? {
+            b'y' => { self.bytes(1)?; },
+            b'n' | b'q' => { self.align(2); self.bytes(2)?; },
+            b'x' | b't' | b'd' => { self.align(8); self.bytes(8)?; },
+            b'h' => { let idx = self.u32()?; self.fd_indices.push(idx) },
+            b'b' | b'i' | b'u' => { self.u32()?; },
+            b's' | b'o' => { let len = self.u32()? as usize; self.bytes(len.checked_add(1)?)?; },
+            b'g' => { self.signature()?; },
+            b'v' => { let s = self.signature()?; self.value(s)?; },
+            b'a' => {
+                let len = self.u32()? as usize;
+                let elem = &sig[1..];
+                self.align(alignment(*elem.first()?));
+                let end = self.pos.checked_add(len)?;
+                if elem.contains(&b'h') || elem.contains(&b'v') {
+                    while self.pos < end { self.value(elem)?; }
+                }
+                self.pos = end;
+            },
+            b'(' | b'{' => {
+                self.align(8);
+                let mut s = sig.get(1..sig.len() - 1)?;
+                while !s.is_empty() {
+                    let (first, rest) = split_single(s)?;
+                    self.value(first)?;
+                    s = rest;
+                }
+            },
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+/// The header fields of a marshalled message.
+struct Header<'a> {
+    // Code and position of every field
+    fields: Vec<(u8, std::ops::Range<usize>)>,
+    signature: &'a [u8],
+    unix_fds: usize,
+    body_start: usize,
+}
+
+fn parse_header(v: &[u8]) -> Option<Header> {
+    let mut w = Walker { buf: v, pos: 12, big_endian: v[0] == b'B', depth: 0, fd_indices: vec!() };
+    let fields_end = 16usize.checked_add(w.u32()? as usize)?;
+    let mut h = Header { fields: vec!(), signature: b"", unix_fds: 0, body_start: (fields_end + 7) & !7 };
+    while w.pos < fields_end {
+        // Every header field is a struct of a code byte and a variant
+        w.align(8);
+        let start = w.pos;
+        let code = w.bytes(1)?[0];
+        let sig = w.signature()?;
+        match (code, sig) {
+            (8, b"g") => h.signature = w.signature()?,
+            (9, b"u") => h.unix_fds = w.u32()? as usize,
+            _ => w.value(sig)?,
+        }
+        h.fields.push((code, start..w.pos));
+    }
+    Some(h)
+}
+
+/// Demarshals a message and attaches the file descriptors received along with it.
+///
+/// libdbus can only attach file descriptors to a message when appending arguments, so the
+/// message is demarshalled without its UNIX_FDS header field, and then rebuilt with its arguments
+/// copied over, with the received file descriptors in place of the unix fd arguments.
+fn demarshal_with_fds(v: &[u8], fds: &[OwnedFd]) -> Result<Message, Error> {
+    let bad = || Error::new_failed("Protocol error");
+    let Header { fields, signature, body_start, .. } = parse_header(v).ok_or_else(bad)?;
+    let big_endian = v[0] == b'B';
+    let u32_bytes = |x: usize| if big_endian { (x as u32).to_be_bytes() } else { (x as u32).to_le_bytes() };
+
+    // Reassembles the message, leaving out the UNIX_FDS header field, and optionally the body.
+    let assemble = |with_body: bool| {
+        let mut r = v[..16].to_vec();
+        for (code, range) in &fields {
+            if *code == 9 || (*code == 8 && !with_body) { continue }
+            r.resize((r.len() + 7) & !7, 0);
+            r.extend_from_slice(&v[range.clone()]);
+        }
+        let fields_len = u32_bytes(r.len() - 16);
+        r[12..16].copy_from_slice(&fields_len);
+        r.resize((r.len() + 7) & !7, 0);
+        let body = if with_body { &v[body_start..] } else { &[] };
+        r[4..8].copy_from_slice(&u32_bytes(body.len()));
+        r.extend_from_slice(body);
+        r
+    };
+    let src = Message::demarshal(&assemble(true))?;
+
+    // libdbus has validated the body now, so it is safe to walk over it.
+    let mut w = Walker { buf: v, pos: body_start, big_endian, depth: 0, fd_indices: vec!() };
+    let mut sig = signature;
+    while !sig.is_empty() {
+        let (first, rest) = split_single(sig).ok_or_else(bad)?;
+        w.value(first).ok_or_else(bad)?;
+        sig = rest;
+    }
+    let files = w.fd_indices.iter().map(|&idx| {
+        let fd = fds.get(idx as usize).ok_or_else(|| Error::new_failed("Unix file descriptor missing"))?;
+        fd.try_clone().map(File::from).map_err(|e| Error::new_failed(&e.to_string()))
+    }).collect::<Result<Vec<_>, _>>()?;
+
+    let mut m = Message::demarshal(&assemble(false))?;
+    copy_args(&mut src.iter_init(), &mut IterAppend::new(&mut m), &mut files.into_iter())
+        .ok_or_else(bad)?;
+    Ok(m)
 }
 
 async fn do_auth<W: fio::AsyncWrite + std::marker::Unpin, R: fio::AsyncBufRead + std::marker::Unpin>(r: &mut R, w: &mut W) -> Result<bool, Box<dyn stdError>> {
@@ -64,6 +314,10 @@ impl Channel {
     }
 
     /// Creates a new D-Bus connection without blocking.
+    ///
+    /// If unix fd passing is negotiated, messages are read directly from the socket rather than
+    /// through the reader, as file descriptors arrive as ancillary data. The socket must then stay
+    /// in blocking mode.
     pub async fn get_private_async<R, W, F>(bus: BusType, f: F) -> Result<Channel, Box<dyn stdError>>
     where
         R: fio::AsyncBufRead + 'static + Send,
@@ -89,6 +343,7 @@ impl Channel {
             out_queue: Default::default(),
             reader: FMutex::new(r),
             writer: FMutex::new(w),
+            in_fds: Default::default(),
         };
         let msg = Message::new_method_call("org.freedesktop.DBus", "/org/freedesktop/DBus", "org.freedesktop.DBus", "Hello")?;
         let r = c.send_with_reply_async(msg).await?;
@@ -114,6 +369,11 @@ impl Channel {
 
     async fn write_message(&self, msg: Message) -> Result<(), fio::Error> {
         use fio::AsyncWriteExt;
+        let mut fds = vec!();
+        collect_fds(&mut msg.iter_init(), &mut fds);
+        if !fds.is_empty() && !self.unix_fd {
+            return Err(fio::Error::new(fio::ErrorKind::InvalidInput, "Cannot send file descriptors on this connection"));
+        }
         let mut v = vec!();
         let _: Result<(), ()> = msg.marshal(|b| {
             // At some point we might be able to skip this copy. For now let's just try to get
@@ -122,13 +382,53 @@ impl Channel {
             Ok(())
         });
         let mut writer = self.writer.lock().await;
-        writer.write_all(&v).await
+        let mut sent = 0;
+        if !fds.is_empty() {
+            // The file descriptors go along with the first byte(s), as ancillary data
+            writer.flush().await?;
+            let fds: Vec<RawFd> = fds.iter().map(|f| f.as_raw_fd()).collect();
+            sent = dbus_native_channel::unixfd::send_with_fds(self, &v, &fds)?;
+        }
+        writer.write_all(&v[sent..]).await
+    }
+
+    /// Reads exactly buf.len() bytes from the socket, keeping the file descriptors sent along.
+    fn recv_exact(&self, mut buf: &mut [u8]) -> Result<(), Error> {
+        let mut fds = vec!();
+        while !buf.is_empty() {
+            let n = match dbus_native_channel::unixfd::recv_with_fds(self, buf, &mut fds) {
+                Ok(0) => return Err(Error::new_failed("Connection closed")),
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::new_failed(&e.to_string())),
+            };
+            buf = &mut buf[n..];
+        }
+        self.in_fds.lock().unwrap().extend(fds);
+        Ok(())
     }
 
     async fn read_message(&self) -> Result<Message, Error> {
         use fio::AsyncReadExt;
         let mut v = vec![0; 16];
         let mut reader = self.reader.lock().await;
+        if self.unix_fd {
+            // The reader is still locked, so that reads are not interleaved.
+            // Nothing is left in the reader's buffer after authentication, as the bus does not
+            // send anything before our Hello call.
+            self.recv_exact(&mut v)?;
+            let count = Message::demarshal_bytes_needed(&v).map_err(|_| Error::new_failed("Protocol error"))?;
+            v.resize(count, 0);
+            self.recv_exact(&mut v[16..])?;
+            let fd_count = parse_header(&v).ok_or_else(|| Error::new_failed("Protocol error"))?.unix_fds;
+            if fd_count == 0 { return Message::demarshal(&v) }
+            let fds: Vec<OwnedFd> = {
+                let mut in_fds = self.in_fds.lock().unwrap();
+                if fd_count > in_fds.len() { return Err(Error::new_failed("Unix file descriptor missing")) }
+                in_fds.drain(..fd_count).collect()
+            };
+            return demarshal_with_fds(&v, &fds);
+        }
         reader.read_exact(&mut v).await.map_err(|e| Error::new_failed(&e.to_string()))?;
         let count = Message::demarshal_bytes_needed(&v).map_err(|_| Error::new_failed("Protocol error"))?;
         // dbg!(&v, &count);
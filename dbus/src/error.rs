@@ -186,3 +186,216 @@ impl From<Error> for MethodErr {
         MethodErr(String::from(n).into(), m.into())
     }
 }
+
+/// A Rust type corresponding to a set of D-Bus error names.
+///
+/// With the `derive` feature, this can be derived for enums, see `dbus_derive::DBusError`.
+/// The derive also implements conversion into `MethodErr` and `Error`, and `TryFrom<Error>`.
+pub trait DBusError: Sized {
+    /// Creates the error from an error name and message, or returns None if the name is not known.
+    fn from_name_and_message(name: &str, message: &str) -> Option<Self>;
+
+    /// The D-Bus error name, e g 'org.freedesktop.DBus.Error.Failed'.
+    fn error_name(&self) -> ErrorName<'static>;
+
+    /// The error message.
+    fn error_message(&self) -> String;
+
+    /// Converts an Error, or returns None if its name is not known.
+    fn from_error(e: &Error) -> Option<Self> {
+        Self::from_name_and_message(e.name()?, e.message().unwrap_or(""))
+    }
+
+    /// Converts to a MethodErr, to be returned from a method handler.
+    fn to_method_err(&self) -> MethodErr { (self.error_name(), self.error_message()).into() }
+
+    /// Converts to an Error.
+    fn to_error(&self) -> Error { Error::new_custom(self.error_name(), &self.error_message()) }
+}
+
+macro_rules! standard_errors {
+    ($($(#[$attr: meta])* $variant: ident => $name: expr,)*) => {
+        /// The standard errors in the 'org.freedesktop.DBus.Error' namespace.
+        ///
+        /// Every variant contains the error message.
+        ///
+        /// # Example
+        ///
+        /// ```
+        /// use dbus::{DBusError, StandardError};
+        ///
+        /// let e = dbus::Error::new_custom("org.freedesktop.DBus.Error.ServiceUnknown", "No such service");
+        /// match StandardError::from_error(&e) {
+        ///     Some(StandardError::ServiceUnknown(msg)) => assert_eq!(msg, "No such service"),
+        ///     _ => unreachable!(),
+        /// }
+        /// ```
+        #[derive(Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash)]
+        #[non_exhaustive]
+        pub enum StandardError {
+            $($(#[$attr])* $variant(String),)*
+        }
+
+        impl DBusError for StandardError {
+            fn from_name_and_message(name: &str, message: &str) -> Option<Self> {
+                let suffix = name.strip_prefix("org.freedesktop.DBus.Error.")?;
+                match suffix {
+                    $($name => Some(StandardError::$variant(message.into())),)*
+                    _ => None,
+                }
+            }
+
+            fn error_name(&self) -> ErrorName<'static> {
+                match self {
+                    $(StandardError::$variant(_) => concat!("org.freedesktop.DBus.Error.", $name).into(),)*
+                }
+            }
+
+            fn error_message(&self) -> String {
+                match self {
+                    $(StandardError::$variant(m) => m.clone(),)*
+                }
+            }
+        }
+    }
+}
+
+standard_errors! {
+    /// A generic error; "something went wrong".
+    Failed => "Failed",
+    /// There was not enough memory to complete an operation.
+    NoMemory => "NoMemory",
+    /// The bus doesn't know how to launch a service to supply the bus name you wanted.
+    ServiceUnknown => "ServiceUnknown",
+    /// The bus name you referenced doesn't exist (i e no application owns it).
+    NameHasNoOwner => "NameHasNoOwner",
+    /// No reply to a message expecting one, usually means a timeout occurred.
+    NoReply => "NoReply",
+    /// Something went wrong reading or writing to a socket, for example.
+    IOError => "IOError",
+    /// A D-Bus bus address was malformed.
+    BadAddress => "BadAddress",
+    /// Requested operation isn't supported (like ENOSYS on UNIX).
+    NotSupported => "NotSupported",
+    /// Some limited resource is exhausted.
+    LimitsExceeded => "LimitsExceeded",
+    /// Security restrictions don't allow doing what you're trying to do.
+    AccessDenied => "AccessDenied",
+    /// Authentication didn't work.
+    AuthFailed => "AuthFailed",
+    /// Unable to connect to server (probably caused by ECONNREFUSED on a socket).
+    NoServer => "NoServer",
+    /// Certain timeout errors, possibly ETIMEDOUT on a socket.
+    Timeout => "Timeout",
+    /// No network access (probably ENETUNREACH on a socket).
+    NoNetwork => "NoNetwork",
+    /// Can't bind a socket since its address is in use (i e EADDRINUSE).
+    AddressInUse => "AddressInUse",
+    /// The connection is disconnected and you're trying to use it.
+    Disconnected => "Disconnected",
+    /// Invalid arguments passed to a method call.
+    InvalidArgs => "InvalidArgs",
+    /// Missing file.
+    FileNotFound => "FileNotFound",
+    /// Existing file and the operation you're using does not silently overwrite.
+    FileExists => "FileExists",
+    /// Method name you invoked isn't known by the object you invoked it on.
+    UnknownMethod => "UnknownMethod",
+    /// Object you invoked a method on isn't known.
+    UnknownObject => "UnknownObject",
+    /// Interface you invoked a method on isn't known by the object.
+    UnknownInterface => "UnknownInterface",
+    /// Property you tried to access isn't known by the object.
+    UnknownProperty => "UnknownProperty",
+    /// Property you tried to set is read-only.
+    PropertyReadOnly => "PropertyReadOnly",
+    /// Certain timeout errors, e g while starting a service.
+    TimedOut => "TimedOut",
+    /// Tried to remove or modify a match rule that didn't exist.
+    MatchRuleNotFound => "MatchRuleNotFound",
+    /// The match rule isn't syntactically valid.
+    MatchRuleInvalid => "MatchRuleInvalid",
+    /// While starting a new process, the exec() call failed.
+    SpawnExecFailed => "Spawn.ExecFailed",
+    /// While starting a new process, the fork() call failed.
+    SpawnForkFailed => "Spawn.ForkFailed",
+    /// While starting a new process, the child exited with a status code.
+    SpawnChildExited => "Spawn.ChildExited",
+    /// While starting a new process, the child exited on a signal.
+    SpawnChildSignaled => "Spawn.ChildSignaled",
+    /// While starting a new process, something went wrong.
+    SpawnFailed => "Spawn.Failed",
+    /// We failed to setup the environment correctly.
+    SpawnSetupFailed => "Spawn.FailedToSetup",
+    /// We failed to setup the config parser correctly.
+    SpawnConfigInvalid => "Spawn.ConfigInvalid",
+    /// Bus name was not valid.
+    SpawnServiceNotValid => "Spawn.ServiceNotValid",
+    /// Service file not found in system-services directory.
+    SpawnServiceNotFound => "Spawn.ServiceNotFound",
+    /// Permissions are incorrect on the setuid helper.
+    SpawnPermissionsInvalid => "Spawn.PermissionsInvalid",
+    /// Service file invalid (Name, User or Exec missing).
+    SpawnFileInvalid => "Spawn.FileInvalid",
+    /// There was not enough memory to complete the operation.
+    SpawnNoMemory => "Spawn.NoMemory",
+    /// Tried to get a UNIX process ID and it wasn't available.
+    UnixProcessIdUnknown => "UnixProcessIdUnknown",
+    /// A type signature is not valid.
+    InvalidSignature => "InvalidSignature",
+    /// A file contains invalid syntax or is otherwise broken.
+    InvalidFileContent => "InvalidFileContent",
+    /// Asked for SELinux security context and it wasn't available.
+    SELinuxSecurityContextUnknown => "SELinuxSecurityContextUnknown",
+    /// Asked for ADT audit data and it wasn't available.
+    AdtAuditDataUnknown => "AdtAuditDataUnknown",
+    /// There's already an object with the requested object path.
+    ObjectPathInUse => "ObjectPathInUse",
+    /// The message meta data does not match the payload, e g expected number of file
+    /// descriptors were not sent over the socket this message was received on.
+    InconsistentMessage => "InconsistentMessage",
+    /// The message is not allowed without performing interactive authorization,
+    /// but could have succeeded if an interactive authorization step was allowed.
+    InteractiveAuthorizationRequired => "InteractiveAuthorizationRequired",
+    /// The connection is not from a container, or the specified container instance does not exist.
+    NotContainer => "NotContainer",
+}
+
+impl fmt::Display for StandardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error_message())
+    }
+}
+
+impl stdError for StandardError {}
+
+impl From<StandardError> for MethodErr {
+    fn from(t: StandardError) -> MethodErr { t.to_method_err() }
+}
+
+impl From<StandardError> for Error {
+    fn from(t: StandardError) -> Error { t.to_error() }
+}
+
+impl std::convert::TryFrom<Error> for StandardError {
+    type Error = Error;
+    fn try_from(e: Error) -> Result<StandardError, Error> { StandardError::from_error(&e).ok_or(e) }
+}
+
+#[test]
+fn standard_errors() {
+    use std::convert::TryFrom;
+    let e = Error::new_custom("org.freedesktop.DBus.Error.Spawn.ChildExited", "Exited with 1");
+    let s = StandardError::try_from(e).unwrap();
+    assert_eq!(s, StandardError::SpawnChildExited("Exited with 1".into()));
+    assert_eq!(&*s.error_name(), "org.freedesktop.DBus.Error.Spawn.ChildExited");
+
+    let m: MethodErr = StandardError::AccessDenied("Go away".into()).into();
+    assert_eq!(&**m.errorname(), "org.freedesktop.DBus.Error.AccessDenied");
+    assert_eq!(m.description(), "Go away");
+
+    let e = Error::new_custom("com.example.Error.Other", "Something else");
+    let e = StandardError::try_from(e).unwrap_err();
+    assert_eq!(e.name(), Some("com.example.Error.Other"));
+    assert!(StandardError::from_name_and_message("org.freedesktop.DBus.Error.Unknown", "").is_none());
+}
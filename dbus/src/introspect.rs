@@ -0,0 +1,781 @@
+//! A typed model of D-Bus introspection data, with a parser and serializer.
+//!
+//! This module requires the `introspect` feature.
+//!
+//! # Example
+//!
+//! ```
+//! use dbus::introspect::Node;
+//!
+//! let node = Node::from_xml(r#"<node>
+//!   <interface name="com.example.Foo">
+//!     <method name="Bar"><arg name="x" type="i" direction="in"/></method>
+//!   </interface>
+//! </node>"#)?;
+//! let method = node.interface("com.example.Foo").unwrap().method("Bar").unwrap();
+//! assert_eq!(method.in_signature(), "i");
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::{error, fmt};
+use crate::{Error, Signature};
+use crate::arg::{AppendAll, Iter, IterAppend, ReadAll, RefArg, TypeMismatchError, Variant};
+
+/// Error returned when introspection XML could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid introspection data: {}", self.0)
+    }
+}
+
+impl error::Error for ParseError {}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Error { Error::new_failed(&e.to_string()) }
+}
+
+/// A name-value pair attached to an interface, member, property or argument.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Annotation {
+    /// The annotation name, e g "org.freedesktop.DBus.Deprecated".
+    pub name: String,
+    /// The annotation value, e g "true".
+    pub value: String,
+}
+
+/// Direction of a method argument.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Input argument, sent by the caller.
+    In,
+    /// Output argument, sent in the reply.
+    Out,
+}
+
+impl Direction {
+    /// Returns "in" or "out".
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+}
+
+/// A method or signal argument.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Arg {
+    /// The argument name, if any.
+    pub name: Option<String>,
+    /// The argument's type signature.
+    pub typ: Signature<'static>,
+    /// The direction, if specified. Method arguments default to `In` and signal arguments to `Out`.
+    pub direction: Option<Direction>,
+    /// Annotations on this argument.
+    pub annotations: Vec<Annotation>,
+}
+
+impl Arg {
+    /// Creates a new argument without annotations.
+    pub fn new<N: Into<String>>(name: Option<N>, typ: Signature<'static>, direction: Option<Direction>) -> Self {
+        Arg { name: name.map(Into::into), typ, direction, annotations: vec!() }
+    }
+}
+
+/// A method.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Method {
+    /// The method name.
+    pub name: String,
+    /// Input and output arguments, in the order they were declared.
+    pub args: Vec<Arg>,
+    /// Annotations on this method.
+    pub annotations: Vec<Annotation>,
+}
+
+impl Method {
+    /// Iterates over the input arguments.
+    pub fn in_args(&self) -> impl Iterator<Item=&Arg> {
+        self.args.iter().filter(|a| a.direction != Some(Direction::Out))
+    }
+
+    /// Iterates over the output arguments.
+    pub fn out_args(&self) -> impl Iterator<Item=&Arg> {
+        self.args.iter().filter(|a| a.direction == Some(Direction::Out))
+    }
+
+    /// The signature of all input arguments, concatenated.
+    pub fn in_signature(&self) -> String { self.in_args().map(|a| &*a.typ).collect() }
+
+    /// The signature of all output arguments, concatenated.
+    pub fn out_signature(&self) -> String { self.out_args().map(|a| &*a.typ).collect() }
+}
+
+/// A signal.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Signal {
+    /// The signal name.
+    pub name: String,
+    /// The signal's arguments.
+    pub args: Vec<Arg>,
+    /// Annotations on this signal.
+    pub annotations: Vec<Annotation>,
+}
+
+impl Signal {
+    /// The signature of all arguments, concatenated.
+    pub fn signature(&self) -> String { self.args.iter().map(|a| &*a.typ).collect() }
+}
+
+/// Whether a property can be read, written, or both.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Access {
+    /// The property is read-only.
+    Read,
+    /// The property is write-only.
+    Write,
+    /// The property can be both read and written.
+    ReadWrite,
+}
+
+impl Access {
+    /// Returns "read", "write" or "readwrite".
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::ReadWrite => "readwrite",
+        }
+    }
+
+    /// Whether the property can be read.
+    pub fn can_read(&self) -> bool { *self != Access::Write }
+
+    /// Whether the property can be written.
+    pub fn can_write(&self) -> bool { *self != Access::Read }
+}
+
+/// A property.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Property {
+    /// The property name.
+    pub name: String,
+    /// The property's type signature.
+    pub typ: Signature<'static>,
+    /// Whether this property can be read and/or written.
+    pub access: Access,
+    /// Annotations on this property.
+    pub annotations: Vec<Annotation>,
+}
+
+/// An interface, i e a collection of methods, signals and properties.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Interface {
+    /// The interface name, e g "org.freedesktop.DBus.Properties".
+    pub name: String,
+    /// The methods of this interface.
+    pub methods: Vec<Method>,
+    /// The signals of this interface.
+    pub signals: Vec<Signal>,
+    /// The properties of this interface.
+    pub properties: Vec<Property>,
+    /// Annotations on this interface.
+    pub annotations: Vec<Annotation>,
+}
+
+impl Interface {
+    /// Looks up a method by name.
+    pub fn method(&self, name: &str) -> Option<&Method> { self.methods.iter().find(|m| m.name == name) }
+
+    /// Looks up a signal by name.
+    pub fn signal(&self, name: &str) -> Option<&Signal> { self.signals.iter().find(|m| m.name == name) }
+
+    /// Looks up a property by name.
+    pub fn property(&self, name: &str) -> Option<&Property> { self.properties.iter().find(|m| m.name == name) }
+}
+
+/// An object, as returned by a call to "org.freedesktop.DBus.Introspectable.Introspect".
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Node {
+    /// The node name. The root node usually has no name, child nodes have a name relative
+    /// to their parent.
+    pub name: Option<String>,
+    /// The interfaces this object implements.
+    pub interfaces: Vec<Interface>,
+    /// Child nodes. These are usually empty except for their name; introspect them
+    /// separately to find out what they contain.
+    pub children: Vec<Node>,
+}
+
+enum Elem {
+    Node(Node),
+    Interface(Interface),
+    Method(Method),
+    Signal(Signal),
+    Property(Property),
+    Arg(Arg),
+    Ignored,
+}
+
+impl Elem {
+    fn annotations(&mut self) -> Option<&mut Vec<Annotation>> {
+        match self {
+            Elem::Interface(x) => Some(&mut x.annotations),
+            Elem::Method(x) => Some(&mut x.annotations),
+            Elem::Signal(x) => Some(&mut x.annotations),
+            Elem::Property(x) => Some(&mut x.annotations),
+            Elem::Arg(x) => Some(&mut x.annotations),
+            Elem::Node(_) | Elem::Ignored => None,
+        }
+    }
+}
+
+fn find_attr<'a>(a: &'a [xml::attribute::OwnedAttribute], n: &str) -> Option<&'a str> {
+    a.iter().find(|q| q.name.prefix.is_none() && q.name.local_name == n).map(|f| &*f.value)
+}
+
+fn req_attr<'a>(a: &'a [xml::attribute::OwnedAttribute], n: &str, elem: &str) -> Result<&'a str, ParseError> {
+    find_attr(a, n).ok_or_else(|| ParseError(format!("{} without \"{}\" attribute", elem, n)))
+}
+
+fn sig_attr(a: &[xml::attribute::OwnedAttribute], elem: &str) -> Result<Signature<'static>, ParseError> {
+    let s = req_attr(a, "type", elem)?;
+    Signature::new(s).map_err(|_| ParseError(format!("{} with invalid type {:?}", elem, s)))
+}
+
+fn start_elem(name: &str, a: &[xml::attribute::OwnedAttribute], parent: Option<&Elem>) -> Result<Elem, ParseError> {
+    let misplaced = || ParseError(format!("unexpected <{}>", name));
+    Ok(match (name, parent) {
+        ("node", None) | ("node", Some(Elem::Node(_))) => Elem::Node(Node {
+            name: find_attr(a, "name").map(Into::into), ..Default::default()
+        }),
+        ("interface", Some(Elem::Node(_))) => Elem::Interface(Interface {
+            name: req_attr(a, "name", "interface")?.into(), ..Default::default()
+        }),
+        ("method", Some(Elem::Interface(_))) => Elem::Method(Method {
+            name: req_attr(a, "name", "method")?.into(), ..Default::default()
+        }),
+        ("signal", Some(Elem::Interface(_))) => Elem::Signal(Signal {
+            name: req_attr(a, "name", "signal")?.into(), ..Default::default()
+        }),
+        ("property", Some(Elem::Interface(_))) => Elem::Property(Property {
+            name: req_attr(a, "name", "property")?.into(),
+            typ: sig_attr(a, "property")?,
+            access: match req_attr(a, "access", "property")? {
+                "read" => Access::Read,
+                "write" => Access::Write,
+                "readwrite" => Access::ReadWrite,
+                x => return Err(ParseError(format!("property with invalid access {:?}", x))),
+            },
+            annotations: vec!(),
+        }),
+        ("arg", Some(Elem::Method(_))) | ("arg", Some(Elem::Signal(_))) => Elem::Arg(Arg {
+            name: find_attr(a, "name").map(Into::into),
+            typ: sig_attr(a, "arg")?,
+            direction: match find_attr(a, "direction") {
+                None => None,
+                Some("in") => Some(Direction::In),
+                Some("out") => Some(Direction::Out),
+                Some(x) => return Err(ParseError(format!("arg with invalid direction {:?}", x))),
+            },
+            annotations: vec!(),
+        }),
+        ("annotation", Some(_)) => Elem::Ignored,
+        ("node", _) | ("interface", _) | ("method", _) | ("signal", _) | ("property", _) | ("arg", _) |
+        ("annotation", _) => return Err(misplaced()),
+        // Unknown elements, such as documentation, are skipped together with their content.
+        _ => Elem::Ignored,
+    })
+}
+
+fn end_elem(elem: Elem, parent: &mut Elem) {
+    match (elem, parent) {
+        (Elem::Node(x), Elem::Node(p)) => p.children.push(x),
+        (Elem::Interface(x), Elem::Node(p)) => p.interfaces.push(x),
+        (Elem::Method(x), Elem::Interface(p)) => p.methods.push(x),
+        (Elem::Signal(x), Elem::Interface(p)) => p.signals.push(x),
+        (Elem::Property(x), Elem::Interface(p)) => p.properties.push(x),
+        (Elem::Arg(x), Elem::Method(p)) => p.args.push(x),
+        (Elem::Arg(x), Elem::Signal(p)) => p.args.push(x),
+        _ => {},
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => r += "&amp;",
+            '<' => r += "&lt;",
+            '>' => r += "&gt;",
+            '"' => r += "&quot;",
+            '\'' => r += "&apos;",
+            _ => r.push(c),
+        }
+    }
+    r
+}
+
+fn write_annotations(r: &mut String, anns: &[Annotation], indent: &str) {
+    for a in anns {
+        *r += &format!("{}<annotation name=\"{}\" value=\"{}\"/>\n", indent, escape(&a.name), escape(&a.value));
+    }
+}
+
+fn write_args(r: &mut String, args: &[Arg], indent: &str) {
+    for a in args {
+        *r += &format!("{}<arg", indent);
+        if let Some(n) = &a.name { *r += &format!(" name=\"{}\"", escape(n)) }
+        *r += &format!(" type=\"{}\"", escape(&a.typ));
+        if let Some(d) = a.direction { *r += &format!(" direction=\"{}\"", d.as_str()) }
+        if a.annotations.is_empty() {
+            *r += "/>\n";
+        } else {
+            *r += ">\n";
+            write_annotations(r, &a.annotations, &format!("{}  ", indent));
+            *r += &format!("{}</arg>\n", indent);
+        }
+    }
+}
+
+impl Interface {
+    fn write_xml(&self, r: &mut String, indent: &str) {
+        let i2 = format!("{}  ", indent);
+        let i3 = format!("{}    ", indent);
+        *r += &format!("{}<interface name=\"{}\">\n", indent, escape(&self.name));
+        for m in &self.methods {
+            *r += &format!("{}<method name=\"{}\">\n", i2, escape(&m.name));
+            write_args(r, &m.args, &i3);
+            write_annotations(r, &m.annotations, &i3);
+            *r += &format!("{}</method>\n", i2);
+        }
+        for s in &self.signals {
+            *r += &format!("{}<signal name=\"{}\">\n", i2, escape(&s.name));
+            write_args(r, &s.args, &i3);
+            write_annotations(r, &s.annotations, &i3);
+            *r += &format!("{}</signal>\n", i2);
+        }
+        for p in &self.properties {
+            *r += &format!("{}<property name=\"{}\" type=\"{}\" access=\"{}\"", i2, escape(&p.name),
+                escape(&p.typ), p.access.as_str());
+            if p.annotations.is_empty() {
+                *r += "/>\n";
+            } else {
+                *r += ">\n";
+                write_annotations(r, &p.annotations, &i3);
+                *r += &format!("{}</property>\n", i2);
+            }
+        }
+        write_annotations(r, &self.annotations, &i2);
+        *r += &format!("{}</interface>\n", indent);
+    }
+}
+
+impl Node {
+    /// Parses introspection XML.
+    ///
+    /// Elements not covered by the introspection format, such as embedded documentation,
+    /// are skipped.
+    pub fn from_xml(xml: &str) -> Result<Node, ParseError> {
+        use xml::reader::{EventReader, XmlEvent};
+        let mut stack: Vec<Elem> = vec!();
+        let mut root = None;
+        for e in EventReader::from_str(xml) {
+            match e.map_err(|e| ParseError(e.to_string()))? {
+                XmlEvent::StartElement { name, attributes, .. } => {
+                    if root.is_some() { return Err(ParseError("content after the root node".into())) }
+                    let elem = if name.prefix.is_some() || matches!(stack.last(), Some(Elem::Ignored)) {
+                        Elem::Ignored
+                    } else {
+                        start_elem(&name.local_name, &attributes, stack.last())?
+                    };
+                    if let (Elem::Ignored, Some(p)) = (&elem, stack.last_mut()) {
+                        if name.prefix.is_none() && name.local_name == "annotation" {
+                            let a = Annotation {
+                                name: req_attr(&attributes, "name", "annotation")?.into(),
+                                value: req_attr(&attributes, "value", "annotation")?.into(),
+                            };
+                            if let Some(anns) = p.annotations() { anns.push(a) }
+                        }
+                    }
+                    stack.push(elem);
+                }
+                XmlEvent::EndElement { .. } => {
+                    let elem = stack.pop().unwrap();
+                    match stack.last_mut() {
+                        Some(p) => end_elem(elem, p),
+                        None => if let Elem::Node(n) = elem { root = Some(n) },
+                    }
+                }
+                _ => {},
+            }
+        }
+        root.ok_or_else(|| ParseError("no root node".into()))
+    }
+
+    /// Serializes this node to introspection XML, including the DOCTYPE header.
+    pub fn to_xml(&self) -> String {
+        let mut r = String::from(concat!(
+            "<!DOCTYPE node PUBLIC \"-//freedesktop//DTD D-BUS Object Introspection 1.0//EN\"\n",
+            " \"http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd\">\n"));
+        self.write_xml(&mut r, "");
+        r
+    }
+
+    fn write_xml(&self, r: &mut String, indent: &str) {
+        *r += &format!("{}<node", indent);
+        if let Some(n) = &self.name { *r += &format!(" name=\"{}\"", escape(n)) }
+        if self.interfaces.is_empty() && self.children.is_empty() {
+            *r += "/>\n";
+            return;
+        }
+        *r += ">\n";
+        let i2 = format!("{}  ", indent);
+        for i in &self.interfaces { i.write_xml(r, &i2) }
+        for c in &self.children { c.write_xml(r, &i2) }
+        *r += &format!("{}</node>\n", indent);
+    }
+
+    /// Looks up an interface by name.
+    pub fn interface(&self, name: &str) -> Option<&Interface> {
+        self.interfaces.iter().find(|i| i.name == name)
+    }
+
+    /// Looks up a child node by name.
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|c| c.name.as_deref() == Some(name))
+    }
+}
+
+impl<'a, T: crate::blocking::BlockingSender, C: std::ops::Deref<Target=T>> crate::blocking::Proxy<'a, C> {
+    /// Calls "org.freedesktop.DBus.Introspectable.Introspect" on the remote object and parses the result.
+    ///
+    /// This method requires the `introspect` feature.
+    pub fn introspect_node(&self) -> Result<Node, Error> {
+        let (xml,): (String,) = self.method_call("org.freedesktop.DBus.Introspectable", "Introspect", ())?;
+        Ok(Node::from_xml(&xml)?)
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<'a, T: crate::nonblock::NonblockReply, C: std::ops::Deref<Target=T>> crate::nonblock::Proxy<'a, C> {
+    /// Calls "org.freedesktop.DBus.Introspectable.Introspect" on the remote object and parses the result.
+    ///
+    /// This method requires the `introspect` feature.
+    pub fn introspect_node(&self) -> crate::nonblock::MethodReply<Node> {
+        self.method_call("org.freedesktop.DBus.Introspectable", "Introspect", ())
+            .and_then(|(xml,): (String,)| Ok(Node::from_xml(&xml)?))
+    }
+}
+
+/// A proxy for calling methods and accessing properties that are unknown at compile time.
+///
+/// The proxy keeps a copy of the remote object's introspection data, and checks method calls
+/// and property accesses against it before anything is sent. This way, a misspelled
+/// method name or an argument of the wrong type results in a descriptive local error instead
+/// of a reply from the remote side.
+///
+/// `P` is either a `blocking::Proxy` or a `nonblock::Proxy`. Create one with `into_dynamic`
+/// on either of these.
+#[derive(Debug, Clone)]
+pub struct DynamicProxy<P> {
+    proxy: P,
+    node: Node,
+}
+
+struct RefArgs<'b>(&'b [Box<dyn RefArg>]);
+
+impl AppendAll for RefArgs<'_> {
+    fn append(&self, ia: &mut IterAppend) {
+        for a in self.0 { a.append(ia) }
+    }
+}
+
+struct AllRefArgs(Vec<Box<dyn RefArg>>);
+
+impl ReadAll for AllRefArgs {
+    fn read(i: &mut Iter) -> Result<Self, TypeMismatchError> { Ok(AllRefArgs(i.collect())) }
+}
+
+const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+
+impl<P> DynamicProxy<P> {
+    /// Creates a new dynamic proxy from an existing proxy and its introspection data.
+    pub fn new(proxy: P, node: Node) -> Self { DynamicProxy { proxy, node } }
+
+    /// The underlying proxy.
+    pub fn proxy(&self) -> &P { &self.proxy }
+
+    /// The cached introspection data.
+    pub fn node(&self) -> &Node { &self.node }
+
+    /// Returns the underlying proxy and the cached introspection data.
+    pub fn into_inner(self) -> (P, Node) { (self.proxy, self.node) }
+
+    fn interface(&self, i: &str) -> Result<&Interface, Error> {
+        self.node.interface(i).ok_or_else(|| Error::new_custom("org.freedesktop.DBus.Error.UnknownInterface",
+            &format!("Interface {:?} not found in introspection data", i)))
+    }
+
+    /// Checks that a method exists, and that `args` match its input signature.
+    pub fn check_method_call(&self, i: &str, m: &str, args: &[Box<dyn RefArg>]) -> Result<&Method, Error> {
+        let method = self.interface(i)?.method(m).ok_or_else(|| Error::new_custom("org.freedesktop.DBus.Error.UnknownMethod",
+            &format!("Method {:?} not found on interface {:?}", m, i)))?;
+        let expected: Vec<_> = method.in_args().collect();
+        if expected.len() != args.len() {
+            return Err(Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs",
+                &format!("Method {:?} expects {} argument(s) of signature {:?}, got {}",
+                m, expected.len(), method.in_signature(), args.len())));
+        }
+        for (idx, (e, a)) in expected.iter().zip(args).enumerate() {
+            let sig = a.signature();
+            if sig != e.typ {
+                return Err(Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs",
+                    &format!("Argument {} ({}) of method {:?} should be of type {:?}, got {:?}",
+                    idx, e.name.as_deref().unwrap_or("unnamed"), m, &*e.typ, &*sig)));
+            }
+        }
+        Ok(method)
+    }
+
+    fn property(&self, i: &str, p: &str) -> Result<&Property, Error> {
+        self.interface(i)?.property(p).ok_or_else(|| Error::new_custom("org.freedesktop.DBus.Error.UnknownProperty",
+            &format!("Property {:?} not found on interface {:?}", p, i)))
+    }
+
+    /// Checks that a property exists and is readable.
+    pub fn check_get(&self, i: &str, p: &str) -> Result<&Property, Error> {
+        let prop = self.property(i, p)?;
+        if !prop.access.can_read() {
+            return Err(Error::new_custom("org.freedesktop.DBus.Error.AccessDenied",
+                &format!("Property {:?} is write-only", p)));
+        }
+        Ok(prop)
+    }
+
+    /// Checks that a property exists, is writable, and that `value` has the right type.
+    pub fn check_set(&self, i: &str, p: &str, value: &dyn RefArg) -> Result<&Property, Error> {
+        let prop = self.property(i, p)?;
+        if !prop.access.can_write() {
+            return Err(Error::new_custom("org.freedesktop.DBus.Error.PropertyReadOnly",
+                &format!("Property {:?} is read-only", p)));
+        }
+        let sig = value.signature();
+        if sig != prop.typ {
+            return Err(Error::new_custom("org.freedesktop.DBus.Error.InvalidArgs",
+                &format!("Property {:?} should be of type {:?}, got {:?}", p, &*prop.typ, &*sig)));
+        }
+        Ok(prop)
+    }
+}
+
+impl<'a, C> crate::blocking::Proxy<'a, C> {
+    /// Introspects the remote object, and wraps this proxy in a `DynamicProxy`.
+    ///
+    /// This method requires the `introspect` feature.
+    pub fn into_dynamic<T: crate::blocking::BlockingSender>(self) -> Result<DynamicProxy<Self>, Error>
+    where C: std::ops::Deref<Target=T> {
+        let node = self.introspect_node()?;
+        Ok(DynamicProxy::new(self, node))
+    }
+}
+
+impl<'a, T: crate::blocking::BlockingSender, C: std::ops::Deref<Target=T>> DynamicProxy<crate::blocking::Proxy<'a, C>> {
+    /// Introspects the remote object again, replacing the cached introspection data.
+    pub fn refresh(&mut self) -> Result<(), Error> {
+        self.node = self.proxy.introspect_node()?;
+        Ok(())
+    }
+
+    /// Checks the method call against the introspection data, then calls the method
+    /// and blocks waiting for the reply.
+    pub fn method_call(&self, i: &str, m: &str, args: &[Box<dyn RefArg>]) -> Result<Vec<Box<dyn RefArg>>, Error> {
+        self.check_method_call(i, m, args)?;
+        let r: AllRefArgs = self.proxy.method_call(i, m, RefArgs(args))?;
+        Ok(r.0)
+    }
+
+    /// Checks that the property is readable, then gets its value.
+    pub fn get(&self, i: &str, p: &str) -> Result<Box<dyn RefArg>, Error> {
+        self.check_get(i, p)?;
+        let (v,): (Variant<Box<dyn RefArg>>,) = self.proxy.method_call(PROPERTIES, "Get", (i, p))?;
+        Ok(v.0)
+    }
+
+    /// Checks that the property is writable and of the right type, then sets its value.
+    pub fn set(&self, i: &str, p: &str, value: Box<dyn RefArg>) -> Result<(), Error> {
+        self.check_set(i, p, &*value)?;
+        self.proxy.method_call(PROPERTIES, "Set", (i, p, Variant(value)))
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<'a, C> crate::nonblock::Proxy<'a, C> {
+    /// Introspects the remote object, and wraps this proxy in a `DynamicProxy`.
+    ///
+    /// This method requires the `introspect` feature.
+    pub async fn into_dynamic<T: crate::nonblock::NonblockReply>(self) -> Result<DynamicProxy<Self>, Error>
+    where C: std::ops::Deref<Target=T> {
+        let node = self.introspect_node().await?;
+        Ok(DynamicProxy::new(self, node))
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<'a, T: crate::nonblock::NonblockReply, C: std::ops::Deref<Target=T>> DynamicProxy<crate::nonblock::Proxy<'a, C>> {
+    /// Introspects the remote object again, replacing the cached introspection data.
+    pub async fn refresh(&mut self) -> Result<(), Error> {
+        self.node = self.proxy.introspect_node().await?;
+        Ok(())
+    }
+
+    /// Checks the method call against the introspection data, then calls the method.
+    ///
+    /// If the check fails, the returned future resolves to the error immediately.
+    pub fn method_call(&self, i: &str, m: &str, args: &[Box<dyn RefArg>]) -> crate::nonblock::MethodReply<Vec<Box<dyn RefArg>>> {
+        if let Err(e) = self.check_method_call(i, m, args) {
+            return crate::nonblock::MethodReply::new(async { Err(e) });
+        }
+        self.proxy.method_call(i, m, RefArgs(args)).and_then(|r: AllRefArgs| Ok(r.0))
+    }
+
+    /// Checks that the property is readable, then gets its value.
+    pub fn get(&self, i: &str, p: &str) -> crate::nonblock::MethodReply<Box<dyn RefArg>> {
+        if let Err(e) = self.check_get(i, p) {
+            return crate::nonblock::MethodReply::new(async { Err(e) });
+        }
+        self.proxy.method_call(PROPERTIES, "Get", (i, p))
+            .and_then(|(v,): (Variant<Box<dyn RefArg>>,)| Ok(v.0))
+    }
+
+    /// Checks that the property is writable and of the right type, then sets its value.
+    pub fn set(&self, i: &str, p: &str, value: Box<dyn RefArg>) -> crate::nonblock::MethodReply<()> {
+        if let Err(e) = self.check_set(i, p, &*value) {
+            return crate::nonblock::MethodReply::new(async { Err(e) });
+        }
+        self.proxy.method_call(PROPERTIES, "Set", (i, p, Variant(value)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    static SAMPLE: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node name="/com/example/sample_object0">
+  <interface name="com.example.SampleInterface0">
+    <method name="Frobate">
+      <arg name="foo" type="i" direction="in"/>
+      <arg name="bar" type="s" direction="out"/>
+      <arg name="baz" type="a{us}" direction="out"/>
+      <annotation name="org.freedesktop.DBus.Deprecated" value="true"/>
+    </method>
+    <method name="Bazify">
+      <arg name="bar" type="(iiu)" direction="in"/>
+      <arg name="bar" type="v" direction="out"/>
+    </method>
+    <signal name="Changed">
+      <arg name="new_value" type="b"/>
+    </signal>
+    <property name="Bar" type="y" access="readwrite">
+      <annotation name="org.freedesktop.DBus.Property.EmitsChangedSignal" value="invalidates"/>
+    </property>
+    <doc:doc xmlns:doc="http://www.freedesktop.org/dbus/1.0/doc.dtd"><doc:summary>Ignored</doc:summary></doc:doc>
+  </interface>
+  <node name="child_of_sample_object"/>
+  <node name="another_child_of_sample_object">
+    <interface name="com.example.Other">
+      <annotation name="a &amp; b" value="&lt;&quot;&gt;"/>
+    </interface>
+  </node>
+</node>"#;
+
+    #[test]
+    fn parse() {
+        let n = Node::from_xml(SAMPLE).unwrap();
+        assert_eq!(n.name.as_deref(), Some("/com/example/sample_object0"));
+        let i = n.interface("com.example.SampleInterface0").unwrap();
+        let m = i.method("Frobate").unwrap();
+        assert_eq!(m.in_signature(), "i");
+        assert_eq!(m.out_signature(), "sa{us}");
+        assert_eq!(m.annotations[0].name, "org.freedesktop.DBus.Deprecated");
+        assert_eq!(i.signal("Changed").unwrap().signature(), "b");
+        let p = i.property("Bar").unwrap();
+        assert_eq!(p.access, Access::ReadWrite);
+        assert_eq!(&*p.typ, "y");
+        assert_eq!(p.annotations[0].value, "invalidates");
+        assert_eq!(n.children.len(), 2);
+        assert!(n.child("child_of_sample_object").unwrap().interfaces.is_empty());
+        let other = &n.child("another_child_of_sample_object").unwrap().interfaces[0];
+        assert_eq!(other.annotations[0], Annotation { name: "a & b".into(), value: "<\">".into() });
+    }
+
+    #[test]
+    fn roundtrip() {
+        let n = Node::from_xml(SAMPLE).unwrap();
+        let xml = n.to_xml();
+        assert_eq!(Node::from_xml(&xml).unwrap(), n);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Node::from_xml("").is_err());
+        assert!(Node::from_xml("<node><method name=\"Foo\"/></node>").is_err());
+        assert!(Node::from_xml("<node><interface/></node>").is_err());
+        assert!(Node::from_xml(r#"<node><interface name="a.b"><property name="P" type="ii" access="read"/></interface></node>"#).is_err());
+        assert!(Node::from_xml(r#"<node><interface name="a.b"><property name="P" type="i" access="none"/></interface></node>"#).is_err());
+        assert!(Node::from_xml(r#"<node><interface name="a.b"><method name="M"><arg type="i" direction="up"/></method></interface></node>"#).is_err());
+    }
+
+    #[test]
+    fn introspect_bus() {
+        use crate::blocking::{Connection, Proxy};
+        let c = Connection::new_session().unwrap();
+        let p = Proxy::new("org.freedesktop.DBus", "/org/freedesktop/DBus", std::time::Duration::from_secs(5), &c);
+        let n = p.introspect_node().unwrap();
+        let i = n.interface("org.freedesktop.DBus").unwrap();
+        assert_eq!(i.method("GetNameOwner").unwrap().in_signature(), "s");
+    }
+
+    #[test]
+    fn dynamic_proxy() {
+        use crate::blocking::{Connection, Proxy};
+        let c = Connection::new_session().unwrap();
+        let p = Proxy::new("org.freedesktop.DBus", "/org/freedesktop/DBus", std::time::Duration::from_secs(5), &c);
+        let dp = p.into_dynamic().unwrap();
+        let r = dp.method_call("org.freedesktop.DBus", "NameHasOwner", &[Box::new("org.freedesktop.DBus".to_string())]).unwrap();
+        assert_eq!(r[0].as_i64(), Some(1));
+
+        let e = dp.method_call("org.freedesktop.DBus", "NameHasOwnr", &[]).unwrap_err();
+        assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.UnknownMethod"));
+        let e = dp.method_call("org.freedesktop.DBus", "NameHasOwner", &[Box::new(5u32)]).unwrap_err();
+        assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.InvalidArgs"));
+        assert!(e.message().unwrap().contains("should be of type \"s\", got \"u\""));
+        let e = dp.method_call("org.freedesktop.DBus", "NameHasOwner", &[]).unwrap_err();
+        assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.InvalidArgs"));
+        let e = dp.method_call("org.example.Missing", "Foo", &[]).unwrap_err();
+        assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.UnknownInterface"));
+        let e = dp.get("org.freedesktop.DBus", "NoSuchProperty").unwrap_err();
+        assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.UnknownProperty"));
+    }
+
+    #[test]
+    fn dynamic_proxy_checks() {
+        let n = Node::from_xml(SAMPLE).unwrap();
+        let dp = DynamicProxy::new((), n);
+        let i = "com.example.SampleInterface0";
+        assert!(dp.check_method_call(i, "Frobate", &[Box::new(5i32)]).is_ok());
+        assert!(dp.check_set(i, "Bar", &7u8).is_ok());
+        let e = dp.check_set(i, "Bar", &7u16).unwrap_err();
+        assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.InvalidArgs"));
+        let mut n = dp.into_inner().1;
+        n.interfaces[0].properties[0].access = Access::Read;
+        let dp = DynamicProxy::new((), n);
+        let e = dp.check_set(i, "Bar", &7u8).unwrap_err();
+        assert_eq!(e.name(), Some("org.freedesktop.DBus.Error.PropertyReadOnly"));
+    }
+}
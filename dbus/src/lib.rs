@@ -8,7 +8,7 @@
 //! In addition to the API documentation, which you're currently reading, you might want to
 //! look in the examples directory, which contains many examples and some additional documents.
 //! README.md also contains a few quick "getting started" examples (as well as information about
-//! the `futures` and `no-string-validation` features).
+//! the `futures`, `introspect`, `derive` and `no-string-validation` features).
 //!
 //! In addition to this crate, there are some companion crates:
 //!  * dbus-tokio for integrating D-Bus with [Tokio](http://tokio.rs)
@@ -33,7 +33,10 @@ pub mod message;
 pub mod ffidisp;
 
 mod error;
-pub use error::{Error, MethodErr, Result};
+pub use error::{Error, MethodErr, Result, DBusError, StandardError};
+
+#[cfg(feature = "derive")]
+pub use dbus_derive::DBusError;
 
 pub mod channel;
 
@@ -49,6 +52,11 @@ pub use crate::strings::{Signature, Path};
 
 pub mod arg;
 
+pub mod capture;
+
+#[cfg(feature = "introspect")]
+pub mod introspect;
+
 // pub mod tree;
 
 static INITDBUS: std::sync::Once = std::sync::Once::new();
@@ -196,6 +196,23 @@ impl Message {
         unsafe { ffi::dbus_message_set_auto_start(self.msg, if v { 1 } else { 0 }) }
     }
 
+    /// Returns true if the caller is prepared to wait for interactive authorization, e g a
+    /// password prompt, before the method call is answered.
+    ///
+    /// Needs libdbus 1.8.10 or later.
+    #[cfg(feature = "interactive-authorization")]
+    pub fn get_allow_interactive_authorization(&self) -> bool {
+        unsafe { ffi::dbus_message_get_allow_interactive_authorization(self.msg) != 0 }
+    }
+
+    /// Sets whether or not the caller is prepared to wait for interactive authorization.
+    ///
+    /// Defaults to false. Needs libdbus 1.8.10 or later.
+    #[cfg(feature = "interactive-authorization")]
+    pub fn set_allow_interactive_authorization(&mut self, v: bool) {
+        unsafe { ffi::dbus_message_set_allow_interactive_authorization(self.msg, if v { 1 } else { 0 }) }
+    }
+
     /// Add one or more MessageItems to this Message.
     ///
     /// Note: using `append1`, `append2` or `append3` might be faster, especially for large arrays.
@@ -555,6 +572,13 @@ mod test {
         assert!(!m.get_no_reply());
         m.set_no_reply(true);
         assert!(m.get_no_reply());
+
+        #[cfg(feature = "interactive-authorization")]
+        {
+            assert!(!m.get_allow_interactive_authorization());
+            m.set_allow_interactive_authorization(true);
+            assert!(m.get_allow_interactive_authorization());
+        }
     }
 
     #[test]
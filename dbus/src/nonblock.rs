@@ -35,6 +35,9 @@ mod generated_org_freedesktop_standard_interfaces;
 #[allow(dead_code)]
 mod generated_org_freedesktop_dbus;
 
+mod monitor;
+pub use monitor::{Monitor, MonitorMode};
+
 
 /// This module contains some standard interfaces and an easy way to call them.
 ///
@@ -572,7 +575,7 @@ pub struct MethodReply<T>(pin::Pin<Box<dyn Future<Output=Result<T, Error>> + Sen
 
 impl<T> MethodReply<T> {
     /// Creates a new method reply from a future.
-    fn new<Fut: Future<Output=Result<T, Error>> + Send + 'static>(fut: Fut) -> Self {
+    pub (crate) fn new<Fut: Future<Output=Result<T, Error>> + Send + 'static>(fut: Fut) -> Self {
         MethodReply(Box::pin(fut))
     }
 }
@@ -602,4 +605,5 @@ fn test_conn_send_sync() {
     is_send::<SyncConnection>();
     is_sync::<SyncConnection>();
     is_send::<MsgMatch>();
+    is_send::<Monitor<SyncConnection>>();
 }
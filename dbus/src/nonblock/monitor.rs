@@ -0,0 +1,122 @@
+use std::sync::Arc;
+use std::{pin, task};
+use std::time::Duration;
+use futures_util::stream::Stream;
+use futures_channel::mpsc::UnboundedReceiver;
+use crate::{Error, Message};
+use crate::channel::{Channel, MatchingReceiver, Sender, Token};
+use crate::message::MatchRule;
+use crate::blocking::monitor::{is_own_message, is_disconnected, eavesdrop_rules, plain_rules};
+pub use crate::blocking::MonitorMode;
+use super::{NonblockReply, Proxy};
+
+/// A connection that receives a copy of the messages passing through the bus, like `dbus-monitor`.
+///
+/// This is the async version of `blocking::Monitor`, see that struct for how monitoring is set up.
+/// Messages are received as a stream, which ends when the connection is closed.
+///
+/// Once the connection is a monitor, the bus does not allow it to send messages, so it
+/// should not be used for anything else afterwards. Every incoming message is delivered to the
+/// monitor, and no other match or method call handlers on the connection will be called.
+///
+/// When the monitor is dropped, it stops receiving, and match rules it added in the
+/// `Eavesdrop` and `Match` modes are removed from the bus. A connection in `BecomeMonitor` mode
+/// stays a monitor (the bus provides no way back), so it should be dropped as well.
+///
+/// # Example
+///
+/// ```ignore
+/// let (resource, conn) = dbus_tokio::connection::new_session_sync()?;
+/// tokio::spawn(resource);
+/// let mut monitor = Monitor::new(conn, &[]).await?;
+/// while let Some(msg) = monitor.next().await {
+///     println!("{:?}", msg);
+/// }
+/// ```
+pub struct Monitor<C: MatchingReceiver + Sender> {
+    conn: Arc<C>,
+    token: Token,
+    mode: MonitorMode,
+    receiver: UnboundedReceiver<Message>,
+    // Match rules added to the bus, to be removed on drop
+    added: Vec<String>,
+}
+
+impl<C> Monitor<C>
+where C: NonblockReply + MatchingReceiver<F = Box<dyn FnMut(Message, &C) -> bool + Send>> + Sender + AsRef<Channel>
+{
+    /// Starts monitoring the bus on a connection.
+    pub async fn new(conn: Arc<C>, rules: &[MatchRule<'_>]) -> Result<Self, Error> {
+        let (sender, receiver) = futures_channel::mpsc::unbounded();
+        let unique_name = AsRef::<Channel>::as_ref(&*conn).unique_name().map(|s| s.to_string());
+        // Everything must be caught by the monitor: a monitor is not allowed to send
+        // the error replies that unhandled method calls would otherwise get.
+        let token = conn.start_receive(MatchRule::new(), Box::new(move |msg, _| {
+            if is_disconnected(&msg) { return false }
+            is_own_message(&msg, unique_name.as_deref()) || sender.unbounded_send(msg).is_ok()
+        }));
+        let proxy = Proxy::new("org.freedesktop.DBus", "/org/freedesktop/DBus", Duration::from_secs(5), &*conn);
+        let strs: Vec<_> = rules.iter().map(|r| r.match_str()).collect();
+        let r: Result<(), _> = proxy.method_call("org.freedesktop.DBus.Monitoring", "BecomeMonitor", (strs, 0u32)).await;
+        let mut added = vec!();
+        let mode = if r.is_ok() { MonitorMode::BecomeMonitor } else {
+            let proxy = &proxy;
+            // Returns the rules added, also when failing half-way
+            let add_all = |strs: Vec<String>| async move {
+                let mut done = vec!();
+                for s in strs {
+                    if let Err(e) = proxy.method_call::<(), _, _, _>("org.freedesktop.DBus", "AddMatch", (&s,)).await {
+                        return Err((e, done));
+                    }
+                    done.push(s);
+                }
+                Ok(done)
+            };
+            match add_all(eavesdrop_rules(rules)).await {
+                Ok(done) => { added = done; MonitorMode::Eavesdrop },
+                Err((_, done)) => {
+                    remove_matches(&*conn, &done);
+                    match add_all(plain_rules(rules)).await {
+                        Ok(done) => { added = done; MonitorMode::Match },
+                        Err((e, done)) => {
+                            remove_matches(&*conn, &done);
+                            conn.stop_receive(token);
+                            return Err(e);
+                        }
+                    }
+                }
+            }
+        };
+        Ok(Monitor { conn, token, mode, receiver, added })
+    }
+
+    /// How messages are received.
+    pub fn mode(&self) -> MonitorMode { self.mode }
+
+    /// Gets the underlying connection.
+    pub fn connection(&self) -> &Arc<C> { &self.conn }
+}
+
+// Sends RemoveMatch calls without waiting for the replies, as this happens on drop.
+fn remove_matches<C: Sender>(conn: &C, rules: &[String]) {
+    for s in rules {
+        let mut msg = Message::new_method_call("org.freedesktop.DBus", "/org/freedesktop/DBus", "org.freedesktop.DBus", "RemoveMatch")
+            .unwrap().append1(s);
+        msg.set_no_reply(true);
+        let _ = conn.send(msg);
+    }
+}
+
+impl<C: MatchingReceiver + Sender> Stream for Monitor<C> {
+    type Item = Message;
+    fn poll_next(mut self: pin::Pin<&mut Self>, cx: &mut task::Context) -> task::Poll<Option<Message>> {
+        pin::Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+impl<C: MatchingReceiver + Sender> Drop for Monitor<C> {
+    fn drop(&mut self) {
+        self.conn.stop_receive(self.token);
+        remove_matches(&*self.conn, &self.added);
+    }
+}
@@ -8,8 +8,11 @@
 use std::{str, fmt, ops, default, hash};
 use std::ffi::{CStr, CString};
 use std::borrow::{Borrow, Cow};
+use std::convert::TryFrom;
 use std::os::raw::c_char;
 
+pub use dbus_strings::{Type, BasicType};
+
 #[cfg(not(feature = "no-string-validation"))]
 use crate::Error;
 #[cfg(not(feature = "no-string-validation"))]
@@ -191,6 +194,54 @@ impl Signature<'static> {
     pub fn make<A: super::arg::Arg>() -> Signature<'static> { A::signature() }
 }
 
+impl<'m> Signature<'m> {
+    /// Parses this signature into a type tree for each of its complete types.
+    ///
+    /// # Panics
+    ///
+    /// If the no-string-validation feature is activated and this signature is not valid.
+    pub fn parse(&self) -> Vec<Type> {
+        <&dbus_strings::SignatureMulti>::try_from(self).unwrap().parse()
+    }
+
+    /// Parses this signature into a type tree, if it is a single complete type.
+    pub fn parse_single(&self) -> Option<Type> {
+        <&dbus_strings::SignatureSingle>::try_from(self).ok().map(|s| s.parse())
+    }
+}
+
+impl<'a> TryFrom<&'a Signature<'_>> for &'a dbus_strings::SignatureMulti {
+    type Error = dbus_strings::InvalidStringError;
+    fn try_from(s: &'a Signature<'_>) -> Result<Self, Self::Error> {
+        <dbus_strings::SignatureMulti as dbus_strings::StringLike>::new(s)
+    }
+}
+
+impl<'a> TryFrom<&'a Signature<'_>> for &'a dbus_strings::SignatureSingle {
+    type Error = dbus_strings::InvalidStringError;
+    fn try_from(s: &'a Signature<'_>) -> Result<Self, Self::Error> {
+        <dbus_strings::SignatureSingle as dbus_strings::StringLike>::new(s)
+    }
+}
+
+impl From<&dbus_strings::SignatureSingle> for Signature<'static> {
+    fn from(s: &dbus_strings::SignatureSingle) -> Signature<'static> {
+        Signature(Cow::Owned(format!("{}\0", s)))
+    }
+}
+
+impl From<dbus_strings::SignatureSingleBuf> for Signature<'static> {
+    fn from(s: dbus_strings::SignatureSingleBuf) -> Signature<'static> {
+        let mut s = s.into_inner();
+        s.push('\0');
+        Signature(Cow::Owned(s))
+    }
+}
+
+impl From<&Type> for Signature<'static> {
+    fn from(t: &Type) -> Signature<'static> { t.signature().into() }
+}
+
 /// A wrapper around a string that is guaranteed to be
 /// a valid D-Bus object path.
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
@@ -231,6 +282,115 @@ pub struct ErrorName<'a>(Cow<'a, str>);
 
 cstring_wrapper!(ErrorName, dbus_validate_error_name);
 
+#[doc(hidden)]
+pub use dbus_strings::validity as __validity;
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __string_literal {
+    ($t: ident, $validate: ident, $s: literal) => {{
+        const _: () = assert!($crate::strings::__validity::$validate($s.as_bytes()).is_ok(),
+            "{}", concat!("Invalid D-Bus ", stringify!($t), ": ", $s));
+        #[allow(unused_unsafe)]
+        unsafe { $crate::strings::$t::from_slice_unchecked(concat!($s, "\0")) }
+    }}
+}
+
+/// Creates a `Path<'static>` from a string literal, which is validated at compile time.
+///
+/// The result can be used in const contexts, too.
+///
+/// ```
+/// const PATH: dbus::Path<'static> = dbus::path!("/org/example/Foo");
+/// assert_eq!(&*PATH, "/org/example/Foo");
+/// ```
+///
+/// ```compile_fail
+/// let p = dbus::path!("/not/valid/");
+/// ```
+#[macro_export]
+macro_rules! path {
+    ($s: literal) => { $crate::__string_literal!(Path, is_valid_object_path, $s) }
+}
+
+/// Creates an `Interface<'static>` from a string literal, which is validated at compile time.
+///
+/// ```
+/// let i = dbus::interface!("org.freedesktop.DBus.Properties");
+/// assert_eq!(&*i, "org.freedesktop.DBus.Properties");
+/// ```
+///
+/// ```compile_fail
+/// let i = dbus::interface!("NoDots");
+/// ```
+#[macro_export]
+macro_rules! interface {
+    ($s: literal) => { $crate::__string_literal!(Interface, is_valid_interface_name, $s) }
+}
+
+/// Creates a `Member<'static>` from a string literal, which is validated at compile time.
+///
+/// ```
+/// let m = dbus::member!("GetAll");
+/// assert_eq!(&*m, "GetAll");
+/// ```
+///
+/// ```compile_fail
+/// let m = dbus::member!("Get.All");
+/// ```
+#[macro_export]
+macro_rules! member {
+    ($s: literal) => { $crate::__string_literal!(Member, is_valid_member_name, $s) }
+}
+
+/// Creates a `BusName<'static>` from a string literal, which is validated at compile time.
+///
+/// ```
+/// let b = dbus::bus_name!("org.freedesktop.DBus");
+/// assert_eq!(&*b, "org.freedesktop.DBus");
+/// ```
+///
+/// ```compile_fail
+/// let b = dbus::bus_name!("1.54");
+/// ```
+#[macro_export]
+macro_rules! bus_name {
+    ($s: literal) => { $crate::__string_literal!(BusName, is_valid_bus_name, $s) }
+}
+
+/// Creates an `ErrorName<'static>` from a string literal, which is validated at compile time.
+///
+/// ```
+/// let e = dbus::error_name!("org.freedesktop.DBus.Error.Failed");
+/// assert_eq!(&*e, "org.freedesktop.DBus.Error.Failed");
+/// ```
+///
+/// ```compile_fail
+/// let e = dbus::error_name!("Failed");
+/// ```
+#[macro_export]
+macro_rules! error_name {
+    ($s: literal) => { $crate::__string_literal!(ErrorName, is_valid_error_name, $s) }
+}
+
+/// Creates a `Signature<'static>` of zero or more complete types from a string literal,
+/// which is validated at compile time.
+///
+/// ```
+/// let s = dbus::signature!("a{sv}");
+/// assert_eq!(&*s, "a{sv}");
+/// let s = dbus::signature!("su");
+/// assert_eq!(s.parse().len(), 2);
+/// ```
+///
+/// ```compile_fail
+/// let s = dbus::signature!("a{vs}");
+/// ```
+#[macro_export]
+macro_rules! signature {
+    ($s: literal) => { $crate::__string_literal!(Signature, is_valid_signature_multi, $s) }
+}
+
 #[test]
 fn some_path() {
     let p1: Path = "/valid".into();
@@ -267,3 +427,35 @@ fn partial_eq_str() {
     assert!(Signature::new("s").unwrap() != "s\0");
     assert_eq!(Path::new("/hello").unwrap(), "/hello");
 }
+
+#[test]
+fn sig_type_tree() {
+    let s = Signature::make::<(u8, Vec<String>)>();
+    let t = s.parse_single().unwrap();
+    assert_eq!(s.parse(), vec!(t.clone()));
+    assert_eq!(t, Type::Struct(vec!(Type::Basic(BasicType::Byte),
+        Type::Array(Box::new(Type::Basic(BasicType::String))))));
+    assert_eq!(Signature::from(&t), s);
+    let single: &dbus_strings::SignatureSingle = TryFrom::try_from(&s).unwrap();
+    assert_eq!(Signature::from(single), s);
+    assert_eq!(Signature::from(single.to_owned()), s);
+
+    // Signatures received as arguments can contain any number of complete types
+    let multi = unsafe { Signature::from_slice_unchecked("a{sv}u\0") };
+    assert_eq!(multi.parse(), vec!(Type::Dict(BasicType::String, Box::new(Type::Variant)), Type::Basic(BasicType::UInt32)));
+    assert_eq!(multi.parse_single(), None);
+    let empty = unsafe { Signature::from_slice_unchecked("\0") };
+    assert_eq!(empty.parse(), vec!());
+    assert_eq!(empty.parse_single(), None);
+}
+
+#[test]
+fn literal_macros() {
+    const P: Path<'static> = crate::path!("/org/example");
+    assert_eq!(P, Path::new("/org/example").unwrap());
+    assert_eq!(crate::interface!("org.example.Foo"), Interface::new("org.example.Foo").unwrap());
+    assert_eq!(crate::member!("Bar"), Member::new("Bar").unwrap());
+    assert_eq!(crate::bus_name!(":1.54"), BusName::new(":1.54").unwrap());
+    assert_eq!(crate::error_name!("org.example.Error"), ErrorName::new("org.example.Error").unwrap());
+    assert_eq!(crate::signature!("(sa{sv})"), Signature::new("(sa{sv})").unwrap());
+}
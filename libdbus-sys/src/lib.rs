@@ -250,6 +250,8 @@ extern "C" {
     pub fn dbus_message_set_no_reply(message: *mut DBusMessage, no_reply: u32);
     pub fn dbus_message_get_auto_start(message: *mut DBusMessage) -> u32;
     pub fn dbus_message_set_auto_start(message: *mut DBusMessage, no_reply: u32);
+    pub fn dbus_message_get_allow_interactive_authorization(message: *mut DBusMessage) -> u32;
+    pub fn dbus_message_set_allow_interactive_authorization(message: *mut DBusMessage, allow: u32);
     pub fn dbus_message_copy(message: *const DBusMessage) -> *mut DBusMessage;
 
     pub fn dbus_message_iter_append_basic(iter: *mut DBusMessageIter, t: c_int, value: *const c_void) -> u32;